@@ -28,7 +28,7 @@
 extern crate bitflags;
 
 // IMPORTS
-use core::{slice, str};
+use core::{mem, slice, str};
 
 
 bitflags! {
@@ -90,6 +90,11 @@ impl Multiboot {
 		Self { header: & *(address as *const MultibootHeader) }
 	}
 
+	/// Returns the size in bytes of the Multiboot information structure itself, without the data it points to.
+	pub fn info_size(&self) -> usize {
+		mem::size_of::<MultibootHeader>()
+	}
+
 	pub fn command_line_address(&self) -> Option<usize> {
 		if {self.header.flags}.contains(Flags::MULTIBOOT_INFO_CMDLINE) {
 			Some(self.header.cmdline as usize)
@@ -133,6 +138,11 @@ impl Multiboot {
 		}
 	}
 
+	/// Returns the size in bytes of the memory map buffer at memory_map_address.
+	pub fn memory_map_length(&self) -> usize {
+		self.header.mmap_length as usize
+	}
+
 	pub fn memory_map(&self) -> Option<MemoryMapIter> {
 		self.memory_map_address().map(|address|
 			MemoryMapIter {
@@ -141,6 +151,42 @@ impl Multiboot {
 			}
 		)
 	}
+
+	pub fn framebuffer(&self) -> Option<Framebuffer> {
+		if {self.header.flags}.contains(Flags::MULTIBOOT_INFO_FRAMEBUFFER_INFO) {
+			Some(Framebuffer {
+				address: self.header.framebuffer_addr as usize,
+				pitch: self.header.framebuffer_pitch as usize,
+				width: self.header.framebuffer_width as usize,
+				height: self.header.framebuffer_height as usize,
+				bpp: self.header.framebuffer_bpp,
+				framebuffer_type: self.header.framebuffer_type,
+			})
+		} else {
+			None
+		}
+	}
+}
+
+
+/// Framebuffer type for direct RGB color, the other ones are indexed color and EGA text.
+pub const FRAMEBUFFER_TYPE_RGB: u8 = 1;
+
+/// Framebuffer set up by the boot loader.
+#[derive(Clone, Copy, Debug)]
+pub struct Framebuffer {
+	/// Physical address of the framebuffer
+	pub address: usize,
+	/// Number of bytes per line
+	pub pitch: usize,
+	/// Width in pixels
+	pub width: usize,
+	/// Height in pixels
+	pub height: usize,
+	/// Bits per pixel
+	pub bpp: u8,
+	/// One of the FRAMEBUFFER_TYPE_* constants
+	pub framebuffer_type: u8,
 }
 
 #[repr(C, packed)]
@@ -161,6 +207,17 @@ impl Module {
 	pub fn end_address(&self) -> usize {
 		self.mod_end as usize
 	}
+
+	/// Returns the physical address of the NUL-terminated string associated to this module
+	/// (usually its command line) or None if there is none.
+	#[inline]
+	pub fn string_address(&self) -> Option<usize> {
+		if self.string > 0 {
+			Some(self.string as usize)
+		} else {
+			None
+		}
+	}
 }
 
 
@@ -189,6 +246,13 @@ impl MemoryMapEntry {
 	pub fn length(&self) -> usize {
 		self.length as usize
 	}
+
+	/// Returns the raw type of this memory region (1 = available RAM, 2 = reserved, 3 = ACPI reclaimable,
+	/// 4 = ACPI NVS, 5 = defective RAM).
+	#[inline]
+	pub fn memory_type(&self) -> u32 {
+		self.ty
+	}
 }
 
 pub struct MemoryMapIter {
@@ -70,6 +70,7 @@ typedef struct _HermitSpinlockIrqSave HermitSpinlockIrqSave;
 
 typedef void (*entry_point_t)(void*);
 typedef void (*signal_handler_t)(int);
+typedef void (*panic_hook_t)(const char* reason, size_t length, size_t count);
 
 
 /* Task priorities */
@@ -128,6 +129,7 @@ void sys_yield(void);
 int sys_kill(tid_t dest, int signum);
 int sys_signal(signal_handler_t handler);
 unsigned int sys_rand();
+void sys_set_panic_hook(panic_hook_t hook);
 
 struct ucontext;
 typedef struct ucontext ucontext_t;
@@ -21,11 +21,11 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use arch::x86_64::io::Port;
 use arch::x86_64::mm::paging;
 use arch::x86_64::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
 use arch::x86_64::mm::virtualmem;
 use core::{mem, slice, str};
-use x86::shared::io::*;
 
 
 /// Memory at this physical address is supposed to contain a pointer to the Extended BIOS Data Area (EBDA).
@@ -56,6 +56,8 @@ const AML_BYTEPREFIX: u8 = 0x0A;
 
 /// Bit to enable an ACPI Sleep State.
 const SLP_EN: u16 = 1 << 13;
+/// Bit in the FADT flags indicating that the reset_reg field is valid ("RESET_REG_SUP").
+const FADT_FLAGS_RESET_REG_SUPPORTED: u32 = 1 << 10;
 
 /// The "Multiple APIC Description Table" (MADT) preserved for get_apic_table().
 static mut MADT: Option<AcpiTable> = None;
@@ -63,6 +65,21 @@ static mut MADT: Option<AcpiTable> = None;
 static mut PM1A_CNT_BLK: Option<u16> = None;
 /// The Sleeping State Type code for powering off the computer through ACPI.
 static mut SLP_TYPA: Option<u8> = None;
+/// The I/O Port and value for resetting the computer through ACPI.
+static mut RESET_REG: Option<(u16, u8)> = None;
+
+/// Maximum number of DMA Remapping Hardware Units taken from the DMAR table.
+const MAX_DMA_REMAPPING_UNITS: usize = 8;
+/// DMAR remapping structure type of a DMA Remapping Hardware Unit Definition (DRHD).
+const DMAR_TYPE_DRHD: u16 = 0;
+/// Bit in the DRHD flags indicating that the unit covers all PCI devices of its segment not covered by another unit.
+const DRHD_FLAGS_INCLUDE_PCI_ALL: u8 = 1 << 0;
+
+/// The DMA Remapping Hardware Units (IOMMUs) found in the DMAR table.
+static mut DMA_REMAPPING_UNITS: [DmaRemappingUnit; MAX_DMA_REMAPPING_UNITS] = [DmaRemappingUnit { register_base_address: 0, segment: 0, include_pci_all: false }; MAX_DMA_REMAPPING_UNITS];
+static mut DMA_REMAPPING_UNIT_COUNT: usize = 0;
+/// Maximum DMA physical addressability of the platform in bits, as reported by the DMAR table.
+static mut DMA_HOST_ADDRESS_WIDTH: u8 = 0;
 
 
 /// The "Root System Description Pointer" structure providing pointers to all other ACPI tables.
@@ -252,6 +269,45 @@ struct AcpiFadt {
 }
 
 
+/// The fixed part of the "DMA Remapping Reporting" (DMAR) table, followed by a list of remapping structures.
+/// Described in Intel Virtualization Technology for Directed I/O, 8.1 DMA Remapping Reporting Structure.
+#[repr(C, packed)]
+struct AcpiDmar {
+	host_address_width: u8,
+	flags: u8,
+	reserved: [u8; 10],
+}
+
+/// The header of every remapping structure in the DMAR table.
+#[repr(C, packed)]
+struct AcpiDmarStructureHeader {
+	structure_type: u16,
+	length: u16,
+}
+
+/// A DMA Remapping Hardware Unit Definition (DRHD) structure of the DMAR table, without its device scopes.
+/// Described in Intel Virtualization Technology for Directed I/O, 8.3 DMA Remapping Hardware Unit Definition Structure.
+#[repr(C, packed)]
+struct AcpiDmarDrhd {
+	header: AcpiDmarStructureHeader,
+	flags: u8,
+	reserved: u8,
+	segment: u16,
+	register_base_address: u64,
+}
+
+/// A DMA Remapping Hardware Unit (IOMMU) for VT-d.
+#[derive(Clone, Copy, Debug)]
+pub struct DmaRemappingUnit {
+	/// Physical address of the remapping unit registers.
+	pub register_base_address: u64,
+	/// PCI segment of the devices covered by this unit.
+	pub segment: u16,
+	/// Whether this unit covers all devices of its segment that are not covered by another unit.
+	pub include_pci_all: bool,
+}
+
+
 /// Verifies the checksum of an ACPI table.
 /// Tables supporting this feature contain a "checksum" field. The value of this field is chosen, so that a
 /// (wrapping) sum over all table fields equals zero.
@@ -409,6 +465,17 @@ fn parse_fadt(fadt: AcpiTable) {
 	};
 	unsafe { PM1A_CNT_BLK = Some(pm1a_cnt_blk); }
 
+	// The reset register has been introduced in ACPI 2.0 and may also be in memory space.
+	// We only support it in I/O space, which is what all known chipsets use.
+	let reset_value_field_address = &fadt_table.reset_value as *const _ as usize;
+	if
+		reset_value_field_address < fadt.table_end_address() &&
+		fadt_table.flags & FADT_FLAGS_RESET_REG_SUPPORTED > 0 &&
+		fadt_table.reset_reg.address_space == GENERIC_ADDRESS_IO_SPACE
+	{
+		unsafe { RESET_REG = Some((fadt_table.reset_reg.address as u16, fadt_table.reset_value)); }
+	}
+
 	// Map the "Differentiated System Description Table" (DSDT).
 	// TODO: This must not require "unsafe", see https://github.com/rust-lang/rust/issues/46043#issuecomment-393072398
 	let x_dsdt_field_address = unsafe { &fadt_table.x_dsdt as *const _ as usize };
@@ -449,22 +516,98 @@ fn parse_ssdt(ssdt: AcpiTable) {
 }
 
 
+fn parse_dmar(dmar: AcpiTable) {
+	if dmar.table_start_address() + mem::size_of::<AcpiDmar>() > dmar.table_end_address() {
+		warn!("DMAR table is too short, ignoring it");
+		return;
+	}
+
+	// The table stores the width minus one, so a corrupted value of 255 would overflow.
+	let dmar_table = unsafe { & *(dmar.table_start_address() as *const AcpiDmar) };
+	match dmar_table.host_address_width.checked_add(1) {
+		Some(width) => unsafe { DMA_HOST_ADDRESS_WIDTH = width; },
+		None => {
+			warn!("DMAR table has an invalid host address width of {}, ignoring it", dmar_table.host_address_width);
+			return;
+		}
+	}
+
+	// Walk through all remapping structures and collect the DMA Remapping Hardware Units.
+	let mut current_address = dmar.table_start_address() + mem::size_of::<AcpiDmar>();
+	while current_address + mem::size_of::<AcpiDmarStructureHeader>() <= dmar.table_end_address() {
+		let structure_header = unsafe { & *(current_address as *const AcpiDmarStructureHeader) };
+		let length = structure_header.length as usize;
+		if length < mem::size_of::<AcpiDmarStructureHeader>() || current_address + length > dmar.table_end_address() {
+			warn!("Found a DMAR remapping structure with invalid length {} at {:#X}, stopping", length, current_address);
+			break;
+		}
+
+		if structure_header.structure_type == DMAR_TYPE_DRHD && length >= mem::size_of::<AcpiDmarDrhd>() {
+			let drhd = unsafe { & *(current_address as *const AcpiDmarDrhd) };
+			let unit = DmaRemappingUnit {
+				register_base_address: drhd.register_base_address,
+				segment: drhd.segment,
+				include_pci_all: drhd.flags & DRHD_FLAGS_INCLUDE_PCI_ALL > 0,
+			};
+			debug!("Found DMA Remapping Hardware Unit: {:?}", unit);
+
+			unsafe {
+				if DMA_REMAPPING_UNIT_COUNT < MAX_DMA_REMAPPING_UNITS {
+					DMA_REMAPPING_UNITS[DMA_REMAPPING_UNIT_COUNT] = unit;
+					DMA_REMAPPING_UNIT_COUNT += 1;
+				} else {
+					warn!("Ignoring DMA Remapping Hardware Unit at {:#X}, because there are too many", unit.register_base_address);
+				}
+			}
+		}
+
+		current_address += length;
+	}
+}
+
+
 pub fn get_madt() -> Option<&'static AcpiTable<'static>> {
 	unsafe { MADT.as_ref() }
 }
 
+/// Returns whether DMA remapping hardware (an IOMMU for VT-d) has been found in the ACPI tables.
+pub fn has_iommu() -> bool {
+	unsafe { DMA_REMAPPING_UNIT_COUNT > 0 }
+}
+
+/// Returns the DMA Remapping Hardware Units found in the ACPI DMAR table.
+pub fn get_dma_remapping_units() -> &'static [DmaRemappingUnit] {
+	unsafe { &DMA_REMAPPING_UNITS[..DMA_REMAPPING_UNIT_COUNT] }
+}
+
+/// Returns the maximum DMA physical addressability in bits according to the DMAR table or 0 without a DMAR table.
+pub fn get_dma_host_address_width() -> u8 {
+	unsafe { DMA_HOST_ADDRESS_WIDTH }
+}
+
 pub fn poweroff() {
 	unsafe {
 		if let (Some(pm1a_cnt_blk), Some(slp_typa)) = (PM1A_CNT_BLK, SLP_TYPA) {
 			let bits = (slp_typa as u16) << 10 | SLP_EN;
 			debug!("Powering Off through ACPI (port {:#X}, bitmask {:#X})", pm1a_cnt_blk, bits);
-			outw(pm1a_cnt_blk, bits);
+			Port::<u16>::new(pm1a_cnt_blk).write(bits);
 		} else {
 			debug!("ACPI Power Off is not available");
 		}
 	}
 }
 
+pub fn reboot() {
+	unsafe {
+		if let Some((port, value)) = RESET_REG {
+			debug!("Rebooting through ACPI (port {:#X}, value {:#X})", port, value);
+			Port::<u8>::new(port).write(value);
+		} else {
+			debug!("ACPI Reset is not available");
+		}
+	}
+}
+
 pub fn init() {
 	// Detect the RSDP and get a pointer to either the XSDT (64-bit) or RSDT (32-bit), whichever is available.
 	// Both are called RSDT in the following.
@@ -519,6 +662,20 @@ pub fn init() {
 				"SSDT at {:#X} has invalid checksum", table_physical_address
 			);
 			parse_ssdt(table);
+		} else if table.header.signature() == "DMAR" {
+			// The "DMA Remapping Reporting" (DMAR) table describes the IOMMUs for VT-d.
+			// It is optional, so a broken one is skipped instead of stopping the boot.
+			if verify_checksum(table.header_start_address(), table.header.length as usize).is_ok() {
+				parse_dmar(table);
+			} else {
+				warn!("DMAR at {:#X} has invalid checksum, ignoring it", table_physical_address);
+			}
 		}
 	}
+
+	if has_iommu() {
+		info!("DMA remapping (VT-d) is available with {} remapping unit(s) and a host address width of {} bits", get_dma_remapping_units().len(), get_dma_host_address_width());
+	} else {
+		info!("DMA remapping (VT-d) is not available");
+	}
 }
@@ -27,15 +27,19 @@ include!(concat!(env!("CARGO_TARGET_DIR"), "/smp_boot_code.rs"));
 use alloc::boxed::Box;
 use alloc::vec::Vec;
 use arch::x86_64::acpi;
+use arch::x86_64::gdt;
 use arch::x86_64::idt;
 use arch::x86_64::irq;
 use arch::x86_64::mm::paging;
-use arch::x86_64::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
+use arch::x86_64::mm::paging::{BasePageSize, MemoryType, PageSize, PageTableEntryFlags};
+use arch::x86_64::mm::physicalmem;
 use arch::x86_64::mm::virtualmem;
 use arch::x86_64::percore::*;
+use arch::x86_64::pic;
 use arch::x86_64::processor;
-use core::sync::atomic::spin_loop_hint;
-use core::{fmt, mem, ptr, str, u32};
+use arch::x86_64::shutdown;
+use core::{cmp, fmt, mem, ptr, str, u32};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use environment;
 use mm;
 use scheduler;
@@ -47,19 +51,20 @@ extern "C" {
 	static cpu_online: u32;
 	static mut current_stack_address: usize;
 	static mut current_percore_address: usize;
+	static mut boot_addresses_taken: u32;
 }
 
 const APIC_ICR2: usize = 0x0310;
 
 const APIC_DIV_CONF_DIVIDE_BY_128: u64      = 0b1010;
 const APIC_EOI_ACK: u64                     = 0;
-const APIC_ICR_DELIVERY_MODE_FIXED: u64     = 0x000;
-const APIC_ICR_DELIVERY_MODE_INIT: u64      = 0x500;
-const APIC_ICR_DELIVERY_MODE_STARTUP: u64   = 0x600;
 const APIC_ICR_DELIVERY_STATUS_PENDING: u32 = 1 << 12;
-const APIC_ICR_LEVEL_TRIGGERED: u64         = 1 << 15;
 const APIC_ICR_LEVEL_ASSERT: u64            = 1 << 14;
+const APIC_ICR_LEVEL_TRIGGERED: u64         = 1 << 15;
+const APIC_ICR_DESTINATION_SHIFT: u64       = 32;
+const APIC_ICR_SHORTHAND_SHIFT: u64         = 18;
 const APIC_LVT_MASK: u64                    = 1 << 16;
+const APIC_LVT_TIMER_PERIODIC: u64          = 1 << 17;
 const APIC_SIVR_ENABLED: u64                = 1 << 8;
 
 /// Register index: ID
@@ -71,6 +76,7 @@ const IOAPIC_REG_VER: u32					= 0x0001;
 const IOAPIC_REG_TABLE: u32					= 0x0010;
 
 const TLB_FLUSH_INTERRUPT_NUMBER: u8 = 112;
+const SHUTDOWN_INTERRUPT_NUMBER: u8  = 113;
 const WAKEUP_INTERRUPT_NUMBER: u8    = 121;
 pub const TIMER_INTERRUPT_NUMBER: u8 = 123;
 const ERROR_INTERRUPT_NUMBER: u8     = 126;
@@ -81,12 +87,21 @@ const SPURIOUS_INTERRUPT_NUMBER: u8  = 127;
 /// While our boot processor is already in x86-64 mode, application processors boot up in 16-bit real mode
 /// and need an address in the CS:IP addressing scheme to jump to.
 /// The CS:IP addressing scheme is limited to 2^20 bytes (= 1 MiB).
+/// boot.asm is assembled for this address, so it cannot be moved at runtime.
 const SMP_BOOT_CODE_ADDRESS: usize = 0x8000;
 
 const SMP_BOOT_CODE_OFFSET_PML4: usize = 0x04;
 
+/// Default time in milliseconds that an Application Processor gets to finish its initialization.
+/// Can be changed through the "ap_timeout" command line argument.
+const DEFAULT_AP_BOOT_TIMEOUT_MS: usize = 1000;
+
+const APIC_BASE_GLOBAL_ENABLE: u64 = 1 << 11;
 const X2APIC_ENABLE: u64 = 1 << 10;
 
+/// Set if no usable Local APIC has been found and interrupts are only handled through the PIC.
+/// The kernel then runs on the Boot Processor only.
+static mut PIC_ONLY_MODE: bool = false;
 static mut LOCAL_APIC_ADDRESS: usize = 0;
 static mut IOAPIC_ADDRESS: usize = 0;
 
@@ -95,6 +110,26 @@ static mut IOAPIC_ADDRESS: usize = 0;
 /// we have to encapsulate it in an Option...
 static mut CPU_LOCAL_APIC_IDS: Option<Vec<u8>> = None;
 
+/// Stores the Local APIC IDs of all CPUs that did not come online during boot_application_processors.
+static mut FAILED_CPU_LOCAL_APIC_IDS: Option<Vec<u8>> = None;
+/// Local APIC ID of the Boot Processor, set by init_boot_processor_id.
+static mut BOOT_PROCESSOR_ID: u32 = 0;
+
+/// Number of APIC Error Interrupts on all cores since boot.
+static ERROR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Error bits of the Error Status Register (Intel SDM Vol. 3A, 10.5.3) and what they mean.
+const ESR_DESCRIPTIONS: [(u32, &'static str); 8] = [
+	(1 << 0, "Send Checksum Error"),
+	(1 << 1, "Receive Checksum Error"),
+	(1 << 2, "Send Accept Error (IPI not accepted by any APIC)"),
+	(1 << 3, "Receive Accept Error"),
+	(1 << 4, "Redirectable IPI (lowest priority delivery not supported)"),
+	(1 << 5, "Send Illegal Vector"),
+	(1 << 6, "Received Illegal Vector"),
+	(1 << 7, "Illegal Register Address"),
+];
+
 /// After calibration, initialize the APIC Timer with this counter value to let it fire an interrupt
 /// after a single tick of the timer specified by processor::TIMER_FREQUENCY.
 static mut CALIBRATED_COUNTER_VALUE: usize = 0;
@@ -149,6 +184,91 @@ impl fmt::Display for IoApicRecord {
 }
 
 
+/// Delivery modes of an Inter-Processor Interrupt, numbered like in the ICR (Intel SDM Vol. 3A, 10.6.1).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeliveryMode {
+	Fixed = 0b000,
+	LowestPriority = 0b001,
+	Smi = 0b010,
+	Nmi = 0b100,
+	Init = 0b101,
+	Startup = 0b110,
+}
+
+/// Destination shorthands of an Inter-Processor Interrupt.
+/// With any shorthand except None, the target passed to send_ipi is ignored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DestinationShorthand {
+	None = 0b00,
+	SelfOnly = 0b01,
+	AllIncludingSelf = 0b10,
+	AllExcludingSelf = 0b11,
+}
+
+/// Trigger mode and level of an Inter-Processor Interrupt.
+/// Only INIT uses level triggering, everything else is sent edge-triggered with the level asserted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TriggerMode {
+	Edge,
+	LevelAssert,
+	LevelDeassert,
+}
+
+/// An Inter-Processor Interrupt to send through send_ipi.
+#[derive(Clone, Copy, Debug)]
+pub struct IpiMessage {
+	pub delivery_mode: DeliveryMode,
+	/// Interrupt number for Fixed and LowestPriority, page number of the boot code for Startup, ignored otherwise.
+	pub vector: u8,
+	pub shorthand: DestinationShorthand,
+	pub trigger: TriggerMode,
+}
+
+impl IpiMessage {
+	/// A Fixed IPI raising interrupt `vector` on the target.
+	pub fn fixed(vector: u8) -> Self {
+		IpiMessage { delivery_mode: DeliveryMode::Fixed, vector: vector, shorthand: DestinationShorthand::None, trigger: TriggerMode::Edge }
+	}
+
+	/// A Non-Maskable Interrupt.
+	pub fn nmi() -> Self {
+		IpiMessage { delivery_mode: DeliveryMode::Nmi, vector: 0, shorthand: DestinationShorthand::None, trigger: TriggerMode::Edge }
+	}
+
+	/// Asserts or deasserts INIT, which resets the target into its wait-for-SIPI state.
+	pub fn init(assert: bool) -> Self {
+		let trigger = if assert { TriggerMode::LevelAssert } else { TriggerMode::LevelDeassert };
+		IpiMessage { delivery_mode: DeliveryMode::Init, vector: 0, shorthand: DestinationShorthand::None, trigger: trigger }
+	}
+
+	/// A STARTUP IPI letting the target execute the real-mode code at physical address `address`.
+	pub fn startup(address: usize) -> Self {
+		assert!(address % BasePageSize::SIZE == 0 && address < 0x100000, "Boot code at {:#X} is not reachable in real mode", address);
+		IpiMessage { delivery_mode: DeliveryMode::Startup, vector: (address / BasePageSize::SIZE) as u8, shorthand: DestinationShorthand::None, trigger: TriggerMode::Edge }
+	}
+
+	/// Encodes this message for the x2APIC ICR, local_apic_write converts it for xAPIC.
+	fn encode(&self, target: u32) -> u64 {
+		let vector = match self.delivery_mode {
+			DeliveryMode::Fixed | DeliveryMode::LowestPriority | DeliveryMode::Startup => self.vector as u64,
+			DeliveryMode::Smi | DeliveryMode::Nmi | DeliveryMode::Init => 0,
+		};
+		let trigger = match self.trigger {
+			TriggerMode::Edge => APIC_ICR_LEVEL_ASSERT,
+			TriggerMode::LevelAssert => APIC_ICR_LEVEL_TRIGGERED | APIC_ICR_LEVEL_ASSERT,
+			TriggerMode::LevelDeassert => APIC_ICR_LEVEL_TRIGGERED,
+		};
+		let destination = if self.shorthand == DestinationShorthand::None { target as u64 } else { 0 };
+
+		(destination << APIC_ICR_DESTINATION_SHIFT)
+			| ((self.shorthand as u64) << APIC_ICR_SHORTHAND_SHIFT)
+			| trigger
+			| ((self.delivery_mode as u64) << 8)
+			| vector
+	}
+}
+
+
 extern "x86-interrupt" fn tlb_flush_handler(_stack_frame: &mut irq::ExceptionStackFrame) {
 	debug!("Received TLB Flush Interrupt");
 	unsafe { cr3_write(cr3()); }
@@ -156,11 +276,17 @@ extern "x86-interrupt" fn tlb_flush_handler(_stack_frame: &mut irq::ExceptionSta
 }
 
 extern "x86-interrupt" fn error_interrupt_handler(stack_frame: &mut irq::ExceptionStackFrame) {
-	error!("APIC LVT Error Interrupt");
-	error!("ESR: {:#X}", local_apic_read(IA32_X2APIC_ESR));
-	error!("{:#?}", stack_frame);
+	let error_status = read_error_status();
+	ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
+
+	error!("APIC Error Interrupt on core {}, ESR: {:#X}", core_id(), error_status);
+	for &(bit, description) in ESR_DESCRIPTIONS.iter() {
+		if error_status & bit != 0 {
+			error!("  {}", description);
+		}
+	}
+	debug!("{:#?}", stack_frame);
 	eoi();
-	scheduler::abort();
 }
 
 extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: &mut irq::ExceptionStackFrame) {
@@ -168,6 +294,11 @@ extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: &mut irq::Exce
 	scheduler::abort();
 }
 
+extern "x86-interrupt" fn shutdown_handler(_stack_frame: &mut irq::ExceptionStackFrame) {
+	eoi();
+	shutdown::park_current_core();
+}
+
 extern "x86-interrupt" fn wakeup_handler(_stack_frame: &mut irq::ExceptionStackFrame) {
 	debug!("Received Wakeup Interrupt");
 	eoi();
@@ -176,7 +307,7 @@ extern "x86-interrupt" fn wakeup_handler(_stack_frame: &mut irq::ExceptionStackF
 
 fn detect_from_acpi() -> Result<usize, ()> {
 	// Get the Multiple APIC Description Table (MADT) from the ACPI information and its specific table header.
-	let madt = acpi::get_madt().expect("HermitCore requires a MADT in the ACPI tables");
+	let madt = acpi::get_madt().ok_or(())?;
 	let madt_header = unsafe { & *(madt.table_start_address() as *const AcpiMadtHeader) };
 
 	// Jump to the actual table entries (after the table header).
@@ -216,7 +347,7 @@ fn detect_from_acpi() -> Result<usize, ()> {
 						IOAPIC_ADDRESS,
 						ioapic_record.address as usize,
 						1,
-						PageTableEntryFlags::WRITABLE | PageTableEntryFlags::CACHE_DISABLE | PageTableEntryFlags::EXECUTE_DISABLE,
+						PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE | paging::device_memory_flags(MemoryType::Uncacheable),
 						false
 					);
 				}
@@ -229,6 +360,13 @@ fn detect_from_acpi() -> Result<usize, ()> {
 		current_address += record.length as usize - mem::size_of::<AcpiMadtRecordHeader>();
 	}
 
+	// Sequential CPU number 0 is always the Boot Processor, no matter where the firmware has listed it.
+	let boot_processor_id = boot_processor_id() as u8;
+	if let Some(position) = local_apic_ids.iter().position(|&apic_id| apic_id == boot_processor_id) {
+		local_apic_ids.remove(position);
+	}
+	local_apic_ids.insert(0, boot_processor_id);
+
 	// Successfully derived all information from the MADT.
 	// Return the physical address of the Local APIC.
 	Ok(madt_header.local_apic_address as usize)
@@ -236,6 +374,14 @@ fn detect_from_acpi() -> Result<usize, ()> {
 
 fn detect_from_uhyve() -> Result<usize, ()> {
 	if environment::is_uhyve() {
+		// uhyve starts its virtual CPUs itself and gives them consecutive Local APIC IDs, starting with the Boot Processor.
+		// Record them anyway to map CPU numbers to Core IDs.
+		let mut local_apic_ids = Vec::new();
+		for apic_id in 0..cmp::max(environment::get_possible_cpus(), 1) {
+			local_apic_ids.push(apic_id as u8);
+		}
+
+		unsafe { CPU_LOCAL_APIC_IDS = Some(local_apic_ids); }
 		return Ok(0xFEE00000 as usize);
 	}
 
@@ -244,14 +390,80 @@ fn detect_from_uhyve() -> Result<usize, ()> {
 
 #[no_mangle]
 pub extern "C" fn eoi() {
-	local_apic_write(IA32_X2APIC_EOI, APIC_EOI_ACK);
+	if unsafe { PIC_ONLY_MODE } {
+		// Only the master PIC is unmasked in this mode.
+		pic::eoi(pic::PIC1_INTERRUPT_OFFSET);
+	} else {
+		local_apic_write(IA32_X2APIC_EOI, APIC_EOI_ACK);
+	}
+}
+
+/// Sets the Core ID of the Boot Processor to its Local APIC ID before anything uses it.
+/// The PerCoreVariables of the Boot Processor are statically allocated with Core ID 0, but the firmware
+/// does not have to start the system on the CPU with Local APIC ID 0.
+/// Must be called right after percore::init on the Boot Processor.
+pub fn init_boot_processor_id() {
+	// CPUID.01H:EBX[31:24] reports the initial Local APIC ID.
+	let apic_id = processor::cpuid(1, 0).ebx >> 24;
+
+	unsafe { BOOT_PROCESSOR_ID = apic_id; }
+	set_core_id(apic_id);
+}
+
+/// Returns the Local APIC ID of the Boot Processor, which is also its Core ID.
+#[inline]
+pub fn boot_processor_id() -> u32 {
+	unsafe { BOOT_PROCESSOR_ID }
+}
+
+/// Returns whether this code runs on the Boot Processor.
+/// Use it to assert that code, which must only run on the Boot Processor (like certain ACPI operations), is not called on an Application Processor.
+#[inline]
+pub fn is_boot_processor() -> bool {
+	core_id() == boot_processor_id()
+}
+
+/// Returns whether the CPU has a Local APIC, which has not been disabled by the firmware.
+fn is_local_apic_usable() -> bool {
+	processor::supports_apic() && (unsafe { rdmsr(IA32_APIC_BASE) } & APIC_BASE_GLOBAL_ENABLE) > 0
+}
+
+/// Returns whether interrupts are handled through the APIC.
+/// Otherwise, the kernel runs in the reduced PIC-only mode on a single core.
+#[inline]
+pub fn is_available() -> bool {
+	!unsafe { PIC_ONLY_MODE }
+}
+
+/// Reduced-capability mode for environments without a usable APIC.
+/// Interrupts are only handled through the PIC, the scheduler is driven by the PIT, and only the Boot Processor is used.
+fn init_pic_only_mode() {
+	warn!("No usable APIC found, falling back to PIC-only interrupt handling on a single core");
+
+	unsafe {
+		PIC_ONLY_MODE = true;
+
+		let mut local_apic_ids = Vec::new();
+		local_apic_ids.push(core_id() as u8);
+		CPU_LOCAL_APIC_IDS = Some(local_apic_ids);
+	}
 }
 
 pub fn init() {
 	// Detect CPUs and APICs.
-	let local_apic_physical_address = detect_from_uhyve()
-		.or_else(|_e| detect_from_acpi())
-		.expect("HermitCore requires an APIC system");
+	let detection_result = if is_local_apic_usable() {
+		detect_from_uhyve().or_else(|_e| detect_from_acpi())
+	} else {
+		Err(())
+	};
+
+	let local_apic_physical_address = match detection_result {
+		Ok(address) => address,
+		Err(_) => {
+			init_pic_only_mode();
+			return;
+		}
+	};
 
 	// Initialize x2APIC or xAPIC, depending on what's available.
 	init_x2apic();
@@ -266,7 +478,7 @@ pub fn init() {
 				LOCAL_APIC_ADDRESS,
 				local_apic_physical_address,
 				1,
-				PageTableEntryFlags::WRITABLE | PageTableEntryFlags::CACHE_DISABLE | PageTableEntryFlags::EXECUTE_DISABLE,
+				PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE | paging::device_memory_flags(MemoryType::Uncacheable),
 				false
 			);
 		}
@@ -277,6 +489,7 @@ pub fn init() {
 	idt::set_gate(ERROR_INTERRUPT_NUMBER, error_interrupt_handler as usize, 1);
 	idt::set_gate(SPURIOUS_INTERRUPT_NUMBER, spurious_interrupt_handler as usize, 1);
 	idt::set_gate(WAKEUP_INTERRUPT_NUMBER, wakeup_handler as usize, 1);
+	idt::set_gate(SHUTDOWN_INTERRUPT_NUMBER, shutdown_handler as usize, 1);
 
 	// Initialize interrupt handling over APIC.
 	// All interrupts of the PIC have already been masked, so it doesn't need to be disabled again.
@@ -295,18 +508,34 @@ fn init_ioapic() {
 	let max_entry = ioapic_max_redirection_entry()+1;
 	info!("IOAPIC v{} has {} entries", ioapic_version(), max_entry);
 
-	// now lets turn everything else on
+	// now lets turn everything else on and deliver all interrupts to the Boot Processor
+	let destination = ioapic_destination();
 	for i in 0..max_entry {
 		if i != 2 {
-			ioapic_inton(i, 0 /*apic_processors[boot_processor]->id*/).unwrap();
+			ioapic_inton(i, destination).unwrap();
 		} else {
 			// now, we don't longer need the IOAPIC timer and turn it off
 			info!("Disable IOAPIC timer");
-			ioapic_intoff(2, 0 /*apic_processors[boot_processor]->id*/).unwrap();
+			ioapic_intoff(2, destination as u32).unwrap();
 		}
 	}
 }
 
+/// Returns the destination of the I/O APIC redirection entries, which is the Boot Processor.
+/// In physical destination mode, the I/O APIC only supports 8-bit Local APIC IDs.
+fn ioapic_destination() -> u8 {
+	let apic_id = boot_processor_id();
+	assert!(apic_id <= 0xFF, "Boot Processor with Local APIC ID {} cannot receive I/O APIC interrupts", apic_id);
+	apic_id as u8
+}
+
+/// Routes the PIT interrupt through the I/O APIC to the given interrupt number on the Boot Processor.
+/// Like most systems, we assume that ISA IRQ 0 of the PIT is connected to pin 2 of the I/O APIC.
+pub fn route_pit_interrupt(interrupt_number: u8) {
+	ioapic_write(IOAPIC_REG_TABLE + 2*2, interrupt_number as u32);
+	ioapic_write(IOAPIC_REG_TABLE + 2*2 + 1, (ioapic_destination() as u32) << 24);
+}
+
 fn ioapic_inton(irq: u8, apicid: u8) -> Result<(), ()>
 {
 	if irq > 24 {
@@ -349,8 +578,9 @@ pub fn init_local_apic() {
 	local_apic_write(IA32_X2APIC_LVT_LINT0, APIC_LVT_MASK);
 	local_apic_write(IA32_X2APIC_LVT_LINT1, APIC_LVT_MASK);
 
-	// Set the interrupt number of the Error interrupt.
+	// Set the interrupt number of the Error interrupt and discard any errors latched before.
 	local_apic_write(IA32_X2APIC_LVT_ERROR, ERROR_INTERRUPT_NUMBER as u64);
+	read_error_status();
 
 	// allow all interrupts
 	local_apic_write(IA32_X2APIC_TPR, 0x00);
@@ -371,27 +601,28 @@ fn calibrate_timer() {
 	// Disable interrupts for calibration accuracy and initialize the counter.
 	// Dividing by the maximum value of 128 still provides enough accuracy for later setting timeouts in the range
 	// of milliseconds, but especially allows for long timeouts.
-	irq::disable();
-	local_apic_write(IA32_X2APIC_DIV_CONF, APIC_DIV_CONF_DIVIDE_BY_128);
-	local_apic_write(IA32_X2APIC_INIT_COUNT, u32::MAX as u64);
+	let counter_value = irq::without_interrupts(|| {
+		local_apic_write(IA32_X2APIC_DIV_CONF, APIC_DIV_CONF_DIVIDE_BY_128);
+		local_apic_write(IA32_X2APIC_INIT_COUNT, u32::MAX as u64);
+
+		// Wait until the 3 ticks have elapsed.
+		let end = processor::get_timestamp() + cycles;
+		while processor::get_timestamp() < end {
+			processor::pause();
+		}
 
-	// Wait until the 3 ticks have elapsed.
-	let end = processor::get_timestamp() + cycles;
-	while processor::get_timestamp() < end {
-		spin_loop_hint();
-	}
+		// The difference of the initial value and current value is the result of the calibration.
+		(u32::MAX - local_apic_read(IA32_X2APIC_CUR_COUNT)) / tick_count as u32
+	});
 
-	// Save the difference of the initial value and current value as the result of the calibration
-	// and reenable interrupts.
 	unsafe {
-		CALIBRATED_COUNTER_VALUE = ((u32::MAX - local_apic_read(IA32_X2APIC_CUR_COUNT)) / tick_count as u32) as usize;
+		CALIBRATED_COUNTER_VALUE = counter_value as usize;
 		debug!(
 			"Calibrated APIC Timer with a counter value of {} for a single tick of a {} Hz timer",
 			CALIBRATED_COUNTER_VALUE,
 			processor::TIMER_FREQUENCY
 		);
 	}
-	irq::enable();
 }
 
 pub fn set_oneshot_timer(wakeup_time: Option<usize>) {
@@ -410,6 +641,18 @@ pub fn set_oneshot_timer(wakeup_time: Option<usize>) {
 	}
 }
 
+/// Lets the APIC Timer of the current core fire an interrupt with the given frequency until it is
+/// reprogrammed through set_oneshot_timer.
+/// Unlike the one-shot mode, this needs no reprogramming on each tick, but also wakes up the core
+/// on every tick, even if no task has to be woken up.
+pub fn set_periodic_timer(hz: u64) {
+	let counter_value = unsafe { CALIBRATED_COUNTER_VALUE } as u64 * processor::TIMER_FREQUENCY as u64 / hz;
+	assert!(counter_value > 0, "APIC Timer cannot run at {} Hz", hz);
+
+	local_apic_write(IA32_X2APIC_LVT_TIMER, APIC_LVT_TIMER_PERIODIC | (TIMER_INTERRUPT_NUMBER as u64));
+	local_apic_write(IA32_X2APIC_INIT_COUNT, counter_value);
+}
+
 pub fn init_x2apic() {
 	if processor::supports_x2apic() {
 		// The CPU supports the modern x2APIC mode, which uses MSRs for communication.
@@ -420,15 +663,113 @@ pub fn init_x2apic() {
 	}
 }
 
-/// Boot all Application Processors
-/// This algorithm is derived from Intel MultiProcessor Specification 1.4, B.4, but testing has shown
-/// that a second STARTUP IPI and setting the BIOS Reset Vector are no longer necessary.
-/// This is partly confirmed by https://wiki.osdev.org/Symmetric_Multiprocessing
+/// Order in which the application processors are booted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BootOrder {
+	/// Boot all SMT threads of a core before moving on to the next core.
+	Compact,
+	/// Boot one SMT thread per physical core first, alternating between the packages, followed by their siblings.
+	Scatter,
+}
+
+/// Returns the Local APIC IDs of all CPUs in the order they shall be booted.
+/// The order can be chosen through the "smp_order=compact|scatter" command line argument and defaults to scatter,
+/// so that early parallel work gets full cores before SMT siblings are added.
+fn get_boot_order() -> Vec<u8> {
+	let order = match environment::get_arg("smp_order") {
+		None | Some("scatter") => BootOrder::Scatter,
+		Some("compact") => BootOrder::Compact,
+		Some(value) => {
+			warn!("Ignoring invalid SMP boot order \"{}\"", value);
+			BootOrder::Scatter
+		}
+	};
+
+	let topology = processor::topology();
+	let mut apic_ids = unsafe { CPU_LOCAL_APIC_IDS.as_ref().unwrap().clone() };
+
+	match order {
+		BootOrder::Compact => apic_ids.sort_unstable(),
+		BootOrder::Scatter => apic_ids.sort_unstable_by_key(|apic_id| topology.spread_key(*apic_id as u32)),
+	}
+
+	debug!("Booting CPUs in {:?} order", order);
+	apic_ids
+}
+
+/// Returns the IPIs of the INIT-SIPI-SIPI sequence for start_ap, each with the time in microseconds to wait after it.
+fn startup_sequence(trampoline_page: usize) -> [(IpiMessage, u64); 4] {
+	[
+		(IpiMessage::init(true), 200),
+		(IpiMessage::init(false), 10000),
+		(IpiMessage::startup(trampoline_page), 200),
+		(IpiMessage::startup(trampoline_page), 200),
+	]
+}
+
+/// Starts the Application Processor with Local APIC ID `apic_id` through the INIT-SIPI-SIPI sequence of
+/// Intel MultiProcessor Specification 1.4, B.4, letting it execute the real-mode code at `trampoline_page`.
+/// The caller must have prepared the trampoline, stack, and PerCoreVariables for the CPU.
+///
+/// Timing follows the specification: INIT is asserted and deasserted, then the CPU gets 10 ms to reset.
+/// Each STARTUP IPI is followed by 200 us, which is plenty for a CPU to leave its wait-for-SIPI state.
+/// A CPU ignores the second STARTUP IPI if the first one has already started it, and the BIOS Reset Vector
+/// is not needed, because all supported CPUs have an integrated APIC.
+///
+/// Returns Ok once the CPU has counted up cpu_online, or Err if it didn't within `timeout_ms` milliseconds.
+/// A failed CPU is put back into its wait-for-SIPI state, so it cannot start later and use the stack of the next CPU.
+pub fn start_ap(apic_id: u32, trampoline_page: usize, timeout_ms: usize) -> Result<(), ()> {
+	let current_cpu_online = unsafe { ptr::read_volatile(&cpu_online) };
+
+	let sequence = startup_sequence(trampoline_page);
+	for &(message, delay) in sequence.iter() {
+		send_ipi(apic_id, message);
+		processor::udelay(delay);
+	}
+	debug!("Waiting for CPU with Local APIC ID {} to respond", apic_id);
+
+	// Wait until the application processor has finished initializing.
+	// It will indicate this by counting up cpu_online.
+	let mut waited_ms = 0;
+	while current_cpu_online == unsafe { ptr::read_volatile(&cpu_online) } && waited_ms < timeout_ms {
+		processor::udelay(1000);
+		waited_ms += 1;
+	}
+
+	if current_cpu_online == unsafe { ptr::read_volatile(&cpu_online) } {
+		// Reset the CPU through the same INIT assert and deassert sequence as above.
+		for &(message, delay) in sequence[..2].iter() {
+			send_ipi(apic_id, message);
+			processor::udelay(delay);
+		}
+		Err(())
+	} else {
+		Ok(())
+	}
+}
+
+/// Boot all Application Processors one after another using start_ap.
 pub fn boot_application_processors() {
+	if unsafe { PIC_ONLY_MODE } {
+		info!("Not booting any Application Processors without an APIC");
+		return;
+	}
+
 	// We shouldn't have any problems fitting the boot code into a single page, but let's better be sure.
 	assert!(SMP_BOOT_CODE.len() < BasePageSize::SIZE, "SMP Boot Code is larger than a page");
 	debug!("SMP boot code is {} bytes long", SMP_BOOT_CODE.len());
 
+	// Make sure that the boot code page is RAM not used by the BIOS, EBDA, or boot loader before overwriting it.
+	let boot_order = get_boot_order();
+	let core_id = core_id() as u8;
+	if physicalmem::reserve_low_memory(SMP_BOOT_CODE_ADDRESS, BasePageSize::SIZE).is_err() {
+		error!("Cannot boot any Application Processors, because low memory at {:#X} is not available", SMP_BOOT_CODE_ADDRESS);
+		let failed_apic_ids: Vec<u8> = boot_order.iter().cloned().filter(|apic_id| *apic_id != core_id).collect();
+		unsafe { FAILED_CPU_LOCAL_APIC_IDS = Some(failed_apic_ids); }
+		return;
+	}
+	info!("Using low memory at {:#X} for the SMP boot code", SMP_BOOT_CODE_ADDRESS);
+
 	// Identity-map the boot code page and copy over the code.
 	debug!("Mapping SMP boot code to physical and virtual address {:#X}", SMP_BOOT_CODE_ADDRESS);
 	paging::map::<BasePageSize>(SMP_BOOT_CODE_ADDRESS, SMP_BOOT_CODE_ADDRESS, 1, PageTableEntryFlags::WRITABLE, false);
@@ -437,65 +778,113 @@ pub fn boot_application_processors() {
 	// Pass the PML4 page table address to the boot code.
 	unsafe { *((SMP_BOOT_CODE_ADDRESS + SMP_BOOT_CODE_OFFSET_PML4) as *mut u32) = cr3() as u32; }
 
+	let timeout = match environment::get_arg("ap_timeout").map(environment::parse_integer) {
+		None => DEFAULT_AP_BOOT_TIMEOUT_MS,
+		Some(Some(timeout)) if timeout > 0 => timeout,
+		Some(_) => {
+			warn!("Ignoring invalid ap_timeout, using {} ms", DEFAULT_AP_BOOT_TIMEOUT_MS);
+			DEFAULT_AP_BOOT_TIMEOUT_MS
+		}
+	};
+
 	// Now wake up each application processor.
-	let core_id = core_id() as u8;
+	// The boot order doesn't matter for the per-core indexing, which is always based on CPU_LOCAL_APIC_IDS.
+	let mut failed_apic_ids = Vec::new();
 
-	for apic_id in unsafe { CPU_LOCAL_APIC_IDS.as_ref().unwrap().iter() } {
+	for apic_id in boot_order.iter() {
 		if *apic_id != core_id {
-			let destination = (*apic_id as u64) << 32;
 			debug!("Waking up CPU with Local APIC ID {}", *apic_id);
 
 			// Allocate stack and PerCoreVariables structure for the CPU and pass the addresses.
 			// Keep the stack executable to possibly support dynamically generated code on the stack (see https://security.stackexchange.com/a/47825).
-			let stack = mm::allocate(KERNEL_STACK_SIZE, PageTableEntryFlags::empty());
-			let boxed_percore = Box::new(PerCoreVariables::new(*apic_id as u32));
+			// entry.asm computes the stack pointer from the build-time KERNEL_STACK_SIZE, so adjust the passed address
+			// to let it end up at the top of a stack with the configured size.
+			let stack = mm::allocate_stack(gdt::kernel_stack_size(), PageTableEntryFlags::empty());
+			let percore = Box::into_raw(Box::new(PerCoreVariables::new(*apic_id as u32)));
 			unsafe {
-				ptr::write_volatile(&mut current_stack_address, stack);
-				ptr::write_volatile(&mut current_percore_address, Box::into_raw(boxed_percore) as usize);
+				ptr::write_volatile(&mut boot_addresses_taken, 0);
+				ptr::write_volatile(&mut current_stack_address, stack + gdt::kernel_stack_size() - KERNEL_STACK_SIZE);
+				ptr::write_volatile(&mut current_percore_address, percore as usize);
 			}
 
-			// Save the current number of initialized CPUs.
-			let current_cpu_online = unsafe { ptr::read_volatile(&cpu_online) };
-
-			// Send an INIT IPI.
-			local_apic_write(IA32_X2APIC_ICR, destination | APIC_ICR_LEVEL_TRIGGERED | APIC_ICR_LEVEL_ASSERT | APIC_ICR_DELIVERY_MODE_INIT);
-			processor::udelay(200);
-
-			local_apic_write(IA32_X2APIC_ICR, destination | APIC_ICR_LEVEL_TRIGGERED | APIC_ICR_DELIVERY_MODE_INIT);
-			processor::udelay(10000);
-
-			// Send a STARTUP IPI.
-			local_apic_write(IA32_X2APIC_ICR, destination | APIC_ICR_DELIVERY_MODE_STARTUP | ((SMP_BOOT_CODE_ADDRESS as u64) >> 12));
-			debug!("Waiting for it to respond");
-
-			// Wait until the application processor has finished initializing.
-			// It will indicate this by counting up cpu_online.
-			while current_cpu_online == unsafe { ptr::read_volatile(&cpu_online) } {
-				processor::udelay(1000);
+			if start_ap(*apic_id as u32, SMP_BOOT_CODE_ADDRESS, timeout).is_err() {
+				error!("CPU with Local APIC ID {} failed to come online within {} ms", *apic_id, timeout);
+				failed_apic_ids.push(*apic_id);
+
+				// start_ap has put the CPU back into its wait-for-SIPI state, so it can no longer take the addresses.
+				// Only if it never took them, nothing refers to its stack and PerCoreVariables anymore.
+				if unsafe { ptr::read_volatile(&boot_addresses_taken) } == 0 {
+					mm::deallocate_stack(stack, gdt::kernel_stack_size());
+					unsafe { drop(Box::from_raw(percore)); }
+				} else {
+					warn!("Keeping the stack and PerCoreVariables of CPU with Local APIC ID {}, which it has started to use", *apic_id);
+				}
 			}
 		}
 	}
+
+	info!("{} of {} CPUs are online", unsafe { ptr::read_volatile(&cpu_online) }, boot_order.len());
+	if failed_apic_ids.is_empty() {
+		// All Application Processors are in 64-bit mode now, so the boot code page can be used for the heap.
+		paging::unmap::<BasePageSize>(SMP_BOOT_CODE_ADDRESS, 1, true);
+		physicalmem::reclaim_low_memory(SMP_BOOT_CODE_ADDRESS);
+	} else {
+		// A failed CPU waits for a STARTUP IPI and may still begin executing the boot code, so keep it in place.
+		warn!("CPUs with Local APIC IDs {:?} failed to boot and are not used", failed_apic_ids);
+	}
+
+	unsafe { FAILED_CPU_LOCAL_APIC_IDS = Some(failed_apic_ids); }
+}
+
+/// Returns the Local APIC IDs of all CPUs that failed to come online.
+pub fn failed_cpus() -> &'static [u8] {
+	unsafe { FAILED_CPU_LOCAL_APIC_IDS.as_ref().map_or(&[][..], |ids| &ids[..]) }
 }
 
 pub fn ipi_tlb_flush() {
-	if unsafe { ptr::read_volatile(&cpu_online) } > 1 {
-		let core_id = core_id() as u8;
+	// Only online CPUs can receive the IPI, CPUs that failed to boot are kept in their wait-for-SIPI state.
+	let online_cpus = ::arch::x86_64::online_cpu_mask();
+
+	if online_cpus.count() > 1 {
+		let core_id = core_id();
 
 		// Ensure that all memory operations have completed before issuing a TLB flush.
 		unsafe { asm!("mfence" ::: "memory" : "volatile"); }
 
 		// Send an IPI with our TLB Flush interrupt number to all other CPUs.
-		for apic_id in unsafe { CPU_LOCAL_APIC_IDS.as_ref().unwrap().iter() } {
-			if *apic_id != core_id {
-				let destination = (*apic_id as u64) << 32;
-				local_apic_write(IA32_X2APIC_ICR, destination | APIC_ICR_LEVEL_ASSERT | APIC_ICR_DELIVERY_MODE_FIXED | (TLB_FLUSH_INTERRUPT_NUMBER as u64));
+		for apic_id in online_cpus.iter() {
+			if apic_id != core_id {
+				send_ipi(apic_id, IpiMessage::fixed(TLB_FLUSH_INTERRUPT_NUMBER));
 			}
 		}
 	}
 }
 
+/// Asks all other online CPUs to stop (see shutdown::quiesce_all_cores).
+pub fn ipi_shutdown() {
+	let core_id = core_id();
+
+	for apic_id in ::arch::x86_64::online_cpu_mask().iter() {
+		if apic_id != core_id {
+			send_ipi(apic_id, IpiMessage::fixed(SHUTDOWN_INTERRUPT_NUMBER));
+		}
+	}
+}
+
+/// Sends the Inter-Processor Interrupt `message` to the CPU with Local APIC ID `target`,
+/// or to the CPUs selected by its destination shorthand.
+/// All IPIs of the kernel go through here.
+pub fn send_ipi(target: u32, message: IpiMessage) {
+	assert!(
+		processor::supports_x2apic() || message.shorthand != DestinationShorthand::None || target <= 0xFF,
+		"Local APIC ID {} cannot be addressed in xAPIC mode", target
+	);
+	local_apic_write(IA32_X2APIC_ICR, message.encode(target));
+}
+
 /// Gets the Core ID (here Local APIC ID) for a given sequential CPU number.
 /// Both numbers often match, but don't need to (e.g. when a core has been disabled).
+/// CPU number 0 is guaranteed to be the Boot Processor.
 #[inline]
 pub fn get_core_id_for_cpu_number(cpu_number: usize) -> Option<u32> {
 	let apic_ids = unsafe { CPU_LOCAL_APIC_IDS.as_ref().unwrap() };
@@ -508,9 +897,8 @@ pub fn get_core_id_for_cpu_number(cpu_number: usize) -> Option<u32> {
 
 /// Send an inter-processor interrupt to wake up a CPU Core that is in a HALT state.
 pub fn wakeup_core(core_to_wakeup: u32) {
-	if core_to_wakeup != core_id() {
-		let destination = (core_to_wakeup as u64) << 32;
-		local_apic_write(IA32_X2APIC_ICR, destination | APIC_ICR_LEVEL_ASSERT | APIC_ICR_DELIVERY_MODE_FIXED | (WAKEUP_INTERRUPT_NUMBER as u64));
+	if core_to_wakeup != core_id() && !unsafe { PIC_ONLY_MODE } {
+		send_ipi(core_to_wakeup, IpiMessage::fixed(WAKEUP_INTERRUPT_NUMBER));
 	}
 }
 
@@ -529,6 +917,22 @@ fn local_apic_read(x2apic_msr: u32) -> u32 {
 	}
 }
 
+/// Returns the errors latched in the Error Status Register and clears them.
+/// The ESR is only updated by a write, and the write after reading clears the errors we have just read.
+/// x2APIC raises a #GP for non-zero writes, so always write zero.
+fn read_error_status() -> u32 {
+	local_apic_write(IA32_X2APIC_ESR, 0);
+	let error_status = local_apic_read(IA32_X2APIC_ESR);
+	local_apic_write(IA32_X2APIC_ESR, 0);
+	error_status
+}
+
+/// Returns the number of APIC Error Interrupts on all cores since boot.
+/// A growing count usually means that IPIs are not delivered, see the ESR logged by the interrupt handler.
+pub fn error_count() -> usize {
+	ERROR_COUNT.load(Ordering::Relaxed)
+}
+
 fn ioapic_write(reg: u32, value: u32)
 {
 	unsafe {
@@ -554,11 +958,26 @@ fn ioapic_version() -> u32
 	ioapic_read(IOAPIC_REG_VER) & 0xFF
 }
 
+/// Returns the number of I/O APIC pins in use. init_ioapic routes pin i to interrupt number 0x20 + i.
+pub fn ioapic_pin_count() -> u8 {
+	if unsafe { IOAPIC_ADDRESS } == 0 || environment::is_uhyve() {
+		0
+	} else {
+		ioapic_max_redirection_entry() + 1
+	}
+}
+
 fn ioapic_max_redirection_entry() -> u8
 {
 	((ioapic_read(IOAPIC_REG_VER) >> 16) & 0xFF) as u8
 }
 
+/// Returns the xAPIC ICR2 register value for the x2APIC ICR `value`, whose lower half goes into ICR1 unchanged.
+/// The destination field in ICR2 is only 8 bits wide and sits in the topmost byte.
+fn xapic_icr2(value: u64) -> u32 {
+	((value >> 8) & 0xFF00_0000) as u32
+}
+
 fn local_apic_write(x2apic_msr: u32, value: u64) {
 	if processor::supports_x2apic() {
 		// x2APIC is simple, we can just write the given value to the given MSR.
@@ -566,10 +985,8 @@ fn local_apic_write(x2apic_msr: u32, value: u64) {
 	} else {
 		if x2apic_msr == IA32_X2APIC_ICR {
 			// Instead of a single 64-bit ICR register, xAPIC has two 32-bit registers (ICR1 and ICR2).
-			// There is a gap between them and the destination field in ICR2 is also 8 bits instead of 32 bits.
-			let destination = ((value >> 8) & 0xFF00_0000) as u32;
 			let icr2 = unsafe { &mut *((LOCAL_APIC_ADDRESS + APIC_ICR2) as *mut u32) };
-			*icr2 = destination;
+			*icr2 = xapic_icr2(value);
 
 			// The remaining data without the destination will now be written into ICR1.
 		}
@@ -583,7 +1000,7 @@ fn local_apic_write(x2apic_msr: u32, value: u64) {
 			// Wait until the CPU clears it.
 			// This bit does not exist in x2APIC mode (cf. Intel Vol. 3A, 10.12.9).
 			while (unsafe { ptr::read_volatile(value_ref) } & APIC_ICR_DELIVERY_STATUS_PENDING) > 0 {
-				spin_loop_hint();
+				processor::pause();
 			}
 		}
 	}
@@ -591,7 +1008,72 @@ fn local_apic_write(x2apic_msr: u32, value: u64) {
 
 pub fn print_information() {
 	infoheader!(" MULTIPROCESSOR INFORMATION ");
-	infoentry!("APIC in use", if processor::supports_x2apic() { "x2APIC" } else { "xAPIC" });
+	infoentry!("APIC in use", if !is_available() { "None (PIC only)" } else if processor::supports_x2apic() { "x2APIC" } else { "xAPIC" });
+	infoentry!("Boot Processor", "Local APIC ID {}", boot_processor_id());
 	infoentry!("Initialized CPUs", unsafe { ptr::read_volatile(&cpu_online) });
+	if let Some(apic_ids) = unsafe { CPU_LOCAL_APIC_IDS.as_ref() } {
+		infoentry!("Physical cores", processor::topology().physical_cores(apic_ids));
+	}
+	infoentry!("Online CPUs", "{:?}", ::arch::x86_64::online_cpu_mask());
+	if !failed_cpus().is_empty() {
+		infoentry!("Failed CPUs (Local APIC IDs)", "{:?}", failed_cpus());
+	}
+	infoentry!("APIC Errors", error_count());
 	infofooter!();
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn each_delivery_mode_is_encoded() {
+		let lowest_priority = IpiMessage { delivery_mode: DeliveryMode::LowestPriority, ..IpiMessage::fixed(0x31) };
+		let smi = IpiMessage { delivery_mode: DeliveryMode::Smi, vector: 0xFF, ..IpiMessage::nmi() };
+
+		assert_eq!(IpiMessage::fixed(0x31).encode(5), 0x5_0000_4031);
+		assert_eq!(lowest_priority.encode(5), 0x5_0000_4131);
+		// SMI, NMI, and INIT have no vector, so a given one is ignored.
+		assert_eq!(smi.encode(5), 0x5_0000_4200);
+		assert_eq!(IpiMessage::nmi().encode(5), 0x5_0000_4400);
+		assert_eq!(IpiMessage { vector: 0xFF, ..IpiMessage::init(true) }.encode(5), 0x5_0000_C500);
+		assert_eq!(IpiMessage::startup(0x8000).encode(5), 0x5_0000_4608);
+	}
+
+	#[test]
+	fn init_and_startup_ipis_are_encoded() {
+		assert_eq!(IpiMessage::init(true).encode(3), 0x3_0000_C500);
+		assert_eq!(IpiMessage::init(false).encode(3), 0x3_0000_8500);
+		assert_eq!(IpiMessage::startup(0x8000).encode(3), 0x3_0000_4608);
+	}
+
+	#[test]
+	fn startup_sequence_follows_the_specification() {
+		let sequence = startup_sequence(0x8000);
+		let encoded: Vec<(u64, u64)> = sequence.iter().map(|&(message, delay)| (message.encode(3), delay)).collect();
+		assert_eq!(encoded, vec![(0x3_0000_C500, 200), (0x3_0000_8500, 10000), (0x3_0000_4608, 200), (0x3_0000_4608, 200)]);
+
+		// The timeout reset of start_ap reuses the INIT assert and deassert at the start.
+		assert!(sequence[..2].iter().all(|&(message, _)| message.delivery_mode == DeliveryMode::Init));
+	}
+
+	#[test]
+	fn shorthand_ignores_target() {
+		let message = IpiMessage { shorthand: DestinationShorthand::AllExcludingSelf, ..IpiMessage::fixed(0x20) };
+		assert_eq!(message.encode(3), 0xC_4020);
+
+		let message = IpiMessage { shorthand: DestinationShorthand::SelfOnly, ..IpiMessage::nmi() };
+		assert_eq!(message.encode(3), 0x4_4400);
+	}
+
+	#[test]
+	fn xapic_destination_is_moved_into_icr2() {
+		let value = IpiMessage::fixed(0x20).encode(0xAB);
+		assert_eq!((xapic_icr2(value), value as u32), (0xAB00_0000, 0x4020));
+
+		// x2APIC IDs beyond 8 bits cannot be addressed in xAPIC mode, send_ipi rejects them.
+		let value = IpiMessage::init(true).encode(0x1_0003);
+		assert_eq!((xapic_icr2(value), value as u32), (0x0300_0000, 0xC500));
+	}
+}
@@ -0,0 +1,123 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Sets of CPU cores, e.g. to keep track of the cores that are online.
+
+use core::fmt;
+
+
+/// Number of Core IDs (Local APIC IDs) that fit into a CpuMask.
+/// xAPIC IDs have 8 bits, so this covers every core addressable without x2APIC cluster mode.
+pub const MAX_CORES: usize = 256;
+
+const BITS_PER_WORD: usize = 64;
+
+
+/// A set of CPU cores, indexed by their Core ID (the Local APIC ID).
+#[derive(Clone, Copy, PartialEq)]
+pub struct CpuMask {
+	words: [u64; MAX_CORES / BITS_PER_WORD],
+}
+
+impl CpuMask {
+	pub const fn new() -> Self {
+		Self { words: [0; MAX_CORES / BITS_PER_WORD] }
+	}
+
+	pub fn insert(&mut self, core_id: u32) {
+		assert!((core_id as usize) < MAX_CORES, "Core ID {} does not fit into a CpuMask", core_id);
+		self.words[core_id as usize / BITS_PER_WORD] |= 1 << (core_id as usize % BITS_PER_WORD);
+	}
+
+	pub fn remove(&mut self, core_id: u32) {
+		if (core_id as usize) < MAX_CORES {
+			self.words[core_id as usize / BITS_PER_WORD] &= !(1 << (core_id as usize % BITS_PER_WORD));
+		}
+	}
+
+	pub fn contains(&self, core_id: u32) -> bool {
+		(core_id as usize) < MAX_CORES && self.words[core_id as usize / BITS_PER_WORD] & (1 << (core_id as usize % BITS_PER_WORD)) > 0
+	}
+
+	/// Returns the number of cores in this set.
+	pub fn count(&self) -> usize {
+		self.words.iter().map(|word| word.count_ones() as usize).sum()
+	}
+
+	/// Returns an iterator over the Core IDs in this set in ascending order.
+	pub fn iter(&self) -> impl Iterator<Item = u32> {
+		let mask = *self;
+		(0..MAX_CORES as u32).filter(move |core_id| mask.contains(*core_id))
+	}
+}
+
+impl fmt::Debug for CpuMask {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_set().entries(self.iter()).finish()
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn cores_are_inserted_and_removed() {
+		let mut mask = CpuMask::new();
+		assert_eq!(mask.count(), 0);
+
+		// Cover the first and last bit of the first word and IDs in higher words.
+		for &core_id in [0, 63, 64, 130, 255].iter() {
+			mask.insert(core_id);
+		}
+		assert_eq!(mask.count(), 5);
+		assert!(mask.contains(63) && mask.contains(64) && mask.contains(255));
+		assert!(!mask.contains(1) && !mask.contains(65));
+
+		mask.remove(63);
+		mask.remove(1);
+		assert_eq!(mask.count(), 4);
+		assert!(!mask.contains(63));
+		assert_eq!(mask.iter().collect::<Vec<u32>>(), vec![0, 64, 130, 255]);
+		assert_eq!(format!("{:?}", mask), "{0, 64, 130, 255}");
+	}
+
+	#[test]
+	fn ids_beyond_the_mask_are_never_contained() {
+		let mut mask = CpuMask::new();
+		mask.insert(0);
+		let unchanged = mask;
+
+		// Removing an ID beyond the mask is a no-op.
+		mask.remove(MAX_CORES as u32);
+		assert!(!mask.contains(MAX_CORES as u32));
+		assert!(mask == unchanged);
+	}
+
+	#[test]
+	#[should_panic]
+	fn inserting_an_id_beyond_the_mask_panics() {
+		CpuMask::new().insert(MAX_CORES as u32);
+	}
+}
@@ -0,0 +1,334 @@
+// Copyright (c) 2017 Stefan Lankes, RWTH Aachen University
+//                    Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Hardware breakpoints and watchpoints through the debug registers DR0-DR3 and DR7, and single-stepping
+//! through the Trap Flag.
+//!
+//! Breakpoints are global for the kernel: they are stored in a table and each core loads them into its debug
+//! registers at the next context switch (or right away for the core calling set_breakpoint).
+//! Single-stepping applies to a single task. A registered callback can decide for each stepped instruction
+//! whether it is reported.
+//!
+//! The #DB handler may interrupt code holding any lock, so it takes none. It only records what happened in
+//! PerCoreVariables, which synchronize logs at the next context switch.
+//! See Intel Vol. 3B, Chapter 17 Debug, Branch Profile, TSC, and Intel Resource Director Technology Features.
+
+use arch::x86_64::irq;
+use arch::x86_64::percore::*;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use synch::spinlock::SpinlockIrqSave;
+
+
+/// Number of breakpoints supported by the hardware (DR0-DR3).
+pub const BREAKPOINT_COUNT: usize = 4;
+
+/// Breakpoint condition bits B0-B3 in DR6.
+pub const DR6_BREAKPOINT_MASK: u64 = 0xF;
+/// Single-step bit BS in DR6.
+pub const DR6_SINGLE_STEP: u64 = 1 << 14;
+/// Value of DR6 after a reset, which is also written to clear the status after handling a #DB.
+const DR6_RESET_VALUE: u64 = 0xFFFF_0FF0;
+
+/// Resume Flag in RFLAGS, which suppresses instruction breakpoints for the next instruction.
+const RFLAGS_RF: u64 = 1 << 16;
+/// Trap Flag in RFLAGS, which raises a #DB after each instruction.
+const RFLAGS_TF: u64 = 1 << 8;
+
+
+/// Gets the address of each instruction of the single-stepped task and returns whether the step shall be reported.
+/// It is called from the #DB handler, so it must neither take locks nor log.
+pub type SingleStepCallback = fn(usize) -> bool;
+
+
+/// Access that triggers a breakpoint (R/W bits in DR7).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BreakpointKind {
+	/// Instruction execution. Such breakpoints always have length Byte.
+	Execute = 0b00,
+	/// Data writes.
+	Write = 0b01,
+	/// Data reads and writes.
+	ReadWrite = 0b11,
+}
+
+/// Size of the watched memory region (LEN bits in DR7).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BreakpointLength {
+	Byte = 0b00,
+	Word = 0b01,
+	DWord = 0b11,
+	QWord = 0b10,
+}
+
+impl BreakpointLength {
+	fn bytes(&self) -> usize {
+		match *self {
+			BreakpointLength::Byte => 1,
+			BreakpointLength::Word => 2,
+			BreakpointLength::DWord => 4,
+			BreakpointLength::QWord => 8,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Breakpoint {
+	address: usize,
+	kind: BreakpointKind,
+	length: BreakpointLength,
+}
+
+
+lazy_static! {
+	static ref BREAKPOINTS: SpinlockIrqSave<[Option<Breakpoint>; BREAKPOINT_COUNT]> = SpinlockIrqSave::new([None; BREAKPOINT_COUNT]);
+}
+
+/// ID of the task being single-stepped plus one, or zero if no task is.
+static SINGLE_STEP_TASK: AtomicUsize = AtomicUsize::new(0);
+
+/// Address of the registered SingleStepCallback, or zero if every step is reported.
+static SINGLE_STEP_CALLBACK: AtomicUsize = AtomicUsize::new(0);
+
+/// Incremented on every change of BREAKPOINTS to let other cores know that they have to reload their debug registers.
+static GENERATION: AtomicUsize = AtomicUsize::new(1);
+
+
+macro_rules! write_debug_register {
+	($register:expr, $value:expr) => {
+		asm!(concat!("movq $0, %db", $register) :: "r"($value as u64) :: "volatile")
+	};
+}
+
+fn read_dr6() -> u64 {
+	let value: u64;
+	unsafe { asm!("movq %db6, $0" : "=r"(value) ::: "volatile"); }
+	value
+}
+
+fn read_dr7() -> u64 {
+	let value: u64;
+	unsafe { asm!("movq %db7, $0" : "=r"(value) ::: "volatile"); }
+	value
+}
+
+/// Returns the debug status (DR6) and resets it, because the processor never clears it by itself.
+pub fn take_debug_status() -> u64 {
+	let dr6 = read_dr6();
+	unsafe { write_debug_register!("6", DR6_RESET_VALUE); }
+	dr6
+}
+
+fn load_debug_registers(breakpoints: &[Option<Breakpoint>; BREAKPOINT_COUNT]) {
+	let mut dr7: u64 = 0;
+	let mut addresses = [0usize; BREAKPOINT_COUNT];
+
+	for (i, breakpoint) in breakpoints.iter().enumerate() {
+		if let Some(ref breakpoint) = *breakpoint {
+			addresses[i] = breakpoint.address;
+
+			// Set the global enable bit G_i and the condition bits of this breakpoint.
+			dr7 |= 1 << (2 * i + 1);
+			dr7 |= (breakpoint.kind as u64) << (16 + 4 * i);
+			dr7 |= (breakpoint.length as u64) << (18 + 4 * i);
+		}
+	}
+
+	unsafe {
+		// Disable all breakpoints while changing the addresses.
+		write_debug_register!("7", 0);
+		write_debug_register!("0", addresses[0]);
+		write_debug_register!("1", addresses[1]);
+		write_debug_register!("2", addresses[2]);
+		write_debug_register!("3", addresses[3]);
+		write_debug_register!("6", DR6_RESET_VALUE);
+		write_debug_register!("7", dr7);
+	}
+}
+
+/// Logs the single-steps and breakpoint hits recorded by the #DB handler on this core since the last call.
+fn report_debug_events() {
+	let (steps, step_address, hits, hit_address) = irq::without_interrupts(|| unsafe {
+		let events = (
+			PERCORE.debug_single_steps.get(),
+			PERCORE.debug_single_step_address.get(),
+			PERCORE.debug_breakpoint_hits.get(),
+			PERCORE.debug_breakpoint_address.get(),
+		);
+		PERCORE.debug_single_steps.set(0);
+		PERCORE.debug_breakpoint_hits.set(0);
+		events
+	});
+
+	if steps > 0 {
+		info!("Single-stepped {} instructions on core {}, last one at {:#X}", steps, core_id(), step_address);
+	}
+
+	for i in 0..BREAKPOINT_COUNT {
+		if (hits & (1 << i)) > 0 {
+			info!("Hit breakpoint {} on core {}, last time at instruction {:#X}", i, core_id(), hit_address);
+		}
+	}
+}
+
+/// Loads the current breakpoints into the debug registers of this core if they have changed
+/// and reports the debug events recorded since the last call.
+/// Called on every context switch and when bringing up a core.
+pub fn synchronize() {
+	report_debug_events();
+
+	let generation = GENERATION.load(Ordering::SeqCst);
+	if unsafe { PERCORE.debug_generation.get() } == generation {
+		return;
+	}
+
+	let breakpoints = BREAKPOINTS.lock();
+	load_debug_registers(&breakpoints);
+	unsafe { PERCORE.debug_generation.set(generation); }
+}
+
+/// Sets a hardware breakpoint for accesses of `kind` to the `length` bytes at `address`.
+/// Returns the index of the used debug register, which can be passed to clear_breakpoint.
+///
+/// Fails if all debug registers are in use, if `address` is not aligned to `length`,
+/// or if an Execute breakpoint has a length other than Byte.
+pub fn set_breakpoint(address: usize, kind: BreakpointKind, length: BreakpointLength) -> Result<usize, ()> {
+	if address % length.bytes() != 0 || (kind == BreakpointKind::Execute && length != BreakpointLength::Byte) {
+		return Err(());
+	}
+
+	let index = {
+		let mut breakpoints = BREAKPOINTS.lock();
+		let index = breakpoints.iter().position(|breakpoint| breakpoint.is_none()).ok_or(())?;
+		breakpoints[index] = Some(Breakpoint {
+			address: address,
+			kind: kind,
+			length: length,
+		});
+		index
+	};
+
+	GENERATION.fetch_add(1, Ordering::SeqCst);
+	synchronize();
+	info!("Set {:?} breakpoint {} at {:#X} ({:?})", kind, index, address, length);
+	Ok(index)
+}
+
+/// Returns the index of the hardware breakpoint of `kind` at `address`, if any.
+pub fn find_breakpoint(address: usize, kind: BreakpointKind) -> Option<usize> {
+	BREAKPOINTS.lock().iter().position(|breakpoint| match *breakpoint {
+		Some(ref breakpoint) => breakpoint.address == address && breakpoint.kind == kind,
+		None => false,
+	})
+}
+
+/// Removes the hardware breakpoint with the given index.
+pub fn clear_breakpoint(index: usize) {
+	assert!(index < BREAKPOINT_COUNT, "Invalid breakpoint index {}", index);
+
+	BREAKPOINTS.lock()[index] = None;
+	GENERATION.fetch_add(1, Ordering::SeqCst);
+	synchronize();
+}
+
+/// Starts or stops single-stepping the current task in the context whose saved RFLAGS are `rflags`,
+/// e.g. the context interrupted by a debugger exception, which resumes with them.
+///
+/// The Trap Flag is part of RFLAGS, so it is saved and restored with the task on every context switch.
+/// Only the instructions of the current task are reported: the CPU clears the Trap Flag when entering an
+/// interrupt handler, and other tasks found with the Trap Flag set (e.g. stepping into the context switch code)
+/// have it cleared.
+pub fn single_step(rflags: &mut u64, enable: bool) {
+	if enable {
+		SINGLE_STEP_TASK.store(current_task_id() as usize + 1, Ordering::SeqCst);
+		*rflags |= RFLAGS_TF;
+	} else {
+		*rflags &= !RFLAGS_TF;
+		SINGLE_STEP_TASK.store(0, Ordering::SeqCst);
+	}
+}
+
+/// Registers the callback deciding which single-stepped instructions are reported, replacing any previous one.
+/// Without a callback, every instruction is reported.
+pub fn set_single_step_callback(callback: Option<SingleStepCallback>) {
+	SINGLE_STEP_CALLBACK.store(callback.map_or(0, |callback| callback as usize), Ordering::SeqCst);
+}
+
+/// Handles a single-step #DB, which stopped before the instruction at `address` in the context with the saved
+/// RFLAGS `rflags`.
+/// Returns whether the step shall be reported, i.e. it belongs to the task being single-stepped and the registered
+/// callback has not filtered it out.
+pub fn handle_single_step(address: usize, rflags: &mut u64) -> bool {
+	if SINGLE_STEP_TASK.load(Ordering::SeqCst) != current_task_id() as usize + 1 {
+		// This is not the task being stepped.
+		*rflags &= !RFLAGS_TF;
+		return false;
+	}
+
+	match SINGLE_STEP_CALLBACK.load(Ordering::SeqCst) {
+		0 => true,
+		callback => {
+			let callback: SingleStepCallback = unsafe { mem::transmute(callback) };
+			callback(address)
+		}
+	}
+}
+
+/// Handles a Debug Exception (#DB) and returns whether it has been caused by one of our breakpoints
+/// or by single-stepping. Otherwise, the caller should treat the exception as fatal.
+pub fn handle_debug_exception(stack_frame: &mut irq::ExceptionStackFrame) -> bool {
+	let dr6 = take_debug_status();
+	let triggered = dr6 & DR6_BREAKPOINT_MASK;
+
+	if (dr6 & DR6_SINGLE_STEP) > 0 && handle_single_step(stack_frame.instruction_pointer as usize, &mut stack_frame.cpu_flags) {
+		unsafe {
+			PERCORE.debug_single_steps.set(PERCORE.debug_single_steps.get() + 1);
+			PERCORE.debug_single_step_address.set(stack_frame.instruction_pointer as usize);
+		}
+	}
+
+	if triggered == 0 {
+		return (dr6 & DR6_SINGLE_STEP) > 0;
+	}
+
+	// Get the kinds of the triggered breakpoints from DR7 instead of BREAKPOINTS, which would need its lock.
+	let dr7 = read_dr7();
+	for i in 0..BREAKPOINT_COUNT {
+		if (triggered & (1 << i)) == 0 {
+			continue;
+		}
+
+		// Instruction breakpoints are faults, so the instruction would trigger the breakpoint again on return.
+		if (dr7 >> (16 + 4 * i)) & 0b11 == BreakpointKind::Execute as u64 {
+			stack_frame.cpu_flags |= RFLAGS_RF;
+		}
+	}
+
+	unsafe {
+		PERCORE.debug_breakpoint_hits.set(PERCORE.debug_breakpoint_hits.get() | triggered as usize);
+		PERCORE.debug_breakpoint_address.set(stack_frame.instruction_pointer as usize);
+	}
+
+	true
+}
@@ -112,6 +112,12 @@ align 4
     current_stack_address dq boot_stack_bottom
     current_percore_address dq PERCORE
 
+; Versioned boot information filled in by uhyve, see environment::BootInfo.
+; Older uhyve builds leave it zeroed and only set the fields above.
+align 8
+    global boot_info
+    boot_info times 64 db 0
+
 SECTION .ktext
 align 4
 start64:
@@ -128,6 +134,11 @@ start64:
     ; => see ABI
     cld
 
+    ; Tell the Boot Processor that this core takes the stack and PerCoreVariables passed to it.
+    ; XCHG implies a full memory barrier, so the flag is set before the addresses are read.
+    mov eax, 1
+    xchg DWORD [boot_addresses_taken], eax
+
     ; set default stack pointer
     mov rsp, QWORD [current_stack_address]
     add rsp, (KERNEL_STACK_SIZE - 0x10)
@@ -269,9 +280,28 @@ irq%1:
 %assign i i+1
 %endrep
 
+; Table of functions raising the interrupts 0 to 31 by software (see irq::raise_software_interrupt).
+; Each entry is 4 bytes long, so the entry for an interrupt is at irq_trigger_table + 4 * interrupt.
+global irq_trigger_table
+align 4
+irq_trigger_table:
+%assign i 0
+%rep    32
+    align 4
+    int 32+i
+    ret
+%assign i i+1
+%endrep
+
 
 SECTION .data
 
+; Set by each core before it reads current_stack_address and current_percore_address.
+; Lets the Boot Processor tell whether an Application Processor that failed to boot has used them.
+align 4
+    global boot_addresses_taken
+    boot_addresses_taken dd 0
+
 ; This stack is used by the Boot Processor only.
 align 4096
 boot_stack_bottom:
@@ -0,0 +1,208 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Linear framebuffer set up by the Multiboot loader.
+//!
+//! The framebuffer is mapped Write-Combining, which makes writes fast, but reads very slow.
+//! Therefore, all drawing goes to a back buffer in regular RAM by default, and present() copies
+//! the changed lines to the framebuffer using non-temporal stores (mm::memcpy_nt), which neither read the
+//! framebuffer nor pollute the caches.
+//! Scrolling only moves memory within the back buffer.
+//!
+//! The `framebuffer=direct` command-line argument draws into the framebuffer directly instead,
+//! which needs no memory for a back buffer, but makes scrolling slow.
+//! Only 32 bits per pixel RGB framebuffers are supported.
+
+use alloc::vec::Vec;
+use arch::x86_64::mm::paging::{BasePageSize, MemoryType, PageSize, PageTableEntryFlags};
+use arch::x86_64::mtrr;
+use arch::x86_64::mtrr::MtrrType;
+use arch::x86_64::processor;
+use core::{cmp, ptr};
+use environment;
+use hermit_multiboot::FRAMEBUFFER_TYPE_RGB;
+use mm;
+use synch::spinlock::SpinlockIrqSave;
+
+static FRAMEBUFFER: SpinlockIrqSave<Option<FramebufferScreen>> = SpinlockIrqSave::new(None);
+
+
+struct FramebufferScreen {
+	/// Virtual address of the framebuffer
+	framebuffer: *mut u32,
+	/// Memory drawn to, if it is not the framebuffer itself
+	back_buffer: Option<Vec<u32>>,
+	/// Number of pixels per line, including any padding of the framebuffer
+	stride: usize,
+	width: usize,
+	height: usize,
+	/// Range of lines that have been changed in the back buffer since the last present()
+	dirty_lines: Option<(usize, usize)>,
+}
+
+// The framebuffer pointer is only accessed with the FRAMEBUFFER lock held.
+unsafe impl Send for FramebufferScreen {}
+
+impl FramebufferScreen {
+	/// Returns the memory to draw to.
+	fn target(&mut self) -> *mut u32 {
+		match self.back_buffer {
+			Some(ref mut buffer) => buffer.as_mut_ptr(),
+			None => self.framebuffer,
+		}
+	}
+
+	fn mark_dirty(&mut self, first_line: usize, last_line: usize) {
+		self.dirty_lines = match self.dirty_lines {
+			Some((first, last)) => Some((cmp::min(first, first_line), cmp::max(last, last_line))),
+			None => Some((first_line, last_line)),
+		};
+	}
+
+	fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u32) {
+		if x >= self.width || y >= self.height || width == 0 || height == 0 {
+			return;
+		}
+
+		let width = cmp::min(width, self.width - x);
+		let height = cmp::min(height, self.height - y);
+		let target = self.target();
+
+		for line in y..y + height {
+			let start = line * self.stride + x;
+			for i in start..start + width {
+				unsafe { ptr::write_volatile(target.offset(i as isize), color); }
+			}
+		}
+
+		self.mark_dirty(y, y + height - 1);
+	}
+
+	fn scroll_up(&mut self, lines: usize, color: u32) {
+		let lines = cmp::min(lines, self.height);
+		let moved_pixels = (self.height - lines) * self.stride;
+		let target = self.target();
+
+		unsafe { ptr::copy(target.offset((lines * self.stride) as isize), target, moved_pixels); }
+
+		let (width, height) = (self.width, self.height);
+		self.fill_rect(0, height - lines, width, lines, color);
+		self.mark_dirty(0, height - 1);
+	}
+
+	fn present(&mut self) {
+		let (first_line, last_line) = match self.dirty_lines.take() {
+			Some(dirty_lines) => dirty_lines,
+			None => return,
+		};
+
+		if let Some(ref buffer) = self.back_buffer {
+			// The lines are consecutive, so copy them in one go, including the padding at the end of each line.
+			let start = first_line * self.stride;
+			let count = (last_line + 1 - first_line) * self.stride;
+			unsafe {
+				mm::memcpy_nt(self.framebuffer.offset(start as isize) as *mut u8, buffer[start..].as_ptr() as *const u8, count * 4);
+			}
+		}
+	}
+}
+
+
+pub fn init() {
+	let info = match environment::get_framebuffer() {
+		Some(info) => info,
+		None => return,
+	};
+
+	if info.framebuffer_type != FRAMEBUFFER_TYPE_RGB || info.bpp != 32 || info.pitch % 4 != 0 {
+		warn!("Unsupported framebuffer ({} bpp, type {}), not using it", info.bpp, info.framebuffer_type);
+		return;
+	}
+
+	// Without a Page Attribute Table, only an MTRR can make the framebuffer Write-Combining.
+	// The framebuffer then needs to be mapped Write-Back, so the memory type of the MTRR applies.
+	// Variable MTRRs only cover power-of-two sizes, so the framebuffer may need several of them.
+	let size = info.pitch * info.height;
+	let virtual_address = if !processor::supports_pat() && mtrr::set_ranges(info.address, align_up!(size, BasePageSize::SIZE), MtrrType::WriteCombining).is_ok() {
+		mm::map_physical(info.address, size, PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE)
+	} else {
+		mm::map_device_memory(info.address, size, MemoryType::WriteCombining)
+	};
+	let framebuffer = virtual_address as *mut u32;
+	let stride = info.pitch / 4;
+	let back_buffer = if environment::get_arg("framebuffer") == Some("direct") {
+		None
+	} else {
+		let mut buffer = Vec::with_capacity(stride * info.height);
+		buffer.resize(stride * info.height, 0);
+		Some(buffer)
+	};
+
+	info!("Using framebuffer at {:#X} with {}x{} pixels ({})", info.address, info.width, info.height,
+		if back_buffer.is_some() { "double-buffered" } else { "direct" });
+
+	let mut screen = FramebufferScreen {
+		framebuffer: framebuffer,
+		back_buffer: back_buffer,
+		stride: stride,
+		width: info.width,
+		height: info.height,
+		dirty_lines: None,
+	};
+
+	screen.fill_rect(0, 0, info.width, info.height, 0);
+	screen.present();
+	*FRAMEBUFFER.lock() = Some(screen);
+}
+
+/// Returns the width and height of the framebuffer in pixels or None if there is none.
+pub fn dimensions() -> Option<(usize, usize)> {
+	FRAMEBUFFER.lock().as_ref().map(|screen| (screen.width, screen.height))
+}
+
+/// Sets a single pixel to the given 0x00RRGGBB color.
+pub fn put_pixel(x: usize, y: usize, color: u32) {
+	fill_rect(x, y, 1, 1, color);
+}
+
+/// Fills a rectangle with the given 0x00RRGGBB color. Parts outside the screen are clipped.
+pub fn fill_rect(x: usize, y: usize, width: usize, height: usize, color: u32) {
+	if let Some(ref mut screen) = *FRAMEBUFFER.lock() {
+		screen.fill_rect(x, y, width, height, color);
+	}
+}
+
+/// Moves the screen content up by `lines` pixel lines and fills the freed lines with the given color.
+pub fn scroll_up(lines: usize, color: u32) {
+	if let Some(ref mut screen) = *FRAMEBUFFER.lock() {
+		screen.scroll_up(lines, color);
+	}
+}
+
+/// Makes all drawing since the last call visible on the screen.
+/// Only the changed lines are copied. Without a back buffer, drawing is immediately visible anyway.
+pub fn present() {
+	if let Some(ref mut screen) = *FRAMEBUFFER.lock() {
+		screen.present();
+	}
+}
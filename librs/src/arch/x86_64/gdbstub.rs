@@ -0,0 +1,621 @@
+// Copyright (c) 2017 Stefan Lankes, RWTH Aachen University
+//                    Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Minimal stub for the GDB Remote Serial Protocol.
+//!
+//! Enabled through the `gdbstub[=<port>]` command-line argument, the stub talks to GDB over a dedicated serial
+//! port (COM2 by default), so `target remote` can attach to a running kernel.
+//! It takes over the Debug (#DB) and Breakpoint (#BP) exceptions and supports reading and writing registers
+//! and memory, continuing, single-stepping (including range stepping through vCont), and hardware breakpoints and
+//! watchpoints through the debug module.
+//!
+//! Only the core hitting an exception is stopped. The other cores keep running.
+//! See https://sourceware.org/gdb/onlinedocs/gdb/Remote-Protocol.html
+
+use alloc::vec::Vec;
+use arch::x86_64::debug::{self, BreakpointKind, BreakpointLength};
+use arch::x86_64::gdt;
+use arch::x86_64::idt;
+use arch::x86_64::mm::paging::{self, BasePageSize, PageSize};
+use arch::x86_64::serial::SerialPort;
+use core::{cmp, str};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use environment;
+use synch::spinlock::SpinlockIrqSave;
+
+
+/// Default port of the GDB connection (COM2), so it doesn't interfere with the kernel messages on COM1.
+const DEFAULT_PORT_ADDRESS: u16 = 0x2F8;
+const BAUDRATE: u32 = 115200;
+
+/// Largest packet we accept and announce to GDB in qSupported.
+const MAXIMUM_PACKET_SIZE: usize = 0x1000;
+
+const RFLAGS_RF: u64 = 1 << 16;
+
+const SIGTRAP: u8 = 5;
+
+
+/// Registers of the interrupted context, as saved by gdbstub_common_entry.
+#[repr(C)]
+pub struct GdbFrame {
+	r15: u64,
+	r14: u64,
+	r13: u64,
+	r12: u64,
+	r11: u64,
+	r10: u64,
+	r9: u64,
+	r8: u64,
+	rbp: u64,
+	rdi: u64,
+	rsi: u64,
+	rdx: u64,
+	rcx: u64,
+	rbx: u64,
+	rax: u64,
+	/// Exception vector pushed by the entry stub.
+	vector: u64,
+	// Pushed by the CPU.
+	rip: u64,
+	cs: u64,
+	rflags: u64,
+	rsp: u64,
+	ss: u64,
+}
+
+impl GdbFrame {
+	/// Returns the general-purpose registers and RIP in the order of GDB's amd64 register numbers 0-16.
+	fn registers(&mut self) -> [&mut u64; 17] {
+		[
+			&mut self.rax, &mut self.rbx, &mut self.rcx, &mut self.rdx,
+			&mut self.rsi, &mut self.rdi, &mut self.rbp, &mut self.rsp,
+			&mut self.r8, &mut self.r9, &mut self.r10, &mut self.r11,
+			&mut self.r12, &mut self.r13, &mut self.r14, &mut self.r15,
+			&mut self.rip,
+		]
+	}
+}
+
+static mut SERIAL_PORT: SerialPort = SerialPort::new(DEFAULT_PORT_ADDRESS);
+
+/// Set once GDB has attached, so we only send stop replies to a connected debugger.
+static mut ATTACHED: bool = false;
+
+/// Address range [start, end) of a vCont range step, in which single-stepped instructions are not reported to GDB.
+static STEP_RANGE_START: AtomicUsize = AtomicUsize::new(0);
+static STEP_RANGE_END: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+	/// Serializes the cores hitting an exception, as there is only one connection to GDB.
+	static ref SESSION_LOCK: SpinlockIrqSave<()> = SpinlockIrqSave::new(());
+}
+
+
+#[naked]
+unsafe extern "C" fn gdbstub_debug_entry() {
+	asm!("pushq $$1; jmp gdbstub_common_entry" :::: "volatile");
+}
+
+#[naked]
+unsafe extern "C" fn gdbstub_breakpoint_entry() {
+	asm!("pushq $$3; jmp gdbstub_common_entry" :::: "volatile");
+}
+
+/// Saves all general-purpose registers to form a GdbFrame, calls gdbstub_handle_exception,
+/// and returns to the possibly modified context.
+#[naked]
+#[no_mangle]
+unsafe extern "C" fn gdbstub_common_entry() {
+	asm!("
+		push %rax
+		push %rbx
+		push %rcx
+		push %rdx
+		push %rsi
+		push %rdi
+		push %rbp
+		push %r8
+		push %r9
+		push %r10
+		push %r11
+		push %r12
+		push %r13
+		push %r14
+		push %r15
+		cld
+		mov %rsp, %rdi
+		sub $$8, %rsp
+		call gdbstub_handle_exception
+		add $$8, %rsp
+		pop %r15
+		pop %r14
+		pop %r13
+		pop %r12
+		pop %r11
+		pop %r10
+		pop %r9
+		pop %r8
+		pop %rbp
+		pop %rdi
+		pop %rsi
+		pop %rdx
+		pop %rcx
+		pop %rbx
+		pop %rax
+		add $$8, %rsp
+		iretq
+	" :::: "volatile");
+}
+
+
+fn hex_digit(value: u8) -> u8 {
+	b"0123456789abcdef"[(value & 0xF) as usize]
+}
+
+fn parse_hex_digit(digit: u8) -> Option<u8> {
+	match digit {
+		b'0'...b'9' => Some(digit - b'0'),
+		b'a'...b'f' => Some(digit - b'a' + 10),
+		b'A'...b'F' => Some(digit - b'A' + 10),
+		_ => None,
+	}
+}
+
+fn parse_hex(data: &[u8]) -> Option<usize> {
+	if data.is_empty() {
+		return None;
+	}
+
+	usize::from_str_radix(str::from_utf8(data).ok()?, 16).ok()
+}
+
+fn push_hex_byte(buffer: &mut Vec<u8>, byte: u8) {
+	buffer.push(hex_digit(byte >> 4));
+	buffer.push(hex_digit(byte));
+}
+
+/// Appends `size` little-endian bytes of `value` as hex, as GDB expects for registers.
+fn push_hex_le(buffer: &mut Vec<u8>, value: u64, size: usize) {
+	for i in 0..size {
+		push_hex_byte(buffer, (value >> (8 * i)) as u8);
+	}
+}
+
+fn parse_hex_le(data: &[u8]) -> Option<u64> {
+	let mut value = 0;
+	for (i, pair) in data.chunks(2).enumerate() {
+		if pair.len() != 2 {
+			return None;
+		}
+
+		let byte = (parse_hex_digit(pair[0])? << 4) | parse_hex_digit(pair[1])?;
+		value |= (byte as u64) << (8 * i);
+	}
+
+	Some(value)
+}
+
+/// Parses "<address>,<length>" as sent in m, M, Z, and z packets.
+fn parse_address_and_length(data: &[u8]) -> Option<(usize, usize)> {
+	let separator = data.iter().position(|&byte| byte == b',')?;
+	Some((parse_hex(&data[..separator])?, parse_hex(&data[separator + 1..])?))
+}
+
+/// Returns whether all `length` bytes at `address` are mapped, and writable if requested.
+fn is_accessible(address: usize, length: usize, write: bool) -> bool {
+	if length == 0 {
+		return true;
+	}
+
+	let end = match address.checked_add(length) {
+		Some(end) => end,
+		None => return false,
+	};
+
+	let mut page = align_down!(address, BasePageSize::SIZE);
+	while page < end {
+		match paging::translate(page) {
+			Some((_, flags)) if !write || flags.is_writable() => {},
+			_ => return false,
+		}
+
+		page += BasePageSize::SIZE;
+	}
+
+	true
+}
+
+
+fn serial_port() -> &'static mut SerialPort {
+	unsafe { &mut SERIAL_PORT }
+}
+
+/// Receives the next packet, verifies its checksum, and acknowledges it.
+fn receive_packet() -> Vec<u8> {
+	loop {
+		// Wait for the start of a packet, ignoring acknowledgements and anything else.
+		while serial_port().read_byte() != b'$' {}
+
+		let mut packet = Vec::new();
+		let mut checksum: u8 = 0;
+		loop {
+			let byte = serial_port().read_byte();
+			if byte == b'#' {
+				break;
+			}
+
+			checksum = checksum.wrapping_add(byte);
+			if packet.len() < MAXIMUM_PACKET_SIZE {
+				packet.push(byte);
+			}
+		}
+
+		let high = parse_hex_digit(serial_port().read_byte());
+		let low = parse_hex_digit(serial_port().read_byte());
+		if let (Some(high), Some(low)) = (high, low) {
+			if (high << 4 | low) == checksum {
+				serial_port().write_raw_byte(b'+');
+				return packet;
+			}
+		}
+
+		serial_port().write_raw_byte(b'-');
+	}
+}
+
+/// Sends a packet and waits until GDB has acknowledged it.
+fn send_packet(data: &[u8]) {
+	let checksum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+
+	loop {
+		serial_port().write_raw_byte(b'$');
+		for &byte in data.iter() {
+			serial_port().write_raw_byte(byte);
+		}
+
+		serial_port().write_raw_byte(b'#');
+		serial_port().write_raw_byte(hex_digit(checksum >> 4));
+		serial_port().write_raw_byte(hex_digit(checksum));
+
+		match serial_port().read_byte() {
+			b'+' => return,
+			// GDB sends a Ctrl+C (0x03) to interrupt, which we are already.
+			b'-' | 0x03 => continue,
+			_ => return,
+		}
+	}
+}
+
+fn send_stop_reply() {
+	let mut reply = Vec::new();
+	reply.push(b'S');
+	push_hex_byte(&mut reply, SIGTRAP);
+	send_packet(&reply);
+}
+
+/// Appends the registers in the layout of GDB's amd64 'g' packet.
+/// The segment registers other than CS and SS are always zero in 64-bit mode, the FPU registers are not transferred.
+fn read_registers(frame: &mut GdbFrame, reply: &mut Vec<u8>) {
+	for register in frame.registers().iter() {
+		push_hex_le(reply, **register, 8);
+	}
+
+	push_hex_le(reply, frame.rflags, 4);
+	push_hex_le(reply, frame.cs, 4);
+	push_hex_le(reply, frame.ss, 4);
+	for _ in 0..4 {
+		push_hex_le(reply, 0, 4);
+	}
+}
+
+fn write_registers(frame: &mut GdbFrame, data: &[u8]) -> bool {
+	// 17 registers with 8 bytes each followed by RFLAGS with 4 bytes.
+	if data.len() < (17 * 8 + 4) * 2 {
+		return false;
+	}
+
+	let rflags = match parse_hex_le(&data[17 * 16..17 * 16 + 8]) {
+		Some(rflags) => rflags,
+		None => return false,
+	};
+
+	let mut values = [0u64; 17];
+	for (i, value) in values.iter_mut().enumerate() {
+		match parse_hex_le(&data[i * 16..(i + 1) * 16]) {
+			Some(parsed) => *value = parsed,
+			None => return false,
+		}
+	}
+
+	for (register, value) in frame.registers().iter_mut().zip(values.iter()) {
+		**register = *value;
+	}
+
+	frame.rflags = rflags;
+	true
+}
+
+fn read_memory(data: &[u8], reply: &mut Vec<u8>) {
+	match parse_address_and_length(data) {
+		Some((address, length)) if is_accessible(address, length, false) => {
+			let length = cmp::min(length, MAXIMUM_PACKET_SIZE / 2);
+			for i in 0..length {
+				push_hex_byte(reply, unsafe { *((address + i) as *const u8) });
+			}
+		},
+		_ => reply.extend_from_slice(b"E14"),
+	}
+}
+
+fn write_memory(data: &[u8], reply: &mut Vec<u8>) {
+	let colon = match data.iter().position(|&byte| byte == b':') {
+		Some(colon) => colon,
+		None => return reply.extend_from_slice(b"E22"),
+	};
+
+	let (address, length) = match parse_address_and_length(&data[..colon]) {
+		Some(parsed) => parsed,
+		None => return reply.extend_from_slice(b"E22"),
+	};
+
+	let bytes = &data[colon + 1..];
+	if bytes.len() != length * 2 || !is_accessible(address, length, true) {
+		return reply.extend_from_slice(b"E14");
+	}
+
+	for i in 0..length {
+		match parse_hex_le(&bytes[i * 2..i * 2 + 2]) {
+			Some(byte) => unsafe { *((address + i) as *mut u8) = byte as u8 },
+			None => return reply.extend_from_slice(b"E22"),
+		}
+	}
+
+	reply.extend_from_slice(b"OK");
+}
+
+/// Maps the type and kind of a Z/z packet to a hardware breakpoint.
+/// Software breakpoints (type 0) are also implemented as hardware breakpoints, because the kernel code is read-only.
+fn breakpoint_parameters(data: &[u8]) -> Option<(usize, BreakpointKind, BreakpointLength)> {
+	if data.len() < 2 || data[1] != b',' {
+		return None;
+	}
+
+	let (address, kind) = parse_address_and_length(&data[2..])?;
+	let kind_and_length = match data[0] {
+		b'0' | b'1' => (BreakpointKind::Execute, BreakpointLength::Byte),
+		b'2' | b'4' => {
+			let length = match kind {
+				1 => BreakpointLength::Byte,
+				2 => BreakpointLength::Word,
+				4 => BreakpointLength::DWord,
+				8 => BreakpointLength::QWord,
+				_ => return None,
+			};
+
+			(if data[0] == b'2' { BreakpointKind::Write } else { BreakpointKind::ReadWrite }, length)
+		},
+		// Read watchpoints (type 3) are not supported by the hardware.
+		_ => return None,
+	};
+
+	Some((address, kind_and_length.0, kind_and_length.1))
+}
+
+/// How GDB lets the stopped context continue.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Resume {
+	Continue,
+	Step,
+	/// Keep stepping while the instruction pointer is within [start, end).
+	RangeStep(usize, usize),
+}
+
+/// Parses the actions of a vCont packet (after "vCont;").
+/// There is only one thread from the view of GDB, so only the first action is used and thread IDs are ignored.
+/// Signals to deliver are ignored as well.
+fn parse_vcont(data: &[u8]) -> Option<Resume> {
+	let action = data.split(|&byte| byte == b';').next()?;
+	let action = action.split(|&byte| byte == b':').next()?;
+
+	match action.first().cloned()? {
+		b'c' | b'C' => Some(Resume::Continue),
+		b's' | b'S' => Some(Resume::Step),
+		b'r' => {
+			let (start, end) = parse_address_and_length(&action[1..])?;
+			Some(Resume::RangeStep(start, end))
+		},
+		_ => None,
+	}
+}
+
+/// Single-step callback of a range step, which only reports instructions outside the range.
+fn is_outside_step_range(address: usize) -> bool {
+	address < STEP_RANGE_START.load(Ordering::SeqCst) || address >= STEP_RANGE_END.load(Ordering::SeqCst)
+}
+
+/// Prepares the stopped context to continue as requested by GDB.
+fn resume(frame: &mut GdbFrame, action: Resume) {
+	// RF prevents an instruction breakpoint at the current RIP from triggering again right away.
+	frame.rflags |= RFLAGS_RF;
+
+	match action {
+		Resume::Continue => {
+			debug::set_single_step_callback(None);
+			debug::single_step(&mut frame.rflags, false);
+		},
+		Resume::Step => {
+			debug::set_single_step_callback(None);
+			debug::single_step(&mut frame.rflags, true);
+		},
+		Resume::RangeStep(start, end) => {
+			STEP_RANGE_START.store(start, Ordering::SeqCst);
+			STEP_RANGE_END.store(end, Ordering::SeqCst);
+			debug::set_single_step_callback(Some(is_outside_step_range));
+			debug::single_step(&mut frame.rflags, true);
+		},
+	}
+
+	unsafe { ATTACHED = true; }
+}
+
+/// Handles a #DB or #BP exception by talking to GDB until it lets the context continue.
+#[no_mangle]
+pub extern "C" fn gdbstub_handle_exception(frame: &mut GdbFrame) {
+	if frame.vector == 1 {
+		// Silently resume steps that are not reported, i.e. in another task or within the range of a range step.
+		let dr6 = debug::take_debug_status();
+		if (dr6 & debug::DR6_BREAKPOINT_MASK) == 0 && (dr6 & debug::DR6_SINGLE_STEP) > 0 && !debug::handle_single_step(frame.rip as usize, &mut frame.rflags) {
+			return;
+		}
+	}
+
+	let _session = SESSION_LOCK.lock();
+
+	if unsafe { ATTACHED } {
+		send_stop_reply();
+	}
+
+	loop {
+		let packet = receive_packet();
+		let mut reply = Vec::new();
+
+		match packet.first().cloned() {
+			Some(b'?') => {
+				unsafe { ATTACHED = true; }
+				reply.push(b'S');
+				push_hex_byte(&mut reply, SIGTRAP);
+			},
+			Some(b'g') => read_registers(frame, &mut reply),
+			Some(b'G') => reply.extend_from_slice(if write_registers(frame, &packet[1..]) { &b"OK"[..] } else { &b"E22"[..] }),
+			Some(b'm') => read_memory(&packet[1..], &mut reply),
+			Some(b'M') => write_memory(&packet[1..], &mut reply),
+			Some(b'c') | Some(b's') => {
+				if let Some(address) = parse_hex(&packet[1..]) {
+					frame.rip = address as u64;
+				}
+
+				resume(frame, if packet[0] == b's' { Resume::Step } else { Resume::Continue });
+				return;
+			},
+			Some(b'v') if packet.starts_with(b"vCont?") => reply.extend_from_slice(b"vCont;c;C;s;S;r"),
+			Some(b'v') if packet.starts_with(b"vCont;") => match parse_vcont(&packet[6..]) {
+				Some(action) => {
+					resume(frame, action);
+					return;
+				},
+				None => reply.extend_from_slice(b"E22"),
+			},
+			Some(b'Z') => match breakpoint_parameters(&packet[1..]) {
+				Some((address, kind, length)) => reply.extend_from_slice(match debug::set_breakpoint(address, kind, length) {
+					Ok(_) => &b"OK"[..],
+					Err(()) => &b"E28"[..],
+				}),
+				None => {},
+			},
+			Some(b'z') => match breakpoint_parameters(&packet[1..]) {
+				Some((address, kind, _)) => {
+					if let Some(index) = debug::find_breakpoint(address, kind) {
+						debug::clear_breakpoint(index);
+					}
+
+					reply.extend_from_slice(b"OK");
+				},
+				None => {},
+			},
+			Some(b'q') if packet.starts_with(b"qSupported") => {
+				// This is MAXIMUM_PACKET_SIZE in hex.
+				reply.extend_from_slice(b"PacketSize=1000");
+			},
+			Some(b'q') if packet.starts_with(b"qAttached") => reply.push(b'1'),
+			Some(b'H') => reply.extend_from_slice(b"OK"),
+			Some(b'D') => {
+				// Detach and let the context continue.
+				send_packet(b"OK");
+				debug::set_single_step_callback(None);
+				debug::single_step(&mut frame.rflags, false);
+				unsafe { ATTACHED = false; }
+				return;
+			},
+			// Unsupported packets get an empty reply.
+			_ => {},
+		}
+
+		send_packet(&reply);
+	}
+}
+
+/// Sets up the stub if requested through the `gdbstub[=<port>]` command-line argument and waits for GDB to attach.
+pub fn init() {
+	let port = match environment::get_arg("gdbstub") {
+		Some("") => DEFAULT_PORT_ADDRESS,
+		Some(value) => match environment::parse_integer(value) {
+			Some(port) if port > 0 && port <= 0xFFFF => port as u16,
+			_ => {
+				warn!("Ignoring invalid gdbstub port \"{}\", using {:#X}", value, DEFAULT_PORT_ADDRESS);
+				DEFAULT_PORT_ADDRESS
+			}
+		},
+		None => return,
+	};
+
+	unsafe {
+		SERIAL_PORT = SerialPort::new(port);
+		SERIAL_PORT.init(BAUDRATE);
+	}
+
+	idt::set_gate(1, gdbstub_debug_entry as usize, gdt::IST_DEBUG);
+	idt::set_gate(3, gdbstub_breakpoint_entry as usize, 1);
+
+	info!("GDB stub is waiting for a connection on serial port {:#X}", port);
+	unsafe { asm!("int3" :::: "volatile"); }
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn vcont_actions_are_parsed() {
+		assert_eq!(parse_vcont(b"c"), Some(Resume::Continue));
+		assert_eq!(parse_vcont(b"C05:1"), Some(Resume::Continue));
+		assert_eq!(parse_vcont(b"s:1;c"), Some(Resume::Step));
+		assert_eq!(parse_vcont(b"S05"), Some(Resume::Step));
+		assert_eq!(parse_vcont(b"r1000,1010:1;c"), Some(Resume::RangeStep(0x1000, 0x1010)));
+		assert_eq!(parse_vcont(b"r1000"), None);
+		assert_eq!(parse_vcont(b"t"), None);
+		assert_eq!(parse_vcont(b""), None);
+	}
+
+	#[test]
+	fn range_steps_only_report_outside_addresses() {
+		STEP_RANGE_START.store(0x1000, Ordering::SeqCst);
+		STEP_RANGE_END.store(0x1010, Ordering::SeqCst);
+		assert!(is_outside_step_range(0xFFF));
+		assert!(!is_outside_step_range(0x1000));
+		assert!(!is_outside_step_range(0x100F));
+		assert!(is_outside_step_range(0x1010));
+	}
+}
@@ -25,9 +25,10 @@
 include!(concat!(env!("CARGO_TARGET_DIR"), "/config.rs"));
 
 use alloc::boxed::Box;
-use arch::x86_64::mm::paging::PageTableEntryFlags;
+use arch::x86_64::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
 use arch::x86_64::percore::*;
 use core::mem;
+use environment;
 use mm;
 use scheduler::task::TaskStatus;
 use x86::bits64::segmentation::*;
@@ -49,10 +50,33 @@ pub const GDT_FIRST_TSS:   u16 = 3;
 /// We dynamically allocate a GDT large enough to hold the maximum number of entries.
 const GDT_ENTRIES: usize = 8192;
 
-/// We use IST1 through IST4.
-/// Each critical exception (NMI, Double Fault, Machine Check) gets a dedicated one while IST1 is shared for all other
+/// We use IST1 through IST5.
+/// Each critical exception (NMI, Double Fault, Machine Check, Debug) gets a dedicated one while IST1 is shared for all other
 /// interrupts. See also irq.rs.
-const IST_ENTRIES: usize = 4;
+const IST_ENTRIES: usize = 5;
+
+/// IST indexes for the IDT gates (1-based, as expected by idt::set_gate).
+pub const IST_NMI: u8 = 2;
+pub const IST_DOUBLE_FAULT: u8 = 3;
+pub const IST_MACHINE_CHECK: u8 = 4;
+pub const IST_DEBUG: u8 = 5;
+
+/// Sizes of the dedicated stacks IST2 through IST5.
+/// IST1 is replaced per task and always has the kernel stack size.
+/// The critical handlers only log and halt or return, so they get by with smaller stacks.
+const CRITICAL_IST_SIZES: [usize; IST_ENTRIES - 1] = [
+	0x4000, // NMI
+	0x4000, // Double Fault
+	0x4000, // Machine Check
+	0x4000, // Debug
+];
+
+/// Smallest kernel stack size accepted on the command line.
+const MINIMUM_KERNEL_STACK_SIZE: usize = 0x4000;
+
+/// Size of the kernel stacks (boot stacks of the application processors and ISTs).
+/// Defaults to the build-time KERNEL_STACK_SIZE and can be changed through the `kernel_stack_size` command-line argument.
+static mut CONFIGURED_KERNEL_STACK_SIZE: usize = KERNEL_STACK_SIZE;
 
 static mut GDT: *mut Gdt = 0 as *mut Gdt;
 static mut GDTR: DescriptorTablePointer<SegmentDescriptor> = DescriptorTablePointer { base: 0 as *const SegmentDescriptor, limit: 0 };
@@ -61,6 +85,37 @@ struct Gdt {
 	entries: [SegmentDescriptor; GDT_ENTRIES]
 }
 
+/// Layout of the GDTR as stored by SGDT.
+#[repr(C, packed)]
+struct Gdtr {
+	limit: u16,
+	base: u64,
+}
+
+const DESCRIPTOR_PRESENT: u64 = 1 << 47;
+const DESCRIPTOR_NOT_SYSTEM: u8 = 1 << 4;
+
+
+/// Applies a `kernel_stack_size` given on the command line.
+/// Must be called after environment::init() and before any kernel stack is allocated.
+pub fn configure_kernel_stack_size() {
+	if let Some(value) = environment::get_arg("kernel_stack_size") {
+		match environment::parse_integer(value) {
+			Some(size) if size % BasePageSize::SIZE == 0 && size >= MINIMUM_KERNEL_STACK_SIZE => unsafe {
+				CONFIGURED_KERNEL_STACK_SIZE = size;
+			},
+			_ => warn!("Invalid kernel_stack_size \"{}\", it must be a multiple of {:#X} and at least {:#X}", value, BasePageSize::SIZE, MINIMUM_KERNEL_STACK_SIZE),
+		}
+	}
+
+	info!("Kernel stack size is {:#X} bytes, with a guard page below each stack", kernel_stack_size());
+}
+
+/// Returns the effective size of the kernel stacks.
+#[inline]
+pub fn kernel_stack_size() -> usize {
+	unsafe { CONFIGURED_KERNEL_STACK_SIZE }
+}
 
 pub fn init() {
 	unsafe {
@@ -104,9 +159,11 @@ pub fn add_current_core() {
 
 	// Allocate all ISTs for this core.
 	// Every task later gets its own IST1, so the IST1 allocated here is only used by the Idle task.
+	// Each IST has a guard page, so a handler overflowing its stack faults instead of overwriting other memory.
 	for i in 0..IST_ENTRIES {
-		let ist = mm::allocate(KERNEL_STACK_SIZE, PageTableEntryFlags::EXECUTE_DISABLE);
-		boxed_tss.ist[i] = (ist + KERNEL_STACK_SIZE - 0x10) as u64;
+		let size = if i == 0 { kernel_stack_size() } else { CRITICAL_IST_SIZES[i - 1] };
+		let ist = mm::allocate_stack(size, PageTableEntryFlags::EXECUTE_DISABLE);
+		boxed_tss.ist[i] = (ist + size - 0x10) as u64;
 	}
 
 	unsafe {
@@ -124,11 +181,68 @@ pub fn add_current_core() {
 	}
 }
 
+/// Prints the loaded GDTR, all present descriptors of the GDT, and the TSS of the current core.
+/// Only the loaded registers and tables are read and no locks are taken, so this can also diagnose a core whose
+/// initialization has gone wrong.
+pub fn dump_current() {
+	let mut gdtr = Gdtr { limit: 0, base: 0 };
+	let tr: u16;
+	unsafe {
+		asm!("sgdt ($0)" :: "r"(&mut gdtr) : "memory" : "volatile");
+		asm!("str $0" : "=r"(tr) ::: "volatile");
+	}
+
+	let (gdt_base, gdt_limit) = (gdtr.base, gdtr.limit);
+	info!("GDT of core {}: base {:#X}, limit {:#X}, TR {:#X}", core_id(), gdt_base, gdt_limit, tr);
+
+	let descriptors = gdt_base as *const u64;
+	let count = (gdt_limit as usize + 1) / mem::size_of::<u64>();
+	let mut i = 0;
+
+	while i < count {
+		let low = unsafe { *descriptors.offset(i as isize) };
+		if low & DESCRIPTOR_PRESENT == 0 {
+			i += 1;
+			continue;
+		}
+
+		let mut base = ((low >> 16) & 0xFF_FFFF) | ((low >> 32) & 0xFF00_0000);
+		let limit = (low & 0xFFFF) | ((low >> 32) & 0xF_0000);
+		let access = (low >> 40) as u8;
+		let flags = (low >> 52) & 0xF;
+
+		// System descriptors like the TSS occupy two entries in 64-bit mode, the second one holds the upper base address.
+		let entries = if access & DESCRIPTOR_NOT_SYSTEM == 0 && i + 1 < count { 2 } else { 1 };
+		if entries == 2 {
+			base |= (unsafe { *descriptors.offset(i as isize + 1) } & 0xFFFF_FFFF) << 32;
+		}
+
+		info!("  {:4}: base {:#018X}, limit {:#07X}, access {:#04X}, flags {:#X}{}",
+			i, base, limit, access, flags, if i == (tr >> 3) as usize { " (TR)" } else { "" });
+		i += entries;
+	}
+
+	let tss = unsafe { PERCORE.tss.get() };
+	if tss.is_null() {
+		info!("No TSS has been set for this core");
+		return;
+	}
+
+	// The TSS is packed, so copy the fields before formatting them.
+	let (rsp0, ists) = unsafe { ((*tss).rsp[0], (*tss).ist) };
+	info!("TSS at {:#X}: RSP0 {:#X}", tss as usize, rsp0);
+	for (i, ist) in ists.iter().enumerate().filter(|&(_, &ist)| ist != 0) {
+		info!("  IST{}: {:#X}", i + 1, ist);
+	}
+}
+
+/// Returns the lowest addresses of the boot stack and IST1 of the current core, which are used by its Idle task.
 pub fn get_boot_stacks() -> (usize, usize) {
 	let tss = unsafe { &(*PERCORE.tss.get()) };
 
-	let stack = tss.rsp[0] as usize;
-	let ist = tss.ist[0] as usize;
+	// set_current_kernel_stack adds the stack sizes again when switching to the Idle task.
+	let stack = tss.rsp[0] as usize + 0x10 - KERNEL_STACK_SIZE;
+	let ist = tss.ist[0] as usize + 0x10 - kernel_stack_size();
 	(stack, ist)
 }
 
@@ -144,5 +258,27 @@ pub extern "C" fn set_current_kernel_stack() {
 	let tss = unsafe { &mut (*PERCORE.tss.get()) };
 
 	tss.rsp[0] = (current_task_borrowed.stack + stack_size - 0x10) as u64;
-	tss.ist[0] = (current_task_borrowed.ist + KERNEL_STACK_SIZE - 0x10) as u64;
+	tss.ist[0] = (current_task_borrowed.ist + kernel_stack_size() - 0x10) as u64;
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn critical_ists_are_distinct_and_page_sized() {
+		let ists = [IST_NMI, IST_DOUBLE_FAULT, IST_MACHINE_CHECK, IST_DEBUG];
+
+		// IST1 is replaced per task, so no critical handler may use it.
+		for (i, &ist) in ists.iter().enumerate() {
+			assert!(ist >= 2 && ist as usize <= IST_ENTRIES, "IST{} is out of range", ist);
+			assert!(!ists[..i].contains(&ist), "IST{} is shared", ist);
+		}
+
+		// Each stack is allocated as whole pages plus a guard page.
+		for &size in CRITICAL_IST_SIZES.iter() {
+			assert!(size >= 0x1000 && size % 0x1000 == 0);
+		}
+	}
 }
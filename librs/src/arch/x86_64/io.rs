@@ -0,0 +1,135 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Typed access to I/O ports.
+//! All port I/O of the kernel goes through this module, so there is a single place to audit and search for it.
+
+use core::marker::PhantomData;
+use x86::shared::io::*;
+
+
+/// A value that can be transferred through an I/O port in a single IN or OUT instruction.
+pub trait PortValue: Copy {
+	unsafe fn read_from_port(port: u16) -> Self;
+	unsafe fn write_to_port(port: u16, value: Self);
+}
+
+impl PortValue for u8 {
+	#[inline(always)]
+	unsafe fn read_from_port(port: u16) -> Self {
+		inb(port)
+	}
+
+	#[inline(always)]
+	unsafe fn write_to_port(port: u16, value: Self) {
+		outb(port, value);
+	}
+}
+
+impl PortValue for u16 {
+	#[inline(always)]
+	unsafe fn read_from_port(port: u16) -> Self {
+		inw(port)
+	}
+
+	#[inline(always)]
+	unsafe fn write_to_port(port: u16, value: Self) {
+		outw(port, value);
+	}
+}
+
+impl PortValue for u32 {
+	#[inline(always)]
+	unsafe fn read_from_port(port: u16) -> Self {
+		inl(port)
+	}
+
+	#[inline(always)]
+	unsafe fn write_to_port(port: u16, value: Self) {
+		outl(port, value);
+	}
+}
+
+
+/// An I/O port transferring values of type `T`.
+/// Reading and writing compiles down to a single IN or OUT instruction.
+/// Both are unsafe, because an access to the wrong port can reconfigure arbitrary hardware.
+#[derive(Clone, Copy)]
+pub struct Port<T> {
+	address: u16,
+	phantom: PhantomData<T>,
+}
+
+impl<T> Port<T> {
+	pub const fn new(address: u16) -> Self {
+		Self { address: address, phantom: PhantomData }
+	}
+
+	#[inline]
+	pub fn address(&self) -> u16 {
+		self.address
+	}
+}
+
+impl<T: PortValue> Port<T> {
+	#[inline(always)]
+	pub unsafe fn read(&self) -> T {
+		T::read_from_port(self.address)
+	}
+
+	#[inline(always)]
+	pub unsafe fn write(&self, value: T) {
+		T::write_to_port(self.address, value);
+	}
+}
+
+
+/// A range of consecutive I/O ports, e.g. the registers of a device.
+#[derive(Clone, Copy)]
+pub struct PortRange {
+	base: u16,
+	length: u16,
+}
+
+impl PortRange {
+	pub const fn new(base: u16, length: u16) -> Self {
+		Self { base: base, length: length }
+	}
+
+	#[inline]
+	pub fn base(&self) -> u16 {
+		self.base
+	}
+
+	#[inline]
+	pub fn len(&self) -> u16 {
+		self.length
+	}
+
+	/// Returns the port at `offset` within this range.
+	#[inline]
+	pub fn port<T>(&self, offset: u16) -> Port<T> {
+		debug_assert!(offset < self.length, "Port offset {:#X} is outside the range of {} ports at {:#X}", offset, self.length, self.base);
+		Port::new(self.base + offset)
+	}
+}
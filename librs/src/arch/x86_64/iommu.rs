@@ -0,0 +1,390 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Minimal support for DMA remapping through an Intel VT-d IOMMU, enabled by the "iommu" feature.
+//!
+//! All remapping units found in the ACPI DMAR table share a single root table. Initially, the context entries
+//! of all devices point to a shared context table that puts them into pass-through mode, so existing drivers
+//! keep working unchanged. A driver opts its device into translation by calling map(), which moves the device
+//! into a single translated domain with a second-level page table. From then on, the device can only reach
+//! the pages mapped for it. The virtio drivers do so for their virtqueues and buffers through VirtioDevice::map_dma.
+//!
+//! Without the feature, without an IOMMU, or if the IOMMU lacks pass-through support, translation stays
+//! disabled and map() only accepts identity mappings.
+//!
+//! See Intel Virtualization Technology for Directed I/O, Architecture Specification.
+
+use alloc::vec::Vec;
+use arch::x86_64::acpi;
+use arch::x86_64::mm::paging::{BasePageSize, MemoryType, PageSize, PageTableEntryFlags};
+use arch::x86_64::mm::physicalmem;
+use arch::x86_64::pci::PciAdapter;
+use arch::x86_64::processor;
+use core::ptr;
+use mm;
+use synch::spinlock::SpinlockIrqSave;
+
+/// Remapping unit registers (see chapter 10.4 of the specification).
+const DMAR_CAP_REG: usize = 0x08;
+const DMAR_ECAP_REG: usize = 0x10;
+const DMAR_GCMD_REG: usize = 0x18;
+const DMAR_GSTS_REG: usize = 0x1C;
+const DMAR_RTADDR_REG: usize = 0x20;
+const DMAR_CCMD_REG: usize = 0x28;
+
+/// Supported Adjusted Guest Address Widths in the Capability Register: 39-bit (3-level) and 48-bit (4-level) tables.
+const CAP_SAGAW_39_BIT: u64 = 1 << 9;
+const CAP_SAGAW_48_BIT: u64 = 1 << 10;
+
+/// Extended Capability Register: page walks are coherent, pass-through is supported, and the IOTLB register offset.
+const ECAP_COHERENCY: u64 = 1 << 0;
+const ECAP_PASS_THROUGH: u64 = 1 << 6;
+const ECAP_IOTLB_REGISTER_OFFSET_SHIFT: u64 = 8;
+const ECAP_IOTLB_REGISTER_OFFSET_MASK: u64 = 0x3FF;
+
+/// Bits of the Global Command and Global Status Registers.
+const GLOBAL_TRANSLATION_ENABLE: u32 = 1 << 31;
+const GLOBAL_SET_ROOT_TABLE_POINTER: u32 = 1 << 30;
+/// Bits of the Global Status Register that are not one-shot and need to be preserved when writing the Global Command Register.
+const GLOBAL_STATUS_PRESERVED_BITS: u32 = 0x96FF_FFFF;
+
+/// Global invalidation of the context-cache and the IOTLB.
+const CCMD_INVALIDATE: u64 = 1 << 63;
+const CCMD_GLOBAL_INVALIDATION: u64 = 1 << 61;
+const IOTLB_INVALIDATE: u64 = 1 << 63;
+const IOTLB_GLOBAL_INVALIDATION: u64 = 1 << 60;
+
+/// Bits of root, context and second-level page table entries.
+const ENTRY_PRESENT: u64 = 1 << 0;
+const CONTEXT_TRANSLATION_TYPE_PASS_THROUGH: u64 = 2 << 2;
+const CONTEXT_DOMAIN_ID_SHIFT: u64 = 8;
+const SECOND_LEVEL_READ: u64 = 1 << 0;
+const SECOND_LEVEL_WRITE: u64 = 1 << 1;
+const ENTRY_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// Domain IDs of the pass-through domain and the translated domain.
+/// Domain ID 0 is avoided, because it is reserved if the remapping unit reports Caching Mode.
+const PASS_THROUGH_DOMAIN_ID: u64 = 1;
+const TRANSLATED_DOMAIN_ID: u64 = 2;
+
+/// Number of 64-bit words in a table page and number of entries of a root or context table (each 128 bits).
+const TABLE_WORDS: usize = 512;
+const TABLE_ENTRIES: usize = 256;
+/// Number of bits of the IOVA resolved per level of the second-level page table.
+const BITS_PER_LEVEL: usize = 9;
+
+static IOMMU: SpinlockIrqSave<Option<Iommu>> = SpinlockIrqSave::new(None);
+
+
+bitflags! {
+	/// Access rights of a device to a page mapped with map().
+	pub struct DmaFlags: u64 {
+		const READ = SECOND_LEVEL_READ;
+		const WRITE = SECOND_LEVEL_WRITE;
+	}
+}
+
+struct Iommu {
+	/// Virtual addresses of the register sets of all remapping units.
+	units: Vec<usize>,
+	/// Whether all units snoop the caches when walking the tables. Otherwise, written entries must be flushed.
+	coherent: bool,
+	/// Number of levels of the second-level page table (3 or 4) and the matching Address Width field value.
+	levels: usize,
+	address_width: u64,
+	/// Physical and virtual addresses of all table pages, used to follow physical addresses in table entries.
+	table_pages: Vec<(usize, usize)>,
+	root_table: usize,
+	/// Physical address of the context table shared by all buses without translated devices.
+	pass_through_context_table: usize,
+	second_level_root: usize,
+}
+
+impl Iommu {
+	/// Allocates and maps a zeroed page for a table and returns its physical address.
+	fn allocate_table(&mut self) -> usize {
+		let _lock = mm::MM_LOCK.lock();
+		let physical_address = physicalmem::allocate(BasePageSize::SIZE);
+		let virtual_address = mm::map_physical(physical_address, BasePageSize::SIZE, PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE);
+		unsafe { ptr::write_bytes(virtual_address as *mut u8, 0, BasePageSize::SIZE); }
+		self.flush(virtual_address, BasePageSize::SIZE);
+
+		self.table_pages.push((physical_address, virtual_address));
+		physical_address
+	}
+
+	/// Returns the words of the table page at the given physical address.
+	fn table(&self, physical_address: usize) -> &'static mut [u64; TABLE_WORDS] {
+		let &(_, virtual_address) = self.table_pages.iter().find(|&&(physical, _)| physical == physical_address)
+			.expect("Physical address is not an IOMMU table page");
+		unsafe { &mut *(virtual_address as *mut [u64; TABLE_WORDS]) }
+	}
+
+	/// Writes back the given range to memory if the remapping units do not snoop the caches.
+	fn flush(&self, virtual_address: usize, size: usize) {
+		if self.coherent {
+			return;
+		}
+
+		for address in (align_down!(virtual_address, 64)..virtual_address + size).step_by(64) {
+			unsafe { asm!("clflush ($0)" :: "r"(address) : "memory" : "volatile"); }
+		}
+		unsafe { asm!("mfence" ::: "memory" : "volatile"); }
+	}
+
+	fn flush_entry(&self, entry: &u64) {
+		self.flush(entry as *const u64 as usize, 16);
+	}
+
+	/// Returns the context table of `bus`, giving the bus its own copy of the pass-through context table first.
+	fn private_context_table(&mut self, bus: u8) -> usize {
+		let root_entry = self.table(self.root_table)[2 * bus as usize];
+		let context_table = (root_entry & ENTRY_ADDRESS_MASK) as usize;
+		if context_table != self.pass_through_context_table {
+			return context_table;
+		}
+
+		let new_context_table = self.allocate_table();
+		let pass_through_words = *self.table(self.pass_through_context_table);
+		*self.table(new_context_table) = pass_through_words;
+		self.flush(self.table(new_context_table).as_ptr() as usize, BasePageSize::SIZE);
+
+		let root_table = self.table(self.root_table);
+		root_table[2 * bus as usize] = new_context_table as u64 | ENTRY_PRESENT;
+		self.flush_entry(&root_table[2 * bus as usize]);
+		new_context_table
+	}
+
+	/// Moves the device at `bus` and `devfn` into the translated domain.
+	fn translate_device(&mut self, bus: u8, devfn: u8) {
+		let context_table_address = self.private_context_table(bus);
+		let context_table = self.table(context_table_address);
+		let index = 2 * devfn as usize;
+		let entry = self.second_level_root as u64 | ENTRY_PRESENT;
+		if context_table[index] != entry {
+			context_table[index] = entry;
+			context_table[index + 1] = self.address_width | (TRANSLATED_DOMAIN_ID << CONTEXT_DOMAIN_ID_SHIFT);
+			self.flush_entry(&context_table[index]);
+		}
+	}
+
+	/// Sets the second-level page table entry for `iova`, allocating intermediate tables on the way.
+	fn map_page(&mut self, iova: usize, physical_address: usize, flags: DmaFlags) {
+		let mut table_address = self.second_level_root;
+
+		for level in (1..self.levels).rev() {
+			let index = (iova >> (12 + level * BITS_PER_LEVEL)) & (TABLE_WORDS - 1);
+			let mut entry = self.table(table_address)[index];
+			if entry & (SECOND_LEVEL_READ | SECOND_LEVEL_WRITE) == 0 {
+				let new_table = self.allocate_table();
+				entry = new_table as u64 | SECOND_LEVEL_READ | SECOND_LEVEL_WRITE;
+				let table = self.table(table_address);
+				table[index] = entry;
+				self.flush_entry(&table[index]);
+			}
+
+			table_address = (entry & ENTRY_ADDRESS_MASK) as usize;
+		}
+
+		let index = (iova >> 12) & (TABLE_WORDS - 1);
+		let table = self.table(table_address);
+		table[index] = physical_address as u64 | flags.bits();
+		self.flush_entry(&table[index]);
+	}
+
+	fn invalidate_caches(&self) {
+		for &unit in self.units.iter() {
+			invalidate_context_cache(unit);
+			invalidate_iotlb(unit);
+		}
+	}
+}
+
+
+fn read_register32(unit: usize, offset: usize) -> u32 {
+	unsafe { ptr::read_volatile((unit + offset) as *const u32) }
+}
+
+fn write_register32(unit: usize, offset: usize, value: u32) {
+	unsafe { ptr::write_volatile((unit + offset) as *mut u32, value); }
+}
+
+fn read_register64(unit: usize, offset: usize) -> u64 {
+	unsafe { ptr::read_volatile((unit + offset) as *const u64) }
+}
+
+fn write_register64(unit: usize, offset: usize, value: u64) {
+	unsafe { ptr::write_volatile((unit + offset) as *mut u64, value); }
+}
+
+/// Issues a command through the Global Command Register and waits until the Global Status Register confirms it.
+fn global_command(unit: usize, command: u32) {
+	let status = read_register32(unit, DMAR_GSTS_REG) & GLOBAL_STATUS_PRESERVED_BITS;
+	write_register32(unit, DMAR_GCMD_REG, status | command);
+
+	while read_register32(unit, DMAR_GSTS_REG) & command == 0 {
+		processor::pause();
+	}
+}
+
+fn invalidate_context_cache(unit: usize) {
+	write_register64(unit, DMAR_CCMD_REG, CCMD_INVALIDATE | CCMD_GLOBAL_INVALIDATION);
+	while read_register64(unit, DMAR_CCMD_REG) & CCMD_INVALIDATE > 0 {
+		processor::pause();
+	}
+}
+
+fn invalidate_iotlb(unit: usize) {
+	let ecap = read_register64(unit, DMAR_ECAP_REG);
+	let iotlb_register = ((ecap >> ECAP_IOTLB_REGISTER_OFFSET_SHIFT) & ECAP_IOTLB_REGISTER_OFFSET_MASK) as usize * 16 + 8;
+
+	write_register64(unit, iotlb_register, IOTLB_INVALIDATE | IOTLB_GLOBAL_INVALIDATION);
+	while read_register64(unit, iotlb_register) & IOTLB_INVALIDATE > 0 {
+		processor::pause();
+	}
+}
+
+/// Maps the 4 KiB page at `physical_address` to the I/O virtual address `iova` for DMA by `device` and moves
+/// the device into the translated domain. From then on, the device can only access pages mapped with this function.
+/// All translated devices share a single domain.
+///
+/// If DMA remapping is not active, devices access physical memory directly, so this only succeeds for identity mappings.
+pub fn map(device: &PciAdapter, iova: usize, physical_address: usize, flags: DmaFlags) -> Result<(), ()> {
+	if iova % BasePageSize::SIZE != 0 || physical_address % BasePageSize::SIZE != 0 || flags.is_empty() {
+		return Err(());
+	}
+
+	let mut iommu_locked = IOMMU.lock();
+	let iommu = match *iommu_locked {
+		Some(ref mut iommu) => iommu,
+		None => return if iova == physical_address { Ok(()) } else { Err(()) },
+	};
+
+	if iova >> (12 + iommu.levels * BITS_PER_LEVEL) != 0 {
+		return Err(());
+	}
+
+	// The PCI code only handles function 0 of each device.
+	let devfn = device.device << 3;
+	iommu.translate_device(device.bus, devfn);
+	iommu.map_page(iova, physical_address, flags);
+
+	// Also required for previously non-present entries, as a unit may cache them if it reports Caching Mode.
+	iommu.invalidate_caches();
+	Ok(())
+}
+
+/// Identity-maps `size` bytes of physical memory at `physical_address` for DMA by `device`.
+pub fn map_identity(device: &PciAdapter, physical_address: usize, size: usize, flags: DmaFlags) -> Result<(), ()> {
+	let start = align_down!(physical_address, BasePageSize::SIZE);
+	let end = align_up!(physical_address + size, BasePageSize::SIZE);
+
+	for address in (start..end).step_by(BasePageSize::SIZE) {
+		map(device, address, address, flags)?;
+	}
+
+	Ok(())
+}
+
+/// Sets up the root and context tables for all remapping units reported by ACPI and enables DMA remapping.
+/// Must be called after acpi::init.
+pub fn init() {
+	if !cfg!(feature = "iommu") {
+		if acpi::has_iommu() {
+			info!("IOMMU support is disabled, devices access memory in pass-through mode");
+		}
+		return;
+	}
+
+	if !acpi::has_iommu() {
+		return;
+	}
+
+	let mut units = Vec::new();
+	let mut capability = CAP_SAGAW_39_BIT | CAP_SAGAW_48_BIT;
+	let mut extended_capability = ECAP_COHERENCY | ECAP_PASS_THROUGH;
+	for unit in acpi::get_dma_remapping_units().iter().filter(|unit| unit.segment == 0) {
+		let registers = mm::map_device_memory(unit.register_base_address as usize, BasePageSize::SIZE, MemoryType::Uncacheable);
+		capability &= read_register64(registers, DMAR_CAP_REG);
+		extended_capability &= read_register64(registers, DMAR_ECAP_REG);
+		units.push(registers);
+	}
+
+	if units.is_empty() {
+		warn!("No IOMMU covers PCI segment 0, not enabling DMA remapping");
+		return;
+	}
+	if extended_capability & ECAP_PASS_THROUGH == 0 {
+		warn!("The IOMMU does not support pass-through, not enabling DMA remapping");
+		return;
+	}
+
+	let (levels, address_width) = if capability & CAP_SAGAW_48_BIT > 0 {
+		(4, 2)
+	} else if capability & CAP_SAGAW_39_BIT > 0 {
+		(3, 1)
+	} else {
+		warn!("The IOMMU supports neither 3-level nor 4-level page tables, not enabling DMA remapping");
+		return;
+	};
+
+	let mut iommu = Iommu {
+		units: units,
+		coherent: extended_capability & ECAP_COHERENCY > 0,
+		levels: levels,
+		address_width: address_width,
+		table_pages: Vec::new(),
+		root_table: 0,
+		pass_through_context_table: 0,
+		second_level_root: 0,
+	};
+	iommu.root_table = iommu.allocate_table();
+	iommu.pass_through_context_table = iommu.allocate_table();
+	iommu.second_level_root = iommu.allocate_table();
+
+	{
+		let context_table = iommu.table(iommu.pass_through_context_table);
+		for i in 0..TABLE_ENTRIES {
+			context_table[2 * i] = CONTEXT_TRANSLATION_TYPE_PASS_THROUGH | ENTRY_PRESENT;
+			context_table[2 * i + 1] = address_width | (PASS_THROUGH_DOMAIN_ID << CONTEXT_DOMAIN_ID_SHIFT);
+		}
+		iommu.flush(context_table.as_ptr() as usize, BasePageSize::SIZE);
+
+		let root_table = iommu.table(iommu.root_table);
+		for i in 0..TABLE_ENTRIES {
+			root_table[2 * i] = iommu.pass_through_context_table as u64 | ENTRY_PRESENT;
+		}
+		iommu.flush(root_table.as_ptr() as usize, BasePageSize::SIZE);
+	}
+
+	for &unit in iommu.units.iter() {
+		write_register64(unit, DMAR_RTADDR_REG, iommu.root_table as u64);
+		global_command(unit, GLOBAL_SET_ROOT_TABLE_POINTER);
+		invalidate_context_cache(unit);
+		invalidate_iotlb(unit);
+		global_command(unit, GLOBAL_TRANSLATION_ENABLE);
+	}
+
+	info!("Enabled DMA remapping on {} IOMMU(s) with {}-level page tables", iommu.units.len(), levels);
+	*IOMMU.lock() = Some(iommu);
+}
@@ -22,15 +22,43 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use arch::x86_64::idt;
 use arch::x86_64::apic;
+#[cfg(feature = "debugger")]
+use arch::x86_64::debug;
+use arch::x86_64::gdt;
+use arch::x86_64::idt;
+use arch::x86_64::mce;
 use arch::x86_64::mm::paging;
+use arch::x86_64::mm::paging::{BasePageSize, PageSize};
 use arch::x86_64::percore::*;
-use core::fmt;
+use core::{cmp, fmt, mem};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use scheduler;
+use synch::spinlock::SpinlockIrqSave;
+use x86::shared::control_regs::{cr0, CR0_ALIGNMENT_MASK};
 use x86::shared::flags::*;
 
 
+/// First IDT vector that is dispatched through the common IRQ stubs and the handler table.
+pub const IRQ_VECTOR_BASE: u8 = 32;
+
+/// Number of vectors dispatched through the common IRQ stubs (irq0 to irq31 in entry.asm).
+pub const IRQ_VECTOR_COUNT: usize = 32;
+
+/// Function called for an interrupt registered with `register_handler`.
+/// It receives the IDT vector and must not send an EOI, this is done by the common dispatch code.
+pub type IrqHandler = fn(u8);
+
+/// Handlers registered by drivers, indexed by the vector minus IRQ_VECTOR_BASE.
+static IRQ_HANDLERS: SpinlockIrqSave<[Option<IrqHandler>; IRQ_VECTOR_COUNT]> = SpinlockIrqSave::new([None; IRQ_VECTOR_COUNT]);
+
+/// Number of legacy IRQs of the PIC, which use the vectors from IRQ_VECTOR_BASE on.
+const LEGACY_IRQ_COUNT: u8 = 16;
+
+/// Owners of the vectors handed out by allocate_vector, indexed by the vector minus IRQ_VECTOR_BASE.
+static VECTOR_OWNERS: SpinlockIrqSave<[Option<&'static str>; IRQ_VECTOR_COUNT]> = SpinlockIrqSave::new([None; IRQ_VECTOR_COUNT]);
+
+
 // Derived from Philipp Oppermann's blog
 // => https://github.com/phil-opp/blog_os/blob/master/src/interrupts/mod.rs
 /// Represents the exception stack frame pushed by the CPU on exception entry.
@@ -73,24 +101,45 @@ impl fmt::Debug for ExceptionStackFrame {
 
 
 /// Enable Interrupts
+#[cfg(not(test))]
 #[inline]
 pub fn enable() {
 	unsafe { asm!("sti" :::: "volatile") };
 }
 
-/// Enable Interrupts and wait for the next interrupt (HLT instruction)
-/// According to https://lists.freebsd.org/pipermail/freebsd-current/2004-June/029369.html, this exact sequence of assembly
-/// instructions is guaranteed to be atomic.
-/// This is important, because another CPU could call wakeup_core right when we decide to wait for the next interrupt.
+/// Disable Interrupts
+#[cfg(not(test))]
 #[inline]
-pub fn enable_and_wait() {
-	unsafe { asm!("sti; hlt" :::: "volatile") };
+pub fn disable() {
+	unsafe { asm!("cli" :::: "volatile") };
 }
 
-/// Disable Interrupts
+/// Returns whether interrupts are currently enabled on this CPU core.
+/// This only reads RFLAGS (pushfq; pop), so it is cheap enough for assertions on every lock operation.
+#[cfg(not(test))]
 #[inline]
+pub fn interrupts_enabled() -> bool {
+	flags().contains(FLAGS_IF)
+}
+
+/// Unit tests run as a process on the host, where cli and sti are privileged.
+/// Each test thread gets its own Interrupt Flag instead.
+#[cfg(test)]
+thread_local!(static INTERRUPT_FLAG: ::core::cell::Cell<bool> = ::core::cell::Cell::new(true));
+
+#[cfg(test)]
+pub fn enable() {
+	INTERRUPT_FLAG.with(|flag| flag.set(true));
+}
+
+#[cfg(test)]
 pub fn disable() {
-	unsafe { asm!("cli" :::: "volatile") };
+	INTERRUPT_FLAG.with(|flag| flag.set(false));
+}
+
+#[cfg(test)]
+pub fn interrupts_enabled() -> bool {
+	INTERRUPT_FLAG.with(|flag| flag.get())
 }
 
 /// Disable IRQs (nested)
@@ -101,7 +150,7 @@ pub fn disable() {
 /// were not activated before calling this function.
 #[inline]
 pub fn nested_disable() -> bool {
-	let was_enabled = flags().contains(FLAGS_IF);
+	let was_enabled = interrupts_enabled();
 	disable();
 	was_enabled
 }
@@ -117,6 +166,36 @@ pub fn nested_enable(was_enabled: bool) {
 	}
 }
 
+/// Disables interrupts for its lifetime and restores the previous state when dropped.
+///
+/// Unlike a pair of nested_disable/nested_enable calls, this also restores the state on an early return.
+/// Guards can be nested, each one restores the state it found.
+pub struct IrqGuard {
+	was_enabled: bool,
+}
+
+impl IrqGuard {
+	pub fn new() -> Self {
+		IrqGuard { was_enabled: nested_disable() }
+	}
+}
+
+impl Drop for IrqGuard {
+	fn drop(&mut self) {
+		nested_enable(self.was_enabled);
+	}
+}
+
+/// Executes `f` with interrupts disabled and restores the previous interrupt state afterwards.
+/// `f` must not enable interrupts itself.
+#[inline]
+pub fn without_interrupts<F, R>(f: F) -> R where F: FnOnce() -> R {
+	let _guard = IrqGuard::new();
+	let result = f();
+	debug_assert!(!interrupts_enabled(), "Interrupts have been enabled inside without_interrupts");
+	result
+}
+
 extern {
 	fn irq0();
 	fn irq1();
@@ -150,26 +229,33 @@ extern {
 	fn irq29();
 	fn irq30();
 	fn irq31();
+
+	static irq_trigger_table: u8;
 }
 
+/// Size of a single entry of irq_trigger_table in entry.asm.
+const IRQ_TRIGGER_ENTRY_SIZE: usize = 4;
+
 pub fn install() {
 	// Set gates to the Interrupt Service Routines (ISRs) for all 32 CPU exceptions.
 	// All of them use a dedicated stack per task (IST1) to prevent clobbering the current task stack.
-	// Some critical exceptions also get their own stacks to always execute on a known good stack:
+	// Some critical exceptions also get their own stacks per core to always execute on a known good stack,
+	// even if they hit while the stack pointer is corrupted:
+	//   - Debug Exception (IST5)
 	//   - Non-Maskable Interrupt Exception (IST2)
 	//   - Double Fault Exception (IST3)
 	//   - Machine Check Exception (IST4)
 	//
 	// Refer to Intel Vol. 3A, 6.14.5 Interrupt Stack Table.
 	idt::set_gate(0, divide_error_exception as usize, 1);
-	idt::set_gate(1, debug_exception as usize, 1);
-	idt::set_gate(2, nmi_exception as usize, 2);
+	idt::set_gate(1, debug_exception as usize, gdt::IST_DEBUG);
+	idt::set_gate(2, nmi_exception as usize, gdt::IST_NMI);
 	idt::set_gate(3, breakpoint_exception as usize, 1);
 	idt::set_gate(4, overflow_exception as usize, 1);
 	idt::set_gate(5, bound_range_exceeded_exception as usize, 1);
 	idt::set_gate(6, invalid_opcode_exception as usize, 1);
 	idt::set_gate(7, device_not_available_exception as usize, 1);
-	idt::set_gate(8, double_fault_exception as usize, 3);
+	idt::set_gate(8, double_fault_exception as usize, gdt::IST_DOUBLE_FAULT);
 	idt::set_gate(9, coprocessor_segment_overrun_exception as usize, 1);
 	idt::set_gate(10, invalid_tss_exception as usize, 1);
 	idt::set_gate(11, segment_not_present_exception as usize, 1);
@@ -179,7 +265,7 @@ pub fn install() {
 	idt::set_gate(15, reserved_exception as usize, 1);
 	idt::set_gate(16, floating_point_exception as usize, 1);
 	idt::set_gate(17, alignment_check_exception as usize, 1);
-	idt::set_gate(18, machine_check_exception as usize, 4);
+	idt::set_gate(18, mce::machine_check_exception as usize, gdt::IST_MACHINE_CHECK);
 	idt::set_gate(19, simd_floating_point_exception as usize, 1);
 	idt::set_gate(20, virtualization_exception as usize, 1);
 	idt::set_gate(21, reserved_exception as usize, 1);
@@ -239,10 +325,166 @@ pub extern "C" fn irq_install_handler(irq_number: u32, handler: usize)
 	idt::set_gate((32+irq_number) as u8, handler, 1);
 }
 
+fn handler_index(vector: u8) -> Result<usize, ()> {
+	if vector >= IRQ_VECTOR_BASE && ((vector - IRQ_VECTOR_BASE) as usize) < IRQ_VECTOR_COUNT {
+		Ok((vector - IRQ_VECTOR_BASE) as usize)
+	} else {
+		Err(())
+	}
+}
+
+/// Attaches `handler` to the given IDT vector, which must be dispatched through the common IRQ stubs.
+/// Fails if the vector is out of range or already has a handler.
+/// Can be called at runtime, the dispatch code only sees either the old or the new table entry.
+pub fn register_handler(vector: u8, handler: IrqHandler) -> Result<(), ()> {
+	let index = handler_index(vector)?;
+	let mut handlers_locked = IRQ_HANDLERS.lock();
+	if handlers_locked[index].is_some() {
+		warn!("Interrupt vector {} already has a handler", vector);
+		return Err(());
+	}
+
+	debug!("Register handler for interrupt vector {}", vector);
+	handlers_locked[index] = Some(handler);
+	Ok(())
+}
+
+/// Detaches the handler from the given IDT vector.
+/// Later interrupts on this vector are logged as unhandled.
+pub fn unregister_handler(vector: u8) -> Result<(), ()> {
+	let index = handler_index(vector)?;
+	let mut handlers_locked = IRQ_HANDLERS.lock();
+	if handlers_locked[index].is_none() {
+		return Err(());
+	}
+
+	debug!("Unregister handler for interrupt vector {}", vector);
+	handlers_locked[index] = None;
+	Ok(())
+}
+
+/// Returns the first vector handed out by allocate_vector.
+/// Every vector below is raised by a legacy IRQ of the PIC or by an unmasked I/O APIC pin (see apic::init_ioapic),
+/// which also includes the PCI interrupts above 15.
+fn first_allocatable_vector() -> u8 {
+	IRQ_VECTOR_BASE + cmp::max(LEGACY_IRQ_COUNT, apic::ioapic_pin_count())
+}
+
+fn allocatable_vector_index(vector: u8) -> Option<usize> {
+	if vector >= first_allocatable_vector() && ((vector - IRQ_VECTOR_BASE) as usize) < IRQ_VECTOR_COUNT {
+		Some((vector - IRQ_VECTOR_BASE) as usize)
+	} else {
+		None
+	}
+}
+
+/// Reserves a free vector for dynamically configured interrupts (e.g. MSI) and records `owner` for it.
+/// The vector is dispatched through the handler table, so the owner attaches its handler with register_handler.
+/// Fails if all vectors are in use.
+pub fn allocate_vector(owner: &'static str) -> Result<u8, ()> {
+	let mut owners_locked = VECTOR_OWNERS.lock();
+	for i in (first_allocatable_vector() - IRQ_VECTOR_BASE) as usize..IRQ_VECTOR_COUNT {
+		if owners_locked[i].is_none() {
+			owners_locked[i] = Some(owner);
+			let vector = IRQ_VECTOR_BASE + i as u8;
+			debug!("Allocated interrupt vector {} for {}", vector, owner);
+			return Ok(vector);
+		}
+	}
+
+	error!("No free interrupt vector left for {}", owner);
+	Err(())
+}
+
+/// Returns a vector obtained from allocate_vector to the pool.
+/// A handler that is still registered for the vector is unregistered.
+pub fn free_vector(vector: u8) -> Result<(), ()> {
+	let index = allocatable_vector_index(vector).ok_or(())?;
+	let mut owners_locked = VECTOR_OWNERS.lock();
+	match owners_locked[index] {
+		Some(owner) => debug!("Freed interrupt vector {} of {}", vector, owner),
+		None => return Err(()),
+	}
+
+	let _ = unregister_handler(vector);
+	owners_locked[index] = None;
+	Ok(())
+}
+
+/// Returns the owner of an allocated vector.
+pub fn vector_owner(vector: u8) -> Option<&'static str> {
+	allocatable_vector_index(vector).and_then(|index| VECTOR_OWNERS.lock()[index])
+}
+
+/// Common dispatch code called by the IRQ stubs in entry.asm.
 #[no_mangle]
 pub extern "C" fn unhandled_interrupt(irq_number: u64) {
-	info!("Receive unhandled interrupt {}", irq_number);
-	apic::eoi();
+	let vector = IRQ_VECTOR_BASE + irq_number as u8;
+
+	// Copy the handler out of the table, so that it runs without holding the lock.
+	// This lets a handler register or unregister handlers itself.
+	let handler = IRQ_HANDLERS.lock()[irq_number as usize];
+	match handler {
+		Some(handler) => handler(vector),
+		None => match vector_owner(vector) {
+			Some(owner) => info!("Receive unhandled interrupt {} on the vector allocated by {}", irq_number, owner),
+			None => info!("Receive unhandled interrupt {}", irq_number),
+		},
+	}
+
+	// An interrupt raised by raise_software_interrupt has not gone through the interrupt controller,
+	// so an EOI would acknowledge another interrupt that is currently in service.
+	if unsafe { PERCORE.software_interrupt.get() } != vector as usize {
+		apic::eoi();
+	}
+}
+
+/// Synchronously invokes the handler of the given IDT vector on this CPU Core through an INT instruction.
+/// This works the same in PIC and APIC modes, because it does not go through the interrupt controller.
+/// Useful to test handlers and for events signaled by software.
+///
+/// The vector must be in the range dispatched through the handler table, i.e. IRQ_VECTOR_BASE
+/// to IRQ_VECTOR_BASE + IRQ_VECTOR_COUNT - 1. Fails for any other vector.
+pub fn raise_software_interrupt(vector: u8) -> Result<(), ()> {
+	let index = handler_index(vector)?;
+
+	without_interrupts(|| unsafe {
+		let trigger_address = &irq_trigger_table as *const u8 as usize + index * IRQ_TRIGGER_ENTRY_SIZE;
+		let trigger: extern "C" fn() = mem::transmute(trigger_address);
+
+		PERCORE.software_interrupt.set(vector as usize);
+		trigger();
+		PERCORE.software_interrupt.set(0);
+	});
+
+	Ok(())
+}
+
+/// Number of interrupts handled by dispatch_test_handler.
+static DISPATCH_TEST_INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+
+fn dispatch_test_handler(_vector: u8) {
+	DISPATCH_TEST_INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Checks that interrupts on a vector from allocate_vector reach the handler registered for it,
+/// by raising a software interrupt on this CPU Core. The vector is freed again afterwards.
+/// Call it after apic::init, which determines the vectors used by the I/O APIC.
+pub fn test_dispatch() {
+	let vector = match allocate_vector("the IRQ dispatch test") {
+		Ok(vector) => vector,
+		Err(()) => return,
+	};
+
+	let interrupts = DISPATCH_TEST_INTERRUPTS.load(Ordering::SeqCst);
+	if register_handler(vector, dispatch_test_handler).is_ok() && raise_software_interrupt(vector).is_ok()
+		&& DISPATCH_TEST_INTERRUPTS.load(Ordering::SeqCst) == interrupts + 1 {
+		debug!("Interrupt vector {} has been dispatched to its handler", vector);
+	} else {
+		error!("Interrupt vector {} has not been dispatched to its handler", vector);
+	}
+
+	free_vector(vector).unwrap();
 }
 
 extern "x86-interrupt" fn unknown_interrupt(_stack_frame: &mut ExceptionStackFrame) {
@@ -256,6 +498,13 @@ extern "x86-interrupt" fn divide_error_exception(stack_frame: &mut ExceptionStac
 }
 
 extern "x86-interrupt" fn debug_exception(stack_frame: &mut ExceptionStackFrame) {
+	#[cfg(feature = "debugger")]
+	{
+		if debug::handle_debug_exception(stack_frame) {
+			return;
+		}
+	}
+
 	error!("Debug (#DB) Exception: {:#?}", stack_frame);
 	scheduler::abort();
 }
@@ -280,8 +529,48 @@ extern "x86-interrupt" fn bound_range_exceeded_exception(stack_frame: &mut Excep
 	scheduler::abort();
 }
 
+/// Maximum length of an x86 instruction in bytes.
+const MAXIMUM_INSTRUCTION_LENGTH: usize = 15;
+
+/// Formats the bytes of the instruction at the given address in hexadecimal.
+/// Stops at the first unmapped page, so that printing cannot cause another exception.
+struct InstructionBytes {
+	address: usize,
+	/// Tells whether the page containing an address is mapped.
+	is_mapped: fn(usize) -> bool,
+}
+
+impl InstructionBytes {
+	fn new(address: usize) -> Self {
+		InstructionBytes { address, is_mapped: |address| paging::translate(address).is_some() }
+	}
+}
+
+impl fmt::Display for InstructionBytes {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for i in 0..MAXIMUM_INSTRUCTION_LENGTH {
+			let address = self.address + i;
+			if (i == 0 || address % BasePageSize::SIZE == 0) && !(self.is_mapped)(address) {
+				if i == 0 {
+					write!(f, "unmapped")?;
+				}
+
+				break;
+			}
+
+			write!(f, "{:02X} ", unsafe { *(address as *const u8) })?;
+		}
+
+		Ok(())
+	}
+}
+
 extern "x86-interrupt" fn invalid_opcode_exception(stack_frame: &mut ExceptionStackFrame) {
-	error!("Invalid Opcode (#UD) Exception: {:#?}", stack_frame);
+	// This is usually an instruction that the CPU or hypervisor doesn't support, e.g. because feature detection
+	// has wrongly reported an extension. The instruction bytes tell which one.
+	let instruction_pointer = stack_frame.instruction_pointer as usize;
+	error!("Invalid Opcode (#UD) Exception at {:#X}, instruction bytes: {}", instruction_pointer, InstructionBytes::new(instruction_pointer));
+	error!("{:#?}", stack_frame);
 	scheduler::abort();
 }
 
@@ -331,13 +620,19 @@ extern "x86-interrupt" fn floating_point_exception(stack_frame: &mut ExceptionSt
 	scheduler::abort();
 }
 
-extern "x86-interrupt" fn alignment_check_exception(stack_frame: &mut ExceptionStackFrame) {
-	error!("Alignment Check (#AC) Exception: {:#?}", stack_frame);
-	scheduler::abort();
-}
+/// Only raised if the kernel has been built with the alignment_check feature.
+/// The CPU doesn't report the misaligned address, so print the instruction that has accessed it instead.
+extern "x86-interrupt" fn alignment_check_exception(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
+	let instruction_pointer = stack_frame.instruction_pointer as usize;
+	error!("Alignment Check (#AC) Exception at {:#X}, instruction bytes: {}", instruction_pointer, InstructionBytes::new(instruction_pointer));
+	error!("{:#?}, error {:#X}", stack_frame, error_code);
 
-extern "x86-interrupt" fn machine_check_exception(stack_frame: &mut ExceptionStackFrame) {
-	error!("Machine Check (#MC) Exception: {:#?}", stack_frame);
+	// Report how the check has been enabled, so that an unexpected #AC can be told apart from a misconfiguration.
+	// The CPU only checks accesses at privilege level 3 with both CR0.AM and RFLAGS.AC set.
+	let cr0_am = unsafe { cr0() }.contains(CR0_ALIGNMENT_MASK);
+	let rflags_ac = Flags::from_bits_truncate(stack_frame.cpu_flags as usize).contains(FLAGS_AC);
+	error!("CR0.AM = {}, RFLAGS.AC = {}, CPL = {}, built with alignment_check = {}",
+		cr0_am, rflags_ac, stack_frame.code_segment & 3, cfg!(feature = "alignment_check"));
 	scheduler::abort();
 }
 
@@ -355,3 +650,119 @@ extern "x86-interrupt" fn reserved_exception(stack_frame: &mut ExceptionStackFra
 	error!("Reserved Exception: {:#?}", stack_frame);
 	scheduler::abort();
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn test_handler(_vector: u8) {}
+
+	/// Only this test uses the global tables, so it does not race with other tests.
+	/// It avoids all paths that log at the Info level or above, because the console is not available on the host.
+	#[test]
+	fn handlers_and_vectors_are_managed() {
+		// Without an I/O APIC, all vectors behind the legacy IRQs of the PIC can be allocated.
+		let first_vector = IRQ_VECTOR_BASE + LEGACY_IRQ_COUNT;
+		assert_eq!(first_allocatable_vector(), first_vector);
+
+		assert!(register_handler(IRQ_VECTOR_BASE - 1, test_handler).is_err());
+		assert!(register_handler(IRQ_VECTOR_BASE + IRQ_VECTOR_COUNT as u8, test_handler).is_err());
+		assert!(unregister_handler(IRQ_VECTOR_BASE).is_err());
+
+		assert_eq!(allocate_vector("first"), Ok(first_vector));
+		assert_eq!(allocate_vector("second"), Ok(first_vector + 1));
+		assert_eq!(vector_owner(first_vector), Some("first"));
+		assert_eq!(vector_owner(first_vector + 1), Some("second"));
+		assert_eq!(vector_owner(IRQ_VECTOR_BASE), None);
+		assert!(register_handler(first_vector, test_handler).is_ok());
+
+		// Freeing a vector also removes its handler, and a freed vector is handed out again.
+		assert_eq!(free_vector(first_vector), Ok(()));
+		assert!(unregister_handler(first_vector).is_err());
+		assert_eq!(vector_owner(first_vector), None);
+		assert_eq!(free_vector(first_vector), Err(()));
+		assert_eq!(allocate_vector("third"), Ok(first_vector));
+
+		// Vectors of legacy IRQs are never allocated, so they cannot be freed either.
+		assert_eq!(free_vector(IRQ_VECTOR_BASE + 1), Err(()));
+
+		assert_eq!(free_vector(first_vector), Ok(()));
+		assert_eq!(free_vector(first_vector + 1), Ok(()));
+	}
+
+	#[test]
+	fn nested_guards_restore_the_state_they_found() {
+		{
+			let _outer = IrqGuard::new();
+			assert!(!interrupts_enabled());
+
+			{
+				let _inner = IrqGuard::new();
+				assert!(!interrupts_enabled());
+			}
+
+			// The inner guard found interrupts disabled and must not enable them.
+			assert!(!interrupts_enabled());
+		}
+		assert!(interrupts_enabled());
+
+		// Interrupts that were already disabled stay disabled after the guard.
+		disable();
+		without_interrupts(|| assert!(!interrupts_enabled()));
+		assert!(!interrupts_enabled());
+		enable();
+	}
+
+	#[test]
+	fn invalid_opcodes_are_printed() {
+		// UD2, which is guaranteed to raise #UD, followed by NOPs.
+		let mut code = [0x90u8; MAXIMUM_INSTRUCTION_LENGTH + 1];
+		code[0] = 0x0F;
+		code[1] = 0x0B;
+
+		let bytes = InstructionBytes { address: code.as_ptr() as usize, is_mapped: |_| true };
+		assert_eq!(format!("{}", bytes), "0F 0B 90 90 90 90 90 90 90 90 90 90 90 90 90 ");
+
+		let bytes = InstructionBytes { address: code.as_ptr() as usize, is_mapped: |_| false };
+		assert_eq!(format!("{}", bytes), "unmapped");
+	}
+
+	#[test]
+	fn instruction_bytes_stop_at_an_unmapped_page() {
+		// Let the instruction start 3 bytes before a page boundary and pretend that the next page is unmapped.
+		let memory = vec![0xCCu8; 2 * BasePageSize::SIZE];
+		let page_boundary = align_up!(memory.as_ptr() as usize + 1, BasePageSize::SIZE);
+
+		fn is_below_boundary(address: usize) -> bool {
+			address % BasePageSize::SIZE != 0
+		}
+
+		let bytes = InstructionBytes { address: page_boundary - 3, is_mapped: is_below_boundary };
+		assert_eq!(format!("{}", bytes), "CC CC CC ");
+	}
+
+	#[test]
+	#[should_panic(expected = "Interrupts have been enabled inside without_interrupts")]
+	fn enabling_interrupts_inside_without_interrupts_is_caught() {
+		without_interrupts(enable);
+	}
+
+	#[test]
+	fn early_returns_restore_interrupts() {
+		fn first_even(values: &[u32]) -> Option<u32> {
+			let _guard = IrqGuard::new();
+			for &value in values {
+				if value % 2 == 0 {
+					return Some(value);
+				}
+			}
+			None
+		}
+
+		assert_eq!(first_even(&[1, 4, 5]), Some(4));
+		assert!(interrupts_enabled());
+		assert_eq!(without_interrupts(|| first_even(&[3])), None);
+		assert!(interrupts_enabled());
+	}
+}
@@ -0,0 +1,238 @@
+// Copyright (c) 2017 Stefan Lankes, RWTH Aachen University
+//                    Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Machine Check Architecture (MCA) support.
+//!
+//! The CPU reports hardware errors (e.g. memory or cache ECC errors) in its machine-check banks.
+//! Corrected errors are only logged in the banks, while uncorrected ones raise a Machine Check Exception (#MC).
+//! See Intel Vol. 3B, Chapter 15 Machine-Check Architecture.
+
+use arch::x86_64::irq;
+use arch::x86_64::percore::*;
+use arch::x86_64::processor;
+use arch::x86_64::shutdown;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86::shared::msr::*;
+
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17A;
+const IA32_MCG_CTL: u32 = 0x17B;
+const IA32_MC0_CTL: u32 = 0x400;
+
+const MCG_CAP_COUNT_MASK: u64 = 0xFF;
+const MCG_CAP_CTL_PRESENT: u64 = 1 << 8;
+
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+const MCG_STATUS_MCIP: u64 = 1 << 2;
+
+const MCI_STATUS_VAL: u64 = 1 << 63;
+const MCI_STATUS_OVER: u64 = 1 << 62;
+const MCI_STATUS_UC: u64 = 1 << 61;
+const MCI_STATUS_EN: u64 = 1 << 60;
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+const MCI_STATUS_PCC: u64 = 1 << 57;
+
+/// Interval in timer ticks between two checks of the machine-check banks from the timer interrupt.
+const POLL_INTERVAL_TICKS: usize = processor::TIMER_FREQUENCY;
+
+static mut BANK_COUNT: u32 = 0;
+static CORRECTED_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Returns the MSR number of a register of bank `bank`.
+/// Each bank has four consecutive registers: CTL, STATUS, ADDR, and MISC.
+#[inline]
+fn bank_msr(bank: u32, register: u32) -> u32 {
+	IA32_MC0_CTL + 4 * bank + register
+}
+
+/// Decodes the category of the MCA error code in bits 15:0 of IA32_MCi_STATUS (cf. Intel Vol. 3B, 15.9.2).
+struct ErrorCode(u16);
+
+impl fmt::Display for ErrorCode {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let code = self.0;
+
+		// Bit 12 of compound error codes only filters the reporting, so ignore it.
+		let category = if (code & 0xE800) == 0x0800 {
+			"Bus/Interconnect Error"
+		} else if (code & 0xEF00) == 0x0100 {
+			"Cache Hierarchy Error"
+		} else if (code & 0xEF80) == 0x0080 {
+			"Memory Controller Error"
+		} else if (code & 0xEFF0) == 0x0010 {
+			"TLB Error"
+		} else {
+			match code {
+				0x0001 => "Unclassified Error",
+				0x0002 => "Microcode ROM Parity Error",
+				0x0003 => "External Error",
+				0x0004 => "FRC Error",
+				0x0005 => "Internal Parity Error",
+				0x0006 => "SMM Handler Code Access Violation",
+				0x0400...0x04FF => "Internal Timer Error",
+				_ => "Internal Unclassified Error",
+			}
+		};
+
+		write!(f, "{} ({:#06X})", category, code)
+	}
+}
+
+/// Returns whether the CPU supports Machine Check Exceptions and the Machine Check Architecture.
+fn is_supported() -> bool {
+	let features = processor::features();
+	features.has_mce() && features.has_mca()
+}
+
+/// Logs the error in bank `bank` if there is one and clears it.
+/// Returns whether the error is uncorrected, i.e. the processor context may be corrupt.
+fn check_bank(bank: u32) -> bool {
+	let status = unsafe { rdmsr(bank_msr(bank, 1)) };
+	if (status & MCI_STATUS_VAL) == 0 {
+		return false;
+	}
+
+	let uncorrected = (status & (MCI_STATUS_UC | MCI_STATUS_PCC)) > 0;
+	let kind = if uncorrected { "Uncorrected" } else { "Corrected" };
+	let error_code = ErrorCode(status as u16);
+
+	if (status & MCI_STATUS_ADDRV) > 0 {
+		let address = unsafe { rdmsr(bank_msr(bank, 2)) };
+		error!("{} Machine Check in bank {}: {}, address {:#X}, status {:#X}", kind, bank, error_code, address, status);
+	} else {
+		error!("{} Machine Check in bank {}: {}, status {:#X}", kind, bank, error_code, status);
+	}
+
+	if (status & MCI_STATUS_OVER) > 0 {
+		error!("Bank {} overflowed, previous errors have been lost", bank);
+	}
+
+	if !uncorrected {
+		CORRECTED_ERRORS.fetch_add(1, Ordering::SeqCst);
+	}
+
+	unsafe { wrmsr(bank_msr(bank, 1), 0); }
+	uncorrected && (status & MCI_STATUS_EN) > 0
+}
+
+/// Enables reporting for all machine-check banks of the current core.
+/// Errors left in the banks from before the (warm) reset are logged and cleared.
+pub fn init() {
+	if !is_supported() {
+		debug!("Machine Check Architecture is not supported");
+		return;
+	}
+
+	let capabilities = unsafe { rdmsr(IA32_MCG_CAP) };
+	let bank_count = (capabilities & MCG_CAP_COUNT_MASK) as u32;
+	unsafe { BANK_COUNT = bank_count; }
+
+	unsafe {
+		if (capabilities & MCG_CAP_CTL_PRESENT) > 0 {
+			wrmsr(IA32_MCG_CTL, u64::max_value());
+		}
+
+		for bank in 0..bank_count {
+			wrmsr(bank_msr(bank, 0), u64::max_value());
+		}
+	}
+
+	poll();
+	debug!("Enabled {} machine-check banks", bank_count);
+}
+
+/// Checks all machine-check banks of the current core for corrected errors, which do not raise an exception.
+/// The timer interrupt calls this through poll_periodically to keep the count of corrected errors up to date.
+pub fn poll() {
+	for bank in 0..unsafe { BANK_COUNT } {
+		check_bank(bank);
+	}
+}
+
+/// Calls poll if POLL_INTERVAL_TICKS have passed since the last check of the banks on the current core.
+/// Called from the timer interrupt handler.
+pub fn poll_periodically() {
+	if unsafe { BANK_COUNT } == 0 {
+		return;
+	}
+
+	let ticks = processor::update_timer_ticks();
+	unsafe {
+		if ticks < PERCORE.mce_next_poll.get() {
+			return;
+		}
+
+		PERCORE.mce_next_poll.set(ticks + POLL_INTERVAL_TICKS);
+	}
+
+	poll();
+}
+
+/// Returns the number of corrected machine-check errors found so far on all cores.
+pub fn corrected_error_count() -> usize {
+	CORRECTED_ERRORS.load(Ordering::SeqCst)
+}
+
+pub extern "x86-interrupt" fn machine_check_exception(stack_frame: &mut irq::ExceptionStackFrame) {
+	error!("Machine Check (#MC) Exception: {:#?}", stack_frame);
+
+	let mut uncorrected = false;
+	for bank in 0..unsafe { BANK_COUNT } {
+		uncorrected |= check_bank(bank);
+	}
+
+	// Without a valid return address, we cannot safely continue either.
+	// The error may have corrupted memory used by all cores, so none of them may continue.
+	let global_status = unsafe { rdmsr(IA32_MCG_STATUS) };
+	if uncorrected || (global_status & MCG_STATUS_RIPV) == 0 {
+		error!("Uncorrectable Machine Check, halting all cores");
+		shutdown::quiesce_all_cores();
+		shutdown::park_current_core();
+	}
+
+	// Clearing MCIP allows the next Machine Check Exception. Otherwise, it would cause a shutdown.
+	unsafe { wrmsr(IA32_MCG_STATUS, global_status & !MCG_STATUS_MCIP); }
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn error_codes_are_categorized() {
+		assert_eq!(format!("{}", ErrorCode(0x0001)), "Unclassified Error (0x0001)");
+		assert_eq!(format!("{}", ErrorCode(0x0405)), "Internal Timer Error (0x0405)");
+		assert_eq!(format!("{}", ErrorCode(0x0011)), "TLB Error (0x0011)");
+		assert_eq!(format!("{}", ErrorCode(0x009F)), "Memory Controller Error (0x009F)");
+		assert_eq!(format!("{}", ErrorCode(0x0134)), "Cache Hierarchy Error (0x0134)");
+		assert_eq!(format!("{}", ErrorCode(0x0E0B)), "Bus/Interconnect Error (0x0E0B)");
+
+		// The filtering bit 12 does not change the category of compound error codes.
+		assert_eq!(format!("{}", ErrorCode(0x1134)), "Cache Hierarchy Error (0x1134)");
+	}
+}
@@ -25,9 +25,114 @@ pub mod paging;
 pub mod physicalmem;
 pub mod virtualmem;
 
+use arch::x86_64::processor;
+use core::ptr;
+
+/// Copies or sets with less than this number of bytes use regular stores, because the data is likely
+/// to be used again soon and non-temporal stores only pay off for larger blocks anyway.
+const NONTEMPORAL_THRESHOLD: usize = 4096;
+
 
 pub fn init() {
 	paging::init();
 	physicalmem::init();
 	virtualmem::init();
 }
+
+/// Copies `count` bytes from `source` to `destination` using non-temporal stores, which bypass the
+/// caches. The areas must not overlap.
+///
+/// The stores use MOVNTI with general-purpose registers, as the kernel does not save the SSE/AVX registers
+/// of tasks when it is entered and therefore must not use them.
+pub unsafe fn memcpy_nt(destination: *mut u8, source: *const u8, count: usize) {
+	if count < NONTEMPORAL_THRESHOLD || !processor::supports_sse2() {
+		ptr::copy_nonoverlapping(source, destination, count);
+		return;
+	}
+
+	// Align the destination to 8 bytes with regular stores.
+	let head = (8 - destination as usize % 8) % 8;
+	ptr::copy_nonoverlapping(source, destination, head);
+
+	let words = (count - head) / 8;
+	let source_words = source.offset(head as isize) as *const u64;
+	let destination_words = destination.offset(head as isize) as *mut u64;
+	for i in 0..words {
+		let word = ptr::read_unaligned(source_words.offset(i as isize));
+		asm!("movnti $1, ($0)" :: "r"(destination_words.offset(i as isize)), "r"(word) : "memory" : "volatile");
+	}
+
+	let tail = head + words * 8;
+	ptr::copy_nonoverlapping(source.offset(tail as isize), destination.offset(tail as isize), count - tail);
+
+	// Order the non-temporal stores with all following stores.
+	asm!("sfence" ::: "memory" : "volatile");
+}
+
+/// Sets `count` bytes at `destination` to `value` using non-temporal stores, which bypass the caches.
+/// See memcpy_nt.
+pub unsafe fn memset_nt(destination: *mut u8, value: u8, count: usize) {
+	if count < NONTEMPORAL_THRESHOLD || !processor::supports_sse2() {
+		ptr::write_bytes(destination, value, count);
+		return;
+	}
+
+	let head = (8 - destination as usize % 8) % 8;
+	ptr::write_bytes(destination, value, head);
+
+	let words = (count - head) / 8;
+	let destination_words = destination.offset(head as isize) as *mut u64;
+	let word = value as u64 * 0x0101_0101_0101_0101;
+	for i in 0..words {
+		asm!("movnti $1, ($0)" :: "r"(destination_words.offset(i as isize)), "r"(word) : "memory" : "volatile");
+	}
+
+	let tail = head + words * 8;
+	ptr::write_bytes(destination.offset(tail as isize), value, count - tail);
+
+	asm!("sfence" ::: "memory" : "volatile");
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Sizes below, at and above NONTEMPORAL_THRESHOLD, including ones that leave a head and a tail of bytes.
+	const SIZES: [usize; 6] = [0, 7, NONTEMPORAL_THRESHOLD - 1, NONTEMPORAL_THRESHOLD, NONTEMPORAL_THRESHOLD + 13, 3 * NONTEMPORAL_THRESHOLD + 5];
+
+	#[test]
+	fn memcpy_nt_matches_a_byte_wise_copy() {
+		let source: Vec<u8> = (0..4 * NONTEMPORAL_THRESHOLD).map(|i| (i * 7 + i / 256) as u8).collect();
+
+		for &size in SIZES.iter() {
+			// Misalign both areas differently, so the alignment of the destination is actually needed.
+			for &(source_offset, destination_offset) in [(0, 0), (3, 0), (0, 5), (1, 7)].iter() {
+				let mut destination = vec![0xAAu8; size + 16];
+				let mut expected = destination.clone();
+				for i in 0..size {
+					expected[destination_offset + i] = source[source_offset + i];
+				}
+
+				unsafe { memcpy_nt(destination[destination_offset..].as_mut_ptr(), source[source_offset..].as_ptr(), size); }
+				assert!(destination == expected, "memcpy_nt of {} bytes from offset {} to offset {}", size, source_offset, destination_offset);
+			}
+		}
+	}
+
+	#[test]
+	fn memset_nt_matches_a_byte_wise_fill() {
+		for &size in SIZES.iter() {
+			for &offset in [0, 1, 6].iter() {
+				let mut destination = vec![0x55u8; size + 16];
+				let mut expected = destination.clone();
+				for byte in &mut expected[offset..offset + size] {
+					*byte = 0xC3;
+				}
+
+				unsafe { memset_nt(destination[offset..].as_mut_ptr(), 0xC3, size); }
+				assert!(destination == expected, "memset_nt of {} bytes at offset {}", size, offset);
+			}
+		}
+	}
+}
@@ -21,17 +21,22 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use alloc::vec::Vec;
 use arch::x86_64::apic;
 use arch::x86_64::irq;
 use arch::x86_64::mm::physicalmem;
+use arch::x86_64::mm::physicalmem::frame_ref;
 use arch::x86_64::mm::virtualmem;
 use arch::x86_64::percore::*;
 use arch::x86_64::processor;
-use core::{fmt, ptr};
+use core::{cmp, fmt, mem, ptr};
 use core::marker::PhantomData;
-use hermit_multiboot::Multiboot;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use environment;
+use hermit_multiboot::{Module, Multiboot};
 use mm;
 use scheduler;
+use synch::spinlock::SpinlockIrqSave;
 use x86::shared::control_regs;
 
 
@@ -39,8 +44,6 @@ extern "C" {
 	#[linkage = "extern_weak"]
 	static runtime_osinit: *const u8;
 
-	static cmdline: *const u8;
-	static cmdsize: usize;
 	static mb_info: usize;
 }
 
@@ -48,6 +51,10 @@ extern "C" {
 /// Pointer to the root page table (PML4)
 const PML4_ADDRESS: *mut PageTable<PML4> = 0xFFFF_FFFF_FFFF_F000 as *mut PageTable<PML4>;
 
+/// Pointer to the root page table (PML5) if 5-level paging is enabled.
+/// The root table maps itself in its last entry, so it is found at the same address as a PML4 root table.
+const PML5_ADDRESS: *mut PageTable<PML5> = 0xFFFF_FFFF_FFFF_F000 as *mut PageTable<PML5>;
+
 /// Number of Offset bits of a virtual address for a 4 KiB page, which are shifted away to get its Page Frame Number (PFN).
 const PAGE_BITS: usize = 12;
 
@@ -57,6 +64,31 @@ const PAGE_MAP_BITS: usize = 9;
 /// A mask where PAGE_MAP_BITS are set to calculate a table index.
 const PAGE_MAP_MASK: usize = 0x1FF;
 
+lazy_static! {
+	/// Virtual memory regions that are reserved for demand-zero mapping, but not necessarily backed yet.
+	static ref LAZY_REGIONS: SpinlockIrqSave<Vec<LazyRegion>> = SpinlockIrqSave::new(Vec::new());
+}
+
+/// Number of page faults resolved by backing a page of a demand-zero region.
+static LAZY_FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+/// A virtual memory region whose 4 KiB pages are backed by zeroed physical memory on first access.
+struct LazyRegion {
+	start: usize,
+	end: usize,
+	flags: PageTableEntryFlags,
+}
+
+impl LazyRegion {
+	fn contains(&self, virtual_address: usize) -> bool {
+		virtual_address >= self.start && virtual_address < self.end
+	}
+
+	fn overlaps(&self, other: &LazyRegion) -> bool {
+		self.start < other.end && other.start < self.end
+	}
+}
+
 
 bitflags! {
 	/// Possible flags for an entry in either table (PML4, PDPT, PDT, PGT)
@@ -88,10 +120,19 @@ bitflags! {
 		/// Only for page entries in PDPT or PDT: Set if this entry references a 1 GiB (PDPT) or 2 MiB (PDT) page.
 		const HUGE_PAGE = 1 << 7;
 
+		/// Only for page entries in PGT: Selects the memory type from the Page Attribute Table together
+		/// with WRITE_THROUGH and CACHE_DISABLE (see processor::configure).
+		/// This is the same bit as HUGE_PAGE in the higher levels.
+		const PAT = 1 << 7;
+
 		/// Only for page entries: Set if this address translation is global for all tasks and does not need to
 		/// be flushed from the TLB when CR3 is reset.
 		const GLOBAL = 1 << 8;
 
+		/// Only for page entries: Software-defined bit (ignored by the CPU), set if the page is shared
+		/// read-only and shall be copied on the first write access.
+		const COPY_ON_WRITE = 1 << 9;
+
 		/// Set if code execution shall be disabled for memory referenced by this entry.
 		const EXECUTE_DISABLE = 1 << 63;
     }
@@ -101,6 +142,87 @@ impl PageTableEntryFlags {
 	/// An empty set of flags for unused/zeroed table entries.
 	/// Needed as long as empty() is no const function.
 	const BLANK: PageTableEntryFlags = PageTableEntryFlags { bits: 0 };
+
+	#[inline]
+	pub fn is_writable(&self) -> bool {
+		self.contains(PageTableEntryFlags::WRITABLE)
+	}
+
+	#[inline]
+	pub fn is_executable(&self) -> bool {
+		!self.contains(PageTableEntryFlags::EXECUTE_DISABLE)
+	}
+
+	#[inline]
+	pub fn is_user_accessible(&self) -> bool {
+		self.contains(PageTableEntryFlags::USER_ACCESSIBLE)
+	}
+
+	#[inline]
+	pub fn is_copy_on_write(&self) -> bool {
+		self.contains(PageTableEntryFlags::COPY_ON_WRITE)
+	}
+
+	/// Whether the memory is cached as Write-Back, i.e. none of the bits selecting another memory type is set.
+	/// Only meaningful for 4 KiB page entries, see PAT.
+	#[inline]
+	pub fn is_write_back(&self) -> bool {
+		!self.intersects(PageTableEntryFlags::WRITE_THROUGH | PageTableEntryFlags::CACHE_DISABLE | PageTableEntryFlags::PAT)
+	}
+}
+
+/// Formats the access rights of a mapping of the given page size like "rw- kernel".
+/// 4 KiB pages with another memory type than Write-Back are marked, because their flags select it through PAT.
+struct MappingAccess {
+	flags: PageTableEntryFlags,
+	size: usize,
+}
+
+impl fmt::Display for MappingAccess {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "r{}{} {}",
+			if self.flags.is_writable() { "w" } else { "-" },
+			if self.flags.is_executable() { "x" } else { "-" },
+			if self.flags.is_user_accessible() { "user" } else { "kernel" })?;
+
+		if self.size == BasePageSize::SIZE && !self.flags.is_write_back() {
+			write!(f, ", not Write-Back")?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Returns the flags for mapping memory copy-on-write, which is read-only until the first write access
+/// gives the faulting mapping its own copy of the page.
+pub fn copy_on_write_flags(flags: PageTableEntryFlags) -> PageTableEntryFlags {
+	(flags - PageTableEntryFlags::WRITABLE) | PageTableEntryFlags::COPY_ON_WRITE
+}
+
+/// Returns the flags for the private, writable copy of a copy-on-write page.
+fn writable_copy_flags(flags: PageTableEntryFlags) -> PageTableEntryFlags {
+	(flags - PageTableEntryFlags::COPY_ON_WRITE) | PageTableEntryFlags::WRITABLE
+}
+
+/// Memory types for mapping device memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryType {
+	/// For device registers, whose accesses must neither be cached nor reordered.
+	Uncacheable,
+	/// For framebuffers, where writes may be combined into bursts.
+	WriteCombining,
+}
+
+/// Returns the flags of a 4 KiB page table entry selecting the given memory type.
+/// Without a Page Attribute Table, Write-Combining falls back to the slower, but still correct Uncacheable type.
+pub fn device_memory_flags(memory_type: MemoryType) -> PageTableEntryFlags {
+	if memory_type == MemoryType::WriteCombining && processor::supports_pat() {
+		// PAT entry 4
+		PageTableEntryFlags::PAT
+	} else {
+		// PAT entry 3, which is UC for all CPUs.
+		PageTableEntryFlags::WRITE_THROUGH | PageTableEntryFlags::CACHE_DISABLE
+	}
 }
 
 /// An entry in either table (PML4, PDPT, PDT, PGT)
@@ -116,6 +238,11 @@ impl PageTableEntry {
 		self.physical_address_and_flags & !(BasePageSize::SIZE - 1) & !(PageTableEntryFlags::EXECUTE_DISABLE).bits()
 	}
 
+	/// Return the stored flags.
+	pub fn flags(&self) -> PageTableEntryFlags {
+		PageTableEntryFlags::from_bits_truncate(self.physical_address_and_flags)
+	}
+
 	/// Returns whether this entry is valid (present).
 	fn is_present(&self) -> bool {
 		(self.physical_address_and_flags & PageTableEntryFlags::PRESENT.bits()) != 0
@@ -128,17 +255,12 @@ impl PageTableEntry {
 	/// * `physical_address` - The physical memory address this entry shall translate to
 	/// * `flags` - Flags from PageTableEntryFlags (note that the PRESENT and ACCESSED flags are set automatically)
 	fn set(&mut self, physical_address: usize, flags: PageTableEntryFlags) {
-		if flags.contains(PageTableEntryFlags::HUGE_PAGE) {
-			// HUGE_PAGE may indicate a 2 MiB or 1 GiB page.
-			// We don't know this here, so we can only verify that at least the offset bits for a 2 MiB page are zero.
-			assert!(physical_address % LargePageSize::SIZE == 0, "Physical address is not on a 2 MiB page boundary (physical_address = {:#X})", physical_address);
-		} else {
-			// Verify that the offset bits for a 4 KiB page are zero.
-			assert!(physical_address % BasePageSize::SIZE == 0, "Physical address is not on a 4 KiB page boundary (physical_address = {:#X})", physical_address);
-		}
+		// Verify that the offset bits for a 4 KiB page are zero.
+		// Larger pages are checked in map_page_in_this_table, as HUGE_PAGE is the PAT bit for 4 KiB pages.
+		assert!(physical_address % BasePageSize::SIZE == 0, "Physical address is not on a 4 KiB page boundary (physical_address = {:#X})", physical_address);
 
 		// Verify that the physical address does not exceed the CPU's physical address width.
-		assert!(physical_address >> processor::get_physical_address_bits() == 0, "Physical address exceeds CPU's physical address width (physical_address = {:#X})", physical_address);
+		assert!(physical_address >> processor::phys_address_bits() == 0, "Physical address exceeds CPU's physical address width (physical_address = {:#X})", physical_address);
 
 		self.physical_address_and_flags = physical_address | (PageTableEntryFlags::PRESENT | PageTableEntryFlags::ACCESSED | flags).bits();
 	}
@@ -212,15 +334,18 @@ impl<S: PageSize> Page<S> {
 
 	/// Returns whether the given virtual address is a valid one in the x86-64 memory model.
 	///
-	/// Current x86-64 supports only 48-bit for virtual memory addresses.
+	/// With 4-level paging, x86-64 supports only 48-bit for virtual memory addresses.
 	/// This is enforced by requiring bits 63 through 48 to replicate bit 47 (cf. Intel Vol. 1, 3.3.7.1).
 	/// As a consequence, the address space is divided into the two valid regions 0x8000_0000_0000
 	/// and 0xFFFF_8000_0000_0000.
 	///
-	/// Although we could make this check depend on the actual linear address width from the CPU,
-	/// any extension above 48-bit would require a new page table level, which we don't implement.
+	/// With 5-level paging, the same applies to 57-bit addresses and bit 56.
 	fn is_valid_address(virtual_address: usize) -> bool {
-		(virtual_address < 0x8000_0000_0000 || virtual_address >= 0xFFFF_8000_0000_0000)
+		if processor::is_la57_enabled() {
+			(virtual_address < 0x0100_0000_0000_0000 || virtual_address >= 0xFF00_0000_0000_0000)
+		} else {
+			(virtual_address < 0x8000_0000_0000 || virtual_address >= 0xFFFF_8000_0000_0000)
+		}
 	}
 
 	/// Returns a Page including the given virtual address.
@@ -274,7 +399,7 @@ impl<S: PageSize> Iterator for PageIter<S> {
 /// An interface to allow for a generic implementation of struct PageTable for all 4 page tables.
 /// Must be implemented by all page tables.
 trait PageTableLevel {
-	/// Numeric page table level (from 0 for PT through 3 for PML4) to enable numeric comparisons.
+	/// Numeric page table level (from 0 for PT through 4 for PML5) to enable numeric comparisons.
 	const LEVEL: usize;
 }
 
@@ -287,6 +412,17 @@ trait PageTableLevelWithSubtables: PageTableLevel {
 	type SubtableLevel;
 }
 
+/// The Page Map Level 5 (PML5) table, with numeric level 4 and PML4 subtables.
+/// Only used if 5-level paging is enabled.
+enum PML5 {}
+impl PageTableLevel for PML5 {
+	const LEVEL: usize = 4;
+}
+
+impl PageTableLevelWithSubtables for PML5 {
+	type SubtableLevel = PML4;
+}
+
 /// The Page Map Level 4 (PML4) table, with numeric level 3 and PDPT subtables.
 enum PML4 {}
 impl PageTableLevel for PML4 {
@@ -323,7 +459,7 @@ impl PageTableLevel for PT {
 	const LEVEL: usize = 0;
 }
 
-/// Representation of any page table (PML4, PDPT, PD, PT) in memory.
+/// Representation of any page table (PML5, PML4, PDPT, PD, PT) in memory.
 /// Parameter L supplies information for Rust's typing system to distinguish between the different tables.
 struct PageTable<L> {
 	/// Each page table has 512 entries (can be calculated using PAGE_MAP_BITS).
@@ -349,6 +485,7 @@ impl<L: PageTableLevel> PageTableMethods for PageTable<L> {
 	/// Must only be called if a page of this size is mapped at this page table level!
 	fn map_page_in_this_table<S: PageSize>(&mut self, page: Page<S>, physical_address: usize, flags: PageTableEntryFlags) -> bool {
 		assert!(L::LEVEL == S::MAP_LEVEL);
+		assert!(physical_address % S::SIZE == 0, "Physical address is not on a {:#X} page boundary (physical_address = {:#X})", S::SIZE, physical_address);
 		let index = page.table_index::<L>();
 		let flush = self.entries[index].is_present();
 
@@ -481,6 +618,62 @@ impl<L: PageTableLevelWithSubtables> PageTable<L> where L::SubtableLevel: PageTa
 	}
 }
 
+/// Returns the PageTableEntry for the given page, starting the walk at the root table.
+/// The root table is a PML5 if 5-level paging is enabled and a PML4 otherwise.
+fn root_get_page_table_entry<S: PageSize>(page: Page<S>) -> Option<PageTableEntry> {
+	if processor::is_la57_enabled() {
+		unsafe { (*PML5_ADDRESS).get_page_table_entry(page) }
+	} else {
+		unsafe { (*PML4_ADDRESS).get_page_table_entry(page) }
+	}
+}
+
+/// Maps a single page, starting the walk at the root table (see root_get_page_table_entry).
+fn root_map_page<S: PageSize>(page: Page<S>, physical_address: usize, flags: PageTableEntryFlags) -> bool {
+	if processor::is_la57_enabled() {
+		unsafe { (*PML5_ADDRESS).map_page(page, physical_address, flags) }
+	} else {
+		unsafe { (*PML4_ADDRESS).map_page(page, physical_address, flags) }
+	}
+}
+
+/// Maps a continuous range of pages, starting the walk at the root table (see root_get_page_table_entry).
+fn root_map_pages<S: PageSize>(range: PageIter<S>, physical_address: usize, flags: PageTableEntryFlags, do_ipi: bool) {
+	if processor::is_la57_enabled() {
+		unsafe { (*PML5_ADDRESS).map_pages(range, physical_address, flags, do_ipi) }
+	} else {
+		unsafe { (*PML4_ADDRESS).map_pages(range, physical_address, flags, do_ipi) }
+	}
+}
+
+/// Walks the active page tables for the given virtual address without knowing the page size in advance.
+///
+/// Returns the entry mapping the address and the size of the mapped page if the address is mapped.
+/// Otherwise, returns the size of the unmapped region (aligned to this size) the address is part of,
+/// determined by the page table level where the walk stopped.
+fn walk(virtual_address: usize) -> Result<(&'static mut PageTableEntry, usize), usize> {
+	let mut level = if processor::is_la57_enabled() { PML5::LEVEL } else { PML4::LEVEL };
+	let mut table_address = PML4_ADDRESS as usize;
+
+	loop {
+		let index = virtual_address >> PAGE_BITS >> level * PAGE_MAP_BITS & PAGE_MAP_MASK;
+		let entry = unsafe { &mut (*(table_address as *mut PageTable<PT>)).entries[index] };
+		let size = BasePageSize::SIZE << level * PAGE_MAP_BITS;
+
+		if !entry.is_present() {
+			return Err(size);
+		}
+
+		// Entries in PDPT and PDT may directly reference a 1 GiB or 2 MiB page.
+		if level == PT::LEVEL || (level <= PDPT::LEVEL && entry.flags().contains(PageTableEntryFlags::HUGE_PAGE)) {
+			return Ok((entry, size));
+		}
+
+		table_address = (table_address << PAGE_MAP_BITS) | (index << PAGE_BITS);
+		level -= 1;
+	}
+}
+
 bitflags! {
 	/// Possible flags for the error code of a Page-Fault Exception.
 	///
@@ -516,9 +709,105 @@ impl fmt::Display for PageFaultError {
 }
 
 
+/// Resolves a write access to a page shared copy-on-write.
+/// Returns whether the page fault at the given address has been handled.
+fn handle_copy_on_write_fault(virtual_address: usize) -> bool {
+	let (entry, size) = match walk(virtual_address) {
+		Ok(mapping) => mapping,
+		Err(_) => return false
+	};
+
+	// Copy-on-write is only supported for 4 KiB pages.
+	if size != BasePageSize::SIZE {
+		return false;
+	}
+
+	// Another CPU may have resolved the fault already, leaving us with a stale TLB entry.
+	let page = Page::<BasePageSize>::including_address(virtual_address);
+	if entry.flags().is_writable() {
+		page.flush_from_tlb();
+		return true;
+	}
+
+	if !entry.flags().is_copy_on_write() {
+		return false;
+	}
+
+	let _lock = mm::MM_LOCK.lock();
+	let physical_address = entry.address();
+	let flags = writable_copy_flags(entry.flags());
+	if frame_ref::refcount(physical_address) > 1 {
+		// Copy the shared frame into a new one through a temporary mapping.
+		let new_physical_address = physicalmem::allocate(BasePageSize::SIZE);
+		let temporary_address = virtualmem::allocate(BasePageSize::SIZE);
+		map::<BasePageSize>(temporary_address, new_physical_address, 1, PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE, false);
+		unsafe { ptr::copy_nonoverlapping(page.address() as *const u8, temporary_address as *mut u8, BasePageSize::SIZE); }
+		unmap::<BasePageSize>(temporary_address, 1, false);
+		virtualmem::deallocate(temporary_address, BasePageSize::SIZE);
+
+		debug_mem!("Copied shared page {:#X} ({:#X} => {:#X})", page.address(), physical_address, new_physical_address);
+		entry.physical_address_and_flags = new_physical_address | flags.bits();
+
+		// The new frame is exclusively owned by this mapping.
+		// The shared frame is still referenced by at least one other mapping, so this never frees it.
+		frame_ref::incref(new_physical_address);
+		frame_ref::decref(physical_address);
+	} else {
+		// This is the last mapping of the frame, so we can simply make it writable again.
+		debug_mem!("Taking over shared page {:#X} ({:#X})", page.address(), physical_address);
+		entry.physical_address_and_flags = physical_address | flags.bits();
+	}
+
+	page.flush_from_tlb();
+	apic::ipi_tlb_flush();
+	true
+}
+
+/// Backs a page of a demand-zero region with zeroed physical memory.
+/// Returns whether the page fault at the given address has been handled.
+fn handle_lazy_fault(virtual_address: usize) -> bool {
+	let flags = match LAZY_REGIONS.lock().iter().find(|r| r.contains(virtual_address)) {
+		Some(region) => region.flags,
+		None => return false
+	};
+
+	let _lock = mm::MM_LOCK.lock();
+	let page = Page::<BasePageSize>::including_address(virtual_address);
+
+	// Another CPU may have backed this page already.
+	if walk(page.address()).is_ok() {
+		page.flush_from_tlb();
+		return true;
+	}
+
+	let physical_address = physicalmem::allocate(BasePageSize::SIZE);
+	debug_mem!("Backing demand-zero page {:#X} ({:#X})", page.address(), physical_address);
+
+	// The page has to be writable while zeroing it.
+	root_map_page(page, physical_address, flags | PageTableEntryFlags::WRITABLE);
+	unsafe { ptr::write_bytes(page.address() as *mut u8, 0, BasePageSize::SIZE); }
+	if !flags.is_writable() {
+		root_map_page(page, physical_address, flags);
+	}
+
+	LAZY_FAULTS.fetch_add(1, Ordering::Relaxed);
+	true
+}
+
 pub extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut irq::ExceptionStackFrame, error_code: u64) {
 	let virtual_address = unsafe { control_regs::cr2() };
 
+	// Is this a write access to a page shared copy-on-write?
+	let pferror = PageFaultError { bits: error_code };
+	if pferror.contains(PageFaultError::PROTECTION_VIOLATION | PageFaultError::WRITE) && handle_copy_on_write_fault(virtual_address) {
+		return;
+	}
+
+	// Is this the first access to a page of a demand-zero region?
+	if !pferror.contains(PageFaultError::PROTECTION_VIOLATION) && handle_lazy_fault(virtual_address) {
+		return;
+	}
+
 	// Is a heap associated to the current task?
 	if let Some(ref heap) = core_scheduler().current_task.borrow().heap {
 		let heap_borrowed = heap.borrow();
@@ -527,12 +816,13 @@ pub extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut irq::Exceptio
 		// Is the requested virtual address within the boundary of that heap?
 		if virtual_address >= heap_locked.start && virtual_address < heap_locked.end {
 			// Then allocate physical memory for a 2 MiB page and map it to this virtual address.
-			let physical_address = physicalmem::allocate_aligned(LargePageSize::SIZE, LargePageSize::SIZE);
-			let root_pagetable = unsafe { &mut *PML4_ADDRESS };
+			let frame = physicalmem::allocate_aligned_frames::<LargePageSize>(1);
+			kassert!(frame.is_ok(), "Could not allocate a 2 MiB page for the task heap");
+			let physical_address = frame.unwrap().start_address().into();
 			let page = Page::<LargePageSize>::including_address(virtual_address);
 
 			debug_mem!("Mapping 2 MiB page for task heap ({:#X} => {:#X})", page.address(), physical_address);
-			root_pagetable.map_page(
+			root_map_page(
 				page,
 				physical_address,
 				PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE
@@ -552,9 +842,9 @@ pub extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut irq::Exceptio
 	}
 
 	// Anything else is an error!
-	let pferror = PageFaultError { bits: error_code };
 	error!("Page Fault (#PF) Exception: {:#?}", stack_frame);
 	error!("virtual_address = {:#X}, page fault error = {}", virtual_address, pferror);
+	dump_range(virtual_address, virtual_address.saturating_add(1));
 	scheduler::abort();
 }
 
@@ -569,16 +859,14 @@ pub fn get_page_table_entry<S: PageSize>(virtual_address: usize) -> Option<PageT
 	debug_mem!("Looking up Page Table Entry for {:#X}", virtual_address);
 
 	let page = Page::<S>::including_address(virtual_address);
-	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
-	root_pagetable.get_page_table_entry(page)
+	root_get_page_table_entry(page)
 }
 
 pub fn get_physical_address<S: PageSize>(virtual_address: usize) -> usize {
 	debug_mem!("Getting physical address for {:#X}", virtual_address);
 
 	let page = Page::<S>::including_address(virtual_address);
-	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
-	let address = root_pagetable.get_page_table_entry(page).expect("Entry not present").address();
+	let address = root_get_page_table_entry(page).expect("Entry not present").address();
 	let offset = virtual_address & (S::SIZE - 1);
 	address | offset
 }
@@ -594,8 +882,8 @@ pub fn virtual_to_physical(virtual_address: usize) -> usize {
 		// The kernel image is mapped in 2 MiB pages.
 		get_physical_address::<LargePageSize>(virtual_address)
 	} else if virtual_address < virtualmem::task_heap_start() {
-		// The kernel memory is mapped in 4 KiB pages.
-		get_physical_address::<BasePageSize>(virtual_address)
+		// The kernel memory is mapped in 4 KiB pages, except for large device memory mappings.
+		translate(virtual_address).expect("Entry not present").0
 	} else if virtual_address < virtualmem::task_heap_end() {
 		// The application memory is mapped in 2 MiB pages.
 		get_physical_address::<LargePageSize>(virtual_address)
@@ -610,12 +898,137 @@ pub extern "C" fn virt_to_phys(virtual_address: usize) -> usize {
 	virtual_to_physical(virtual_address)
 }
 
+/// Translates a virtual memory address to a physical one by walking the active page tables.
+/// Unlike virtual_to_physical, this works for any mapped address and page size.
+///
+/// Returns the physical address and the flags of the mapping or None if the address is not mapped.
+pub fn translate(virtual_address: usize) -> Option<(usize, PageTableEntryFlags)> {
+	if !Page::<BasePageSize>::is_valid_address(virtual_address) {
+		return None;
+	}
+
+	let (entry, size) = walk(virtual_address).ok()?;
+	let physical_address = (entry.address() & !(size - 1)) | (virtual_address & (size - 1));
+	Some((physical_address, entry.flags()))
+}
+
+/// Returns the size of the page mapping the given virtual address or None if the address is not mapped.
+pub fn page_size(virtual_address: usize) -> Option<usize> {
+	if !Page::<BasePageSize>::is_valid_address(virtual_address) {
+		return None;
+	}
+
+	walk(virtual_address).ok().map(|(_, size)| size)
+}
+
+/// Prints the mappings of the virtual memory range from `start_address` to `end_address` (exclusive).
+/// Consecutive unmapped pages are printed as a single range.
+pub fn dump_range(start_address: usize, end_address: usize) {
+	infoheader!(" PAGE TABLE MAPPINGS ");
+
+	let mut virtual_address = align_down!(start_address, BasePageSize::SIZE);
+	let mut unmapped_start = None;
+
+	while virtual_address < end_address {
+		if !Page::<BasePageSize>::is_valid_address(virtual_address) {
+			info!("{:#018X} - {:#018X}: non-canonical", virtual_address, end_address);
+			break;
+		}
+
+		let (next_address, mapping) = match walk(virtual_address) {
+			Ok((entry, size)) => (align_down!(virtual_address, size) + size, Some((entry, size))),
+			Err(size) => (align_down!(virtual_address, size) + size, None)
+		};
+
+		match mapping {
+			Some((entry, size)) => {
+				if let Some(start) = unmapped_start.take() {
+					info!("{:#018X} - {:#018X}: not mapped", start, virtual_address);
+				}
+
+				let physical_address = (entry.address() & !(size - 1)) | (virtual_address & (size - 1));
+				let access = MappingAccess { flags: entry.flags(), size };
+				info!("{:#018X} - {:#018X}: {:#018X} ({} KiB page, {}, {:?})", virtual_address, next_address, physical_address, size >> 10, access, entry.flags());
+			},
+			None => {
+				if unmapped_start.is_none() {
+					unmapped_start = Some(virtual_address);
+				}
+			}
+		}
+
+		// Stop on overflow at the end of the address space.
+		if next_address <= virtual_address {
+			virtual_address = end_address;
+			break;
+		}
+
+		virtual_address = next_address;
+	}
+
+	if let Some(start) = unmapped_start {
+		info!("{:#018X} - {:#018X}: not mapped", start, cmp::min(virtual_address, end_address));
+	}
+
+	infofooter!();
+}
+
 pub fn map<S: PageSize>(virtual_address: usize, physical_address: usize, count: usize, flags: PageTableEntryFlags, do_ipi: bool) {
 	debug_mem!("Mapping virtual address {:#X} to physical address {:#X} ({} pages)", virtual_address, physical_address, count);
 
 	let range = get_page_range::<S>(virtual_address, count);
-	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
-	root_pagetable.map_pages(range, physical_address, flags, do_ipi);
+	root_map_pages(range, physical_address, flags, do_ipi);
+}
+
+/// Removes the mapping of `count` pages of size S starting at `virtual_address`.
+/// The referenced physical memory is not freed.
+pub fn unmap<S: PageSize>(virtual_address: usize, count: usize, do_ipi: bool) {
+	debug_mem!("Unmapping virtual address {:#X} ({} pages)", virtual_address, count);
+
+	let mut send_ipi = false;
+
+	for page in get_page_range::<S>(virtual_address, count) {
+		if let Ok((entry, size)) = walk(page.address()) {
+			assert!(size == S::SIZE, "Virtual address {:#X} is not mapped using pages of size {:#X}", page.address(), S::SIZE);
+			entry.physical_address_and_flags = 0;
+			page.flush_from_tlb();
+			send_ipi = true;
+		}
+	}
+
+	if do_ipi && send_ipi {
+		apic::ipi_tlb_flush();
+	}
+}
+
+/// Reserves `count` 4 KiB pages starting at `virtual_address` for demand-zero mapping.
+/// The pages are not mapped until they are first accessed. Then, each of them is backed by a zeroed physical
+/// page and mapped using the given flags.
+pub fn map_lazy(virtual_address: usize, count: usize, flags: PageTableEntryFlags) {
+	debug_mem!("Reserving virtual address {:#X} for demand-zero mapping ({} pages)", virtual_address, count);
+
+	let start = Page::<BasePageSize>::including_address(virtual_address).address();
+	let region = LazyRegion {
+		start: start,
+		end: start + count * BasePageSize::SIZE,
+		flags: flags,
+	};
+
+	let mut regions = LAZY_REGIONS.lock();
+	assert!(!regions.iter().any(|r| r.overlaps(&region)), "Demand-zero region at {:#X} overlaps an existing one", region.start);
+	regions.push(region);
+}
+
+pub fn print_information() {
+	let regions = LAZY_REGIONS.lock();
+	let reserved: usize = regions.iter().map(|r| r.end - r.start).sum();
+	let faults = LAZY_FAULTS.load(Ordering::Relaxed);
+
+	infoheader!(" DEMAND-ZERO MEMORY ");
+	infoentry!("Regions", regions.len());
+	infoentry!("Reserved", "{} KiB", reserved / 1024);
+	infoentry!("Backed", "{} KiB ({} page faults)", faults * BasePageSize::SIZE / 1024, faults);
+	infofooter!();
 }
 
 pub fn identity_map(start_address: usize, end_address: usize) {
@@ -623,9 +1036,8 @@ pub fn identity_map(start_address: usize, end_address: usize) {
 	let last_page = Page::<BasePageSize>::including_address(end_address);
 	assert!(last_page.address() < mm::kernel_start_address(), "Address {:#X} to be identity-mapped is not below Kernel start address", last_page.address());
 
-	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
 	let range = Page::<BasePageSize>::range(first_page, last_page);
-	root_pagetable.map_pages(range, first_page.address(), PageTableEntryFlags::EXECUTE_DISABLE, false);
+	root_map_pages(range, first_page.address(), PageTableEntryFlags::EXECUTE_DISABLE, false);
 }
 
 #[no_mangle]
@@ -634,6 +1046,12 @@ pub extern "C" fn getpagesize() -> i32 {
 }
 
 pub fn init() {
+	// All page table accesses rely on the root table (PML4 or PML5) mapping itself in its last entry.
+	// Verify that this is the case, especially when the loader has set up 5-level paging.
+	let root_table_address = unsafe { control_regs::cr3() } as usize & !(BasePageSize::SIZE - 1);
+	let recursive_entry = unsafe { (*PML4_ADDRESS).entries[(1 << PAGE_MAP_BITS) - 1] };
+	assert!(recursive_entry.address() == root_table_address, "Root page table {:#X} is not mapped recursively ({}-level paging)", root_table_address, if processor::is_la57_enabled() { 5 } else { 4 });
+
 	// Identity-map the supplied Multiboot information and command line.
 	unsafe {
 		if mb_info > 0 {
@@ -643,10 +1061,102 @@ pub fn init() {
 			let mb = Multiboot::new(mb_info);
 			let memory_map_address = mb.memory_map_address().expect("Could not find a memory map in the Multiboot information");
 			identity_map(memory_map_address, memory_map_address);
+
+			// Map the list of modules, so they can be reserved before physical memory is allocated.
+			if let Some(modules) = mb.modules() {
+				if !modules.is_empty() {
+					let modules_address = modules.as_ptr() as usize;
+					identity_map(modules_address, modules_address + modules.len() * mem::size_of::<Module>() - 1);
+				}
+			}
 		}
 
-		if cmdsize > 0 {
-			identity_map(cmdline as usize, cmdline as usize + cmdsize - 1);
+		let (cmdline_address, cmdline_size) = environment::command_line_range();
+		if cmdline_size > 0 {
+			identity_map(cmdline_address, cmdline_address + cmdline_size - 1);
 		}
 	}
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn copy_on_write_round_trip() {
+		let flags = PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE;
+
+		let shared = copy_on_write_flags(flags);
+		assert!(!shared.is_writable());
+		assert!(shared.is_copy_on_write());
+		assert!(shared.contains(PageTableEntryFlags::EXECUTE_DISABLE));
+
+		let copy = writable_copy_flags(shared);
+		assert!(copy.is_writable());
+		assert!(!copy.is_copy_on_write());
+		assert_eq!(copy, flags);
+	}
+
+	#[test]
+	fn flags_round_trip_to_raw_values() {
+		let flags = PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE | PageTableEntryFlags::GLOBAL | PageTableEntryFlags::EXECUTE_DISABLE;
+		assert_eq!(flags.bits(), (1 << 0) | (1 << 1) | (1 << 8) | (1 << 63));
+		assert_eq!(PageTableEntryFlags::from_bits(flags.bits()), Some(flags));
+
+		// Every single bit survives the round trip, and the physical address bits of an entry are dropped.
+		for flag in [PageTableEntryFlags::USER_ACCESSIBLE, PageTableEntryFlags::WRITE_THROUGH, PageTableEntryFlags::CACHE_DISABLE,
+			PageTableEntryFlags::ACCESSED, PageTableEntryFlags::DIRTY, PageTableEntryFlags::PAT, PageTableEntryFlags::COPY_ON_WRITE].iter() {
+			assert_eq!(PageTableEntryFlags::from_bits(flag.bits()), Some(*flag));
+		}
+		assert!(PageTableEntryFlags::from_bits(0x1234_5000 | flags.bits()).is_none());
+		assert_eq!(PageTableEntryFlags::from_bits_truncate(0x1234_5000 | flags.bits()), flags);
+		assert_eq!(PageTableEntryFlags::from_bits(0), Some(PageTableEntryFlags::BLANK));
+	}
+
+	#[test]
+	fn flag_queries_match_the_bits() {
+		let flags = PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE;
+		assert!(flags.is_writable() && !flags.is_executable() && !flags.is_user_accessible() && flags.is_write_back());
+
+		let flags = PageTableEntryFlags::USER_ACCESSIBLE | PageTableEntryFlags::CACHE_DISABLE;
+		assert!(!flags.is_writable() && flags.is_executable() && flags.is_user_accessible() && !flags.is_write_back());
+		assert!(!PageTableEntryFlags::PAT.is_write_back());
+	}
+
+	#[test]
+	fn mapping_access_is_printed() {
+		let flags = PageTableEntryFlags::PRESENT | PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE;
+		assert_eq!(format!("{}", MappingAccess { flags, size: BasePageSize::SIZE }), "rw- kernel");
+
+		let flags = PageTableEntryFlags::PRESENT | PageTableEntryFlags::USER_ACCESSIBLE | PageTableEntryFlags::PAT;
+		assert_eq!(format!("{}", MappingAccess { flags, size: BasePageSize::SIZE }), "r-x user, not Write-Back");
+
+		// In larger pages, the PAT bit is HUGE_PAGE.
+		let flags = PageTableEntryFlags::PRESENT | PageTableEntryFlags::HUGE_PAGE;
+		assert_eq!(format!("{}", MappingAccess { flags, size: LargePageSize::SIZE }), "r-x kernel");
+	}
+
+	#[test]
+	fn lazy_regions_are_half_open() {
+		let region = LazyRegion { start: 0x10000, end: 0x12000, flags: PageTableEntryFlags::WRITABLE };
+		assert!(!region.contains(0xFFFF));
+		assert!(region.contains(0x10000));
+		assert!(region.contains(0x11FFF));
+		assert!(!region.contains(0x12000));
+
+		let before = LazyRegion { start: 0xF000, end: 0x10000, flags: PageTableEntryFlags::WRITABLE };
+		let inside = LazyRegion { start: 0x11000, end: 0x11000 + BasePageSize::SIZE, flags: PageTableEntryFlags::WRITABLE };
+		let after = LazyRegion { start: 0x12000, end: 0x13000, flags: PageTableEntryFlags::WRITABLE };
+		assert!(!region.overlaps(&before) && !before.overlaps(&region));
+		assert!(region.overlaps(&inside) && inside.overlaps(&region));
+		assert!(!region.overlaps(&after) && !after.overlaps(&region));
+	}
+
+	#[test]
+	fn read_only_flags_become_writable_copies() {
+		let shared = copy_on_write_flags(PageTableEntryFlags::BLANK);
+		assert_eq!(shared, PageTableEntryFlags::COPY_ON_WRITE);
+		assert_eq!(writable_copy_flags(shared), PageTableEntryFlags::WRITABLE);
+	}
+}
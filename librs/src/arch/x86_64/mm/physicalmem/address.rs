@@ -0,0 +1,199 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Types for physical memory addresses, which cannot be mixed up with virtual addresses.
+
+use arch::x86_64::mm::paging::{BasePageSize, PageSize};
+use core::fmt;
+use core::marker::PhantomData;
+use core::ops::{Add, Sub};
+
+
+/// A physical memory address.
+#[derive(Clone, Copy, Eq, Ord, PartialEq, PartialOrd)]
+pub struct PhysAddr(usize);
+
+impl PhysAddr {
+	pub const fn from(x: usize) -> Self {
+		PhysAddr(x)
+	}
+
+	pub const fn into(self) -> usize {
+		self.0
+	}
+
+	/// Whether the address is a multiple of `alignment`, which must be a power of two.
+	pub fn is_aligned(self, alignment: usize) -> bool {
+		self.0 & (alignment - 1) == 0
+	}
+
+	pub fn align_down(self, alignment: usize) -> Self {
+		PhysAddr(align_down!(self.0, alignment))
+	}
+
+	pub fn align_up(self, alignment: usize) -> Self {
+		PhysAddr(align_up!(self.0, alignment))
+	}
+}
+
+impl Add<usize> for PhysAddr {
+	type Output = PhysAddr;
+
+	fn add(self, offset: usize) -> PhysAddr {
+		PhysAddr(self.0 + offset)
+	}
+}
+
+impl Sub<usize> for PhysAddr {
+	type Output = PhysAddr;
+
+	fn sub(self, offset: usize) -> PhysAddr {
+		PhysAddr(self.0 - offset)
+	}
+}
+
+/// The distance in bytes between two physical addresses.
+impl Sub<PhysAddr> for PhysAddr {
+	type Output = usize;
+
+	fn sub(self, other: PhysAddr) -> usize {
+		self.0 - other.0
+	}
+}
+
+impl fmt::Debug for PhysAddr {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "PhysAddr({:#X})", self.0)
+	}
+}
+
+impl fmt::Display for PhysAddr {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{:#X}", self.0)
+	}
+}
+
+
+/// A frame of physical memory of the size given by S.
+/// Its start address is always aligned to the frame size, which is checked when creating it.
+#[derive(Clone, Copy)]
+pub struct PhysFrame<S: PageSize = BasePageSize> {
+	start_address: PhysAddr,
+
+	/// Required by Rust to support the S parameter.
+	size: PhantomData<S>,
+}
+
+impl<S: PageSize> PhysFrame<S> {
+	/// Returns the frame starting at `address` or an error if `address` is not aligned to the frame size.
+	pub fn from_start_address(address: PhysAddr) -> Result<Self, ()> {
+		if address.is_aligned(S::SIZE) {
+			Ok(Self { start_address: address, size: PhantomData })
+		} else {
+			Err(())
+		}
+	}
+
+	pub fn start_address(&self) -> PhysAddr {
+		self.start_address
+	}
+
+	pub fn size(&self) -> usize {
+		S::SIZE
+	}
+}
+
+impl<S: PageSize> PartialEq for PhysFrame<S> {
+	fn eq(&self, other: &PhysFrame<S>) -> bool {
+		self.start_address == other.start_address
+	}
+}
+
+impl<S: PageSize> Eq for PhysFrame<S> {}
+
+/// The frame `count` frames after this one.
+impl<S: PageSize> Add<usize> for PhysFrame<S> {
+	type Output = PhysFrame<S>;
+
+	fn add(self, count: usize) -> PhysFrame<S> {
+		PhysFrame { start_address: self.start_address + count * S::SIZE, size: PhantomData }
+	}
+}
+
+impl<S: PageSize> fmt::Debug for PhysFrame<S> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "PhysFrame({:#X}, {:#X} bytes)", self.start_address.into(), S::SIZE)
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use arch::x86_64::mm::paging::LargePageSize;
+
+	#[test]
+	fn addresses_are_converted_and_aligned() {
+		let address = PhysAddr::from(0x12_3456);
+		assert_eq!(address.into(), 0x12_3456);
+		assert!(!address.is_aligned(BasePageSize::SIZE));
+		assert!(PhysAddr::from(0x12_3000).is_aligned(BasePageSize::SIZE));
+
+		assert_eq!(address.align_down(BasePageSize::SIZE), PhysAddr::from(0x12_3000));
+		assert_eq!(address.align_up(BasePageSize::SIZE), PhysAddr::from(0x12_4000));
+		assert_eq!(address.align_up(LargePageSize::SIZE), PhysAddr::from(0x20_0000));
+
+		// Aligning an aligned address keeps it.
+		assert_eq!(PhysAddr::from(0x20_0000).align_up(LargePageSize::SIZE), PhysAddr::from(0x20_0000));
+	}
+
+	#[test]
+	fn address_arithmetic_yields_addresses_and_distances() {
+		let start = PhysAddr::from(0x1000);
+		let end = start + 0x2345;
+		assert_eq!(end, PhysAddr::from(0x3345));
+		assert_eq!(end - 0x345, PhysAddr::from(0x3000));
+		assert_eq!(end - start, 0x2345);
+		assert!(start < end);
+
+		assert_eq!(format!("{}", end), "0x3345");
+		assert_eq!(format!("{:?}", end), "PhysAddr(0x3345)");
+	}
+
+	#[test]
+	fn frames_keep_their_alignment() {
+		let frame = PhysFrame::<BasePageSize>::from_start_address(PhysAddr::from(0x5000)).unwrap();
+		assert_eq!(frame.start_address(), PhysAddr::from(0x5000));
+		assert_eq!(frame.size(), BasePageSize::SIZE);
+		assert!(PhysFrame::<BasePageSize>::from_start_address(PhysAddr::from(0x5800)).is_err());
+
+		// Frame arithmetic moves by whole frames.
+		assert_eq!(frame + 3, PhysFrame::from_start_address(PhysAddr::from(0x8000)).unwrap());
+
+		// An address aligned to 4 KiB is not necessarily aligned to 2 MiB.
+		assert!(PhysFrame::<LargePageSize>::from_start_address(PhysAddr::from(0x5000)).is_err());
+		let large_frame = PhysFrame::<LargePageSize>::from_start_address(PhysAddr::from(0x40_0000)).unwrap();
+		assert_eq!((large_frame + 1).start_address(), PhysAddr::from(0x60_0000));
+		assert_eq!(format!("{:?}", large_frame), "PhysFrame(0x400000, 0x200000 bytes)");
+	}
+}
@@ -0,0 +1,167 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Reference counts for physical frames.
+//!
+//! The table holds one counter per 4 KiB frame of the physical memory managed by physicalmem.
+//! A count of zero means that the frame is not tracked. When the last reference to a tracked frame
+//! is dropped through decref, the frame is returned to physicalmem.
+
+use arch::x86_64::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
+use arch::x86_64::mm::virtualmem;
+use core::{ptr, slice};
+use core::sync::atomic::{AtomicU32, Ordering};
+use mm;
+use mm::POOL;
+
+
+/// Pointer to the first counter of the table.
+static mut TABLE: *const AtomicU32 = ptr::null();
+
+/// Physical address of the frame referenced by the first counter.
+static mut FIRST_FRAME: usize = 0;
+
+/// Number of counters in the table.
+static mut FRAME_COUNT: usize = 0;
+
+/// Maximum number of ranges that can be added to physicalmem after init, see add_range.
+const MAX_EXTRA_RANGES: usize = 8;
+
+/// Counters for a range of frames added through add_range.
+#[derive(Clone, Copy)]
+struct ExtraRange {
+	table: *const AtomicU32,
+	first_frame: usize,
+	frame_count: usize,
+}
+
+static mut EXTRA_RANGES: [ExtraRange; MAX_EXTRA_RANGES] = [ExtraRange { table: ptr::null(), first_frame: 0, frame_count: 0 }; MAX_EXTRA_RANGES];
+static mut EXTRA_RANGE_COUNT: usize = 0;
+
+
+/// Returns whether the frame at the given physical address is covered by the counters starting at `first_frame`.
+fn covers(first_frame: usize, frame_count: usize, physical_address: usize) -> bool {
+	physical_address >= first_frame && (physical_address - first_frame) / BasePageSize::SIZE < frame_count
+}
+
+/// Returns the counter for the frame at the given physical address.
+fn counter(physical_address: usize) -> &'static AtomicU32 {
+	unsafe {
+		assert!(!TABLE.is_null(), "Frame reference table has not been initialized");
+
+		let (table, first_frame, frame_count) = if covers(FIRST_FRAME, FRAME_COUNT, physical_address) {
+			(TABLE, FIRST_FRAME, FRAME_COUNT)
+		} else {
+			let range = EXTRA_RANGES[..EXTRA_RANGE_COUNT].iter().find(|range| covers(range.first_frame, range.frame_count, physical_address));
+			let range = range.expect("Physical address is not part of the managed memory");
+			(range.table, range.first_frame, range.frame_count)
+		};
+
+		let table = slice::from_raw_parts(table, frame_count);
+		&table[(physical_address - first_frame) / BasePageSize::SIZE]
+	}
+}
+
+/// Allocates zeroed counters for `frame_count` frames.
+/// Only few frames are ever tracked, so the table is mapped demand-zero and only the pages holding
+/// counters that are actually accessed consume physical memory.
+fn allocate_table(frame_count: usize) -> (*const AtomicU32, usize) {
+	let size = align_up!(frame_count * 4, BasePageSize::SIZE);
+	let table = {
+		let _lock = mm::MM_LOCK.lock();
+		virtualmem::allocate(size)
+	};
+	mm::map_lazy(table, size, PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE);
+
+	(table as *const AtomicU32, size)
+}
+
+/// Increments the reference count of the frame at the given physical address and returns the new count.
+pub fn incref(physical_address: usize) -> u32 {
+	counter(physical_address).fetch_add(1, Ordering::SeqCst) + 1
+}
+
+/// Decrements the reference count of the frame at the given physical address and returns the new count.
+/// The frame is freed if the count drops to zero.
+pub fn decref(physical_address: usize) -> u32 {
+	let previous = counter(physical_address).fetch_sub(1, Ordering::SeqCst);
+	assert!(previous > 0, "Reference count of frame {:#X} is already zero", physical_address);
+
+	if previous == 1 {
+		let _lock = mm::MM_LOCK.lock();
+
+		unsafe {
+			POOL.maintain();
+			super::deallocate(align_down!(physical_address, BasePageSize::SIZE), BasePageSize::SIZE);
+		}
+	}
+
+	previous - 1
+}
+
+/// Returns the reference count of the frame at the given physical address.
+pub fn refcount(physical_address: usize) -> u32 {
+	counter(physical_address).load(Ordering::SeqCst)
+}
+
+/// Allocates the table for all frames between `start` and `end`.
+/// Must be called once the kernel heap is available.
+pub fn init(start: usize, end: usize) {
+	let first_frame = align_down!(start, BasePageSize::SIZE);
+	let frame_count = (align_up!(end, BasePageSize::SIZE) - first_frame) / BasePageSize::SIZE;
+	let (table, size) = allocate_table(frame_count);
+
+	unsafe {
+		FIRST_FRAME = first_frame;
+		FRAME_COUNT = frame_count;
+		TABLE = table;
+	}
+
+	info!("Frame reference table covers {:#X} - {:#X} ({} KiB)", first_frame, first_frame + frame_count * BasePageSize::SIZE, size >> 10);
+}
+
+/// Adds counters for the frames between `start` and `end`, which physicalmem is about to manage in addition
+/// to the range passed to init.
+/// Must be called before any frame of the range can be allocated.
+/// Nothing needs to be done before init, because init covers everything physicalmem manages at that time.
+pub fn add_range(start: usize, end: usize) -> Result<(), ()> {
+	let first_frame = align_down!(start, BasePageSize::SIZE);
+	let frame_count = (align_up!(end, BasePageSize::SIZE) - first_frame) / BasePageSize::SIZE;
+
+	unsafe {
+		if TABLE.is_null() || (covers(FIRST_FRAME, FRAME_COUNT, first_frame) && covers(FIRST_FRAME, FRAME_COUNT, end - 1)) {
+			return Ok(());
+		}
+		if EXTRA_RANGE_COUNT == MAX_EXTRA_RANGES {
+			warn!("Frame reference table cannot cover more than {} additional ranges", MAX_EXTRA_RANGES);
+			return Err(());
+		}
+
+		let (table, _) = allocate_table(frame_count);
+		EXTRA_RANGES[EXTRA_RANGE_COUNT] = ExtraRange { table: table, first_frame: first_frame, frame_count: frame_count };
+		EXTRA_RANGE_COUNT += 1;
+	}
+
+	debug!("Frame reference table now also covers {:#X} - {:#X}", first_frame, first_frame + frame_count * BasePageSize::SIZE);
+	Ok(())
+}
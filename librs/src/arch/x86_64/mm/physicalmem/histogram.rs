@@ -0,0 +1,60 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Optional histogram of the requested physical memory allocation sizes, enabled by the "alloc_histogram" feature.
+//!
+//! Each request is counted in the power-of-two size class it falls into, which shows whether a dedicated cache
+//! for a particular size would pay off.
+
+use core::cmp;
+use synch::spinlock::SpinlockIrqSave;
+
+
+/// Number of size classes, one for each possible power of two of a 64-bit size.
+pub const SIZE_CLASSES: usize = 64;
+
+/// Number of requests per size class. Class `i` counts the sizes from 2^(i-1) + 1 up to 2^i bytes.
+/// The last class also counts all sizes above 2^63 bytes.
+static SIZE_HISTOGRAM: SpinlockIrqSave<[usize; SIZE_CLASSES]> = SpinlockIrqSave::new([0; SIZE_CLASSES]);
+
+
+/// Returns the size class of an allocation of `size` bytes.
+fn size_class(size: usize) -> usize {
+	if size <= 1 {
+		0
+	} else {
+		cmp::min(SIZE_CLASSES - (size - 1).leading_zeros() as usize, SIZE_CLASSES - 1)
+	}
+}
+
+pub fn record(size: usize) {
+	SIZE_HISTOGRAM.lock()[size_class(size)] += 1;
+}
+
+pub fn get() -> [usize; SIZE_CLASSES] {
+	*SIZE_HISTOGRAM.lock()
+}
+
+pub fn reset() {
+	*SIZE_HISTOGRAM.lock() = [0; SIZE_CLASSES];
+}
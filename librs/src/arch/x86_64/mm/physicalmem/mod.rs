@@ -0,0 +1,1242 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+mod address;
+pub mod frame_ref;
+#[cfg(feature = "alloc_histogram")]
+mod histogram;
+
+pub use self::address::{PhysAddr, PhysFrame};
+#[cfg(feature = "alloc_histogram")]
+pub use self::histogram::SIZE_CLASSES;
+
+use arch::x86_64::processor;
+use arch::x86_64::mm::paging::{BasePageSize, PageSize};
+use collections::{ArrayVec, Node};
+use core::{cmp, fmt, mem};
+use environment;
+use hermit_multiboot::{Module, Multiboot};
+use mm;
+use mm::freelist::{FreeList, FreeListEntry, FreeListStatistics};
+use mm::POOL;
+use synch::spinlock::Spinlock;
+
+
+extern "C" {
+	static mb_info: usize;
+}
+
+static mut PHYSICAL_FREE_LIST: PhysicalFreeList = PhysicalFreeList::new();
+
+/// Start and end address of the physical memory managed by PHYSICAL_FREE_LIST after initialization.
+/// Can be easily accessed through managed_range()
+static mut MANAGED_START: PhysAddr = PhysAddr::from(0);
+static mut MANAGED_END: PhysAddr = PhysAddr::from(0);
+
+/// Number of spare nodes of PHYSICAL_FREE_LIST for the allocations of early boot.
+/// When they are used up, further nodes are taken from the Bootstrap Allocator, whose small heap
+/// could not hold a node for each of the MAX_RAM_REGIONS regions anyway.
+const EARLY_ARENA_NODES: usize = 8;
+
+/// Memory set aside by init_kernel_reserve, which only kernel heap allocations may use once PHYSICAL_FREE_LIST is exhausted.
+static mut KERNEL_RESERVE: PhysicalFreeList = PhysicalFreeList::new();
+static mut KERNEL_RESERVE_START: PhysAddr = PhysAddr::from(0);
+static mut KERNEL_RESERVE_END: PhysAddr = PhysAddr::from(0);
+static mut KERNEL_RESERVE_USED: usize = 0;
+
+/// Total number of bytes managed by PHYSICAL_FREE_LIST, free or allocated.
+/// No allocation can ever exceed this, which allows rejecting oversized requests without walking the list.
+static mut TOTAL_MEMORY: usize = 0;
+
+/// Allocated memory taken out of the managed memory through reserve_region, which only add_region gives back.
+static mut RESERVED_REGIONS: PhysicalFreeList = PhysicalFreeList::new();
+static mut RESERVED_MEMORY: usize = 0;
+
+/// Serializes adding new memory in add_region, which cannot hold MM_LOCK while the frame reference counters
+/// for the new memory are allocated.
+static ADD_REGION_LOCK: Spinlock<()> = Spinlock::new(());
+
+
+/// A Free List of physical memory.
+/// The entries of mm::freelist::FreeList are plain addresses, because it shares mm::POOL with the virtual memory
+/// management. This wrapper converts them at the boundary, so the rest of this module only deals with PhysAddr and
+/// PhysFrame. As all entries are page-aligned, each allocation starts a frame.
+struct PhysicalFreeList {
+	free_list: FreeList,
+}
+
+impl PhysicalFreeList {
+	const fn new() -> Self {
+		Self { free_list: FreeList::new() }
+	}
+
+	/// Adds the whole pages within `start` to `end` as free memory.
+	/// Only used during initialization, where Node::new still takes the node from the Bootstrap Allocator.
+	fn add_range(&mut self, start: PhysAddr, end: PhysAddr) {
+		let start = start.align_up(BasePageSize::SIZE);
+		let end = end.align_down(BasePageSize::SIZE);
+		if start >= end {
+			return;
+		}
+
+		let entry = Node::new(
+			FreeListEntry {
+				start: start.into(),
+				end: end.into()
+			}
+		);
+		self.free_list.list.push(entry);
+	}
+
+	fn frame(address: usize) -> PhysFrame {
+		PhysFrame::from_start_address(PhysAddr::from(address)).unwrap()
+	}
+
+	fn allocate(&mut self, size: usize) -> Result<PhysFrame, ()> {
+		self.free_list.allocate(size).map(Self::frame)
+	}
+
+	fn allocate_high(&mut self, size: usize) -> Result<PhysFrame, ()> {
+		self.free_list.allocate_high(size).map(Self::frame)
+	}
+
+	fn allocate_below(&mut self, size: usize, limit: PhysAddr) -> Result<PhysFrame, AllocError> {
+		self.free_list.allocate_below(size, limit.align_down(BasePageSize::SIZE).into())
+			.map(Self::frame)
+			.map_err(|_| AllocError::OutOfMemory)
+	}
+
+	fn allocate_aligned(&mut self, size: usize, alignment: usize) -> Result<PhysFrame, AllocError> {
+		self.free_list.allocate_aligned(size, alignment)
+			.map(Self::frame)
+			.map_err(|_| AllocError::OutOfMemory)
+	}
+
+	/// Removes the given range, which must be entirely free, from this list.
+	fn reserve(&mut self, start_address: PhysAddr, size: usize) -> Result<(), ()> {
+		self.free_list.reserve(start_address.into(), size)
+	}
+
+	fn deallocate(&mut self, start_address: PhysAddr, size: usize) {
+		self.free_list.deallocate(start_address.into(), size);
+	}
+}
+
+/// Everything from here up to 1 MiB may be used by the Extended BIOS Data Area, video memory, and ROMs.
+const LOW_MEMORY_LIMIT: usize = 0x80000;
+
+/// Maximum number of low memory ranges handed out by reserve_low_memory.
+const MAX_LOW_MEMORY_RANGES: usize = 4;
+
+/// A range of low memory reserved through reserve_low_memory.
+/// Once reclaimed, it belongs to PHYSICAL_FREE_LIST.
+#[derive(Clone, Copy)]
+struct LowMemoryRange {
+	start: usize,
+	end: usize,
+	reclaimed: bool,
+}
+
+static mut LOW_MEMORY_RANGES: [LowMemoryRange; MAX_LOW_MEMORY_RANGES] = [LowMemoryRange { start: 0, end: 0, reclaimed: false }; MAX_LOW_MEMORY_RANGES];
+static mut LOW_MEMORY_RANGE_COUNT: usize = 0;
+
+
+/// Reasons why a physical memory allocation can fail.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AllocError {
+	/// A size of zero bytes was requested.
+	ZeroSize,
+	/// The requested alignment is not a power of two.
+	AlignmentNotPowerOfTwo,
+	/// The requested alignment is smaller than a page.
+	AlignmentTooSmall,
+	/// The requested size is not a multiple of the requested alignment.
+	SizeNotMultiple,
+	/// The requested size exceeds the total managed RAM.
+	ExceedsTotalMemory,
+	/// No free memory region can satisfy the request.
+	OutOfMemory,
+}
+
+/// Types of physical memory regions, numbered like in the Multiboot memory map.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u32)]
+pub enum MemoryType {
+	Available = 1,
+	Reserved = 2,
+	AcpiReclaimable = 3,
+	AcpiNvs = 4,
+	Defective = 5,
+}
+
+impl MemoryType {
+	fn from_multiboot(memory_type: u32) -> Self {
+		match memory_type {
+			1 => MemoryType::Available,
+			3 => MemoryType::AcpiReclaimable,
+			4 => MemoryType::AcpiNvs,
+			5 => MemoryType::Defective,
+			_ => MemoryType::Reserved,
+		}
+	}
+}
+
+/// A physical memory region as provided by the boot loader or hypervisor.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct MemoryRegion {
+	pub base: u64,
+	pub length: u64,
+	pub memory_type: MemoryType,
+}
+
+/// Machine-readable copy of the memory map for tools on the host.
+/// Exported under the well-known symbol name `memory_map_export`, so a hypervisor like uhyve can look it up in
+/// the symbol table of the kernel image and compare it to the memory it has provided.
+#[repr(C)]
+pub struct MemoryMapExport {
+	pub count: u32,
+	pub regions: [MemoryRegion; MAX_RAM_REGIONS],
+}
+
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static mut memory_map_export: MemoryMapExport = MemoryMapExport {
+	count: 0,
+	regions: [MemoryRegion { base: 0, length: 0, memory_type: MemoryType::Reserved }; MAX_RAM_REGIONS],
+};
+
+impl fmt::Display for AllocError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let description = match *self {
+			AllocError::ZeroSize => "size is zero",
+			AllocError::AlignmentNotPowerOfTwo => "alignment is not a power of two",
+			AllocError::AlignmentTooSmall => "alignment is smaller than a page",
+			AllocError::SizeNotMultiple => "size is not a multiple of the alignment",
+			AllocError::ExceedsTotalMemory => "size exceeds the total RAM",
+			AllocError::OutOfMemory => "out of memory",
+		};
+
+		write!(f, "{}", description)
+	}
+}
+
+
+/// Maximum number of available RAM regions taken from the Multiboot memory map.
+const MAX_RAM_REGIONS: usize = 128;
+
+/// Subtract the range from `start` to `end` from all regions in `regions[..count]`.
+/// Returns the new number of regions.
+fn clamp_regions(regions: &mut [(usize, usize); MAX_RAM_REGIONS], mut count: usize, start: usize, end: usize) -> usize {
+	let mut i = 0;
+
+	while i < count {
+		let (region_start, region_end) = regions[i];
+
+		if end <= region_start || start >= region_end {
+			// No overlap.
+			i += 1;
+			continue;
+		}
+
+		warn!("Available memory {:#X} - {:#X} overlaps reserved memory {:#X} - {:#X}, clamping it", region_start, region_end, start, end);
+
+		if start <= region_start && end >= region_end {
+			// The reserved range covers the entire region, so remove it.
+			for j in i..count - 1 {
+				regions[j] = regions[j + 1];
+			}
+			count -= 1;
+			continue;
+		} else if start <= region_start {
+			regions[i].0 = end;
+		} else if end >= region_end {
+			regions[i].1 = start;
+		} else {
+			// The reserved range lies in the middle of the region, so split the region.
+			if count == MAX_RAM_REGIONS {
+				warn!("Too many memory regions, dropping {:#X} - {:#X}", end, region_end);
+				regions[i].1 = start;
+			} else {
+				for j in (i + 1..count + 1).rev() {
+					regions[j] = regions[j - 1];
+				}
+				regions[i].1 = start;
+				regions[i + 1].0 = end;
+				count += 1;
+				i += 1;
+			}
+		}
+
+		i += 1;
+	}
+
+	count
+}
+
+/// Collect the available RAM regions of `memory_map` below `physical_limit` into `regions`, sorted by address.
+/// Firmware memory maps are not always sane, so zero-length entries are dropped, overlapping available
+/// entries are merged, and available memory that overlaps a reserved entry is clamped.
+/// Returns the number of valid regions.
+fn sanitize_memory_map(memory_map: &[MemoryRegion], physical_limit: usize, regions: &mut [(usize, usize); MAX_RAM_REGIONS]) -> usize {
+	let mut count = 0;
+
+	for m in memory_map {
+		let (base, length) = (m.base as usize, m.length as usize);
+
+		if length == 0 {
+			warn!("Ignoring zero-length memory map entry at {:#X}", base);
+		} else if m.memory_type == MemoryType::Available {
+			if count == MAX_RAM_REGIONS {
+				warn!("Ignoring memory map entry {:#X} - {:#X}, because there are too many", base, base + length);
+			} else {
+				regions[count] = (base, base + length);
+				count += 1;
+			}
+		}
+	}
+
+	regions[..count].sort_unstable_by_key(|region| region.0);
+
+	// Merge overlapping available regions.
+	let mut merged = 0;
+	for i in 0..count {
+		let (start, end) = regions[i];
+
+		if merged > 0 && start < regions[merged - 1].1 {
+			warn!("Memory map entry {:#X} - {:#X} overlaps {:#X} - {:#X}, merging them", start, end, regions[merged - 1].0, regions[merged - 1].1);
+			if end > regions[merged - 1].1 {
+				regions[merged - 1].1 = end;
+			}
+		} else {
+			regions[merged] = (start, end);
+			merged += 1;
+		}
+	}
+
+	// RAM beyond the physical address width of the CPU is unusable.
+	let mut i = 0;
+	while i < merged {
+		let (start, end) = regions[i];
+
+		if start >= physical_limit {
+			warn!("Ignoring memory {:#X} - {:#X} beyond the physical address width", start, end);
+			for j in i..merged - 1 {
+				regions[j] = regions[j + 1];
+			}
+			merged -= 1;
+			continue;
+		} else if end > physical_limit {
+			warn!("Clamping memory {:#X} - {:#X} to the physical address width", start, end);
+			regions[i].1 = physical_limit;
+		}
+
+		i += 1;
+	}
+
+	// Reserved entries take precedence over available ones.
+	for m in memory_map.iter().filter(|m| m.memory_type != MemoryType::Available && m.length > 0) {
+		merged = clamp_regions(regions, merged, m.base as usize, (m.base + m.length) as usize);
+	}
+
+	merged
+}
+
+/// Collect the usable RAM regions of the Multiboot memory map into `regions` like sanitize_memory_map, but also
+/// leave out the modules and the Multiboot information.
+/// The memory map is taken from memory_map_export, so export_multiboot_memory_map must have been called before.
+/// Returns the number of valid regions.
+fn validate_memory_map(mb: &Multiboot, regions: &mut [(usize, usize); MAX_RAM_REGIONS]) -> usize {
+	let memory_map = unsafe { &memory_map_export.regions[..memory_map_export.count as usize] };
+	let mut merged = sanitize_memory_map(memory_map, 1usize << processor::phys_address_bits(), regions);
+
+	// Modules loaded by the boot loader must not be overwritten.
+	if let Some(modules) = unsafe { mb.modules() } {
+		for module in modules.iter().filter(|module| module.end_address() > module.start_address()) {
+			debug!("Reserving Multiboot module at {:#X} - {:#X}", module.start_address(), module.end_address());
+			merged = clamp_regions(regions, merged, align_down!(module.start_address(), BasePageSize::SIZE), align_up!(module.end_address(), BasePageSize::SIZE));
+		}
+	}
+
+	// Neither must the Multiboot information and the data it points to, as it is still parsed later on.
+	// The command line is even referenced for the entire runtime by environment::get_arg.
+	exclude_boot_data(regions, merged, multiboot_data_ranges(mb))
+}
+
+/// Maximum number of physical memory ranges of Multiboot information collected by multiboot_data_ranges.
+const MAX_BOOT_DATA_RANGES: usize = 16;
+
+/// Start and end addresses of the Multiboot information, see multiboot_data_ranges.
+type BootDataRanges = ArrayVec<[(usize, usize); MAX_BOOT_DATA_RANGES]>;
+
+/// Returns the length of the NUL-terminated string at `address`, including the NUL character.
+fn c_string_size(address: usize) -> usize {
+	let mut size = 0;
+	while unsafe { *((address + size) as *const u8) } != 0 {
+		size += 1;
+	}
+
+	size + 1
+}
+
+/// Adds the `size` bytes at `start` to the ranges of Multiboot information.
+/// If there are already MAX_BOOT_DATA_RANGES, the new range is merged with the one it enlarges the least. The ranges
+/// are only kept free, so a merged range may cover some memory in between, but never misses any data.
+fn add_boot_data_range(ranges: &mut BootDataRanges, start: usize, size: usize) {
+	if size == 0 {
+		return;
+	}
+
+	let end = start + size;
+	if ranges.push((start, end)).is_ok() {
+		return;
+	}
+
+	let merged_size = |&(range_start, range_end): &(usize, usize)| cmp::max(range_end, end) - cmp::min(range_start, start);
+	let index = (0..ranges.len()).min_by_key(|&i| merged_size(&ranges[i]) - (ranges[i].1 - ranges[i].0)).unwrap();
+	debug!("Too many Multiboot information ranges, merging {:#X} - {:#X} with {:#X} - {:#X}", start, end, ranges[index].0, ranges[index].1);
+	ranges[index] = (cmp::min(ranges[index].0, start), cmp::max(ranges[index].1, end));
+}
+
+/// Collects the physical memory ranges of the Multiboot information structure, the memory map, the module list,
+/// the command line and the module strings. Multiboot 1 has no tags, so these are all areas there are.
+fn multiboot_data_ranges(mb: &Multiboot) -> BootDataRanges {
+	let mut ranges = ArrayVec::new();
+
+	let info_address = unsafe { mb_info };
+	add_boot_data_range(&mut ranges, info_address, mb.info_size());
+
+	if let Some(address) = mb.memory_map_address() {
+		add_boot_data_range(&mut ranges, address, mb.memory_map_length());
+	}
+
+	if let Some(address) = mb.command_line_address() {
+		add_boot_data_range(&mut ranges, address, c_string_size(address));
+	}
+
+	if let (Some(address), Some(modules)) = (mb.modules_address(), unsafe { mb.modules() }) {
+		add_boot_data_range(&mut ranges, address, modules.len() * mem::size_of::<Module>());
+
+		for module in modules.iter() {
+			if let Some(string_address) = module.string_address() {
+				add_boot_data_range(&mut ranges, string_address, c_string_size(string_address));
+			}
+		}
+	}
+
+	ranges
+}
+
+/// Leaves the pages holding the Multiboot information `ranges` out of `regions[..count]`.
+/// Returns the new number of regions.
+fn exclude_boot_data(regions: &mut [(usize, usize); MAX_RAM_REGIONS], mut count: usize, mut ranges: BootDataRanges) -> usize {
+	while let Some((start, end)) = ranges.pop() {
+		debug!("Reserving Multiboot information at {:#X} - {:#X}", start, end);
+		count = clamp_regions(regions, count, align_down!(start, BasePageSize::SIZE), align_up!(end, BasePageSize::SIZE));
+	}
+
+	count
+}
+
+/// Adds a region to memory_map_export.
+/// The region is dropped if the export is full.
+fn export_region(base: usize, length: usize, memory_type: MemoryType) {
+	unsafe {
+		let count = memory_map_export.count as usize;
+		if count == MAX_RAM_REGIONS {
+			return;
+		}
+
+		memory_map_export.regions[count] = MemoryRegion { base: base as u64, length: length as u64, memory_type: memory_type };
+		memory_map_export.count += 1;
+	}
+}
+
+/// Copies all entries of the Multiboot memory map into memory_map_export.
+fn export_multiboot_memory_map(mb: &Multiboot) {
+	for m in mb.memory_map().expect("Could not find a memory map in the Multiboot information") {
+		export_region(m.base_address(), m.length(), MemoryType::from_multiboot(m.memory_type()));
+	}
+}
+
+fn detect_from_multiboot_info() -> Result<(), ()> {
+	if unsafe { mb_info } == 0 {
+		return Err(());
+	}
+
+	let mb = unsafe { Multiboot::new(mb_info) };
+	export_multiboot_memory_map(&mb);
+
+	let mut regions = [(0, 0); MAX_RAM_REGIONS];
+	let count = validate_memory_map(&mb, &mut regions);
+	let ram_regions = regions[..count].iter().filter(|&&(_, end)| end > mm::kernel_end_address());
+	let mut found_ram = false;
+
+	for &(base, end) in ram_regions {
+		found_ram = true;
+
+		// Only memory behind the kernel image is managed, so a region overlapping the image in any way
+		// (not just one starting below it) is clamped to its end.
+		let start_address = cmp::max(base, mm::kernel_end_address());
+		debug!("Managing physical memory {:#X} - {:#X}", start_address, end);
+		unsafe { PHYSICAL_FREE_LIST.add_range(PhysAddr::from(start_address), PhysAddr::from(end)); }
+	}
+
+	if !found_ram {
+		report_missing_ram(&mb, &regions[..count]);
+	}
+
+	Ok(())
+}
+
+/// Prints everything needed to understand why the Multiboot memory map has no usable RAM and panics.
+fn report_missing_ram(mb: &Multiboot, regions: &[(usize, usize)]) -> ! {
+	error!("No usable RAM found. Only available memory behind the kernel image is used for the heap.");
+	error!("Kernel image: {:#X} - {:#X}", mm::kernel_start_address(), mm::kernel_end_address());
+
+	error!("Multiboot memory map:");
+	for m in mb.memory_map().unwrap() {
+		error!("  {:#016X} - {:#016X} ({:?})", m.base_address(), m.base_address() + m.length(), MemoryType::from_multiboot(m.memory_type()));
+	}
+
+	error!("Available memory after validation:");
+	if regions.is_empty() {
+		error!("  none");
+	}
+	for &(start, end) in regions {
+		error!("  {:#016X} - {:#016X}", start, end);
+	}
+
+	if regions.iter().any(|&(start, _)| start < mm::kernel_end_address()) {
+		error!("All available memory lies below the end of the kernel image. Was the kernel loaded above all RAM?");
+	}
+
+	panic!("Could not find any available RAM in the Multiboot Memory Map");
+}
+
+fn detect_from_limits() -> Result<(), ()> {
+	let limit = environment::get_memory_limit();
+	if limit == 0 {
+		return Err(());
+	}
+
+	unsafe { PHYSICAL_FREE_LIST.add_range(PhysAddr::from(mm::kernel_end_address()), PhysAddr::from(limit)); }
+
+	// Without a memory map, all we know is the RAM from zero up to the limit.
+	export_region(0, limit, MemoryType::Available);
+
+	Ok(())
+}
+
+pub fn init() {
+	// Allocations before the System Allocator is up take their nodes from an arena, see use_node_pool.
+	unsafe { PHYSICAL_FREE_LIST.free_list.use_arena(EARLY_ARENA_NODES); }
+
+	detect_from_multiboot_info()
+		.or_else(|_e| detect_from_limits())
+		.unwrap();
+
+	unsafe {
+		MANAGED_START = PhysAddr::from(PHYSICAL_FREE_LIST.free_list.iter().map(|entry| entry.start).min().unwrap());
+		MANAGED_END = PhysAddr::from(PHYSICAL_FREE_LIST.free_list.iter().map(|entry| entry.end).max().unwrap());
+		TOTAL_MEMORY = PHYSICAL_FREE_LIST.free_list.iter().map(|entry| entry.end - entry.start).sum();
+		info!("Heap uses {} MiB of physical memory in {:#X} - {:#X}", TOTAL_MEMORY / (1024 * 1024), MANAGED_START, MANAGED_END);
+	}
+}
+
+/// Sets aside the number of bytes given by the "kernel_reserve" command-line argument for the kernel heap.
+/// Driver buffers can exhaust the regular free memory, but not this reserve, so core kernel operations keep working.
+/// Must be called right after init, while the Bootstrap Allocator can still provide the node of the reserve.
+pub fn init_kernel_reserve() {
+	let size = match environment::get_arg("kernel_reserve").map(environment::parse_integer) {
+		None => return,
+		Some(Some(size)) if size > 0 => align_up!(size, BasePageSize::SIZE),
+		Some(_) => {
+			warn!("Ignoring invalid kernel_reserve");
+			return;
+		}
+	};
+
+	let start = match unsafe { PHYSICAL_FREE_LIST.allocate(size) } {
+		Ok(frame) => frame.start_address(),
+		Err(_) => {
+			warn!("Could not set aside a kernel reserve of {:#X} bytes", size);
+			return;
+		}
+	};
+
+	unsafe {
+		KERNEL_RESERVE.add_range(start, start + size);
+		KERNEL_RESERVE_START = start;
+		KERNEL_RESERVE_END = start + size;
+	}
+
+	info!("Reserved {} KiB of physical memory at {:#X} for the kernel heap", size / 1024, start);
+}
+
+/// Lets PHYSICAL_FREE_LIST take its nodes from mm::POOL instead of the early boot arena.
+/// Called by mm::init as soon as the System Allocator is up.
+pub fn use_node_pool() {
+	unsafe { PHYSICAL_FREE_LIST.free_list.use_pool(); }
+}
+
+/// Returns the number of used and total bytes of the kernel reserve (see init_kernel_reserve).
+pub fn kernel_reserve_usage() -> (usize, usize) {
+	unsafe { (KERNEL_RESERVE_USED, KERNEL_RESERVE_END - KERNEL_RESERVE_START) }
+}
+
+/// Returns the start and end address of the physical memory managed by this module.
+pub fn managed_range() -> (usize, usize) {
+	unsafe { (MANAGED_START.into(), MANAGED_END.into()) }
+}
+
+/// Returns the total number of bytes of physical memory managed by this module, free or allocated.
+pub fn total_memory() -> usize {
+	unsafe { TOTAL_MEMORY }
+}
+
+/// Counts an allocation request of `size` bytes in the size histogram.
+/// Compiles to nothing without the "alloc_histogram" feature.
+#[inline]
+fn record_allocation_size(_size: usize) {
+	#[cfg(feature = "alloc_histogram")]
+	histogram::record(_size);
+}
+
+/// Returns the number of allocation requests per power-of-two size class since boot or the last reset.
+/// Class `i` counts the requests from 2^(i-1) + 1 up to 2^i bytes, the last class also all larger ones.
+#[cfg(feature = "alloc_histogram")]
+pub fn size_histogram() -> [usize; SIZE_CLASSES] {
+	histogram::get()
+}
+
+/// Clears the size histogram, e.g. to measure a single phase of a workload.
+#[cfg(feature = "alloc_histogram")]
+pub fn reset_size_histogram() {
+	histogram::reset();
+}
+
+/// Fails fast for requests that could never be satisfied, instead of walking the entire Free List.
+fn check_total_memory(size: usize) {
+	kassert!(size <= total_memory(), "Requested {:#X} bytes of physical memory, which exceeds the total RAM of {:#X} bytes", size, total_memory());
+}
+
+/// Allocates `count` consecutive 4 KiB frames of physical memory.
+pub fn allocate_frames(count: usize) -> PhysFrame {
+	assert!(count > 0);
+	let size = count * BasePageSize::SIZE;
+	check_total_memory(size);
+	record_allocation_size(size);
+
+	let result = unsafe { PHYSICAL_FREE_LIST.allocate(size) };
+	kassert!(result.is_ok(), "Could not allocate {:#X} bytes of physical memory", size);
+	result.unwrap()
+}
+
+/// Like allocate_frames, but falls back to the kernel reserve when the regular free memory is exhausted.
+/// Only the kernel heap shall use this.
+pub fn allocate_frames_for_heap(count: usize) -> PhysFrame {
+	assert!(count > 0);
+	let size = count * BasePageSize::SIZE;
+	check_total_memory(size);
+	record_allocation_size(size);
+
+	let result = unsafe { allocate_with_reserve(&mut PHYSICAL_FREE_LIST, &mut KERNEL_RESERVE, size) };
+	kassert!(result.is_ok(), "Could not allocate {:#X} bytes of physical memory for the kernel heap, even from the kernel reserve", size);
+
+	let (frame, from_reserve) = result.unwrap();
+	if from_reserve {
+		unsafe { KERNEL_RESERVE_USED += size; }
+	}
+
+	frame
+}
+
+/// Allocates `size` bytes from `free_list` or, if it has no suitable block left, from `reserve`.
+/// Also returns whether the memory has been taken from the reserve.
+fn allocate_with_reserve(free_list: &mut PhysicalFreeList, reserve: &mut PhysicalFreeList, size: usize) -> Result<(PhysFrame, bool), ()> {
+	if let Ok(frame) = free_list.allocate(size) {
+		return Ok((frame, false));
+	}
+
+	reserve.allocate(size).map(|frame| (frame, true))
+}
+
+/// Like allocate_frames, but prefers the highest available physical addresses.
+/// This keeps low memory free for devices and structures that depend on it.
+pub fn allocate_high_frames(count: usize) -> PhysFrame {
+	assert!(count > 0);
+	let size = count * BasePageSize::SIZE;
+	check_total_memory(size);
+	record_allocation_size(size);
+
+	let result = unsafe { PHYSICAL_FREE_LIST.allocate_high(size) };
+	kassert!(result.is_ok(), "Could not allocate {:#X} bytes of high physical memory", size);
+	result.unwrap()
+}
+
+/// Allocates `count` consecutive 4 KiB frames of physical memory that lie entirely below the physical address `limit`.
+/// This is meant for devices with a limited addressing capability, e.g. a limit of 4 GiB for 32-bit DMA.
+pub fn allocate_frames_below(count: usize, limit: PhysAddr) -> Result<PhysFrame, AllocError> {
+	if count == 0 {
+		return Err(AllocError::ZeroSize);
+	}
+	let size = count * BasePageSize::SIZE;
+	if size > total_memory() {
+		return Err(AllocError::ExceedsTotalMemory);
+	}
+	record_allocation_size(size);
+
+	unsafe {
+		POOL.maintain();
+		PHYSICAL_FREE_LIST.allocate_below(size, limit)
+	}
+}
+
+/// Checks the parameters of an aligned allocation of `size` bytes from `total_memory` bytes of RAM.
+fn check_aligned_request(size: usize, alignment: usize, total_memory: usize) -> Result<(), AllocError> {
+	if size == 0 {
+		return Err(AllocError::ZeroSize);
+	}
+	if !alignment.is_power_of_two() {
+		return Err(AllocError::AlignmentNotPowerOfTwo);
+	}
+	if alignment < BasePageSize::SIZE {
+		return Err(AllocError::AlignmentTooSmall);
+	}
+	if size % alignment != 0 {
+		return Err(AllocError::SizeNotMultiple);
+	}
+	if size > total_memory {
+		return Err(AllocError::ExceedsTotalMemory);
+	}
+
+	Ok(())
+}
+
+/// Allocates `size` bytes of physical memory aligned to `alignment` bytes as consecutive 4 KiB frames.
+fn allocate_aligned_frame(size: usize, alignment: usize) -> Result<PhysFrame, AllocError> {
+	check_aligned_request(size, alignment, total_memory())?;
+	record_allocation_size(size);
+
+	unsafe {
+		POOL.maintain();
+		PHYSICAL_FREE_LIST.allocate_aligned(size, alignment)
+	}
+}
+
+/// Allocates `count` consecutive frames of the size given by S, which are aligned to that size.
+pub fn allocate_aligned_frames<S: PageSize>(count: usize) -> Result<PhysFrame<S>, AllocError> {
+	let frame = allocate_aligned_frame(count * S::SIZE, S::SIZE)?;
+	Ok(PhysFrame::from_start_address(frame.start_address()).unwrap())
+}
+
+/// Like allocate_frames, but with `usize` sizes and addresses.
+pub fn allocate(size: usize) -> usize {
+	assert!(size > 0);
+	assert!(size % BasePageSize::SIZE == 0, "Size {:#X} is not a multiple of {:#X}", size, BasePageSize::SIZE);
+	allocate_frames(size / BasePageSize::SIZE).start_address().into()
+}
+
+/// Like allocate_frames_for_heap, but with `usize` sizes and addresses.
+pub fn allocate_for_heap(size: usize) -> usize {
+	assert!(size > 0);
+	assert!(size % BasePageSize::SIZE == 0, "Size {:#X} is not a multiple of {:#X}", size, BasePageSize::SIZE);
+	allocate_frames_for_heap(size / BasePageSize::SIZE).start_address().into()
+}
+
+/// Like allocate_high_frames, but with `usize` sizes and addresses.
+pub fn allocate_high(size: usize) -> usize {
+	assert!(size > 0);
+	assert!(size % BasePageSize::SIZE == 0, "Size {:#X} is not a multiple of {:#X}", size, BasePageSize::SIZE);
+	allocate_high_frames(size / BasePageSize::SIZE).start_address().into()
+}
+
+/// Like allocate_frames_below, but with `usize` sizes and addresses.
+pub fn allocate_below(size: usize, limit: usize) -> Result<usize, AllocError> {
+	if size % BasePageSize::SIZE != 0 {
+		return Err(AllocError::SizeNotMultiple);
+	}
+
+	allocate_frames_below(size / BasePageSize::SIZE, PhysAddr::from(limit)).map(|frame| frame.start_address().into())
+}
+
+/// Allocate `size` bytes of physical memory aligned to `alignment` bytes.
+/// Invalid parameters and exhausted memory are reported through an `AllocError` instead of a panic.
+pub fn allocate_aligned_checked(size: usize, alignment: usize) -> Result<usize, AllocError> {
+	allocate_aligned_frame(size, alignment).map(|frame| frame.start_address().into())
+}
+
+pub fn allocate_aligned(size: usize, alignment: usize) -> usize {
+	match allocate_aligned_checked(size, alignment) {
+		Ok(address) => address,
+		Err(AllocError::ExceedsTotalMemory) => kpanic!("Requested {:#X} bytes of physical memory, which exceeds the total RAM of {:#X} bytes", size, total_memory()),
+		Err(AllocError::OutOfMemory) => kpanic!("Could not allocate {:#X} bytes of physical memory aligned to {} bytes", size, alignment),
+		Err(e) => panic!("Invalid physical memory allocation of {:#X} bytes aligned to {} bytes: {}", size, alignment, e),
+	}
+}
+
+/// Checks a region of new RAM from `start` to `end` for add_region.
+/// It must be page-aligned, lie between `lowest_address` and `physical_limit`, and must not overlap any entry
+/// of `memory_map`, as that memory may be in use. `memory_map` must also have room for the new region.
+fn check_new_region(start: usize, end: usize, lowest_address: usize, physical_limit: usize, memory_map: &[MemoryRegion]) -> Result<(), ()> {
+	if start >= end || start % BasePageSize::SIZE != 0 || end % BasePageSize::SIZE != 0 {
+		warn!("Not adding invalid physical memory region {:#X} - {:#X}", start, end);
+		return Err(());
+	}
+	if start < lowest_address || end > physical_limit {
+		warn!("Not adding physical memory region {:#X} - {:#X} outside the usable address range", start, end);
+		return Err(());
+	}
+	if let Some(m) = memory_map.iter().find(|m| m.length > 0 && start < (m.base + m.length) as usize && end > m.base as usize) {
+		warn!("Not adding physical memory region {:#X} - {:#X}, because it overlaps {:?} memory {:#X} - {:#X}", start, end, m.memory_type, m.base, m.base + m.length);
+		return Err(());
+	}
+	if memory_map.len() >= MAX_RAM_REGIONS {
+		warn!("Not adding physical memory region {:#X} - {:#X}, because there are too many regions", start, end);
+		return Err(());
+	}
+
+	Ok(())
+}
+
+/// Adds the physical memory from `start` to `end` to the free memory at runtime.
+/// This is either memory hot-plugged by the hypervisor, or memory that reserve_region has taken out before,
+/// like pages returned by a balloon driver.
+/// New memory is checked by check_new_region and gets reference counters for its frames before it is freed.
+pub fn add_region(start: usize, end: usize) -> Result<(), ()> {
+	if start >= end || start % BasePageSize::SIZE != 0 || end % BasePageSize::SIZE != 0 {
+		warn!("Not adding invalid physical memory region {:#X} - {:#X}", start, end);
+		return Err(());
+	}
+
+	// Reserved memory is still part of the managed memory, so it only needs to go back to the Free List.
+	{
+		let _lock = mm::MM_LOCK.lock();
+
+		unsafe {
+			POOL.maintain();
+
+			if RESERVED_REGIONS.reserve(PhysAddr::from(start), end - start).is_ok() {
+				PHYSICAL_FREE_LIST.deallocate(PhysAddr::from(start), end - start);
+				RESERVED_MEMORY -= end - start;
+				TOTAL_MEMORY += end - start;
+				return Ok(());
+			}
+		}
+	}
+
+	let _add_region_lock = ADD_REGION_LOCK.lock();
+	let memory_map = unsafe { &memory_map_export.regions[..memory_map_export.count as usize] };
+	check_new_region(start, end, mm::kernel_end_address(), 1usize << processor::phys_address_bits(), memory_map)?;
+
+	// The counters are allocated from the kernel heap, which takes MM_LOCK itself.
+	if frame_ref::add_range(start, end).is_err() {
+		warn!("Not adding physical memory region {:#X} - {:#X}, because its frames cannot be reference-counted", start, end);
+		return Err(());
+	}
+
+	let _lock = mm::MM_LOCK.lock();
+	export_region(start, end - start, MemoryType::Available);
+
+	unsafe {
+		POOL.maintain();
+		PHYSICAL_FREE_LIST.deallocate(PhysAddr::from(start), end - start);
+
+		if PhysAddr::from(start) < MANAGED_START {
+			MANAGED_START = PhysAddr::from(start);
+		}
+		if PhysAddr::from(end) > MANAGED_END {
+			MANAGED_END = PhysAddr::from(end);
+		}
+		TOTAL_MEMORY += end - start;
+	}
+
+	info!("Added physical memory region {:#X} - {:#X}", start, end);
+	Ok(())
+}
+
+/// Takes the allocated physical memory from `start` to `end` out of the managed memory, e.g. pages handed to
+/// the hypervisor by a balloon driver.
+/// The memory counts towards reserved_memory instead of total_memory until add_region gives it back.
+pub fn reserve_region(start: usize, end: usize) -> Result<(), ()> {
+	if start >= end || start % BasePageSize::SIZE != 0 || end % BasePageSize::SIZE != 0 {
+		warn!("Not reserving invalid physical memory region {:#X} - {:#X}", start, end);
+		return Err(());
+	}
+
+	let _lock = mm::MM_LOCK.lock();
+
+	unsafe {
+		if PhysAddr::from(start) < MANAGED_START || PhysAddr::from(end) > MANAGED_END {
+			warn!("Not reserving physical memory region {:#X} - {:#X} outside the managed memory", start, end);
+			return Err(());
+		}
+
+		POOL.maintain();
+		RESERVED_REGIONS.deallocate(PhysAddr::from(start), end - start);
+		TOTAL_MEMORY -= end - start;
+		RESERVED_MEMORY += end - start;
+	}
+
+	Ok(())
+}
+
+/// Returns the number of bytes taken out of the managed memory through reserve_region.
+pub fn reserved_memory() -> usize {
+	unsafe { RESERVED_MEMORY }
+}
+
+/// Reserves the given page-aligned range of low memory (below 1 MiB) for code that must run there,
+/// like the real-mode trampoline of the Application Processors.
+/// Low memory is never managed by PHYSICAL_FREE_LIST, so this only checks that the range is RAM not used by the
+/// BIOS, EBDA, Multiboot information, or another reservation.
+pub fn reserve_low_memory(start: usize, size: usize) -> Result<(), ()> {
+	let end = start + size;
+	if size == 0 || start % BasePageSize::SIZE != 0 || size % BasePageSize::SIZE != 0 {
+		warn!("Not reserving invalid low memory range {:#X} - {:#X}", start, end);
+		return Err(());
+	}
+
+	// The first page holds the Real Mode Interrupt Vector Table and the BIOS Data Area.
+	if start < BasePageSize::SIZE || end > LOW_MEMORY_LIMIT {
+		warn!("Not reserving low memory {:#X} - {:#X}, which may belong to the BIOS", start, end);
+		return Err(());
+	}
+
+	let _lock = mm::MM_LOCK.lock();
+
+	let known_regions = unsafe { &memory_map_export.regions[..memory_map_export.count as usize] };
+	let is_ram = known_regions.iter().any(|region| {
+		region.memory_type == MemoryType::Available && start >= region.base as usize && end <= (region.base + region.length) as usize
+	});
+	let overlaps_reserved = known_regions.iter().any(|region| {
+		region.memory_type != MemoryType::Available && start < (region.base + region.length) as usize && end > region.base as usize
+	});
+	if !is_ram || overlaps_reserved {
+		warn!("Not reserving low memory {:#X} - {:#X}, because it is not available RAM", start, end);
+		return Err(());
+	}
+
+	if unsafe { mb_info } != 0 {
+		let mb = unsafe { Multiboot::new(mb_info) };
+		if multiboot_data_ranges(&mb).iter().any(|&(range_start, range_end)| start < range_end && end > range_start) {
+			warn!("Not reserving low memory {:#X} - {:#X}, because it holds Multiboot information", start, end);
+			return Err(());
+		}
+	}
+
+	unsafe {
+		if LOW_MEMORY_RANGES[..LOW_MEMORY_RANGE_COUNT].iter().any(|range| start < range.end && end > range.start) {
+			warn!("Low memory {:#X} - {:#X} is already reserved", start, end);
+			return Err(());
+		}
+		if LOW_MEMORY_RANGE_COUNT == MAX_LOW_MEMORY_RANGES {
+			warn!("Not reserving low memory {:#X} - {:#X}, because there are too many reservations", start, end);
+			return Err(());
+		}
+
+		LOW_MEMORY_RANGES[LOW_MEMORY_RANGE_COUNT] = LowMemoryRange { start: start, end: end, reclaimed: false };
+		LOW_MEMORY_RANGE_COUNT += 1;
+	}
+
+	debug!("Reserved low memory {:#X} - {:#X}", start, end);
+	Ok(())
+}
+
+/// Adds a range reserved through reserve_low_memory to PHYSICAL_FREE_LIST, after its code is no longer needed.
+/// The range stays reserved if the frame reference table cannot be extended to cover it.
+pub fn reclaim_low_memory(start: usize) {
+	let _lock = mm::MM_LOCK.lock();
+
+	unsafe {
+		let range = LOW_MEMORY_RANGES[..LOW_MEMORY_RANGE_COUNT].iter_mut().find(|range| range.start == start);
+		let range = range.expect("Reclaiming low memory that has not been reserved");
+		assert!(!range.reclaimed, "Low memory at {:#X} has already been reclaimed", start);
+
+		// Frames of the free list may be shared, so they need a reference counter before they can be allocated.
+		if frame_ref::add_range(range.start, range.end).is_err() {
+			warn!("Keeping low memory {:#X} - {:#X} reserved", range.start, range.end);
+			return;
+		}
+
+		POOL.maintain();
+		PHYSICAL_FREE_LIST.deallocate(PhysAddr::from(range.start), range.end - range.start);
+		range.reclaimed = true;
+
+		if PhysAddr::from(range.start) < MANAGED_START {
+			MANAGED_START = PhysAddr::from(range.start);
+		}
+		TOTAL_MEMORY += range.end - range.start;
+		debug!("Reclaimed low memory {:#X} - {:#X}", range.start, range.end);
+	}
+}
+
+/// Returns whether the given range lies within low memory that has been reclaimed through reclaim_low_memory.
+fn is_reclaimed_low_memory(start: usize, end: usize) -> bool {
+	unsafe {
+		LOW_MEMORY_RANGES[..LOW_MEMORY_RANGE_COUNT].iter().any(|range| {
+			range.reclaimed && start >= range.start && end <= range.end
+		})
+	}
+}
+
+/// Returns `count` frames starting at `first_frame` to the free memory they have been allocated from.
+/// The same restrictions as for deallocate apply.
+pub fn deallocate_frames<S: PageSize>(first_frame: PhysFrame<S>, count: usize) {
+	let start_address = first_frame.start_address();
+	assert!(count > 0);
+
+	let address: usize = start_address.into();
+	let size = count * S::SIZE;
+	assert!(
+		start_address >= PhysAddr::from(mm::kernel_end_address()) || is_reclaimed_low_memory(address, address + size),
+		"Physical address {} is neither behind the kernel nor in reclaimed low memory", start_address
+	);
+	unsafe {
+		if start_address >= KERNEL_RESERVE_START && start_address < KERNEL_RESERVE_END {
+			KERNEL_RESERVE.deallocate(start_address, size);
+			KERNEL_RESERVE_USED -= size;
+		} else {
+			PHYSICAL_FREE_LIST.deallocate(start_address, size);
+		}
+	}
+}
+
+/// Like deallocate_frames, but with `usize` sizes and addresses.
+///
+/// This function must only be called from mm::deallocate!
+/// Otherwise, it may fail due to an empty node pool (POOL.maintain() is called in virtualmem::deallocate)
+pub fn deallocate(physical_address: usize, size: usize) {
+	assert!(size % BasePageSize::SIZE == 0, "Size {:#X} is not a multiple of {:#X}", size, BasePageSize::SIZE);
+	let first_frame = PhysFrame::<BasePageSize>::from_start_address(PhysAddr::from(physical_address));
+	assert!(first_frame.is_ok(), "Physical address {:#X} is not a multiple of {:#X}", physical_address, BasePageSize::SIZE);
+	deallocate_frames(first_frame.unwrap(), size / BasePageSize::SIZE);
+}
+
+pub fn print_information() {
+	unsafe { PHYSICAL_FREE_LIST.free_list.print_information(" PHYSICAL MEMORY FREE LIST "); }
+
+	let (used, total) = kernel_reserve_usage();
+	if total > 0 {
+		infoheader!(" KERNEL RESERVE ");
+		infoentry!("Used", "{} of {} KiB", used / 1024, total / 1024);
+		infofooter!();
+	}
+
+	if reserved_memory() > 0 {
+		infoheader!(" RESERVED MEMORY ");
+		infoentry!("Reserved", "{} KiB", reserved_memory() / 1024);
+		infofooter!();
+	}
+}
+
+/// Returns the number of free blocks, free bytes and the largest free block of the physical memory.
+pub fn statistics() -> FreeListStatistics {
+	let _lock = mm::MM_LOCK.lock();
+	unsafe { PHYSICAL_FREE_LIST.free_list.statistics() }
+}
+
+/// Returns 1 minus the share of the largest free block in the free physical memory (see FreeListStatistics).
+/// A rising value over a workload means that the free memory gets split into ever smaller blocks.
+pub fn fragmentation() -> f32 {
+	statistics().fragmentation()
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Returns a Free List of the given ranges, which takes its nodes from an own arena instead of mm::POOL.
+	fn free_list(ranges: &[(usize, usize)]) -> PhysicalFreeList {
+		let mut list = PhysicalFreeList::new();
+		list.free_list.use_arena(4);
+
+		for &(start, end) in ranges {
+			list.deallocate(PhysAddr::from(start), end - start);
+		}
+
+		list
+	}
+
+	#[test]
+	fn aligned_request_errors() {
+		let total = 0x100000;
+
+		assert_eq!(check_aligned_request(0, 0x1000, total), Err(AllocError::ZeroSize));
+		assert_eq!(check_aligned_request(0x3000, 0x3000, total), Err(AllocError::AlignmentNotPowerOfTwo));
+		assert_eq!(check_aligned_request(0x800, 0x800, total), Err(AllocError::AlignmentTooSmall));
+		assert_eq!(check_aligned_request(0x3000, 0x2000, total), Err(AllocError::SizeNotMultiple));
+		assert_eq!(check_aligned_request(0x200000, 0x1000, total), Err(AllocError::ExceedsTotalMemory));
+		assert_eq!(check_aligned_request(0x4000, 0x2000, total), Ok(()));
+	}
+
+	#[test]
+	fn aligned_allocation_out_of_memory() {
+		let mut list = free_list(&[(0x1000, 0x3000)]);
+
+		assert_eq!(list.allocate_aligned(0x4000, 0x1000).err(), Some(AllocError::OutOfMemory));
+		assert_eq!(list.allocate_aligned(0x2000, 0x2000).err(), Some(AllocError::OutOfMemory));
+		assert_eq!(list.allocate_aligned(0x1000, 0x2000).unwrap().start_address(), PhysAddr::from(0x2000));
+	}
+
+	#[test]
+	fn high_allocation_uses_topmost_region() {
+		let mut list = free_list(&[(0x1000, 0x10000), (0x100000, 0x104000), (0x200000, 0x202000)]);
+
+		// Fits into the top-most region, which is taken from its end.
+		assert_eq!(list.allocate_high(0x1000).unwrap().start_address(), PhysAddr::from(0x201000));
+		// Consumes the rest of the top-most region exactly.
+		assert_eq!(list.allocate_high(0x1000).unwrap().start_address(), PhysAddr::from(0x200000));
+		// Larger than the middle region, so it comes from the lowest one.
+		assert_eq!(list.allocate_high(0x8000).unwrap().start_address(), PhysAddr::from(0x8000));
+		assert_eq!(list.allocate_high(0x4000).unwrap().start_address(), PhysAddr::from(0x100000));
+		// The regular allocator still starts at the bottom.
+		assert_eq!(list.allocate(0x1000).unwrap().start_address(), PhysAddr::from(0x1000));
+	}
+
+	#[test]
+	fn overlapping_memory_map_is_sanitized() {
+		let region = |base: u64, end: u64, memory_type| MemoryRegion { base: base, length: end - base, memory_type: memory_type };
+		let memory_map = [
+			region(0x400000, 0x1000000, MemoryType::Available),
+			region(0x0, 0x9F000, MemoryType::Available),
+			region(0x500000, 0x500000, MemoryType::Available),
+			region(0x100000, 0x800000, MemoryType::Available),
+			region(0x700000, 0x710000, MemoryType::Reserved),
+			region(0x9F000, 0x100000, MemoryType::Reserved),
+			region(0x1800000, 0x2800000, MemoryType::Available),
+			region(0x3000000, 0x4000000, MemoryType::Available),
+		];
+		let mut regions = [(0, 0); MAX_RAM_REGIONS];
+
+		let count = sanitize_memory_map(&memory_map, 0x2000000, &mut regions);
+		assert_eq!(&regions[..count], &[(0x0, 0x9F000), (0x100000, 0x700000), (0x710000, 0x1000000), (0x1800000, 0x2000000)]);
+
+		// The resulting Free List is sorted and has no overlapping entries.
+		let list = free_list(&regions[..count]);
+		let entries: Vec<(usize, usize)> = list.free_list.iter().map(|entry| (entry.start, entry.end)).collect();
+		assert_eq!(&entries[..], &regions[..count]);
+		assert!(entries.windows(2).all(|pair| pair[0].1 < pair[1].0));
+	}
+
+	#[test]
+	fn multiboot_information_is_excluded_from_the_free_list() {
+		let mut ranges = BootDataRanges::new();
+		// The information structure and the memory map share a page, the command line lies in the middle of the RAM.
+		add_boot_data_range(&mut ranges, 0x9500, 0x88);
+		add_boot_data_range(&mut ranges, 0x9588, 0x90);
+		add_boot_data_range(&mut ranges, 0x8000, 0);
+		add_boot_data_range(&mut ranges, 0x300100, 0x20);
+		assert_eq!(ranges.len(), 3);
+
+		let mut regions = [(0, 0); MAX_RAM_REGIONS];
+		regions[0] = (0x0, 0x9F000);
+		regions[1] = (0x100000, 0x800000);
+		let count = exclude_boot_data(&mut regions, 2, ranges);
+		assert_eq!(&regions[..count], &[(0x0, 0x9000), (0xA000, 0x9F000), (0x100000, 0x300000), (0x301000, 0x800000)]);
+
+		let list = free_list(&regions[..count]);
+		for &address in [0x9000, 0x9500, 0x9617, 0x300000, 0x300100].iter() {
+			assert!(list.free_list.find(address).is_none(), "Multiboot information at {:#X} is in the Free List", address);
+		}
+		assert!(list.free_list.find(0xA000).is_some());
+	}
+
+	#[test]
+	fn excess_multiboot_information_ranges_are_merged() {
+		let mut ranges = BootDataRanges::new();
+		for i in 0..MAX_BOOT_DATA_RANGES {
+			add_boot_data_range(&mut ranges, i * 0x10000, 0x100);
+		}
+
+		// The new range is merged with its closest neighbor, which covers the gap but drops nothing.
+		add_boot_data_range(&mut ranges, 0x50200, 0x100);
+		assert_eq!(ranges.len(), MAX_BOOT_DATA_RANGES);
+		assert_eq!(ranges[5], (0x50000, 0x50300));
+		assert_eq!(ranges[4], (0x40000, 0x40100));
+		assert_eq!(ranges[6], (0x60000, 0x60100));
+	}
+
+	#[test]
+	fn absurd_sizes_fail_fast() {
+		let absurd_size = 1 << 52;
+
+		assert_eq!(allocate_aligned_checked(absurd_size, BasePageSize::SIZE).err(), Some(AllocError::ExceedsTotalMemory));
+		assert_eq!(allocate_below(absurd_size, 1 << 32).err(), Some(AllocError::ExceedsTotalMemory));
+
+		// A request is only rejected up front if it exceeds the total RAM.
+		let total = 0x4000000;
+		assert_eq!(check_aligned_request(total + BasePageSize::SIZE, BasePageSize::SIZE, total), Err(AllocError::ExceedsTotalMemory));
+		assert_eq!(check_aligned_request(total, BasePageSize::SIZE, total), Ok(()));
+	}
+
+	#[test]
+	fn heap_allocations_fall_back_to_the_kernel_reserve() {
+		let mut list = free_list(&[(0x100000, 0x102000)]);
+		let mut reserve = free_list(&[(0x200000, 0x202000)]);
+		let heap_allocation = |list: &mut PhysicalFreeList, reserve: &mut PhysicalFreeList| {
+			allocate_with_reserve(list, reserve, BasePageSize::SIZE).map(|(frame, from_reserve)| (frame.start_address(), from_reserve))
+		};
+
+		// The regular free memory is used up first.
+		assert_eq!(heap_allocation(&mut list, &mut reserve), Ok((PhysAddr::from(0x100000), false)));
+		assert!(list.allocate(BasePageSize::SIZE).is_ok());
+
+		// Other allocations fail now, but the kernel heap can still draw from the reserve until it is exhausted too.
+		assert!(list.allocate(BasePageSize::SIZE).is_err());
+		assert_eq!(heap_allocation(&mut list, &mut reserve), Ok((PhysAddr::from(0x200000), true)));
+		assert_eq!(heap_allocation(&mut list, &mut reserve), Ok((PhysAddr::from(0x201000), true)));
+		assert_eq!(heap_allocation(&mut list, &mut reserve), Err(()));
+
+		// Memory given back to the reserve can be used again, but regular memory is preferred.
+		reserve.deallocate(PhysAddr::from(0x200000), BasePageSize::SIZE);
+		assert_eq!(heap_allocation(&mut list, &mut reserve), Ok((PhysAddr::from(0x200000), true)));
+		list.deallocate(PhysAddr::from(0x100000), BasePageSize::SIZE);
+		reserve.deallocate(PhysAddr::from(0x201000), BasePageSize::SIZE);
+		assert_eq!(heap_allocation(&mut list, &mut reserve), Ok((PhysAddr::from(0x100000), false)));
+	}
+
+	#[test]
+	fn added_region_can_be_allocated() {
+		let region = |base: u64, end: u64| MemoryRegion { base: base, length: end - base, memory_type: MemoryType::Available };
+		let memory_map = [region(0x0, 0x9F000), region(0x100000, 0x8000000)];
+		let (start, end) = (0x10000000, 0x10004000);
+
+		assert!(check_new_region(start, end, 0x200000, 1 << 36, &memory_map).is_ok());
+		assert!(check_new_region(start + 0x800, end, 0x200000, 1 << 36, &memory_map).is_err());
+		assert!(check_new_region(0x7FFF000, 0x8001000, 0x200000, 1 << 36, &memory_map).is_err());
+		assert!(check_new_region(0x1000, 0x2000, 0x200000, 1 << 36, &memory_map).is_err());
+		assert!(check_new_region(start, end, 0x200000, start + 0x1000, &memory_map).is_err());
+
+		// Use up the known memory, so the allocations below can only be served by the new region.
+		let mut list = free_list(&[(0x200000, 0x8000000)]);
+		assert!(list.allocate(0x8000000 - 0x200000).is_ok());
+
+		list.deallocate(PhysAddr::from(start), end - start);
+		assert_eq!(list.allocate(0x2000).unwrap().start_address(), PhysAddr::from(start));
+		assert_eq!(list.allocate(0x2000).unwrap().start_address(), PhysAddr::from(start + 0x2000));
+		assert!(list.allocate(0x1000).is_err());
+	}
+}
@@ -59,6 +59,22 @@ pub fn allocate(size: usize) -> usize {
 	result.unwrap()
 }
 
+/// Allocate `size` bytes of kernel virtual address space aligned to `alignment` bytes, e.g. to map device memory
+/// with large pages.
+pub fn allocate_aligned(size: usize, alignment: usize) -> usize {
+	assert!(size > 0);
+	assert!(alignment.is_power_of_two() && alignment >= BasePageSize::SIZE, "Alignment {:#X} is not a power of two >= {:#X}", alignment, BasePageSize::SIZE);
+	assert!(size % BasePageSize::SIZE == 0, "Size {:#X} is not a multiple of {:#X}", size, BasePageSize::SIZE);
+
+	// Splitting a free region around the aligned block may need a node from the pool.
+	let result = unsafe {
+		POOL.maintain();
+		KERNEL_FREE_LIST.allocate_aligned(size, alignment)
+	};
+	assert!(result.is_ok(), "Could not allocate {:#X} bytes of virtual memory aligned to {:#X} bytes", size, alignment);
+	result.unwrap()
+}
+
 pub fn deallocate(virtual_address: usize, size: usize) {
 	assert!(virtual_address >= mm::kernel_end_address(), "Virtual address {:#X} is not >= KERNEL_END_ADDRESS", virtual_address);
 	assert!(virtual_address < KERNEL_VIRTUAL_MEMORY_END, "Virtual address {:#X} is not < KERNEL_VIRTUAL_MEMORY_END", virtual_address);
@@ -99,3 +115,66 @@ pub fn task_heap_start() -> usize {
 pub fn task_heap_end() -> usize {
 	TASK_VIRTUAL_MEMORY_END
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use arch::x86_64::mm::paging::LargePageSize;
+
+	/// Assumed end of the kernel image, which is not aligned to a 2 MiB boundary.
+	const KERNEL_END: usize = 0x123_4000;
+
+	/// Returns a Free List of the kernel virtual memory like init does, which takes its nodes from an own arena.
+	fn kernel_free_list() -> FreeList {
+		let mut list = FreeList::new();
+		list.use_arena(8);
+		list.list.push(Node::new(FreeListEntry { start: KERNEL_END, end: KERNEL_VIRTUAL_MEMORY_END }));
+		list
+	}
+
+	fn entries(list: &FreeList) -> Vec<(usize, usize)> {
+		list.iter().map(|entry| (entry.start, entry.end)).collect()
+	}
+
+	#[test]
+	fn aligned_allocations_leave_the_gap_free() {
+		let mut list = kernel_free_list();
+
+		// Like a 4 MiB device mapping with large pages, which must start at the next 2 MiB boundary.
+		let address = list.allocate_aligned(2 * LargePageSize::SIZE, LargePageSize::SIZE).unwrap();
+		assert_eq!(address, 0x140_0000);
+		assert_eq!(entries(&list), vec![(KERNEL_END, 0x140_0000), (0x180_0000, KERNEL_VIRTUAL_MEMORY_END)]);
+
+		// Small allocations are served from the gap in front of the aligned block first.
+		assert_eq!(list.allocate(BasePageSize::SIZE), Ok(KERNEL_END));
+
+		// An already aligned start is used as it is.
+		let address = list.allocate_aligned(LargePageSize::SIZE, LargePageSize::SIZE).unwrap();
+		assert_eq!(address, 0x180_0000);
+		assert_eq!(address % LargePageSize::SIZE, 0);
+
+		// Alignments up to the whole kernel address space are possible, but nothing beyond it.
+		assert!(list.allocate_aligned(LargePageSize::SIZE, 0x4000_0000).unwrap() % 0x4000_0000 == 0);
+		assert!(list.allocate_aligned(LargePageSize::SIZE, KERNEL_VIRTUAL_MEMORY_END).is_err());
+	}
+
+	#[test]
+	fn freed_memory_is_coalesced() {
+		let mut list = kernel_free_list();
+
+		let first = list.allocate(BasePageSize::SIZE).unwrap();
+		let large = list.allocate_aligned(LargePageSize::SIZE, LargePageSize::SIZE).unwrap();
+		let second = list.allocate(3 * BasePageSize::SIZE).unwrap();
+		assert_eq!((first, large, second), (KERNEL_END, 0x140_0000, KERNEL_END + BasePageSize::SIZE));
+		assert_eq!(entries(&list), vec![(KERNEL_END + 4 * BasePageSize::SIZE, 0x140_0000), (0x160_0000, KERNEL_VIRTUAL_MEMORY_END)]);
+
+		// Each freed block is merged with its free neighbors, until the initial region is restored.
+		list.deallocate(large, LargePageSize::SIZE);
+		assert_eq!(entries(&list), vec![(KERNEL_END + 4 * BasePageSize::SIZE, KERNEL_VIRTUAL_MEMORY_END)]);
+		list.deallocate(first, BasePageSize::SIZE);
+		assert_eq!(entries(&list), vec![(KERNEL_END, KERNEL_END + BasePageSize::SIZE), (KERNEL_END + 4 * BasePageSize::SIZE, KERNEL_VIRTUAL_MEMORY_END)]);
+		list.deallocate(second, 3 * BasePageSize::SIZE);
+		assert_eq!(entries(&list), vec![(KERNEL_END, KERNEL_VIRTUAL_MEMORY_END)]);
+	}
+}
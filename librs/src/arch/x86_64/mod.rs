@@ -24,10 +24,20 @@
 
 pub mod acpi;
 pub mod apic;
+pub mod cpumask;
+#[cfg(feature = "debugger")]
+pub mod debug;
+pub mod fb;
+#[cfg(feature = "gdbstub")]
+pub mod gdbstub;
 pub mod gdt;
 pub mod idt;
+pub mod io;
+pub mod iommu;
 pub mod irq;
+pub mod mce;
 pub mod mm;
+pub mod mtrr;
 pub mod percore;
 pub mod pci;
 pub mod pic;
@@ -35,34 +45,78 @@ pub mod pit;
 pub mod processor;
 pub mod scheduler;
 pub mod serial;
+pub mod shutdown;
+pub mod time;
 #[cfg(feature = "vga")]
 pub mod vga;
 
 pub use arch::x86_64::apic::get_core_id_for_cpu_number;
-pub use arch::x86_64::apic::set_oneshot_timer;
+pub use arch::x86_64::apic::is_boot_processor;
 pub use arch::x86_64::apic::wakeup_core;
 pub use arch::x86_64::gdt::get_boot_stacks;
+pub use arch::x86_64::gdt::kernel_stack_size;
 pub use arch::x86_64::gdt::set_current_kernel_stack;
 pub use arch::x86_64::percore::PERCORE;
-use arch::x86_64::serial::SerialPort;
+pub use arch::x86_64::scheduler::restart_periodic_tick;
+pub use arch::x86_64::scheduler::set_oneshot_timer;
+pub use arch::x86_64::scheduler::stop_periodic_tick;
+use arch::x86_64::cpumask::CpuMask;
+use arch::x86_64::serial::{BufferMode, SerialPort};
+use console;
+use core::fmt;
+use core::fmt::Write;
 use environment;
 use kernel_message_buffer;
-use synch::spinlock::Spinlock;
+use synch::barrier::Barrier;
+use synch::once::Once;
+use synch::spinlock::SpinlockIrqSave;
 
+/// Default serial port settings, which can be overridden using the serial=<port>,<baudrate> command-line option.
 const SERIAL_PORT_ADDRESS: u16 = 0xc110; //0x3F8;
 const SERIAL_PORT_BAUDRATE: u32 = 115200;
 
+/// Number of attempts to take the console lock before flush_message_output writes out the buffered output anyway.
+const FLUSH_LOCK_ATTEMPTS: usize = 1 << 20;
+
 
 extern "C" {
 	static mut cpu_online: u32;
 }
 
-lazy_static! {
-	static ref CPU_ONLINE: Spinlock<&'static mut u32> =
-		Spinlock::new(unsafe { &mut cpu_online });
+/// The CPUs that have finished their initialization.
+/// The counter is shared with entry.asm, which uses it to tell the Boot Processor from Application Processors.
+struct OnlineCpus {
+	count: &'static mut u32,
+	mask: CpuMask,
 }
 
-static COM1: SerialPort = SerialPort::new(SERIAL_PORT_ADDRESS);
+static CPU_ONLINE: Once<SpinlockIrqSave<OnlineCpus>> = Once::new();
+
+/// Holds back the Application Processors from entering their schedulers until all of them have been booted.
+/// Armed by boot_application_processors once the number of online CPUs is known.
+static STARTUP_BARRIER: Barrier = Barrier::new(0);
+
+static mut COM1: SerialPort = SerialPort::new(SERIAL_PORT_ADDRESS);
+
+/// Set through the "log_timestamps" command-line flag to prefix each output line with the uptime.
+static mut LOG_TIMESTAMPS: bool = false;
+
+/// Whether the next byte passed to output_message_byte starts a new line.
+static mut AT_LINE_START: bool = true;
+
+
+/// Writes formatted output directly to the output device without any allocation.
+struct RawOutput;
+
+impl fmt::Write for RawOutput {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		for byte in s.bytes() {
+			output_raw_byte(byte);
+		}
+
+		Ok(())
+	}
+}
 
 
 // FUNCTIONS
@@ -70,18 +124,142 @@ static COM1: SerialPort = SerialPort::new(SERIAL_PORT_ADDRESS);
 /// Earliest initialization function called by the Boot Processor.
 pub fn message_output_init() {
 	percore::init();
+	apic::init_boot_processor_id();
+	time::init();
 
 	if environment::is_single_kernel() {
 		// We can only initialize the serial port here, because VGA requires processor
 		// configuration first.
-		COM1.init(SERIAL_PORT_BAUDRATE);
+		unsafe { COM1.init(SERIAL_PORT_BAUDRATE); }
+	}
+}
+
+/// Parses the value of the serial=<port>,<baudrate> command-line option.
+fn parse_serial_config(value: &str) -> Option<(u16, u32)> {
+	let mut parts = value.split(',');
+	let port = environment::parse_integer(parts.next()?)?;
+	let baudrate = match parts.next() {
+		Some(baudrate_str) => environment::parse_integer(baudrate_str)?,
+		None => SERIAL_PORT_BAUDRATE as usize,
+	};
+
+	// The port must be a valid I/O port and the UART needs an integer divisor of its 115200 baud base rate.
+	if port == 0 || port > 0xFFFF || baudrate == 0 || baudrate > 115200 || 115200 % baudrate != 0 || parts.next().is_some() {
+		return None;
+	}
+
+	Some((port as u16, baudrate as u32))
+}
+
+/// Reconfigures COM1 if the serial=<port>,<baudrate> command-line option has been given.
+/// The command line is only accessible after the memory manager has mapped it, so the serial port
+/// is brought up with the default settings in message_output_init first.
+///
+/// The output is line-buffered by default. The serial_buffer=none|line|block command-line option selects
+/// unbuffered output, e.g. for debugging a hang, or buffering until the buffer is full.
+fn configure_serial_port() {
+	if !environment::is_single_kernel() {
+		return;
+	}
+
+	let buffer_mode = match environment::get_arg("serial_buffer") {
+		None | Some("line") => BufferMode::Line,
+		Some("none") => BufferMode::Unbuffered,
+		Some("block") => BufferMode::Block,
+		Some(value) => {
+			warn!("Invalid serial_buffer command-line option \"{}\", using line buffering", value);
+			BufferMode::Line
+		}
+	};
+
+	if environment::is_uhyve() {
+		unsafe { COM1.set_buffer_mode(buffer_mode); }
+		return;
+	}
+
+	if let Some(value) = environment::get_arg("serial") {
+		match parse_serial_config(value) {
+			Some((port, baudrate)) => {
+				unsafe {
+					COM1.flush();
+					COM1 = SerialPort::new(port);
+					COM1.init(baudrate);
+				}
+
+				info!("Serial port configured at {:#X} with {} baud", port, baudrate);
+			},
+			None => warn!("Invalid serial command-line option \"{}\", keeping {:#X} with {} baud", value, SERIAL_PORT_ADDRESS, SERIAL_PORT_BAUDRATE),
+		}
+	}
+
+	unsafe { COM1.set_buffer_mode(buffer_mode); }
+}
+
+/// Writes out all buffered output, e.g. before halting after a panic or powering off.
+/// The console lock keeps other cores from writing into the buffer meanwhile.
+/// On a panic, the current core or a stopped one may hold it, so the output is written out without the lock
+/// if it cannot be taken within FLUSH_LOCK_ATTEMPTS attempts.
+pub fn flush_message_output() {
+	if !environment::is_single_kernel() {
+		return;
+	}
+
+	for _ in 0..FLUSH_LOCK_ATTEMPTS {
+		if let Some(_console) = console::CONSOLE.try_lock() {
+			unsafe { COM1.flush(); }
+			return;
+		}
+
+		processor::pause();
+	}
+
+	unsafe { COM1.flush(); }
+}
+
+/// Writes the uptime prefix for a new output line.
+/// The monotonic clock is not available before the CPU frequency is known, so question marks are printed then.
+fn output_timestamp() {
+	if time::is_clock_available() {
+		let uptime_us = time::uptime_ns() / 1000;
+		let _ = write!(RawOutput, "[{:5}.{:06}] ", uptime_us / 1_000_000, uptime_us % 1_000_000);
+	} else {
+		let _ = write!(RawOutput, "[    ?.??????] ");
+	}
+}
+
+/// Warns once if COM1 has stopped accepting bytes.
+/// This is called with the console locked, so the warning cannot go through the logging macros.
+fn report_serial_timeout() {
+	if environment::is_single_kernel() && unsafe { COM1.take_transmit_timeout() } {
+		let _ = write!(
+			RawOutput,
+			"[{}][WARNING] Serial port {:#X} did not become ready for transmission, output may have been lost\n",
+			percore::core_id(),
+			unsafe { COM1.port_address() }
+		);
 	}
 }
 
 pub fn output_message_byte(byte: u8) {
+	unsafe {
+		if AT_LINE_START {
+			report_serial_timeout();
+
+			if LOG_TIMESTAMPS {
+				output_timestamp();
+			}
+		}
+
+		AT_LINE_START = byte == b'\n';
+	}
+
+	output_raw_byte(byte);
+}
+
+fn output_raw_byte(byte: u8) {
 	if environment::is_single_kernel() {
 		// Output messages to the serial port and VGA screen in unikernel mode.
-		COM1.write_byte(byte);
+		unsafe { COM1.write_byte(byte); }
 
 		// vga::write_byte() checks if VGA support has been initialized,
 		// so we don't need any additional if clause around it.
@@ -106,6 +284,18 @@ pub fn boot_processor_init() {
 	::mm::init();
 	::mm::print_information();
 	environment::init();
+	configure_serial_port();
+
+	if environment::is_single_kernel() && !environment::is_uhyve() {
+		fb::init();
+	}
+
+	processor::configure_idle();
+	unsafe { LOG_TIMESTAMPS = environment::get_arg("log_timestamps").is_some(); }
+	::random::init();
+	::synch::spinlock::configure();
+
+	gdt::configure_kernel_stack_size();
 	gdt::init();
 	gdt::add_current_core();
 	idt::install();
@@ -115,20 +305,59 @@ pub fn boot_processor_init() {
 	}
 
 	irq::install();
+	mce::init();
 	irq::enable();
+
+	#[cfg(feature = "gdbstub")]
+	gdbstub::init();
+
 	processor::detect_frequency();
 	processor::print_information();
 
 	if environment::is_single_kernel() && !environment::is_uhyve() {
+		mtrr::print_information();
 		pci::init();
 		pci::print_information();
 		acpi::init();
+		iommu::init();
 	}
 
 	apic::init();
+	irq::test_dispatch();
 	scheduler::install_timer_handler();
+	time::init_wall_time();
 
-	**CPU_ONLINE.lock() += 1;
+	mark_current_cpu_online();
+}
+
+/// Returns the online CPUs, which are set up on first use.
+fn online_cpus_state() -> &'static SpinlockIrqSave<OnlineCpus> {
+	CPU_ONLINE.call_once(|| {
+		SpinlockIrqSave::new(OnlineCpus { count: unsafe { &mut cpu_online }, mask: CpuMask::new() })
+	})
+}
+
+fn mark_current_cpu_online() {
+	let mut online_cpus = online_cpus_state().lock();
+	*online_cpus.count += 1;
+	online_cpus.mask.insert(percore::core_id());
+}
+
+/// Removes the current CPU from the online CPUs before it is parked.
+/// The counter is kept, as it only tells entry.asm whether the Boot Processor has been initialized.
+pub fn mark_current_cpu_offline() {
+	online_cpus_state().lock().mask.remove(percore::core_id());
+}
+
+/// Returns the number of CPUs that are online.
+pub fn online_cpus() -> usize {
+	online_cpus_state().lock().mask.count()
+}
+
+/// Returns the set of CPUs that are online.
+/// The set is updated together with the count, so both are always consistent.
+pub fn online_cpu_mask() -> CpuMask {
+	online_cpus_state().lock().mask
 }
 
 /// Boots all available Application Processors.
@@ -136,18 +365,37 @@ pub fn boot_processor_init() {
 pub fn boot_application_processors() {
 	apic::boot_application_processors();
 	apic::print_information();
+
+	// All CPUs that have come online are waiting at the barrier now or will shortly.
+	// Release them together with the Boot Processor into their schedulers.
+	STARTUP_BARRIER.reset(online_cpus());
+	STARTUP_BARRIER.wait();
 }
 
 /// Application Processor initialization
 pub fn application_processor_init() {
 	percore::init();
 	processor::configure();
+	if environment::is_single_kernel() && !environment::is_uhyve() {
+		mtrr::configure();
+	}
 	gdt::add_current_core();
+	if environment::get_arg("dump_gdt").is_some() {
+		gdt::dump_current();
+	}
 	idt::install();
+	mce::init();
+	#[cfg(feature = "debugger")]
+	debug::synchronize();
 	apic::init_x2apic();
 	apic::init_local_apic();
+	scheduler::install_current_core_timer();
 	irq::enable();
 
 	debug!("Initialized Application Processor");
-	**CPU_ONLINE.lock() += 1;
+	mark_current_cpu_online();
+
+	// Wait until the Boot Processor has booted all Application Processors.
+	// A CPU that comes online only after its boot has timed out keeps waiting here, as it is treated as failed.
+	STARTUP_BARRIER.wait();
 }
@@ -0,0 +1,304 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Memory Type Range Registers (MTRRs).
+//!
+//! The firmware sets up the MTRRs to make RAM Write-Back and device memory Uncacheable.
+//! Page table entries can only make the memory type stricter (see paging::device_memory_flags),
+//! so on CPUs without a Page Attribute Table, an MTRR is the only way to get Write-Combining memory.
+//! See Intel Vol. 3A, 11.11 Memory Type Range Registers (MTRRs).
+
+use arch::x86_64::irq;
+use arch::x86_64::processor;
+use core::{cmp, fmt};
+use x86::shared::control_regs::*;
+use x86::shared::msr::*;
+
+
+const IA32_MTRRCAP: u32 = 0xFE;
+const IA32_MTRR_PHYSBASE0: u32 = 0x200;
+const IA32_MTRR_FIX64K_00000: u32 = 0x250;
+const IA32_MTRR_FIX16K_80000: u32 = 0x258;
+const IA32_MTRR_FIX4K_C0000: u32 = 0x268;
+const IA32_MTRR_DEF_TYPE: u32 = 0x2FF;
+
+const MTRRCAP_VARIABLE_COUNT_MASK: u64 = 0xFF;
+const MTRRCAP_WRITE_COMBINING: u64 = 1 << 10;
+
+const DEF_TYPE_MASK: u64 = 0xFF;
+const DEF_TYPE_FIXED_ENABLE: u64 = 1 << 10;
+const DEF_TYPE_ENABLE: u64 = 1 << 11;
+
+const PHYSMASK_VALID: u64 = 1 << 11;
+
+/// Maximum number of variable MTRRs set by the kernel.
+const MAX_KERNEL_RANGES: usize = 4;
+
+/// Variable MTRRs set by set_range as (index, PHYSBASE value, PHYSMASK value).
+/// Application Processors apply them as well when calling configure.
+static mut KERNEL_RANGES: [Option<(u32, u64, u64)>; MAX_KERNEL_RANGES] = [None; MAX_KERNEL_RANGES];
+
+
+/// Memory types of the MTRRs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MtrrType {
+	Uncacheable = 0,
+	WriteCombining = 1,
+	WriteThrough = 4,
+	WriteProtected = 5,
+	WriteBack = 6,
+}
+
+impl MtrrType {
+	fn from_raw(value: u64) -> Option<MtrrType> {
+		match value {
+			0 => Some(MtrrType::Uncacheable),
+			1 => Some(MtrrType::WriteCombining),
+			4 => Some(MtrrType::WriteThrough),
+			5 => Some(MtrrType::WriteProtected),
+			6 => Some(MtrrType::WriteBack),
+			_ => None,
+		}
+	}
+}
+
+impl MtrrType {
+	fn name(&self) -> &'static str {
+		match *self {
+			MtrrType::Uncacheable => "Uncacheable",
+			MtrrType::WriteCombining => "Write-Combining",
+			MtrrType::WriteThrough => "Write-Through",
+			MtrrType::WriteProtected => "Write-Protected",
+			MtrrType::WriteBack => "Write-Back",
+		}
+	}
+}
+
+impl fmt::Display for MtrrType {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.name())
+	}
+}
+
+/// Returns the name of a raw memory type read from an MTRR.
+fn type_name(value: u64) -> &'static str {
+	MtrrType::from_raw(value).map_or("Invalid", |mtrr_type| mtrr_type.name())
+}
+
+
+pub fn is_supported() -> bool {
+	processor::features().has_mtrr()
+}
+
+fn variable_count() -> u32 {
+	unsafe { (rdmsr(IA32_MTRRCAP) & MTRRCAP_VARIABLE_COUNT_MASK) as u32 }
+}
+
+/// Mask of the address bits in PHYSBASE and PHYSMASK.
+fn address_mask() -> u64 {
+	((1u64 << processor::phys_address_bits()) - 1) & !0xFFF
+}
+
+/// Updates MTRRs of the current core following the sequence in Intel Vol. 3A, 11.11.8.
+/// Caches are disabled and flushed while the MTRRs are disabled, so no stale memory type can be used.
+fn update<F: FnOnce()>(f: F) {
+	irq::without_interrupts(|| unsafe {
+		let original_cr0 = cr0();
+		let mut disabled_cr0 = original_cr0;
+		disabled_cr0.insert(CR0_CACHE_DISABLE);
+		disabled_cr0.remove(CR0_NOT_WRITE_THROUGH);
+		cr0_write(disabled_cr0);
+		asm!("wbinvd" ::: "memory" : "volatile");
+		flush_tlb();
+
+		let def_type = rdmsr(IA32_MTRR_DEF_TYPE);
+		wrmsr(IA32_MTRR_DEF_TYPE, def_type & !DEF_TYPE_ENABLE);
+
+		f();
+
+		asm!("wbinvd" ::: "memory" : "volatile");
+		flush_tlb();
+		wrmsr(IA32_MTRR_DEF_TYPE, def_type);
+		cr0_write(original_cr0);
+	});
+}
+
+/// Flushes all TLB entries including global ones by toggling CR4.PGE.
+unsafe fn flush_tlb() {
+	let original_cr4 = cr4();
+	if original_cr4.contains(CR4_ENABLE_GLOBAL_PAGES) {
+		let mut cr4_without_global = original_cr4;
+		cr4_without_global.remove(CR4_ENABLE_GLOBAL_PAGES);
+		cr4_write(cr4_without_global);
+		cr4_write(original_cr4);
+	} else {
+		cr3_write(cr3());
+	}
+}
+
+/// Sets a free variable MTRR to give the physical memory from `base` to `base + size` the memory type `mtrr_type`.
+/// `size` must be a power of two of at least 4 KiB and `base` a multiple of it.
+///
+/// All cores need the same MTRRs, so this must be called before the Application Processors are booted,
+/// which take over the range in configure.
+pub fn set_range(base: usize, size: usize, mtrr_type: MtrrType) -> Result<(), ()> {
+	if !is_supported() || !size.is_power_of_two() || size < 0x1000 || base % size != 0 {
+		return Err(());
+	}
+	if mtrr_type == MtrrType::WriteCombining && unsafe { rdmsr(IA32_MTRRCAP) } & MTRRCAP_WRITE_COMBINING == 0 {
+		return Err(());
+	}
+
+	let free_index = (0..variable_count()).find(|&index| {
+		unsafe { rdmsr(IA32_MTRR_PHYSBASE0 + 2 * index + 1) & PHYSMASK_VALID == 0 }
+	});
+	let index = match free_index {
+		Some(index) => index,
+		None => {
+			warn!("No free variable MTRR for {:#X} - {:#X}", base, base + size);
+			return Err(());
+		}
+	};
+
+	let slot = unsafe { KERNEL_RANGES.iter_mut().find(|range| range.is_none()) };
+	let slot = match slot {
+		Some(slot) => slot,
+		None => return Err(()),
+	};
+
+	let physbase = (base as u64 & address_mask()) | mtrr_type as u64;
+	let physmask = (!(size as u64 - 1) & address_mask()) | PHYSMASK_VALID;
+	*slot = Some((index, physbase, physmask));
+
+	update(|| unsafe {
+		wrmsr(IA32_MTRR_PHYSBASE0 + 2 * index, physbase);
+		wrmsr(IA32_MTRR_PHYSBASE0 + 2 * index + 1, physmask);
+	});
+
+	debug!("Set variable MTRR {} for {:#X} - {:#X} to {}", index, base, base + size, mtrr_type);
+	Ok(())
+}
+
+/// Like set_range, but for a page-aligned range of any size, which is split into as few power-of-two ranges as possible.
+/// Sets either all of the required variable MTRRs or none of them.
+pub fn set_ranges(base: usize, size: usize, mtrr_type: MtrrType) -> Result<(), ()> {
+	if !is_supported() || size == 0 || base % 0x1000 != 0 || size % 0x1000 != 0 {
+		return Err(());
+	}
+
+	let mut ranges = [(0, 0); MAX_KERNEL_RANGES];
+	let mut count = 0;
+	let mut start = base;
+	let end = base + size;
+	while start < end {
+		if count == MAX_KERNEL_RANGES {
+			return Err(());
+		}
+
+		// The largest power of two that fits into the rest of the range and is a divisor of its start address.
+		let remaining = end - start;
+		let mut range_size = 1 << (63 - remaining.leading_zeros());
+		if start != 0 {
+			range_size = cmp::min(range_size, 1 << start.trailing_zeros());
+		}
+
+		ranges[count] = (start, range_size);
+		count += 1;
+		start += range_size;
+	}
+
+	let free_mtrrs = (0..variable_count()).filter(|&index| {
+		unsafe { rdmsr(IA32_MTRR_PHYSBASE0 + 2 * index + 1) & PHYSMASK_VALID == 0 }
+	}).count();
+	let free_slots = unsafe { KERNEL_RANGES.iter().filter(|range| range.is_none()).count() };
+	if free_mtrrs < count || free_slots < count {
+		warn!("Not enough free variable MTRRs for {:#X} - {:#X}", base, end);
+		return Err(());
+	}
+
+	for &(range_base, range_size) in ranges[..count].iter() {
+		set_range(range_base, range_size, mtrr_type)?;
+	}
+
+	Ok(())
+}
+
+/// Applies the variable MTRRs set by set_range on the Boot Processor to the current Application Processor.
+pub fn configure() {
+	if !is_supported() || unsafe { KERNEL_RANGES.iter().all(|range| range.is_none()) } {
+		return;
+	}
+
+	update(|| unsafe {
+		for &(index, physbase, physmask) in KERNEL_RANGES.iter().filter_map(|range| range.as_ref()) {
+			wrmsr(IA32_MTRR_PHYSBASE0 + 2 * index, physbase);
+			wrmsr(IA32_MTRR_PHYSBASE0 + 2 * index + 1, physmask);
+		}
+	});
+}
+
+pub fn print_information() {
+	if !is_supported() {
+		return;
+	}
+
+	let def_type = unsafe { rdmsr(IA32_MTRR_DEF_TYPE) };
+
+	infoheader!(" MTRR INFORMATION ");
+	infoentry!("MTRRs", if def_type & DEF_TYPE_ENABLE > 0 { "Enabled" } else { "Disabled" });
+	infoentry!("Default Type", type_name(def_type & DEF_TYPE_MASK));
+	infoentry!("Fixed MTRRs", if def_type & DEF_TYPE_FIXED_ENABLE > 0 { "Enabled" } else { "Disabled" });
+
+	if def_type & DEF_TYPE_FIXED_ENABLE > 0 {
+		// Each fixed MTRR holds the memory types of 8 consecutive ranges below 1 MiB.
+		let fixed_mtrrs = [
+			(IA32_MTRR_FIX64K_00000, 0x0_0000, 0x1_0000),
+			(IA32_MTRR_FIX16K_80000, 0x8_0000, 0x4000),
+			(IA32_MTRR_FIX16K_80000 + 1, 0xA_0000, 0x4000),
+		];
+
+		for &(msr, start, size) in fixed_mtrrs.iter() {
+			let value = unsafe { rdmsr(msr) };
+			infoentry!("Fixed Range", "{:#07X} - {:#07X}: {:#018X}", start, start + 8 * size, value);
+		}
+
+		for i in 0..8 {
+			let start = 0xC_0000 + i * 0x8000;
+			let value = unsafe { rdmsr(IA32_MTRR_FIX4K_C0000 + i as u32) };
+			infoentry!("Fixed Range", "{:#07X} - {:#07X}: {:#018X}", start, start + 0x8000, value);
+		}
+	}
+
+	for index in 0..variable_count() {
+		let (physbase, physmask) = unsafe { (rdmsr(IA32_MTRR_PHYSBASE0 + 2 * index), rdmsr(IA32_MTRR_PHYSBASE0 + 2 * index + 1)) };
+		if physmask & PHYSMASK_VALID == 0 {
+			continue;
+		}
+
+		let base = physbase & address_mask();
+		let size = (!(physmask & address_mask()) & address_mask()) + 0x1000;
+		infoentry!("Variable Range", "{:#X} - {:#X}: {}", base, base + size, type_name(physbase & DEF_TYPE_MASK));
+	}
+
+	infofooter!();
+}
@@ -24,18 +24,18 @@
 include!(concat!(env!("CARGO_TARGET_DIR"), "/pcidata.rs"));
 
 use alloc::vec::Vec;
+use arch::x86_64::io::Port;
 use core::{fmt, u8, u32};
 use synch::spinlock::Spinlock;
-use x86::shared::io::*;
 
 
 const PCI_MAX_BUS_NUMBER: u8 = 32;
 const PCI_MAX_DEVICE_NUMBER: u8 = 32;
 
-const PCI_CONFIG_ADDRESS_PORT: u16 = 0xCF8;
+const PCI_CONFIG_ADDRESS_PORT: Port<u32> = Port::new(0xCF8);
 const PCI_CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
 
-const PCI_CONFIG_DATA_PORT: u16 = 0xCFC;
+const PCI_CONFIG_DATA_PORT: Port<u32> = Port::new(0xCFC);
 const PCI_COMMAND_BUSMASTER: u32 = 1 << 2;
 
 const PCI_ID_REGISTER:        u32 = 0x00;
@@ -169,16 +169,16 @@ impl fmt::Display for PciAdapter {
 fn read_config(bus: u8, device: u8, register: u32) -> u32 {
 	let address = PCI_CONFIG_ADDRESS_ENABLE | (bus as u32) << 16 | (device as u32) << 11 | register;
 	unsafe {
-		outl(PCI_CONFIG_ADDRESS_PORT, address);
-		inl(PCI_CONFIG_DATA_PORT)
+		PCI_CONFIG_ADDRESS_PORT.write(address);
+		PCI_CONFIG_DATA_PORT.read()
 	}
 }
 
 fn write_config(bus: u8, device: u8, register: u32, data: u32) {
 	let address = PCI_CONFIG_ADDRESS_ENABLE | (bus as u32) << 16 | (device as u32) << 11 | register;
 	unsafe {
-		outl(PCI_CONFIG_ADDRESS_PORT, address);
-		outl(PCI_CONFIG_DATA_PORT, data);
+		PCI_CONFIG_ADDRESS_PORT.write(address);
+		PCI_CONFIG_DATA_PORT.write(data);
 	}
 }
 
@@ -22,7 +22,7 @@
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use arch::x86_64::processor;
-use core::ptr;
+use core::{mem, ptr, u32};
 use scheduler::PerCoreScheduler;
 use x86::bits64::task::TaskStateSegment;
 
@@ -32,31 +32,79 @@ extern "C" {
 }
 
 
+/// Number of machine words in the scratch area of each CPU Core.
+pub const SCRATCH_WORDS: usize = 8;
+
+/// Scratch word holding the seed of the Park-Miller-Lehmer generator used by sys_rand without RDRAND.
+pub const SCRATCH_RANDOM_SEED: usize = 0;
+
 #[no_mangle]
 pub static mut PERCORE: PerCoreVariables = PerCoreVariables::new(0);
 
 
+/// Variables of a CPU Core, which are accessed relative to the GS segment.
+///
+/// Each variable is read and written by a single `mov %gs:offset` instruction without any lock,
+/// because only its own core accesses it. GS only points to the right structure after
+/// percore::init, which is called early in the Boot Processor and Application Processor
+/// initialization.
+///
+/// The layout is fixed, so `self_pointer` is always the first field at offset 0.
+#[repr(C)]
 pub struct PerCoreVariables {
+	/// Address of this structure, which lets this_core() get it from GS without knowing the address.
+	self_pointer: PerCoreVariable<*const PerCoreVariables>,
 	/// APIC ID of this CPU Core.
 	core_id: PerCoreVariable<u32>,
 	/// Scheduler for this CPU Core.
 	scheduler: PerCoreVariable<*mut PerCoreScheduler>,
+	/// ID of the task running on this CPU Core or u32::MAX before its scheduler has been added.
+	current_task_id: PerCoreVariable<u32>,
 	/// Task State Segment (TSS) allocated for this CPU Core.
 	pub tss: PerCoreVariable<*mut TaskStateSegment>,
 	/// Value returned by RDTSC/RDTSCP last time the timer ticks were updated in processor::update_timer_ticks.
 	pub last_rdtsc: PerCoreVariable<u64>,
 	/// Counted ticks of a timer with the constant frequency specified in processor::TIMER_FREQUENCY.
 	pub timer_ticks: PerCoreVariable<usize>,
+	/// Generation of the hardware breakpoints loaded into the debug registers of this CPU Core (see debug.rs).
+	pub debug_generation: PerCoreVariable<usize>,
+	/// Number of single-stepped instructions since debug::synchronize last reported them.
+	pub debug_single_steps: PerCoreVariable<usize>,
+	/// Address of the instruction single-stepped last on this CPU Core.
+	pub debug_single_step_address: PerCoreVariable<usize>,
+	/// Breakpoints (bit i for DR_i) hit since debug::synchronize last reported them.
+	pub debug_breakpoint_hits: PerCoreVariable<usize>,
+	/// Instruction pointer of the last breakpoint hit on this CPU Core.
+	pub debug_breakpoint_address: PerCoreVariable<usize>,
+	/// Vector currently raised by irq::raise_software_interrupt on this CPU Core or 0 if none.
+	pub software_interrupt: PerCoreVariable<usize>,
+	/// Scratch area for fast paths of drivers and the scheduler (see scratch and set_scratch).
+	scratch: [PerCoreVariable<usize>; SCRATCH_WORDS],
+	/// Timer ticks at which mce::poll_periodically checks the machine-check banks of this CPU Core next.
+	pub mce_next_poll: PerCoreVariable<usize>,
 }
 
 impl PerCoreVariables {
 	pub const fn new(core_id: u32) -> Self {
 		Self {
+			self_pointer: PerCoreVariable::new(0 as *const PerCoreVariables),
 			core_id: PerCoreVariable::new(core_id),
 			scheduler: PerCoreVariable::new(0 as *mut PerCoreScheduler),
+			current_task_id: PerCoreVariable::new(u32::MAX),
 			tss: PerCoreVariable::new(0 as *mut TaskStateSegment),
 			last_rdtsc: PerCoreVariable::new(0),
 			timer_ticks: PerCoreVariable::new(0),
+			debug_generation: PerCoreVariable::new(0),
+			debug_single_steps: PerCoreVariable::new(0),
+			debug_single_step_address: PerCoreVariable::new(0),
+			debug_breakpoint_hits: PerCoreVariable::new(0),
+			debug_breakpoint_address: PerCoreVariable::new(0),
+			software_interrupt: PerCoreVariable::new(0),
+			scratch: [
+				PerCoreVariable::new(0), PerCoreVariable::new(0), PerCoreVariable::new(0), PerCoreVariable::new(0),
+				PerCoreVariable::new(0), PerCoreVariable::new(0), PerCoreVariable::new(0), PerCoreVariable::new(0),
+			],
+			mce_next_poll: PerCoreVariable::new(0),
 		}
 	}
 }
@@ -77,11 +125,19 @@ impl<T> PerCoreVariable<T> {
 		Self { data: value }
 	}
 
+	/// Returns the offset of this variable inside its PerCoreVariables structure.
+	/// This is a constant for fields of PERCORE. Fields reached through this_core() belong to a structure
+	/// at another address, so their offset is relative to the address of this core's structure.
 	#[inline]
 	unsafe fn offset(&self) -> usize {
 		let base = &PERCORE as *const _ as usize;
 		let field = self as *const _ as usize;
-		field - base
+
+		if field >= base && field < base + mem::size_of::<PerCoreVariables>() {
+			field - base
+		} else {
+			field - PERCORE.self_pointer.get() as usize
+		}
 	}
 }
 
@@ -123,16 +179,73 @@ impl<T: Is32BitVariable> PerCoreVariableMethods<T> for PerCoreVariable<T> {
 }
 
 
+/// Returns the PerCoreVariables of this CPU Core.
+/// Only valid after percore::init has been called on this core.
+/// The variables must still be accessed through get and set, as they are modified through GS.
+#[inline]
+pub fn this_core() -> &'static PerCoreVariables {
+	unsafe { &*PERCORE.self_pointer.get() }
+}
+
+#[cfg(not(test))]
 #[inline]
 pub fn core_id() -> u32 {
 	unsafe { PERCORE.core_id.get() }
 }
 
+/// Unit tests run as a process on the host, where GS does not point to any PerCoreVariables.
+#[cfg(test)]
+pub fn core_id() -> u32 {
+	0
+}
+
+/// Only used for the Boot Processor, whose PerCoreVariables are created before its Local APIC ID is known.
+#[inline]
+pub fn set_core_id(core_id: u32) {
+	unsafe { PERCORE.core_id.set(core_id); }
+}
+
 #[inline]
 pub fn core_scheduler() -> &'static mut PerCoreScheduler {
 	unsafe { &mut *PERCORE.scheduler.get() }
 }
 
+/// Returns the scheduler of this CPU Core or `None` if scheduler::add_current_core has not been called yet.
+#[inline]
+pub fn try_core_scheduler() -> Option<&'static mut PerCoreScheduler> {
+	unsafe {
+		let scheduler = PERCORE.scheduler.get();
+		if scheduler.is_null() {
+			None
+		} else {
+			Some(&mut *scheduler)
+		}
+	}
+}
+
+#[inline]
+pub fn current_task_id() -> u32 {
+	unsafe { PERCORE.current_task_id.get() }
+}
+
+#[inline]
+pub fn set_current_task_id(id: u32) {
+	unsafe { PERCORE.current_task_id.set(id); }
+}
+
+/// Returns a word of the scratch area of this CPU Core.
+#[inline]
+pub fn scratch(index: usize) -> usize {
+	unsafe { PERCORE.scratch[index].get() }
+}
+
+/// Sets a word of the scratch area of this CPU Core.
+/// Code running in interrupt handlers must not share words with code running in task context.
+#[inline]
+pub fn set_scratch(index: usize, value: usize) {
+	unsafe { PERCORE.scratch[index].set(value); }
+}
+
 #[inline]
 pub fn set_core_scheduler(scheduler: *mut PerCoreScheduler) {
 	unsafe { PERCORE.scheduler.set(scheduler); }
@@ -143,5 +256,7 @@ pub fn init() {
 		// Store the address to the PerCoreVariables structure allocated for this core in GS.
 		let address = ptr::read_volatile(&current_percore_address);
 		processor::writegs(address);
+		PERCORE.self_pointer.set(address as *const PerCoreVariables);
+		debug_assert!(this_core() as *const _ as usize == address, "GS does not point to the PerCoreVariables of this core");
 	}
 }
@@ -22,13 +22,13 @@
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use arch::x86_64::idt;
+use arch::x86_64::io::Port;
 use arch::x86_64::irq::ExceptionStackFrame;
-use x86::shared::io::*;
 
-const PIC1_COMMAND_PORT: u16 = 0x20;
-const PIC1_DATA_PORT:    u16 = 0x21;
-const PIC2_COMMAND_PORT: u16 = 0xA0;
-const PIC2_DATA_PORT:    u16 = 0xA1;
+const PIC1_COMMAND_PORT: Port<u8> = Port::new(0x20);
+const PIC1_DATA_PORT:    Port<u8> = Port::new(0x21);
+const PIC2_COMMAND_PORT: Port<u8> = Port::new(0xA0);
+const PIC2_DATA_PORT:    Port<u8> = Port::new(0xA1);
 
 pub const PIC1_INTERRUPT_OFFSET: u8 = 32;
 const PIC2_INTERRUPT_OFFSET: u8 = 40;
@@ -39,15 +39,13 @@ const PIC_EOI_COMMAND:   u8  = 0x20;
 
 
 pub fn eoi(int_no: u8) {
-	unsafe {
-		// For IRQ 8-15 (mapped to interrupt numbers >= 40), we need to send an EOI to the slave PIC.
-		if int_no >= 40 {
-			outb(PIC2_COMMAND_PORT, PIC_EOI_COMMAND);
-		}
-
-		// In all cases, we need to send an EOI to the master PIC.
-		outb(PIC1_COMMAND_PORT, PIC_EOI_COMMAND);
+	// For IRQ 8-15 (mapped to interrupt numbers >= 40), we need to send an EOI to the slave PIC.
+	if int_no >= 40 {
+		unsafe { PIC2_COMMAND_PORT.write(PIC_EOI_COMMAND); }
 	}
+
+	// In all cases, we need to send an EOI to the master PIC.
+	unsafe { PIC1_COMMAND_PORT.write(PIC_EOI_COMMAND); }
 }
 
 pub fn init() {
@@ -58,24 +56,24 @@ pub fn init() {
 
 	unsafe {
 		// Reinitialize PIC1 and PIC2.
-		outb(PIC1_COMMAND_PORT, 0x11);
-		outb(PIC2_COMMAND_PORT, 0x11);
+		PIC1_COMMAND_PORT.write(0x11);
+		PIC2_COMMAND_PORT.write(0x11);
 
 		// Map PIC1 to interrupt numbers >= 32 and PIC2 to interrupt numbers >= 40.
-		outb(PIC1_DATA_PORT, PIC1_INTERRUPT_OFFSET);
-		outb(PIC2_DATA_PORT, PIC2_INTERRUPT_OFFSET);
+		PIC1_DATA_PORT.write(PIC1_INTERRUPT_OFFSET);
+		PIC2_DATA_PORT.write(PIC2_INTERRUPT_OFFSET);
 
 		// Configure PIC1 as master and PIC2 as slave.
-		outb(PIC1_DATA_PORT, 0x04);
-		outb(PIC2_DATA_PORT, 0x02);
+		PIC1_DATA_PORT.write(0x04);
+		PIC2_DATA_PORT.write(0x02);
 
 		// Start them in 8086 mode.
-		outb(PIC1_DATA_PORT, 0x01);
-		outb(PIC2_DATA_PORT, 0x01);
+		PIC1_DATA_PORT.write(0x01);
+		PIC2_DATA_PORT.write(0x01);
 
 		// Mask all interrupts on both PICs.
-		outb(PIC1_DATA_PORT, 0xFF);
-		outb(PIC2_DATA_PORT, 0xFF);
+		PIC1_DATA_PORT.write(0xFF);
+		PIC2_DATA_PORT.write(0xFF);
 	}
 }
 
@@ -88,7 +86,7 @@ extern "x86-interrupt" fn spurious_interrupt_on_slave(_stack_frame: &mut Excepti
 
 	// As this is an interrupt forwarded by the master, we have to acknowledge it on the master
 	// (but not on the slave as with all spurious interrupts).
-	unsafe { outb(PIC1_COMMAND_PORT, PIC_EOI_COMMAND); }
+	unsafe { PIC1_COMMAND_PORT.write(PIC_EOI_COMMAND); }
 }
 
 fn edit_mask(int_no: u8, insert: bool) {
@@ -96,12 +94,12 @@ fn edit_mask(int_no: u8, insert: bool) {
 	let offset = if int_no >= 40 { 40 } else { 32 };
 
 	unsafe {
-		let mask = inb(port);
+		let mask = port.read();
 
 		if insert {
-			outb(port, mask | 1 << (int_no - offset));
+			port.write(mask | 1 << (int_no - offset));
 		} else {
-			outb(port, mask & !(1 << (int_no - offset)));
+			port.write(mask & !(1 << (int_no - offset)));
 		}
 	}
 }
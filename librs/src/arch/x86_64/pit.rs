@@ -24,17 +24,18 @@
 
 #![allow(dead_code)]
 
+use arch::x86_64::io::Port;
 use arch::x86_64::pic;
-use x86::shared::io::*;
+use environment;
 
 
 const PIT_CLOCK: u64 = 1193182;
 pub const PIT_INTERRUPT_NUMBER: u8 = pic::PIC1_INTERRUPT_OFFSET + 0;
 
-const PIT_CHANNEL0_DATA_PORT: u16 = 0x40;
-const PIT_CHANNEL1_DATA_PORT: u16 = 0x41;
-const PIT_CHANNEL2_DATA_PORT: u16 = 0x42;
-const PIT_COMMAND_PORT: u16       = 0x43;
+const PIT_CHANNEL0_DATA_PORT: Port<u8> = Port::new(0x40);
+const PIT_CHANNEL1_DATA_PORT: Port<u8> = Port::new(0x41);
+const PIT_CHANNEL2_DATA_PORT: Port<u8> = Port::new(0x42);
+const PIT_COMMAND_PORT: Port<u8>       = Port::new(0x43);
 
 const PIT_BINARY_OUTPUT: u8              = 0b00000000;
 const PIT_BCD_OUTPUT: u8                 = 0b00000001;
@@ -57,18 +58,29 @@ const PIT_CHANNEL2: u8                   = 0b10000000;
 pub fn init(frequency_in_hz: u64) {
 	pic::unmask(PIT_INTERRUPT_NUMBER);
 
-	unsafe {
-		// Reset the Programmable Interval Timer (PIT).
-		outb(PIT_COMMAND_PORT, PIT_BINARY_OUTPUT | PIT_RATE_GENERATOR_MODE | PIT_LOBYTE_ACCESS | PIT_HIBYTE_ACCESS | PIT_CHANNEL0);
+	// Reset the Programmable Interval Timer (PIT).
+	unsafe { PIT_COMMAND_PORT.write(PIT_BINARY_OUTPUT | PIT_RATE_GENERATOR_MODE | PIT_LOBYTE_ACCESS | PIT_HIBYTE_ACCESS | PIT_CHANNEL0); }
 
-		// Calculate the reload value to count down (round it to the closest integer).
-		// Then transmit it as two individual bytes to the PIT.
-		let count = (PIT_CLOCK + frequency_in_hz/2) / frequency_in_hz;
-		outb(PIT_CHANNEL0_DATA_PORT, count as u8);
-		outb(PIT_CHANNEL0_DATA_PORT, (count >> 8) as u8);
+	// Calculate the reload value to count down (round it to the closest integer).
+	// Then transmit it as two individual bytes to the PIT.
+	let count = (PIT_CLOCK + frequency_in_hz/2) / frequency_in_hz;
+	unsafe {
+		PIT_CHANNEL0_DATA_PORT.write(count as u8);
+		PIT_CHANNEL0_DATA_PORT.write((count >> 8) as u8);
 	}
 }
 
 pub fn deinit() {
-	pic::mask(PIT_INTERRUPT_NUMBER);
+	// uhyve provides neither a PIT nor a PIC and terminates the guest on any access to their ports.
+	if !environment::is_uhyve() {
+		pic::mask(PIT_INTERRUPT_NUMBER);
+	}
+}
+
+/// Lets the PIT fire a periodic interrupt with the given frequency.
+/// Unlike the one-shot APIC Timer, the PIT can only check for events on each tick.
+/// Its resolution is therefore limited to the tick period (10 ms at processor::TIMER_FREQUENCY).
+pub fn set_periodic(frequency_in_hz: u64) {
+	assert!(frequency_in_hz >= 19 && frequency_in_hz <= PIT_CLOCK, "PIT frequency {} Hz is out of range", frequency_in_hz);
+	init(frequency_in_hz);
 }
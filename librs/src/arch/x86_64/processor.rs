@@ -24,14 +24,18 @@
 
 use arch::x86_64::acpi;
 use arch::x86_64::idt;
+use arch::x86_64::io::Port;
 use arch::x86_64::irq;
 use arch::x86_64::percore::*;
 use arch::x86_64::pic;
 use arch::x86_64::pit;
-use core::{fmt, u32};
-use core::sync::atomic::spin_loop_hint;
+use arch::x86_64::shutdown;
+use core::{cmp, fmt, mem};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use environment;
 use raw_cpuid::*;
+#[cfg(test)]
+use synch::once::Once;
 use x86::shared::control_regs::*;
 use x86::shared::msr::*;
 use x86::shared::time::*;
@@ -44,21 +48,56 @@ extern "C" {
 /// Timer frequency in Hz for the ticks counted in update_timer_ticks.
 pub const TIMER_FREQUENCY: usize = 100;
 
+/// CR4 bit enabling 5-level paging with 57-bit linear addresses.
+const CR4_LA57: usize = 1 << 12;
+
+/// Page Attribute Table, which maps the PAT, PCD and PWT bits of a page table entry to a memory type.
+const IA32_PAT: u32 = 0x277;
+/// PAT entries 0-3 keep their power-on memory types WB, WT, UC- and UC, so page table entries without the
+/// PAT bit behave as before. Entry 4 is changed from WB to WC, entries 5-7 keep WT, UC- and UC.
+/// See Intel Vol. 3A, Table 11-12.
+const PAT_VALUE: u64 = 0x0007_0401_0007_0406;
+
+/// XCR0 bits for the AVX-512 state components opmask (bit 5), ZMM_Hi256 (bit 6), and Hi16_ZMM (bit 7).
+const XCR0_AVX512_STATE_BITS: u64 = (1 << 5) | (1 << 6) | (1 << 7);
+
 const IA32_MISC_ENABLE_ENHANCED_SPEEDSTEP: u64 = 1 << 16;
 const IA32_MISC_ENABLE_SPEEDSTEP_LOCK: u64 = 1 << 20;
 const IA32_MISC_ENABLE_TURBO_DISABLE: u64 = 1 << 38;
 
+/// MSR reporting the core temperature read from the Digital Thermal Sensor.
+const IA32_THERM_STATUS: u32 = 0x19C;
+const IA32_THERM_STATUS_THERMAL_STATUS: u64 = 1 << 0;
+const IA32_THERM_STATUS_PROCHOT: u64 = 1 << 2;
+const IA32_THERM_STATUS_READING_VALID: u64 = 1 << 31;
+
+/// MSRs counting at the maximum non-turbo frequency (MPERF) and the actual frequency (APERF) while in C0.
+const IA32_MPERF: u32 = 0xE7;
+const IA32_APERF: u32 = 0xE8;
+
+/// MWAIT extension to treat interrupts as break events even if they are disabled.
+const MWAIT_INTERRUPTS_BREAK_EVENT: u32 = 1 << 0;
+
+/// Command port of the 8042 keyboard controller and its command to pulse the CPU reset line.
+const KEYBOARD_CONTROLLER_COMMAND_PORT: Port<u8> = Port::new(0x64);
+const KEYBOARD_CONTROLLER_PULSE_RESET_LINE: u8 = 0xFE;
+
 
 static mut CPU_FREQUENCY: CpuFrequency = CpuFrequency::new();
 static mut CPU_SPEEDSTEP: CpuSpeedStep = CpuSpeedStep::new();
-static mut PHYSICAL_ADDRESS_BITS: u8 = 0;
-static mut LINEAR_ADDRESS_BITS: u8 = 0;
+static mut FEATURES: CpuFeatures = CpuFeatures::new();
+static mut IDLE_MWAIT_HINT: Option<u32> = None;
+/// A cache line that is never written, monitored by idle cores that only want to be woken up by interrupts.
+static IDLE_MONITOR_LINE: u8 = 0;
+static mut MONITOR_MWAIT_INFO: Option<MonitorMwaitInfo> = None;
+static mut MAX_CPUID_LEAVES: [Option<u32>; 4] = [None; 4];
 static mut MEASUREMENT_TIMER_TICKS: u64 = 0;
-static mut SUPPORTS_1GIB_PAGES: bool = false;
-static mut SUPPORTS_AVX: bool = false;
-static mut SUPPORTS_RDRAND: bool = false;
-static mut SUPPORTS_X2APIC: bool = false;
+static mut SUPPORTS_AVX512: bool = false;
 static mut SUPPORTS_XSAVE: bool = false;
+static mut XSAVE_AREA_SIZE: usize = 0;
+static mut XSAVE_ENABLED_FEATURES: u64 = 0;
+static mut XSAVE_FEATURES: u64 = 0;
+static mut TOPOLOGY: Topology = Topology::new();
 static mut TIMESTAMP_FUNCTION: unsafe fn() -> u64 = get_timestamp_rdtsc;
 
 
@@ -116,6 +155,26 @@ pub struct XSaveBndcsr {
 	pub bndstatus_register: u64,
 }
 
+/// XSave Area for the AVX-512 opmask registers k0 through k7.
+#[repr(C)]
+pub struct XSaveOpmaskState {
+	pub opmask_registers: [u64; 8],
+}
+
+/// XSave Area for the upper 256 bits of the AVX-512 registers ZMM0 through ZMM15.
+#[repr(C)]
+pub struct XSaveZmmHi256State {
+	pub zmm_hi256_space: [u8; 16*32],
+}
+
+/// XSave Area for the full AVX-512 registers ZMM16 through ZMM31.
+#[repr(C)]
+pub struct XSaveHi16ZmmState {
+	pub hi16_zmm_space: [u8; 16*64],
+}
+
+/// Standard-format XSave Area with all components at the offsets defined by Intel Vol. 1, 13.4
+/// (verified against CPUID leaf 0x0D before enabling AVX-512).
 #[repr(C, align(64))]
 pub struct FPUState {
 	pub legacy_region: XSaveLegacyRegion,
@@ -124,6 +183,10 @@ pub struct FPUState {
 	pub lwp_state: XSaveLWPState,
 	pub bndregs: XSaveBndregs,
 	pub bndcsr: XSaveBndcsr,
+	pub padding: [u8; 48],
+	pub opmask_state: XSaveOpmaskState,
+	pub zmm_hi256_state: XSaveZmmHi256State,
+	pub hi16_zmm_state: XSaveHi16ZmmState,
 }
 
 impl FPUState {
@@ -171,14 +234,24 @@ impl FPUState {
 			bndcsr: XSaveBndcsr {
 				bndcfgu_register: 0,
 				bndstatus_register: 0,
-			}
+			},
+			padding: [0; 48],
+			opmask_state: XSaveOpmaskState {
+				opmask_registers: [0; 8],
+			},
+			zmm_hi256_state: XSaveZmmHi256State {
+				zmm_hi256_space: [0; 16*32],
+			},
+			hi16_zmm_state: XSaveHi16ZmmState {
+				hi16_zmm_space: [0; 16*64],
+			},
 		}
 	}
 
 	pub fn restore(&self) {
 		if supports_xsave() {
-			let bitmask = u32::MAX;
-			unsafe { asm!("xrstorq $0" :: "*m"(self as *const Self), "{eax}"(bitmask), "{edx}"(bitmask) :: "volatile"); }
+			let bitmask = xsave_enabled_features();
+			unsafe { asm!("xrstorq $0" :: "*m"(self as *const Self), "{eax}"(bitmask as u32), "{edx}"((bitmask >> 32) as u32) :: "volatile"); }
 		} else {
 			unsafe { asm!("fxrstor $0" :: "*m"(self as *const Self) :: "volatile"); }
 		}
@@ -186,8 +259,8 @@ impl FPUState {
 
 	pub fn save(&mut self) {
 		if supports_xsave() {
-			let bitmask: u32 = u32::MAX;
-			unsafe { asm!("xsaveq $0" : "=*m"(self as *mut Self) : "{eax}"(bitmask), "{edx}"(bitmask) : "memory" : "volatile"); }
+			let bitmask = xsave_enabled_features();
+			unsafe { asm!("xsaveq $0" : "=*m"(self as *mut Self) : "{eax}"(bitmask as u32), "{edx}"((bitmask >> 32) as u32) : "memory" : "volatile"); }
 		} else {
 			unsafe { asm!("fxsave $0; fnclex" : "=*m"(self as *mut Self) :: "memory" : "volatile"); }
 		}
@@ -201,6 +274,7 @@ enum CpuFrequencySources {
 	CpuIdBrandString,
 	Measurement,
 	Hypervisor,
+	BootInfo,
 }
 
 impl fmt::Display for CpuFrequencySources {
@@ -210,6 +284,7 @@ impl fmt::Display for CpuFrequencySources {
 			&CpuFrequencySources::CpuIdBrandString => write!(f, "CPUID Brand String"),
 			&CpuFrequencySources::Measurement => write!(f, "Measurement"),
 			&CpuFrequencySources::Hypervisor => write!(f, "Hypervisor"),
+			&CpuFrequencySources::BootInfo => write!(f, "Host TSC Frequency"),
 			_ => panic!("Attempted to print an invalid CPU Frequency Source"),
 		}
 	}
@@ -259,6 +334,25 @@ impl CpuFrequency {
 		Err(())
 	}
 
+	/// Uses the exact TSC frequency that newer uhyve builds pass in the BootInfo.
+	/// Only values between 100 MHz and 10 GHz are trusted.
+	fn detect_from_boot_info(&mut self) -> Result<(), ()> {
+		let khz = environment::get_tsc_frequency();
+		if khz == 0 {
+			return Err(());
+		}
+
+		if khz < 100_000 || khz > 10_000_000 {
+			warn!("Ignoring the implausible TSC frequency of {} kHz passed by the host", khz);
+			return Err(());
+		}
+
+		info!("Using the TSC frequency of {} kHz passed by the host", khz);
+		self.mhz = ((khz + 500) / 1000) as u16;
+		self.source = CpuFrequencySources::BootInfo;
+		Ok(())
+	}
+
 	unsafe fn detect_from_hypervisor(&mut self) -> Result<(), ()> {
 		if cpu_freq > 0 {
 			self.mhz = cpu_freq as u16;
@@ -301,7 +395,7 @@ impl CpuFrequency {
 				break tick;
 			}
 
-			spin_loop_hint();
+			pause();
 		};
 
 		// Count the number of CPU cycles during 3 timer ticks.
@@ -313,7 +407,7 @@ impl CpuFrequency {
 				break;
 			}
 
-			spin_loop_hint();
+			pause();
 		}
 
 		let end = get_timestamp();
@@ -332,7 +426,8 @@ impl CpuFrequency {
 
 	unsafe fn detect(&mut self) {
 		let cpuid = CpuId::new();
-		self.detect_from_hypervisor()
+		self.detect_from_boot_info()
+			.or_else(|_e| self.detect_from_hypervisor())
 			.or_else(|_e| self.detect_from_cmdline())
 			.or_else(|_e| self.detect_from_cpuid_brand_string(&cpuid))
 			.or_else(|_e| self.measure_frequency())
@@ -502,29 +597,542 @@ impl fmt::Display for CpuSpeedStep {
 }
 
 
+/// Thermal status of the current core, as reported by its Digital Thermal Sensor.
+#[derive(Clone, Copy, Debug)]
+pub struct ThermalStatus {
+	/// Distance of the core temperature to the maximum junction temperature (TjMax) in degrees Celsius.
+	pub degrees_below_tjmax: u8,
+	/// Set if the core is currently being throttled because it reached TjMax or an external PROCHOT# signal is asserted.
+	pub throttling: bool,
+}
+
+impl fmt::Display for ThermalStatus {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} degrees C below TjMax", self.degrees_below_tjmax)?;
+		if self.throttling {
+			write!(f, ", throttling")?;
+		}
+
+		Ok(())
+	}
+}
+
+
+/// Snapshot of the APERF and MPERF counters of the current core.
+///
+/// Take one sample at the start and one at the end of a measured region (on the same core) and use
+/// `FrequencySample::ratio_since` to get the ratio between effective and base frequency in between.
+#[derive(Clone, Copy, Debug)]
+pub struct FrequencySample {
+	aperf: u64,
+	mperf: u64,
+}
+
+impl FrequencySample {
+	/// Takes a sample of the current core, or returns None if the CPU has no APERF/MPERF MSRs.
+	pub fn now() -> Option<Self> {
+		if !supports_aperfmperf() {
+			return None;
+		}
+
+		unsafe {
+			Some(FrequencySample {
+				mperf: rdmsr(IA32_MPERF),
+				aperf: rdmsr(IA32_APERF),
+			})
+		}
+	}
+
+	/// Returns the ratio between effective and base frequency since the `start` sample in percent.
+	/// Values above 100 indicate Turbo Mode, values below 100 indicate throttling or a lower P-State.
+	/// Returns None if the core has not been in C0 in between.
+	pub fn ratio_since(&self, start: &FrequencySample) -> Option<u64> {
+		let aperf_delta = self.aperf.wrapping_sub(start.aperf);
+		let mperf_delta = self.mperf.wrapping_sub(start.mperf);
+		if mperf_delta == 0 {
+			return None;
+		}
+
+		Some(aperf_delta.saturating_mul(100) / mperf_delta)
+	}
+}
+
+
+/// Layout of the APIC ID, which encodes the position of a logical CPU in the CPU topology.
+///
+/// The lowest `smt_shift` bits identify an SMT thread (hyperthread) within a core, the following bits up to
+/// `package_shift` identify a core within a package, and the remaining bits identify the package.
+#[derive(Clone, Copy, Debug)]
+pub struct Topology {
+	smt_shift: u32,
+	package_shift: u32,
+	/// Set if the layout has been read from the Extended Topology leaf (0x0B) instead of the legacy leaves.
+	extended: bool,
+}
+
+impl Topology {
+	/// A topology where each logical CPU is a core of its own package.
+	const fn new() -> Self {
+		Self { smt_shift: 0, package_shift: 0, extended: false }
+	}
+
+	/// Returns the maximum number of SMT threads per core.
+	pub fn threads_per_core(&self) -> u32 {
+		1 << self.smt_shift
+	}
+
+	/// Returns the maximum number of logical CPUs per package.
+	pub fn logical_cpus_per_package(&self) -> u32 {
+		1 << self.package_shift
+	}
+
+	/// Returns the number of the SMT thread within its core for the logical CPU with the given APIC ID.
+	pub fn thread_id(&self, apic_id: u32) -> u32 {
+		apic_id & ((1 << self.smt_shift) - 1)
+	}
+
+	/// Returns the number of the core within its package for the logical CPU with the given APIC ID.
+	pub fn core_id(&self, apic_id: u32) -> u32 {
+		(apic_id & ((1 << self.package_shift) - 1)) >> self.smt_shift
+	}
+
+	/// Returns the number of the package for the logical CPU with the given APIC ID.
+	pub fn package_id(&self, apic_id: u32) -> u32 {
+		apic_id >> self.package_shift
+	}
+
+	/// Returns whether the logical CPUs with the given APIC IDs are SMT threads of the same physical core.
+	pub fn are_siblings(&self, apic_id1: u32, apic_id2: u32) -> bool {
+		(apic_id1 >> self.smt_shift) == (apic_id2 >> self.smt_shift)
+	}
+
+	/// Returns a key to sort APIC IDs by, so that each physical core of each package gets one logical CPU
+	/// before any SMT sibling is added.
+	pub fn spread_key(&self, apic_id: u32) -> (u32, u32, u32) {
+		(self.thread_id(apic_id), self.core_id(apic_id), self.package_id(apic_id))
+	}
+
+	/// Returns the number of physical cores among the logical CPUs with the given APIC IDs.
+	pub fn physical_cores(&self, apic_ids: &[u8]) -> usize {
+		apic_ids.iter().enumerate()
+			.filter(|&(i, &id)| !apic_ids[..i].iter().any(|&other| self.are_siblings(id as u32, other as u32)))
+			.count()
+	}
+}
+
+impl fmt::Display for Topology {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let source = if self.extended { "CPUID.0BH" } else { "legacy CPUID" };
+		write!(f, "{} threads per core, {} logical CPUs per package ({})", self.threads_per_core(), self.logical_cpus_per_package(), source)
+	}
+}
+
+/// Returns the number of bits required to represent `count` different values.
+fn bits_for_count(count: u32) -> u32 {
+	if count <= 1 {
+		0
+	} else {
+		32 - (count - 1).leading_zeros()
+	}
+}
+
+/// Detect the layout of the APIC ID from CPUID.
+/// Uses the Extended Topology leaf (0x0B) if available and falls back to the legacy leaves 0x01 and 0x04 otherwise.
+fn detect_topology(max_leaf: u32) -> Topology {
+	let mut topology = Topology::new();
+
+	if max_leaf >= 0xB {
+		let mut level = 0;
+
+		loop {
+			let CpuidResult { eax, ebx, ecx, .. } = cpuid(0xB, level);
+			let level_type = (ecx >> 8) & 0xFF;
+
+			// An invalid level terminates the enumeration.
+			if level_type == 0 || (ebx & 0xFFFF) == 0 {
+				break;
+			}
+
+			let shift = eax & 0x1F;
+			match level_type {
+				1 => topology.smt_shift = shift,
+				2 => topology.package_shift = shift,
+				_ => {}
+			}
+
+			topology.extended = true;
+			level += 1;
+		}
+
+		if topology.extended {
+			topology.package_shift = cmp::max(topology.package_shift, topology.smt_shift);
+			return topology;
+		}
+	}
+
+	// CPUID.01H:EDX.HTT[bit 28] indicates that CPUID.01H:EBX[23:16] holds the number of addressable
+	// logical CPUs per package.
+	let CpuidResult { ebx, edx, .. } = cpuid(1, 0);
+	if (edx & (1 << 28)) > 0 {
+		let logical_cpus = (ebx >> 16) & 0xFF;
+
+		// CPUID.04H:EAX[31:26] holds the number of addressable cores per package minus one.
+		// Without this information, assume that there are no SMT threads.
+		let cores = if max_leaf >= 4 { (cpuid(4, 0).eax >> 26) + 1 } else { logical_cpus };
+
+		topology = legacy_topology(logical_cpus, cores);
+	}
+
+	topology
+}
+
+/// Returns the layout of the APIC ID for the given number of addressable logical CPUs and cores per package,
+/// as reported by the legacy leaves 0x01 and 0x04.
+fn legacy_topology(logical_cpus: u32, cores: u32) -> Topology {
+	Topology {
+		smt_shift: bits_for_count(logical_cpus / cmp::max(cores, 1)),
+		package_shift: bits_for_count(logical_cpus),
+		extended: false,
+	}
+}
+
+/// Returns whether the CPU supports AVX-512 and can save its opmask, ZMM_Hi256, and Hi16_ZMM state components
+/// at the offsets of our FPUState structure.
+fn detect_avx512_state(max_leaf: u32) -> bool {
+	if max_leaf < 0xD {
+		return false;
+	}
+
+	if !features().has_avx512f() {
+		return false;
+	}
+
+	// CPUID.(EAX=0DH,ECX=0):EAX reports the supported user state components (bits of XCR0).
+	let CpuidResult { eax: supported_components, .. } = cpuid(0xD, 0);
+	if (supported_components as u64 & XCR0_AVX512_STATE_BITS) != XCR0_AVX512_STATE_BITS {
+		return false;
+	}
+
+	// CPUID.(EAX=0DH,ECX=i):EBX reports the offset of state component i in the standard format.
+	// These are the offsets of opmask_state, zmm_hi256_state, and hi16_zmm_state in FPUState.
+	let states = [(5, 0x440), (6, 0x480), (7, 0x680)];
+
+	for &(component, offset) in states.iter() {
+		let CpuidResult { ebx: component_offset, .. } = cpuid(0xD, component);
+		if component_offset != offset {
+			warn!("XSAVE state component {} is at offset {:#X}, expected {:#X}. Disabling AVX-512.", component, component_offset, offset);
+			return false;
+		}
+	}
+
+	true
+}
+
+/// The CPUID feature words, queried once by detect_features on the Boot Processor.
+/// Unsupported leaves are all zeros, so every feature bit in them reads as unsupported.
+#[derive(Clone, Copy, Debug)]
+pub struct CpuFeatures {
+	pub max_leaf: u32,
+	pub max_extended_leaf: u32,
+	/// CPUID.01H:ECX
+	pub leaf1_ecx: u32,
+	/// CPUID.01H:EDX
+	pub leaf1_edx: u32,
+	/// CPUID.06H:EAX
+	pub leaf6_eax: u32,
+	/// CPUID.06H:ECX
+	pub leaf6_ecx: u32,
+	/// CPUID.(EAX=07H,ECX=0):EBX
+	pub leaf7_ebx: u32,
+	/// CPUID.(EAX=07H,ECX=0):ECX
+	pub leaf7_ecx: u32,
+	/// CPUID.(EAX=07H,ECX=0):EDX
+	pub leaf7_edx: u32,
+	/// CPUID.80000001H:ECX
+	pub extended_leaf1_ecx: u32,
+	/// CPUID.80000001H:EDX
+	pub extended_leaf1_edx: u32,
+	/// CPUID.80000007H:EDX
+	pub extended_leaf7_edx: u32,
+	/// CPUID.80000008H:EAX
+	pub extended_leaf8_eax: u32,
+}
+
+impl CpuFeatures {
+	const fn new() -> Self {
+		Self {
+			max_leaf: 0,
+			max_extended_leaf: 0,
+			leaf1_ecx: 0,
+			leaf1_edx: 0,
+			leaf6_eax: 0,
+			leaf6_ecx: 0,
+			leaf7_ebx: 0,
+			leaf7_ecx: 0,
+			leaf7_edx: 0,
+			extended_leaf1_ecx: 0,
+			extended_leaf1_edx: 0,
+			extended_leaf7_edx: 0,
+			extended_leaf8_eax: 0,
+		}
+	}
+
+	fn query() -> Self {
+		let leaf1 = cpuid(1, 0);
+		let leaf6 = cpuid(6, 0);
+		let leaf7 = cpuid(7, 0);
+		let extended_leaf1 = cpuid(0x8000_0001, 0);
+
+		Self {
+			max_leaf: max_cpuid_leaf(0),
+			max_extended_leaf: max_cpuid_leaf(0x8000_0000),
+			leaf1_ecx: leaf1.ecx,
+			leaf1_edx: leaf1.edx,
+			leaf6_eax: leaf6.eax,
+			leaf6_ecx: leaf6.ecx,
+			leaf7_ebx: leaf7.ebx,
+			leaf7_ecx: leaf7.ecx,
+			leaf7_edx: leaf7.edx,
+			extended_leaf1_ecx: extended_leaf1.ecx,
+			extended_leaf1_edx: extended_leaf1.edx,
+			extended_leaf7_edx: cpuid(0x8000_0007, 0).edx,
+			extended_leaf8_eax: cpuid(0x8000_0008, 0).eax,
+		}
+	}
+
+	/// CPUID.01H:EDX.MCE[bit 7]
+	pub fn has_mce(&self) -> bool { (self.leaf1_edx & (1 << 7)) > 0 }
+	/// CPUID.01H:EDX.APIC[bit 9]
+	pub fn has_apic(&self) -> bool { (self.leaf1_edx & (1 << 9)) > 0 }
+	/// CPUID.01H:EDX.SSE2[bit 26]
+	pub fn has_sse2(&self) -> bool { (self.leaf1_edx & (1 << 26)) > 0 }
+	/// CPUID.01H:EDX.PAT[bit 16]
+	pub fn has_pat(&self) -> bool { (self.leaf1_edx & (1 << 16)) > 0 }
+	/// CPUID.01H:EDX.MTRR[bit 12]
+	pub fn has_mtrr(&self) -> bool { (self.leaf1_edx & (1 << 12)) > 0 }
+	/// CPUID.01H:EDX.MCA[bit 14]
+	pub fn has_mca(&self) -> bool { (self.leaf1_edx & (1 << 14)) > 0 }
+	/// CPUID.01H:ECX.MONITOR[bit 3]
+	pub fn has_monitor_mwait(&self) -> bool { (self.leaf1_ecx & (1 << 3)) > 0 }
+	/// CPUID.01H:ECX.EIST[bit 7]
+	pub fn has_eist(&self) -> bool { (self.leaf1_ecx & (1 << 7)) > 0 }
+	/// CPUID.01H:ECX.x2APIC[bit 21]
+	pub fn has_x2apic(&self) -> bool { (self.leaf1_ecx & (1 << 21)) > 0 }
+	/// CPUID.01H:ECX.XSAVE[bit 26]
+	pub fn has_xsave(&self) -> bool { (self.leaf1_ecx & (1 << 26)) > 0 }
+	/// CPUID.01H:ECX.AVX[bit 28]
+	pub fn has_avx(&self) -> bool { (self.leaf1_ecx & (1 << 28)) > 0 }
+	/// CPUID.01H:ECX.RDRAND[bit 30]
+	pub fn has_rdrand(&self) -> bool { (self.leaf1_ecx & (1 << 30)) > 0 }
+	/// CPUID.01H:ECX[bit 31] is set by hypervisors to indicate that we are running virtualized.
+	pub fn is_hypervisor(&self) -> bool { (self.leaf1_ecx & (1 << 31)) > 0 }
+	/// CPUID.06H:EAX.DTS[bit 0]
+	pub fn has_dts(&self) -> bool { (self.leaf6_eax & (1 << 0)) > 0 }
+	/// CPUID.06H:EAX.ARAT[bit 2] indicates a Local APIC Timer that keeps running in deep C-states.
+	pub fn has_arat(&self) -> bool { (self.leaf6_eax & (1 << 2)) > 0 }
+	/// CPUID.06H:ECX[bit 0] indicates the APERF and MPERF MSRs.
+	pub fn has_aperfmperf(&self) -> bool { (self.leaf6_ecx & (1 << 0)) > 0 }
+	/// CPUID.07H:EBX.AVX512F[bit 16]
+	pub fn has_avx512f(&self) -> bool { (self.leaf7_ebx & (1 << 16)) > 0 }
+	/// CPUID.07H:ECX.LA57[bit 16]
+	pub fn has_la57(&self) -> bool { (self.leaf7_ecx & (1 << 16)) > 0 }
+	/// CPUID.80000001H:EDX.Page1GB[bit 26]
+	pub fn has_1gib_pages(&self) -> bool { (self.extended_leaf1_edx & (1 << 26)) > 0 }
+	/// CPUID.80000001H:EDX.RDTSCP[bit 27]
+	pub fn has_rdtscp(&self) -> bool { (self.extended_leaf1_edx & (1 << 27)) > 0 }
+	/// CPUID.80000007H:EDX[bit 8] indicates a TSC running at a constant rate in all P-, C- and T-states.
+	pub fn has_invariant_tsc(&self) -> bool { (self.extended_leaf7_edx & (1 << 8)) > 0 }
+	/// CPUID.80000008H:EAX[7:0]
+	pub fn physical_address_bits(&self) -> u8 { self.extended_leaf8_eax as u8 }
+	/// CPUID.80000008H:EAX[15:8]
+	pub fn linear_address_bits(&self) -> u8 { (self.extended_leaf8_eax >> 8) as u8 }
+}
+
+/// MONITOR/MWAIT capabilities reported by CPUID.05H.
+#[derive(Clone, Copy, Debug)]
+pub struct MonitorMwaitInfo {
+	/// Smallest monitor-line size in bytes (CPUID.05H:EAX[15:0]).
+	pub smallest_monitor_line_size: u16,
+	/// Largest monitor-line size in bytes (CPUID.05H:EBX[15:0]).
+	pub largest_monitor_line_size: u16,
+	/// Whether interrupts wake up MWAIT even when they are disabled (CPUID.05H:ECX[bit 1]).
+	pub interrupts_break_event: bool,
+	/// Number of MWAIT sub-states supported for C0 to C7 (CPUID.05H:EDX, 4 bits each).
+	pub substates: [u8; 8],
+}
+
+impl MonitorMwaitInfo {
+	fn query() -> Self {
+		let CpuidResult { eax, ebx, ecx, edx } = cpuid(5, 0);
+		let mut substates = [0; 8];
+		for (i, substate) in substates.iter_mut().enumerate() {
+			*substate = ((edx >> (i * 4)) & 0xF) as u8;
+		}
+
+		Self {
+			smallest_monitor_line_size: eax as u16,
+			largest_monitor_line_size: ebx as u16,
+			// CPUID.05H:ECX[bit 0] indicates that the MWAIT extensions in ECX[bit 1] are enumerated.
+			interrupts_break_event: (ecx & 0b11) == 0b11,
+			substates: substates,
+		}
+	}
+
+	/// Returns the MWAIT hint for the deepest C-state that is safe for idling, if any.
+	/// Without an always running APIC timer, C-states deeper than C1 may stop the tick and are skipped.
+	pub fn idle_hint(&self) -> Option<u32> {
+		let deepest_cstate = if features().has_arat() { 7 } else { 1 };
+
+		for cstate in (1..deepest_cstate + 1).rev() {
+			if self.substates[cstate] > 0 {
+				// EAX[7:4] selects the C-state minus one, EAX[3:0] the sub-state.
+				return Some((((cstate - 1) as u32) << 4) | (self.substates[cstate] - 1) as u32);
+			}
+		}
+
+		None
+	}
+}
+
+/// Registers returned by the CPUID instruction.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CpuidResult {
+	pub eax: u32,
+	pub ebx: u32,
+	pub ecx: u32,
+	pub edx: u32,
+}
+
+/// Execute the CPUID instruction for the given leaf and subleaf without checking whether the leaf is supported.
+#[inline]
+fn cpuid_unchecked(leaf: u32, subleaf: u32) -> CpuidResult {
+	let eax: u32;
+	let ebx: u32;
+	let ecx: u32;
+	let edx: u32;
+
+	unsafe {
+		asm!("cpuid"
+			: "={eax}"(eax), "={ebx}"(ebx), "={ecx}"(ecx), "={edx}"(edx)
+			: "{eax}"(leaf), "{ecx}"(subleaf)
+			:: "volatile");
+	}
+
+	CpuidResult { eax: eax, ebx: ebx, ecx: ecx, edx: edx }
+}
+
+/// Returns the maximum supported CPUID leaf in the range of the given leaf, which is one of
+/// the basic (0x0), hypervisor (0x4000_0000), extended (0x8000_0000), or Centaur (0xC000_0000) leaves.
+/// The value is returned as reported in EAX, which is below the base of the range if the CPU does not implement it.
+/// The result is cached, because CPUID traps into the hypervisor when running virtualized.
+pub fn max_cpuid_leaf(leaf: u32) -> u32 {
+	let base = leaf & 0xC000_0000;
+	let index = (leaf >> 30) as usize;
+
+	unsafe {
+		if let Some(max_leaf) = MAX_CPUID_LEAVES[index] {
+			return max_leaf;
+		}
+
+		// CPUs that don't implement a range return data of the highest basic leaf instead, which is usually below the base.
+		// Then even the base leaf counts as unsupported in cpuid.
+		let max_leaf = cpuid_unchecked(base, 0).eax;
+		MAX_CPUID_LEAVES[index] = Some(max_leaf);
+		max_leaf
+	}
+}
+
+/// Execute the CPUID instruction for the given leaf and subleaf.
+/// Unsupported leaves return all zeros instead of the data of the highest supported basic leaf,
+/// so no feature bit is ever misread from an unrelated leaf.
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+	if leaf > max_cpuid_leaf(leaf) {
+		return CpuidResult::default();
+	}
+
+	cpuid_unchecked(leaf, subleaf)
+}
+
 pub fn detect_features() {
-	// Detect CPU features
-	let cpuid = CpuId::new();
-	let feature_info = cpuid.get_feature_info().expect("CPUID Feature Info not available!");
-	let extended_function_info = cpuid.get_extended_function_info().expect("CPUID Extended Function Info not available!");
+	unsafe {
+		FEATURES = CpuFeatures::query();
+	}
+
+	let features = features();
+	assert!(features.physical_address_bits() > 0 && features.linear_address_bits() > 0, "CPUID Address Sizes not available!");
 
 	unsafe {
-		PHYSICAL_ADDRESS_BITS = extended_function_info.physical_address_bits().expect("CPUID Physical Address Bits not available!");
-		LINEAR_ADDRESS_BITS = extended_function_info.linear_address_bits().expect("CPUID Linear Address Bits not available!");
-		SUPPORTS_1GIB_PAGES = extended_function_info.has_1gib_pages();
-		SUPPORTS_AVX = feature_info.has_avx();
-		SUPPORTS_RDRAND = feature_info.has_rdrand();
-		SUPPORTS_X2APIC = feature_info.has_x2apic();
-		SUPPORTS_XSAVE = feature_info.has_xsave();
-
-		if extended_function_info.has_rdtscp() {
+		TOPOLOGY = detect_topology(features.max_leaf);
+		SUPPORTS_AVX512 = features.has_avx() && features.has_xsave() && detect_avx512_state(features.max_leaf);
+		SUPPORTS_XSAVE = features.has_xsave();
+
+		if SUPPORTS_XSAVE && features.max_leaf >= 0xD {
+			// CPUID.(EAX=0DH,ECX=0):EDX:EAX reports the state components that may be enabled in XCR0.
+			let CpuidResult { eax, edx, .. } = cpuid(0xD, 0);
+			XSAVE_FEATURES = ((edx as u64) << 32) | eax as u64;
+		} else {
+			SUPPORTS_XSAVE = false;
+		}
+
+		if features.has_rdtscp() {
 			TIMESTAMP_FUNCTION = get_timestamp_rdtscp;
 		}
 
-		CPU_SPEEDSTEP.detect_features(&cpuid);
+		if features.has_monitor_mwait() && features.max_leaf >= 5 {
+			MONITOR_MWAIT_INFO = Some(MonitorMwaitInfo::query());
+		}
+
+		CPU_SPEEDSTEP.detect_features(&CpuId::new());
 	}
 }
 
+/// Returns the MONITOR/MWAIT capabilities or None if the CPU does not support these instructions.
+#[inline]
+pub fn monitor_mwait_info() -> Option<MonitorMwaitInfo> {
+	unsafe { MONITOR_MWAIT_INFO }
+}
+
+/// Chooses between MWAIT and HLT for idle cores, using the idle=mwait|halt command-line option if given.
+/// Many hypervisors advertise MWAIT, but trap it or emulate it as a NOP, which turns idling into busy-waiting.
+/// Hence, MWAIT is only used by default on bare metal.
+pub fn configure_idle() {
+	let use_mwait = match environment::get_arg("idle") {
+		None => !features().is_hypervisor(),
+		Some("mwait") => true,
+		Some("halt") => false,
+		Some(value) => {
+			warn!("Invalid idle command-line option \"{}\", using HLT", value);
+			false
+		}
+	};
+
+	if use_mwait {
+		// Idle cores wait with interrupts disabled, so interrupts must be able to end MWAIT.
+		let hint = monitor_mwait_info().and_then(|info| if info.interrupts_break_event { info.idle_hint() } else { None });
+		if hint.is_none() && environment::get_arg("idle").is_some() {
+			warn!("MWAIT is not usable for idling on this CPU, using HLT");
+		}
+
+		unsafe { IDLE_MWAIT_HINT = hint; }
+	}
+}
+
+/// Returns the CPUID feature words cached by detect_features.
+/// Reading them is cheap, unlike CPUID, which traps into the hypervisor when running virtualized.
+#[cfg(not(test))]
+#[inline]
+pub fn features() -> &'static CpuFeatures {
+	unsafe { &FEATURES }
+}
+
+/// Unit tests run as a process on the host, which never calls detect_features, as it also reads MSRs.
+/// They get the feature words of the host CPU instead.
+#[cfg(test)]
+pub fn features() -> &'static CpuFeatures {
+	static HOST_FEATURES: Once<CpuFeatures> = Once::new();
+	HOST_FEATURES.call_once(CpuFeatures::query)
+}
+
 pub fn configure() {
 	//
 	// CR0 CONFIGURATION
@@ -544,6 +1152,13 @@ pub fn configure() {
 	// Enable caching.
 	cr0.remove(CR0_CACHE_DISABLE | CR0_NOT_WRITE_THROUGH);
 
+	// Let the CPU check the alignment of data accesses, which raises an #AC exception for misaligned ones.
+	// Together with RFLAGS.AC, this makes misaligned pointers visible that would only fail on stricter hardware.
+	// Note that the CPU only checks accesses at privilege level 3 and that legitimate unaligned accesses
+	// (e.g. MOVUPS or packed structures) trip it as well.
+	#[cfg(feature = "alignment_check")]
+	cr0.insert(CR0_ALIGNMENT_MASK);
+
 	unsafe { cr0_write(cr0); }
 
 	//
@@ -553,6 +1168,7 @@ pub fn configure() {
 
 	// Enable Machine Check Exceptions.
 	// No need to check for support here, all x86-64 CPUs support it.
+	// The machine-check banks are enabled later by mce::init.
 	cr4.insert(CR4_ENABLE_MACHINE_CHECK);
 
 	// Enable full SSE support and indicates that the OS saves SSE context using FXSR.
@@ -570,21 +1186,50 @@ pub fn configure() {
 	// XCR0 CONFIGURATION
 	//
 	if supports_xsave() {
-		// Enable saving the context for all known vector extensions.
+		// Enable saving the context for all known vector extensions that are also reported by CPUID.
+		// Enabling a component the CPU (or hypervisor) doesn't support raises a #GP.
 		// Must happen after CR4_ENABLE_OS_XSAVE has been set.
 		let mut xcr0 = unsafe { xcr0() };
+		let legacy_state_bits = (XCR0_FPU_MMX_STATE | XCR0_SSE_STATE).bits() as u64;
+		assert!(xsave_features() & legacy_state_bits == legacy_state_bits, "XSAVE does not support the x87 and SSE state components");
 		xcr0.insert(XCR0_FPU_MMX_STATE | XCR0_SSE_STATE);
 
-		if supports_avx() {
+		if supports_avx() && (xsave_features() & XCR0_AVX_STATE.bits() as u64) > 0 {
 			xcr0.insert(XCR0_AVX_STATE);
 		}
 
+		if supports_avx512() {
+			xcr0.insert(XCR0_AVX512_OPMASK_STATE | XCR0_AVX512_ZMM_HI256_STATE | XCR0_AVX512_HI16_ZMM_STATE);
+		}
+
 		unsafe { xcr0_write(xcr0); }
+
+		// CPUID.(EAX=0DH,ECX=0):EBX reports the XSave Area size required for the components enabled in XCR0.
+		let CpuidResult { ebx: required_size, .. } = cpuid(0xD, 0);
+		assert!(required_size as usize <= mem::size_of::<FPUState>(), "XSave Area requires {} bytes, but FPUState only has {} bytes", required_size, mem::size_of::<FPUState>());
+
+		unsafe {
+			XSAVE_ENABLED_FEATURES = xcr0().bits() as u64;
+			XSAVE_AREA_SIZE = required_size as usize;
+		}
+	}
+
+	//
+	// PAT CONFIGURATION
+	//
+	if supports_pat() {
+		// Provide a Write-Combining memory type for framebuffers (see paging::device_memory_flags).
+		// No mapping uses PAT entry 4 yet, so it can be changed without flushing caches and TLBs.
+		unsafe { wrmsr(IA32_PAT, PAT_VALUE); }
 	}
 
 	// Initialize the FS register, which is later used for Thread-Local Storage.
 	writefs(0);
 
+	// New tasks get RFLAGS.AC from their initial state, but the code running on this core right now needs it as well.
+	#[cfg(feature = "alignment_check")]
+	unsafe { asm!("pushfq; orq $$0x40000, (%rsp); popfq" ::: "memory" : "volatile"); }
+
 	//
 	// ENHANCED INTEL SPEEDSTEP CONFIGURATION
 	//
@@ -616,14 +1261,60 @@ pub fn print_information() {
 	}
 
 	infoentry!("Features", feature_printer);
-	infoentry!("Physical Address Width", "{} bits", get_physical_address_bits());
-	infoentry!("Linear Address Width", "{} bits", get_linear_address_bits());
+	infoentry!("Physical Address Width", "{} bits", phys_address_bits());
+	infoentry!("Linear Address Width", "{} bits", virt_address_bits());
+	infoentry!("5-Level Paging", if is_la57_enabled() { "Enabled" } else if supports_la57() { "Supported, but disabled" } else { "Not Supported" });
 	infoentry!("Supports 1GiB Pages", if supports_1gib_pages() { "Yes" } else { "No" });
+	infoentry!("Invariant TSC", if supports_invariant_tsc() { "Yes" } else { "No" });
+	if let Some(ratio) = current_frequency_ratio() {
+		infoentry!("Effective Frequency", "{} MHz ({}% of base frequency)", get_frequency() as u64 * ratio / 100, ratio);
+	}
+	if let Some(hint) = unsafe { IDLE_MWAIT_HINT } {
+		infoentry!("Idle Instruction", "MWAIT (C{}, sub-state {})", (hint >> 4) + 1, hint & 0xF);
+	} else {
+		infoentry!("Idle Instruction", "HLT");
+	}
+	if supports_xsave() {
+		infoentry!("XSAVE Components", "{:#X} enabled of {:#X} supported, {} bytes", xsave_enabled_features(), xsave_features(), xsave_area_size());
+	}
+	infoentry!("Topology", topology());
+	if let Some(status) = thermal_status() {
+		infoentry!("Thermal Status", status);
+	}
 	infofooter!();
 }
 
+/// Reads the Digital Thermal Sensor of the current core.
+/// Returns None if the CPU has no DTS (as is the case under most hypervisors) or the reading is invalid.
+///
+/// Every call reads an MSR, so this should be polled infrequently (e.g. once per second) and not in hot paths.
+pub fn thermal_status() -> Option<ThermalStatus> {
+	if !supports_dts() {
+		return None;
+	}
+
+	let status = unsafe { rdmsr(IA32_THERM_STATUS) };
+	if (status & IA32_THERM_STATUS_READING_VALID) == 0 {
+		return None;
+	}
+
+	Some(ThermalStatus {
+		degrees_below_tjmax: ((status >> 16) & 0x7F) as u8,
+		throttling: (status & (IA32_THERM_STATUS_THERMAL_STATUS | IA32_THERM_STATUS_PROCHOT)) > 0,
+	})
+}
+
+/// Returns the current ratio between effective and base frequency of this core in percent.
+/// This busy-waits for 1 millisecond between two APERF/MPERF samples.
+/// Returns None if the CPU has no APERF/MPERF MSRs (as is the case under most hypervisors).
+pub fn current_frequency_ratio() -> Option<u64> {
+	let start = FrequencySample::now()?;
+	udelay(1000);
+	FrequencySample::now()?.ratio_since(&start)
+}
+
 pub fn generate_random_number() -> Option<u32> {
-	if unsafe { SUPPORTS_RDRAND } {
+	if features().has_rdrand() {
 		let value: u32;
 		unsafe { asm!("rdrand $0" : "=r"(value) ::: "volatile"); }
 		Some(value)
@@ -632,29 +1323,92 @@ pub fn generate_random_number() -> Option<u32> {
 	}
 }
 
+/// Width of linear (virtual) addresses supported by the CPU, as reported by CPUID.
 #[inline]
-pub fn get_linear_address_bits() -> u8 {
-	unsafe { LINEAR_ADDRESS_BITS }
+pub fn virt_address_bits() -> u8 {
+	features().linear_address_bits()
 }
 
+/// Width of physical addresses supported by the CPU, as reported by CPUID.
 #[inline]
-pub fn get_physical_address_bits() -> u8 {
-	unsafe { PHYSICAL_ADDRESS_BITS }
+pub fn phys_address_bits() -> u8 {
+	features().physical_address_bits()
 }
 
 #[inline]
 pub fn supports_1gib_pages() -> bool {
-	unsafe { SUPPORTS_1GIB_PAGES }
+	features().has_1gib_pages()
+}
+
+#[inline]
+pub fn supports_apic() -> bool {
+	features().has_apic()
 }
 
 #[inline]
 pub fn supports_avx() -> bool {
-	unsafe { SUPPORTS_AVX }
+	features().has_avx()
+}
+
+/// Whether the CPU has the APERF and MPERF MSRs to determine the effective frequency.
+#[inline]
+pub fn supports_aperfmperf() -> bool {
+	features().has_aperfmperf()
+}
+
+/// Returns whether AVX-512 is supported and its register state is saved on context switches.
+#[inline]
+pub fn supports_avx512() -> bool {
+	unsafe { SUPPORTS_AVX512 }
+}
+
+/// Whether the CPU supports SSE2, which includes the non-temporal MOVNTI instruction.
+#[inline]
+pub fn supports_sse2() -> bool {
+	features().has_sse2()
+}
+
+/// Whether the CPU has a Page Attribute Table, which is needed for Write-Combining mappings.
+#[inline]
+pub fn supports_pat() -> bool {
+	features().has_pat()
+}
+
+/// Whether the CPU has a Digital Thermal Sensor.
+#[inline]
+pub fn supports_dts() -> bool {
+	features().has_dts()
+}
+
+/// Whether the Time Stamp Counter runs at the constant rate returned by get_frequency, independent of
+/// power management states. Otherwise, TSC based delays and timestamps may be inaccurate.
+#[inline]
+pub fn supports_invariant_tsc() -> bool {
+	features().has_invariant_tsc()
+}
+
+/// Whether the CPU supports 5-level paging (57-bit linear addresses).
+#[inline]
+pub fn supports_la57() -> bool {
+	features().has_la57()
+}
+
+/// Whether 5-level paging is active, which can only be set up before entering 64-bit mode.
+#[inline]
+pub fn is_la57_enabled() -> bool {
+	unsafe { cr4().bits() & CR4_LA57 > 0 }
+}
+
+/// Returns the layout of the APIC ID detected on the boot processor.
+/// Use it to find out which logical CPUs are SMT threads of the same core or belong to the same package.
+#[inline]
+pub fn topology() -> Topology {
+	unsafe { TOPOLOGY }
 }
 
 #[inline]
 pub fn supports_x2apic() -> bool {
-	unsafe { SUPPORTS_X2APIC }
+	features().has_x2apic()
 }
 
 #[inline]
@@ -662,6 +1416,26 @@ pub fn supports_xsave() -> bool {
 	unsafe { SUPPORTS_XSAVE }
 }
 
+/// Bitmask of the XSAVE state components supported by the CPU, in the format of XCR0.
+/// Zero if XSAVE is not supported.
+#[inline]
+pub fn xsave_features() -> u64 {
+	unsafe { XSAVE_FEATURES }
+}
+
+/// Bitmask of the XSAVE state components enabled in XCR0 and saved on context switches.
+#[inline]
+pub fn xsave_enabled_features() -> u64 {
+	unsafe { XSAVE_ENABLED_FEATURES }
+}
+
+/// Size in bytes of the XSave Area for the enabled state components.
+/// Zero if XSAVE is not supported or configure() has not been called yet.
+#[inline]
+pub fn xsave_area_size() -> usize {
+	unsafe { XSAVE_AREA_SIZE }
+}
+
 /// Search the most significant bit
 #[inline(always)]
 pub fn msb(value: u64) -> Option<u64> {
@@ -674,6 +1448,16 @@ pub fn msb(value: u64) -> Option<u64> {
 	}
 }
 
+/// Hint to the CPU that we are busy-waiting (PAUSE instruction).
+/// This saves power, frees execution resources for the SMT sibling, and lets a hypervisor detect spinning vCPUs.
+/// Call it in every loop polling memory or hardware.
+#[inline(always)]
+pub fn pause() {
+	unsafe {
+		asm!("pause" :::: "volatile");
+	}
+}
+
 /// The halt function stops the processor until the next interrupt arrives
 pub fn halt() {
 	unsafe {
@@ -681,9 +1465,94 @@ pub fn halt() {
 	}
 }
 
+/// Enable interrupts and wait for the next one (STI; HLT), returning after it has been handled.
+/// Interrupts stay enabled afterwards.
+///
+/// STI only takes effect after the following instruction, so no interrupt can be handled between STI and HLT
+/// (see https://lists.freebsd.org/pipermail/freebsd-current/2004-June/029369.html).
+/// Called with interrupts disabled after checking a wakeup condition, this cannot miss a wakeup interrupt arriving
+/// in between, e.g. when another CPU calls wakeup_core right when we decide to wait.
+#[inline]
+pub fn wait_for_interrupt() {
+	unsafe {
+		asm!("sti; hlt" :::: "volatile");
+	}
+}
+
+/// Arm address monitoring for the cache line containing `address` (MONITOR instruction).
+/// Only call it if monitor_mwait_info returns Some.
+#[inline]
+pub unsafe fn monitor(address: *const u8) {
+	asm!("monitor" :: "{rax}"(address), "{ecx}"(0), "{edx}"(0) :: "volatile");
+}
+
+/// Wait for a write to the monitored cache line or an interrupt (MWAIT instruction).
+/// Only call it if monitor_mwait_info returns Some.
+#[inline]
+pub unsafe fn mwait(hint: u32, extensions: u32) {
+	asm!("mwait" :: "{eax}"(hint), "{ecx}"(extensions) :: "volatile");
+}
+
+/// Enable interrupts and wait for the next one, using MWAIT with the C-state chosen by configure_idle or HLT.
+/// Must be called with interrupts disabled, so that a wakeup interrupt arriving before the CPU sleeps is not missed.
+pub fn idle() {
+	debug_assert!(!irq::interrupts_enabled(), "idle must be called with interrupts disabled");
+
+	if let Some(hint) = unsafe { IDLE_MWAIT_HINT } {
+		// A pending interrupt ends MWAIT even though interrupts are still disabled, and is handled after enabling them.
+		unsafe {
+			monitor(&IDLE_MONITOR_LINE);
+			mwait(hint, MWAIT_INTERRUPTS_BREAK_EVENT);
+		}
+
+		irq::enable();
+	} else {
+		wait_for_interrupt();
+	}
+}
+
+/// Wait until `value` may have changed from `current`, e.g. in a lock waiter.
+/// This monitors the cache line of `value` if MWAIT is used for idling and executes PAUSE otherwise.
+/// The function may also return without a change, so callers have to check `value` again.
+pub fn wait_for_change(value: &AtomicUsize, current: usize) {
+	if unsafe { IDLE_MWAIT_HINT.is_some() } {
+		unsafe { monitor(value as *const AtomicUsize as *const u8); }
+
+		// Check again after arming the monitor, as a write in between would not end MWAIT.
+		// Stay in C1 to resume quickly.
+		if value.load(Ordering::Acquire) == current {
+			unsafe { mwait(0, 0); }
+		}
+	} else {
+		pause();
+	}
+}
+
+/// Print a snapshot of the stack, flags and control registers of the current CPU core.
+/// This neither allocates memory nor takes any lock except for the console lock.
+pub fn print_registers() {
+	let rsp: u64;
+	let rbp: u64;
+	unsafe {
+		asm!("mov %rsp, $0" : "=r"(rsp) ::: "volatile");
+		asm!("mov %rbp, $0" : "=r"(rbp) ::: "volatile");
+	}
+
+	println!("RSP = {:#016X}, RBP = {:#016X}", rsp, rbp);
+	println!("RFLAGS = {:?}", ::x86::shared::flags::flags());
+
+	unsafe {
+		println!("CR0 = {:?}", cr0());
+		println!("CR2 = {:#016X}, CR3 = {:#016X}", cr2(), cr3());
+		println!("CR4 = {:?}", cr4());
+	}
+}
+
 /// Shutdown the system
 pub fn shutdown() -> ! {
 	info!("Shutting down system");
+	shutdown::quiesce_all_cores();
+	::arch::x86_64::flush_message_output();
 	acpi::poweroff();
 
 	loop {
@@ -691,6 +1560,21 @@ pub fn shutdown() -> ! {
 	}
 }
 
+/// Reboot the system
+/// Tries the ACPI reset register first and falls back to pulsing the reset line through the keyboard controller.
+pub fn reboot() -> ! {
+	info!("Rebooting system");
+	shutdown::quiesce_all_cores();
+	::arch::x86_64::flush_message_output();
+	acpi::reboot();
+
+	unsafe { KEYBOARD_CONTROLLER_COMMAND_PORT.write(KEYBOARD_CONTROLLER_PULSE_RESET_LINE); }
+
+	loop {
+		halt();
+	}
+}
+
 pub fn update_timer_ticks() -> usize {
 	unsafe {
 		let current_cycles = get_timestamp();
@@ -744,10 +1628,113 @@ unsafe fn get_timestamp_rdtscp() -> u64 {
 }
 
 /// Delay execution by the given number of microseconds using busy-waiting.
+///
+/// This only relies on the TSC and the frequency from detect_frequency, so it also works with
+/// interrupts disabled and before the scheduler is running.
+/// The delay is only accurate with an invariant TSC (see supports_invariant_tsc).
+/// As it keeps the core busy, use it for short delays during device initialization only.
 #[inline]
 pub fn udelay(usecs: u64) {
-	let end = get_timestamp() + get_frequency() as u64 * usecs;
+	ndelay(usecs * 1000);
+}
+
+/// Delay execution by the given number of nanoseconds using busy-waiting.
+/// The delay is rounded up to the next TSC cycle, otherwise the same restrictions as for udelay apply.
+pub fn ndelay(nsecs: u64) {
+	// get_frequency returns MHz, so this is the number of TSC cycles rounded up.
+	let end = get_timestamp() + (get_frequency() as u64 * nsecs + 999) / 1000;
 	while get_timestamp() < end {
-		spin_loop_hint();
+		pause();
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Layout reported by CPUID.0BH for QEMU with "-smp 8,sockets=2,cores=2,threads=2".
+	const QEMU_2X2X2: Topology = Topology { smt_shift: 1, package_shift: 2, extended: true };
+
+	#[test]
+	fn apic_ids_are_decoded() {
+		assert_eq!(QEMU_2X2X2.threads_per_core(), 2);
+		assert_eq!(QEMU_2X2X2.logical_cpus_per_package(), 4);
+
+		// APIC ID 5 is the second thread of the first core of the second package.
+		assert_eq!(QEMU_2X2X2.thread_id(5), 1);
+		assert_eq!(QEMU_2X2X2.core_id(5), 0);
+		assert_eq!(QEMU_2X2X2.package_id(5), 1);
+
+		assert!(QEMU_2X2X2.are_siblings(4, 5));
+		assert!(!QEMU_2X2X2.are_siblings(5, 6));
+		assert!(!QEMU_2X2X2.are_siblings(1, 5));
+	}
+
+	#[test]
+	fn spread_order_fills_cores_before_siblings() {
+		let mut apic_ids: Vec<u8> = (0..8).collect();
+		apic_ids.sort_unstable_by_key(|apic_id| QEMU_2X2X2.spread_key(*apic_id as u32));
+		assert_eq!(apic_ids, vec![0, 4, 2, 6, 1, 5, 3, 7]);
+
+		assert_eq!(QEMU_2X2X2.physical_cores(&apic_ids), 4);
+		assert_eq!(QEMU_2X2X2.physical_cores(&apic_ids[..4]), 4);
+		assert_eq!(QEMU_2X2X2.physical_cores(&[0, 1, 4]), 2);
+	}
+
+	#[test]
+	fn frequency_ratio_handles_wrapping_counters() {
+		let start = FrequencySample { aperf: 1000, mperf: 2000 };
+		assert_eq!(FrequencySample { aperf: 1150, mperf: 2100 }.ratio_since(&start), Some(150));
+		assert_eq!(FrequencySample { aperf: 1080, mperf: 2100 }.ratio_since(&start), Some(80));
+
+		// The core has not been in C0 in between.
+		assert_eq!(FrequencySample { aperf: 1000, mperf: 2000 }.ratio_since(&start), None);
+
+		let start = FrequencySample { aperf: u64::max_value() - 49, mperf: u64::max_value() - 99 };
+		assert_eq!(FrequencySample { aperf: 50, mperf: 100 }.ratio_since(&start), Some(50));
+	}
+
+	#[test]
+	fn legacy_leaves_are_decoded() {
+		assert_eq!(bits_for_count(0), 0);
+		assert_eq!(bits_for_count(1), 0);
+		assert_eq!(bits_for_count(2), 1);
+		assert_eq!(bits_for_count(3), 2);
+		assert_eq!(bits_for_count(4), 2);
+		assert_eq!(bits_for_count(5), 3);
+
+		// QEMU with "-smp 6,cores=3,threads=2" reports 6 logical CPUs and 3 cores per package.
+		let topology = legacy_topology(6, 3);
+		assert_eq!(topology.threads_per_core(), 2);
+		assert_eq!(topology.logical_cpus_per_package(), 8);
+		assert_eq!(topology.core_id(5), 2);
+
+		// Without SMT, every logical CPU is a core of its own.
+		let topology = legacy_topology(4, 4);
+		assert_eq!(topology.threads_per_core(), 1);
+		assert!(!topology.are_siblings(0, 1));
+	}
+
+	/// CPUID can be executed in user mode, so the host CPU can be queried for comparison.
+	#[test]
+	fn cached_features_match_a_direct_query() {
+		let features = CpuFeatures::query();
+		let cpuid = CpuId::new();
+
+		let feature_info = cpuid.get_feature_info().unwrap();
+		assert_eq!(features.has_mce(), feature_info.has_mce());
+		assert_eq!(features.has_eist(), feature_info.has_eist());
+		assert_eq!(features.has_xsave(), feature_info.has_xsave());
+		assert_eq!(features.has_avx(), feature_info.has_avx());
+		assert_eq!(features.has_rdrand(), feature_info.has_rdrand());
+
+		let extended_function_info = cpuid.get_extended_function_info().unwrap();
+		assert_eq!(features.has_rdtscp(), extended_function_info.has_rdtscp());
+
+		// Every x86_64 CPU has the leaves queried for the address sizes.
+		assert!(features.max_extended_leaf >= 0x8000_0008);
+		assert!(features.physical_address_bits() > 0);
+		assert!(features.linear_address_bits() >= 48);
 	}
 }
@@ -30,10 +30,13 @@ use alloc::rc::Rc;
 use arch::x86_64::apic;
 use arch::x86_64::idt;
 use arch::x86_64::irq;
+use arch::x86_64::mce;
 use arch::x86_64::percore::*;
+use arch::x86_64::pit;
 use arch::x86_64::processor;
 use core::cell::RefCell;
 use core::{mem, ptr};
+use environment;
 use scheduler::task::{Task, TaskFrame, TaskTLS};
 
 extern "C" {
@@ -41,6 +44,26 @@ extern "C" {
 	static tls_end: u8;
 }
 
+/// Timers that can drive the scheduler.
+/// Only one of them is active at a time, so that there are never two interrupts for the same tick.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimerSource {
+	/// The one-shot APIC Timer, which is programmed for the next wakeup time of a blocked task.
+	/// Idle cores are only woken up when necessary, but the timer needs to be reprogrammed for each wakeup.
+	Apic,
+	/// The APIC Timer in periodic mode at processor::TIMER_FREQUENCY, which checks for tasks to wake up on each tick.
+	/// This is cheaper per tick than reprogramming the one-shot timer.
+	/// To not wake up idle cores on every tick, it is replaced by a one-shot timer while a core is idle
+	/// (see stop_periodic_tick).
+	ApicPeriodic,
+	/// A periodic PIT interrupt at processor::TIMER_FREQUENCY, which checks for tasks to wake up on each tick.
+	/// This is used when no APIC is available and has a lower resolution.
+	/// The PIT interrupt only reaches the Boot Processor, so Application Processors use the one-shot APIC Timer.
+	Pit,
+}
+
+static mut TIMER_SOURCE: TimerSource = TimerSource::Apic;
+
 #[repr(C, packed)]
 struct State {
 	/// FS register for TLS support
@@ -129,6 +152,9 @@ impl TaskFrame for Task {
 			(*state).rdi = func as u64;
 			(*state).rsi = arg as u64;
 			(*state).rflags = 0x1202u64;
+			if cfg!(feature = "alignment_check") {
+				(*state).rflags |= 1 << 18;
+			}
 
 			// Set the task's stack pointer entry to the stack we have just crafted.
 			self.last_stack_pointer = stack as usize;
@@ -137,10 +163,125 @@ impl TaskFrame for Task {
 }
 
 extern "x86-interrupt" fn timer_handler(_stack_frame: &mut irq::ExceptionStackFrame) {
-	core_scheduler().blocked_tasks.lock().handle_waiting_tasks();
+	// A periodic timer may already tick before the scheduler of this core has been initialized.
+	if let Some(core_scheduler) = try_core_scheduler() {
+		core_scheduler.blocked_tasks.lock().handle_waiting_tasks();
+		core_scheduler.balance();
+	}
+
+	mce::poll_periodically();
 	apic::eoi();
 }
 
 pub fn install_timer_handler() {
 	idt::set_gate(apic::TIMER_INTERRUPT_NUMBER, timer_handler as usize, 1);
+
+	// The PIT can be chosen through the "timer=pit" command line argument and is the only option without an APIC.
+	// uhyve provides no PIT.
+	// The periodic APIC Timer can be chosen through "timer=apic-periodic".
+	let source = if !apic::is_available() {
+		TimerSource::Pit
+	} else {
+		match environment::get_arg("timer") {
+			Some("pit") if !environment::is_uhyve() => TimerSource::Pit,
+			Some("apic-periodic") => TimerSource::ApicPeriodic,
+			_ => TimerSource::Apic,
+		}
+	};
+
+	set_timer_source(source);
+}
+
+/// Starts the APIC Timer of an Application Processor if it is driving the scheduler in periodic mode.
+/// In one-shot mode, which Application Processors also use under TimerSource::Pit, the timer is only
+/// programmed once a task on this core blocks with a timeout.
+pub fn install_current_core_timer() {
+	if timer_source() == TimerSource::ApicPeriodic {
+		apic::set_periodic_timer(processor::TIMER_FREQUENCY as u64);
+	}
+}
+
+/// Returns whether the scheduler of the current core is driven by the one-shot APIC Timer.
+fn uses_oneshot_apic_timer() -> bool {
+	match timer_source() {
+		TimerSource::Apic => true,
+		TimerSource::ApicPeriodic => false,
+		TimerSource::Pit => apic::is_available() && !apic::is_boot_processor(),
+	}
+}
+
+/// Selects the timer driving the scheduler and stops the other one.
+/// The APIC Timer is only programmed for the current core, so this must be called before the Application
+/// Processors boot. Each of them then starts its own timer through install_current_core_timer.
+fn set_timer_source(source: TimerSource) {
+	assert!(::arch::x86_64::online_cpu_mask().count() <= 1, "The scheduler timer can only be changed before the Application Processors boot");
+	let previous_source = timer_source();
+	unsafe { TIMER_SOURCE = source; }
+
+	match source {
+		TimerSource::Apic => {
+			pit::deinit();
+
+			if previous_source != TimerSource::Apic {
+				// Fire a single tick. Its handler programs the APIC Timer for the next wakeup time.
+				apic::set_oneshot_timer(Some(processor::update_timer_ticks() + 1));
+			}
+		},
+		TimerSource::ApicPeriodic => {
+			pit::deinit();
+			apic::set_periodic_timer(processor::TIMER_FREQUENCY as u64);
+		},
+		TimerSource::Pit => {
+			if apic::is_available() {
+				// Stop the APIC Timer and deliver the PIT interrupt through the I/O APIC.
+				apic::set_oneshot_timer(None);
+				apic::route_pit_interrupt(pit::PIT_INTERRUPT_NUMBER);
+			}
+
+			idt::set_gate(pit::PIT_INTERRUPT_NUMBER, timer_handler as usize, 1);
+			pit::set_periodic(processor::TIMER_FREQUENCY as u64);
+		}
+	}
+
+	info!("Scheduler timer: {:?}", source);
+}
+
+/// Returns the timer currently driving the scheduler.
+pub fn timer_source() -> TimerSource {
+	unsafe { TIMER_SOURCE }
+}
+
+/// Stops the periodic tick of the current core before it halts without any runnable task.
+/// The APIC Timer then only fires at the given wakeup time (in timer ticks) or not at all if None is given.
+/// Returns whether the tick has been stopped, which is only the case for TimerSource::ApicPeriodic.
+///
+/// Cores using the one-shot APIC Timer have no tick to stop, but their timer is reprogrammed for the
+/// wakeup time as well. This includes the Application Processors under TimerSource::Pit.
+/// The PIT keeps ticking on the Boot Processor, which therefore never halts longer than a tick.
+pub fn stop_periodic_tick(wakeup_time: Option<usize>) -> bool {
+	if timer_source() == TimerSource::ApicPeriodic {
+		apic::set_oneshot_timer(wakeup_time);
+		true
+	} else {
+		if uses_oneshot_apic_timer() {
+			apic::set_oneshot_timer(wakeup_time);
+		}
+
+		false
+	}
+}
+
+/// Restarts the periodic tick of the current core after stop_periodic_tick, because a task has become runnable.
+pub fn restart_periodic_tick() {
+	if timer_source() == TimerSource::ApicPeriodic {
+		apic::set_periodic_timer(processor::TIMER_FREQUENCY as u64);
+	}
+}
+
+/// Programs the timer to fire at the given wakeup time (in timer ticks) or disables it if None is given.
+/// The periodic timers fire on every tick anyway, so this only affects cores using the one-shot APIC Timer.
+pub fn set_oneshot_timer(wakeup_time: Option<usize>) {
+	if uses_oneshot_apic_timer() {
+		apic::set_oneshot_timer(wakeup_time);
+	}
 }
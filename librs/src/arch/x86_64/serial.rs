@@ -21,16 +21,23 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use core::sync::atomic::spin_loop_hint;
+use arch::x86_64::io::PortRange;
+use arch::x86_64::processor;
 use environment;
-use x86::shared::io::*;
+
+/// Number of consecutive I/O ports occupied by the registers of a 16550 UART.
+const UART_REGISTER_COUNT: u16 = 8;
 
 const UART_TX: u16 = 0;
+const UART_RX: u16 = 0;
 const UART_IER: u16 = 1;
 
 const UART_DLL: u16 = 0;
 const UART_DLM: u16 = 1;
 
+const UART_IIR: u16 = 2;
+const UART_IIR_FIFO_ENABLED: u8 = 0xC0;
+
 const UART_FCR: u16 = 2;
 const UART_FCR_ENABLE_FIFO:            u8 = 0x01;
 const UART_FCR_CLEAR_RECEIVER_FIFO:    u8 = 0x02;
@@ -41,20 +48,63 @@ const UART_LCR_WORD_LENGTH_8BITS:    u8 = 0x03;
 const UART_LCR_DIVISOR_LATCH_ACCESS: u8 = 0x80;
 
 const UART_LSR: u16 = 5;
+const UART_LSR_DATA_READY: u8 = 0x01;
 const UART_LSR_EMPTY_TRANSMITTER_HOLDING_REGISTER: u8 = 0x20;
 
+/// Size of the transmit FIFO of a 16550A UART.
+const UART_FIFO_SIZE: usize = 16;
+
+/// Number of bytes collected in the buffered modes before they are written.
+const SERIAL_BUFFER_SIZE: usize = 256;
+
+/// Number of Line Status Register polls before a UART that never empties its transmitter holding register
+/// is considered wedged. Every poll is an I/O port access, so this amounts to roughly a second on real hardware.
+const UART_TRANSMIT_TIMEOUT_POLLS: usize = 1_000_000;
+
+
+/// How write_byte passes bytes to the UART.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BufferMode {
+	/// Every byte is written immediately, e.g. for debugging a hang where no byte must get lost.
+	Unbuffered,
+	/// Bytes are collected until a newline is written or the buffer is full.
+	Line,
+	/// Bytes are collected until the buffer is full or flush is called.
+	Block,
+}
 
 pub struct SerialPort {
-	port_address: u16
+	registers: PortRange,
+	/// Number of bytes that can be written after the transmitter holding register has become empty.
+	/// This is the FIFO size once init has enabled the FIFO, and 1 otherwise.
+	fifo_size: usize,
+	/// Number of bytes that can still be written before the Line Status Register has to be polled again.
+	transmit_credits: usize,
+	/// Whether the transmitter holding register has failed to become empty in time and not been empty since.
+	transmit_timed_out: bool,
+	/// Whether take_transmit_timeout has already reported the current timeout.
+	transmit_timeout_reported: bool,
+	buffer_mode: BufferMode,
+	buffer: [u8; SERIAL_BUFFER_SIZE],
+	buffer_length: usize,
 }
 
 impl SerialPort {
 	pub const fn new(port_address: u16) -> Self {
-		Self { port_address: port_address }
+		Self {
+			registers: PortRange::new(port_address, UART_REGISTER_COUNT),
+			fifo_size: 1,
+			transmit_credits: 0,
+			transmit_timed_out: false,
+			transmit_timeout_reported: false,
+			buffer_mode: BufferMode::Unbuffered,
+			buffer: [0; SERIAL_BUFFER_SIZE],
+			buffer_length: 0,
+		}
 	}
 
 	fn read_from_register(&self, register: u16) -> u8 {
-		unsafe { inb(self.port_address + register) }
+		unsafe { self.registers.port::<u8>(register).read() }
 	}
 
 	fn is_transmitting(&self) -> bool {
@@ -66,24 +116,130 @@ impl SerialPort {
 		(self.read_from_register(UART_LSR) & UART_LSR_EMPTY_TRANSMITTER_HOLDING_REGISTER == 0)
 	}
 
-	fn write_to_register(&self, register: u16, byte: u8) {
-		while self.is_transmitting() {
-			spin_loop_hint();
+	/// Waits until the transmitter holding register is empty, but gives up after UART_TRANSMIT_TIMEOUT_POLLS
+	/// to not hang the kernel on a wedged UART.
+	/// After a timeout, the register is only polled once per write, so that output does not stall for a
+	/// second every few bytes, but resumes if the UART recovers.
+	/// Once the register is empty again, the timeout is cleared, so a later one is reported anew.
+	fn wait_for_transmitter(&mut self) {
+		let polls = if self.transmit_timed_out { 1 } else { UART_TRANSMIT_TIMEOUT_POLLS };
+
+		for _ in 0..polls {
+			if !self.is_transmitting() {
+				self.transmit_timed_out = false;
+				self.transmit_timeout_reported = false;
+				return;
+			}
+
+			processor::pause();
 		}
 
-		unsafe { outb(self.port_address + register, byte); }
+		self.transmit_timed_out = true;
+	}
+
+	fn write_to_register(&mut self, register: u16, byte: u8) {
+		self.wait_for_transmitter();
+		self.transmit_credits = 0;
+		unsafe { self.registers.port::<u8>(register).write(byte); }
 	}
 
-	pub fn write_byte(&self, byte: u8) {
+	/// Writes a byte to the transmitter holding register.
+	/// Once the register is empty, the entire transmit FIFO is free, so the Line Status Register
+	/// only needs to be polled again after fifo_size bytes.
+	fn transmit_byte(&mut self, byte: u8) {
+		if self.transmit_credits == 0 {
+			self.wait_for_transmitter();
+			self.transmit_credits = self.fifo_size;
+		}
+
+		self.transmit_credits -= 1;
+		unsafe { self.registers.port::<u8>(UART_TX).write(byte); }
+	}
+
+	/// Returns true exactly once for each time the transmitter holding register has failed to become empty in time.
+	/// The caller reports it, because the serial port itself is used to output log messages.
+	pub fn take_transmit_timeout(&mut self) -> bool {
+		if self.transmit_timed_out && !self.transmit_timeout_reported {
+			self.transmit_timeout_reported = true;
+			true
+		} else {
+			false
+		}
+	}
+
+	pub fn port_address(&self) -> u16 {
+		self.registers.base()
+	}
+
+	/// Returns the next received byte or None if no byte is available.
+	pub fn try_read_byte(&self) -> Option<u8> {
+		if self.read_from_register(UART_LSR) & UART_LSR_DATA_READY > 0 {
+			Some(self.read_from_register(UART_RX))
+		} else {
+			None
+		}
+	}
+
+	/// Waits until a byte has been received and returns it.
+	pub fn read_byte(&self) -> u8 {
+		loop {
+			if let Some(byte) = self.try_read_byte() {
+				return byte;
+			}
+
+			processor::pause();
+		}
+	}
+
+	/// Writes a byte without any newline translation, e.g. for binary protocols.
+	pub fn write_raw_byte(&mut self, byte: u8) {
+		self.transmit_byte(byte);
+	}
+
+	pub fn write_byte(&mut self, byte: u8) {
 		// LF newline characters need to be extended to CRLF over a real serial port.
 		if byte == b'\n' {
-			self.write_to_register(UART_TX, b'\r');
+			self.output_byte(b'\r');
+		}
+
+		self.output_byte(byte);
+
+		if self.buffer_mode == BufferMode::Line && byte == b'\n' {
+			self.flush();
+		}
+	}
+
+	fn output_byte(&mut self, byte: u8) {
+		if self.buffer_mode == BufferMode::Unbuffered {
+			self.transmit_byte(byte);
+			return;
 		}
 
-		self.write_to_register(UART_TX, byte);
+		if self.buffer_length == SERIAL_BUFFER_SIZE {
+			self.flush();
+		}
+
+		self.buffer[self.buffer_length] = byte;
+		self.buffer_length += 1;
+	}
+
+	/// Writes all buffered bytes to the UART.
+	pub fn flush(&mut self) {
+		for i in 0..self.buffer_length {
+			let byte = self.buffer[i];
+			self.transmit_byte(byte);
+		}
+
+		self.buffer_length = 0;
+	}
+
+	/// Selects how write_byte passes bytes to the UART, after writing out all bytes buffered so far.
+	pub fn set_buffer_mode(&mut self, buffer_mode: BufferMode) {
+		self.flush();
+		self.buffer_mode = buffer_mode;
 	}
 
-	pub fn init(&self, baudrate: u32) {
+	pub fn init(&mut self, baudrate: u32) {
 		// The virtual serial port is always initialized in uhyve.
 		if environment::is_uhyve() {
 			return;
@@ -104,6 +260,8 @@ impl SerialPort {
 		self.write_to_register(UART_LCR, lcr);
 
 		// Enable and clear FIFOs.
+		// Only a 16550A reports working FIFOs, older UARTs must be fed byte by byte.
 		self.write_to_register(UART_FCR, UART_FCR_ENABLE_FIFO | UART_FCR_CLEAR_RECEIVER_FIFO | UART_FCR_CLEAR_TRANSMITTER_FIFO);
+		self.fifo_size = if self.read_from_register(UART_IIR) & UART_IIR_FIFO_ENABLED == UART_IIR_FIFO_ENABLED { UART_FIFO_SIZE } else { 1 };
 	}
 }
@@ -0,0 +1,80 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Brings all CPU cores to a halt before the system is powered off,
+//! so that no other core touches the hardware while it is torn down.
+
+use arch::x86_64::apic;
+use arch::x86_64::irq;
+use arch::x86_64::percore::*;
+use arch::x86_64::processor;
+
+
+/// Time in milliseconds that the other cores get to acknowledge a stop request.
+const QUIESCE_TIMEOUT_MS: usize = 1000;
+
+
+/// Asks all other online cores to stop and waits until each of them has disabled interrupts and halted.
+/// Cores that do not acknowledge the request in time are logged and left running.
+pub fn quiesce_all_cores() {
+	let core_id = core_id();
+	let mut remaining = ::arch::x86_64::online_cpu_mask();
+	remaining.remove(core_id);
+	if remaining.count() == 0 {
+		return;
+	}
+
+	debug!("Stopping CPUs {:?}", remaining);
+	apic::ipi_shutdown();
+
+	let mut waited_ms = 0;
+	loop {
+		// Each core removes itself from the online CPUs right before it halts.
+		remaining = ::arch::x86_64::online_cpu_mask();
+		remaining.remove(core_id);
+		if remaining.count() == 0 {
+			debug!("All other CPUs have stopped");
+			break;
+		}
+
+		if waited_ms >= QUIESCE_TIMEOUT_MS {
+			error!("CPUs {:?} did not stop within {} ms", remaining, QUIESCE_TIMEOUT_MS);
+			break;
+		}
+
+		processor::udelay(1000);
+		waited_ms += 1;
+	}
+}
+
+/// Takes the current core offline and halts it for good.
+/// Called when receiving the stop request of quiesce_all_cores.
+pub fn park_current_core() -> ! {
+	irq::disable();
+	::arch::x86_64::mark_current_cpu_offline();
+
+	// Interrupts stay disabled, so only an NMI can get us out of the HALT state.
+	loop {
+		processor::halt();
+	}
+}
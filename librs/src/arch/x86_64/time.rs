@@ -0,0 +1,278 @@
+// Copyright (c) 2017 Stefan Lankes, RWTH Aachen University
+//                    Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Time since boot and wall-clock time at boot.
+//!
+//! The monotonic clock is based on the Time Stamp Counter, which starts counting in message_output_init.
+//! It only yields meaningful values once the CPU frequency has been determined in processor::detect_frequency.
+
+use arch::x86_64::io::Port;
+use arch::x86_64::processor;
+use environment;
+
+
+const CMOS_COMMAND_PORT: Port<u8> = Port::new(0x70);
+const CMOS_DATA_PORT: Port<u8>    = Port::new(0x71);
+
+const RTC_SECONDS: u8  = 0x00;
+const RTC_MINUTES: u8  = 0x02;
+const RTC_HOURS: u8    = 0x04;
+const RTC_DAY: u8      = 0x07;
+const RTC_MONTH: u8    = 0x08;
+const RTC_YEAR: u8     = 0x09;
+const RTC_STATUS_A: u8 = 0x0A;
+const RTC_STATUS_B: u8 = 0x0B;
+
+const RTC_STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const RTC_STATUS_B_24_HOUR_MODE: u8       = 1 << 1;
+const RTC_STATUS_B_BINARY_MODE: u8        = 1 << 2;
+const RTC_HOURS_PM: u8                    = 1 << 7;
+
+const NANOSECONDS_PER_SECOND: u64 = 1_000_000_000;
+
+/// An RTC update takes about 2 ms, so a longer update-in-progress phase means the RTC is broken.
+const RTC_UPDATE_TIMEOUT_US: u64 = 10_000;
+
+/// Number of times the date and time registers are read at most to get the same values twice in a row.
+const RTC_READ_ATTEMPTS: usize = 10;
+
+
+/// Time Stamp Counter value when the kernel started.
+static mut BOOT_TSC: u64 = 0;
+
+/// Wall-clock time at boot in seconds since the Unix epoch, if known.
+static mut BOOT_WALL_TIME: Option<u64> = None;
+
+
+/// Reads a register of the Real-Time Clock.
+/// Bit 7 of the CMOS command port is kept clear, as it would disable NMIs until the next write.
+fn rtc_read(register: u8) -> u8 {
+	unsafe {
+		CMOS_COMMAND_PORT.write(register);
+		CMOS_DATA_PORT.read()
+	}
+}
+
+/// Reads all date and time registers of the Real-Time Clock as (seconds, minutes, hours, day, month, year).
+/// Returns None if an update is still in progress after RTC_UPDATE_TIMEOUT_US.
+fn rtc_read_datetime() -> Option<(u8, u8, u8, u8, u8, u8)> {
+	let mut waited_us = 0;
+	while rtc_read(RTC_STATUS_A) & RTC_STATUS_A_UPDATE_IN_PROGRESS > 0 {
+		if waited_us >= RTC_UPDATE_TIMEOUT_US {
+			return None;
+		}
+
+		processor::udelay(10);
+		waited_us += 10;
+	}
+
+	Some((rtc_read(RTC_SECONDS), rtc_read(RTC_MINUTES), rtc_read(RTC_HOURS), rtc_read(RTC_DAY), rtc_read(RTC_MONTH), rtc_read(RTC_YEAR)))
+}
+
+/// Converts a BCD-encoded RTC value into a binary one.
+fn bcd_to_binary(value: u8) -> u8 {
+	(value & 0x0F) + (value >> 4) * 10
+}
+
+/// Returns the number of days of the given month (1 to 12) in the given year of the Gregorian calendar.
+fn days_in_month(year: u64, month: u64) -> u64 {
+	match month {
+		2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+		2 => 28,
+		4 | 6 | 9 | 11 => 30,
+		_ => 31,
+	}
+}
+
+/// Returns whether the given date and time denote a valid point in time between 1970 and 9999.
+/// A broken or uninitialized RTC may provide any value, which must not reach days_since_epoch.
+fn is_valid_datetime(seconds: u64, minutes: u64, hours: u64, day: u64, month: u64, year: u64) -> bool {
+	seconds < 60 && minutes < 60 && hours < 24
+		&& year >= 1970 && year <= 9999
+		&& month >= 1 && month <= 12
+		&& day >= 1 && day <= days_in_month(year, month)
+}
+
+/// Returns the number of days from the Unix epoch (1970-01-01) to the given date of the Gregorian calendar.
+/// The date must have been checked through is_valid_datetime.
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+	// Count years from March to simplify the leap day handling.
+	let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+	let era = year / 400;
+	let year_of_era = year - era * 400;
+	let day_of_year = (153 * month + 2) / 5 + day - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+	era * 146097 + day_of_era - 719468
+}
+
+/// Reads the current wall-clock time from the Real-Time Clock in seconds since the Unix epoch.
+/// The RTC is assumed to run in UTC and in the 21st century.
+/// Returns None if the RTC does not provide a consistent reading.
+fn read_rtc() -> Option<u64> {
+	// Read the registers until we get the same values twice in a row, so we don't catch an update in between.
+	let mut datetime = rtc_read_datetime()?;
+	let mut attempts = 1;
+	loop {
+		let next_datetime = rtc_read_datetime()?;
+		if next_datetime == datetime {
+			break;
+		}
+
+		attempts += 1;
+		if attempts == RTC_READ_ATTEMPTS {
+			return None;
+		}
+
+		datetime = next_datetime;
+		processor::pause();
+	}
+
+	let (mut seconds, mut minutes, mut hours, mut day, mut month, mut year) = datetime;
+	let status_b = rtc_read(RTC_STATUS_B);
+	let pm = hours & RTC_HOURS_PM > 0;
+	hours &= !RTC_HOURS_PM;
+
+	if status_b & RTC_STATUS_B_BINARY_MODE == 0 {
+		seconds = bcd_to_binary(seconds);
+		minutes = bcd_to_binary(minutes);
+		hours = bcd_to_binary(hours);
+		day = bcd_to_binary(day);
+		month = bcd_to_binary(month);
+		year = bcd_to_binary(year);
+	}
+
+	if status_b & RTC_STATUS_B_24_HOUR_MODE == 0 {
+		// Convert 12-hour time, where midnight and noon are both represented by 12.
+		hours %= 12;
+		if pm {
+			hours += 12;
+		}
+	}
+
+	let year = 2000 + year as u64;
+	if !is_valid_datetime(seconds as u64, minutes as u64, hours as u64, day as u64, month as u64, year) {
+		return None;
+	}
+
+	let days = days_since_epoch(year, month as u64, day as u64);
+	Some(((days * 24 + hours as u64) * 60 + minutes as u64) * 60 + seconds as u64)
+}
+
+/// Records the Time Stamp Counter value at the start of the kernel.
+/// Must be called as early as possible.
+pub fn init() {
+	unsafe { BOOT_TSC = processor::get_timestamp(); }
+}
+
+/// Records the wall-clock time at boot.
+/// Called at the end of the Boot Processor initialization.
+pub fn init_wall_time() {
+	// uhyve doesn't provide an emulated RTC, but newer versions pass the time at boot.
+	if let Some(boot_time) = environment::get_boot_time() {
+		unsafe { BOOT_WALL_TIME = Some(boot_time / 1_000_000); }
+		info!("Boot wall-clock time passed by uhyve is {} seconds since the Unix epoch", boot_time / 1_000_000);
+		return;
+	}
+
+	// uhyve and the multi-kernel mode don't provide an emulated RTC.
+	if !environment::is_single_kernel() || environment::is_uhyve() {
+		return;
+	}
+
+	let wall_time = match read_rtc() {
+		Some(wall_time) => wall_time,
+		None => {
+			warn!("The Real-Time Clock does not provide a consistent time, the boot wall-clock time is unknown");
+			return;
+		}
+	};
+	let uptime_seconds = uptime_ns() / NANOSECONDS_PER_SECOND;
+	let boot_time = match wall_time.checked_sub(uptime_seconds) {
+		Some(boot_time) => boot_time,
+		None => {
+			warn!("The Real-Time Clock time is before the uptime of {} seconds, the boot wall-clock time is unknown", uptime_seconds);
+			return;
+		}
+	};
+	unsafe { BOOT_WALL_TIME = Some(boot_time); }
+	info!("Boot wall-clock time is {} seconds since the Unix epoch", boot_time);
+}
+
+/// Returns whether the monotonic clock is available, i.e. the CPU frequency has been determined.
+#[inline]
+pub fn is_clock_available() -> bool {
+	processor::get_frequency() > 0
+}
+
+/// Returns the time since the kernel started in nanoseconds.
+/// Always returns 0 as long as the monotonic clock is not available.
+pub fn uptime_ns() -> u64 {
+	let frequency_hz = processor::get_frequency() as u64 * 1_000_000;
+	if frequency_hz == 0 {
+		return 0;
+	}
+
+	// Split the calculation to prevent an overflow of the intermediate result.
+	let cycles = processor::get_timestamp() - unsafe { BOOT_TSC };
+	(cycles / frequency_hz) * NANOSECONDS_PER_SECOND + (cycles % frequency_hz) * NANOSECONDS_PER_SECOND / frequency_hz
+}
+
+/// Returns the wall-clock time at boot in seconds since the Unix epoch
+/// or None if it could not be determined.
+pub fn boot_wall_time() -> Option<u64> {
+	unsafe { BOOT_WALL_TIME }
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn days_since_epoch_counts_leap_days() {
+		assert_eq!(days_since_epoch(1970, 1, 1), 0);
+		assert_eq!(days_since_epoch(1999, 12, 31), 10956);
+		assert_eq!(days_since_epoch(2000, 2, 29), 11016);
+		assert_eq!(days_since_epoch(2000, 3, 1), 11017);
+		assert_eq!(days_since_epoch(2024, 2, 29), 19782);
+		assert_eq!(days_since_epoch(2100, 2, 28), 47540);
+		assert_eq!(days_since_epoch(2100, 3, 1), 47541);
+	}
+
+	#[test]
+	fn invalid_datetimes_are_rejected() {
+		assert!(is_valid_datetime(59, 59, 23, 31, 12, 2099));
+		assert!(is_valid_datetime(0, 0, 0, 29, 2, 2000));
+		assert!(!is_valid_datetime(0, 0, 0, 29, 2, 2100));
+		assert!(!is_valid_datetime(0, 0, 0, 0, 1, 2000));
+		assert!(!is_valid_datetime(0, 0, 0, 31, 4, 2000));
+		assert!(!is_valid_datetime(0, 0, 0, 1, 0, 2000));
+		assert!(!is_valid_datetime(0, 0, 0, 1, 13, 2000));
+		assert!(!is_valid_datetime(60, 0, 0, 1, 1, 2000));
+		assert!(!is_valid_datetime(0, 60, 0, 1, 1, 2000));
+		assert!(!is_valid_datetime(0, 0, 24, 1, 1, 2000));
+		assert!(!is_valid_datetime(0, 0, 0, 1, 1, 1969));
+	}
+}
@@ -23,11 +23,11 @@
 
 use arch::x86_64::mm::paging;
 use arch::x86_64::mm::paging::{BasePageSize, PageTableEntryFlags};
-use x86::shared::io::*;
+use arch::x86_64::io::Port;
 
 
-const CRT_CONTROLLER_ADDRESS_PORT: u16 = 0x3D4;
-const CRT_CONTROLLER_DATA_PORT:    u16 = 0x3D5;
+const CRT_CONTROLLER_ADDRESS_PORT: Port<u8> = Port::new(0x3D4);
+const CRT_CONTROLLER_DATA_PORT:    Port<u8> = Port::new(0x3D5);
 const CURSOR_START_REGISTER:       u8 = 0x0A;
 const CURSOR_DISABLE:              u8 = 0x20;
 
@@ -84,8 +84,8 @@ impl VgaScreen {
 
 		// Disable the cursor.
 		unsafe {
-			outb(CRT_CONTROLLER_ADDRESS_PORT, CURSOR_START_REGISTER);
-			outb(CRT_CONTROLLER_DATA_PORT, CURSOR_DISABLE);
+			CRT_CONTROLLER_ADDRESS_PORT.write(CURSOR_START_REGISTER);
+			CRT_CONTROLLER_DATA_PORT.write(CURSOR_DISABLE);
 		}
 
 		// Clear the screen.
@@ -0,0 +1,199 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! A vector with a fixed capacity, which lives entirely on the stack or in a static and never allocates.
+//! This is meant for early boot code that runs before the heap is available.
+//!
+//! Our toolchain has no const generics, so the capacity is given by the type of the backing array,
+//! e.g. `ArrayVec<[u8; 16]>` for up to 16 bytes.
+
+use core::{mem, ops, slice};
+
+
+/// Arrays that can back an ArrayVec.
+/// Only implemented for arrays of Copy elements, so no element ever needs to be dropped.
+pub unsafe trait Array {
+	type Item: Copy;
+
+	fn capacity() -> usize;
+	fn as_ptr(&self) -> *const Self::Item;
+	fn as_mut_ptr(&mut self) -> *mut Self::Item;
+}
+
+macro_rules! impl_array {
+	($($capacity:expr),+) => {
+		$(
+			unsafe impl<T: Copy> Array for [T; $capacity] {
+				type Item = T;
+
+				#[inline]
+				fn capacity() -> usize {
+					$capacity
+				}
+
+				#[inline]
+				fn as_ptr(&self) -> *const T {
+					self as *const [T] as *const T
+				}
+
+				#[inline]
+				fn as_mut_ptr(&mut self) -> *mut T {
+					self as *mut [T] as *mut T
+				}
+			}
+		)+
+	}
+}
+
+impl_array!(1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024);
+
+
+pub struct ArrayVec<A: Array> {
+	array: A,
+	len: usize,
+}
+
+impl<A: Array> ArrayVec<A> {
+	pub fn new() -> Self {
+		// Only the first `len` elements are ever read, and all of them have been written before.
+		Self { array: unsafe { mem::uninitialized() }, len: 0 }
+	}
+
+	#[inline]
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	#[inline]
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	#[inline]
+	pub fn is_full(&self) -> bool {
+		self.len == A::capacity()
+	}
+
+	/// Appends an element to the end of the vector.
+	/// Returns the element as the error if the vector is already full.
+	pub fn push(&mut self, element: A::Item) -> Result<(), A::Item> {
+		if self.is_full() {
+			return Err(element);
+		}
+
+		unsafe { *self.array.as_mut_ptr().offset(self.len as isize) = element; }
+		self.len += 1;
+		Ok(())
+	}
+
+	/// Removes the last element and returns it or None if the vector is empty.
+	pub fn pop(&mut self) -> Option<A::Item> {
+		if self.is_empty() {
+			return None;
+		}
+
+		self.len -= 1;
+		Some(unsafe { *self.array.as_ptr().offset(self.len as isize) })
+	}
+
+	pub fn as_slice(&self) -> &[A::Item] {
+		unsafe { slice::from_raw_parts(self.array.as_ptr(), self.len) }
+	}
+
+	pub fn as_mut_slice(&mut self) -> &mut [A::Item] {
+		unsafe { slice::from_raw_parts_mut(self.array.as_mut_ptr(), self.len) }
+	}
+
+	pub fn iter(&self) -> slice::Iter<A::Item> {
+		self.as_slice().iter()
+	}
+}
+
+impl<A: Array> ops::Deref for ArrayVec<A> {
+	type Target = [A::Item];
+
+	fn deref(&self) -> &[A::Item] {
+		self.as_slice()
+	}
+}
+
+impl<A: Array> ops::DerefMut for ArrayVec<A> {
+	fn deref_mut(&mut self) -> &mut [A::Item] {
+		self.as_mut_slice()
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn elements_are_pushed_and_popped_in_order() {
+		let mut vec = ArrayVec::<[u32; 4]>::new();
+		assert!(vec.is_empty());
+		assert_eq!(vec.pop(), None);
+
+		for i in 0..3 {
+			assert_eq!(vec.push(i * 10), Ok(()));
+		}
+		assert_eq!(vec.len(), 3);
+		assert_eq!(vec.iter().cloned().collect::<Vec<u32>>(), vec![0, 10, 20]);
+
+		assert_eq!(vec.pop(), Some(20));
+		assert_eq!(vec.pop(), Some(10));
+		assert_eq!(vec.len(), 1);
+		assert!(!vec.is_empty());
+	}
+
+	#[test]
+	fn pushing_into_a_full_vector_fails() {
+		let mut vec = ArrayVec::<[u8; 2]>::new();
+		assert_eq!(vec.push(1), Ok(()));
+		assert_eq!(vec.push(2), Ok(()));
+		assert!(vec.is_full());
+
+		// The rejected element is handed back and the vector stays unchanged.
+		assert_eq!(vec.push(3), Err(3));
+		assert_eq!(&vec[..], &[1, 2]);
+
+		assert_eq!(vec.pop(), Some(2));
+		assert!(!vec.is_full());
+		assert_eq!(vec.push(3), Ok(()));
+		assert_eq!(&vec[..], &[1, 3]);
+	}
+
+	#[test]
+	fn elements_are_modified_through_slices() {
+		let mut vec = ArrayVec::<[(usize, usize); 4]>::new();
+		vec.push((1, 2)).unwrap();
+		vec.push((3, 4)).unwrap();
+
+		vec[1].0 = 5;
+		for range in vec.as_mut_slice() {
+			range.1 += 1;
+		}
+
+		assert_eq!(vec.as_slice(), &[(1, 3), (5, 5)]);
+	}
+}
@@ -174,6 +174,10 @@ impl<T> DoublyLinkedList<T> {
 	pub fn iter(&self) -> Iter<T> {
 		Iter::<T> { current: self.head.as_ref().map(|node| node.clone()) }
 	}
+
+	pub fn iter_rev(&self) -> RevIter<T> {
+		RevIter::<T> { current: self.tail.as_ref().map(|node| node.clone()) }
+	}
 }
 
 impl<T> Default for DoublyLinkedList<T> {
@@ -198,3 +202,19 @@ impl<T> Iterator for Iter<T> {
 		})
 	}
 }
+
+pub struct RevIter<T> {
+	current: Option<Rc<RefCell<Node<T>>>>
+}
+
+impl<T> Iterator for RevIter<T> {
+	type Item = Rc<RefCell<Node<T>>>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		// Same as Iter, but walk from the list tail to the list head.
+		self.current.take().map(|node| {
+			self.current = node.borrow().prev.clone();
+			node
+		})
+	}
+}
@@ -21,6 +21,8 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+mod arrayvec;
 mod doublylinkedlist;
 
+pub use self::arrayvec::*;
 pub use self::doublylinkedlist::*;
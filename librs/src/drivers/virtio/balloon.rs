@@ -0,0 +1,252 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Driver for the virtio memory balloon device.
+//! The host sets a target number of pages in the device configuration. The driver inflates the balloon by
+//! taking these pages out of the physical free list and passing them to the host, which may then reclaim them.
+//! The pages are reserved through physicalmem::reserve_region, so they no longer count as managed memory.
+//! When the target shrinks, the driver deflates the balloon and returns the pages through physicalmem::add_region.
+
+use alloc::vec::Vec;
+use arch::x86_64::iommu::DmaFlags;
+use arch::x86_64::irq;
+use arch::x86_64::mm::paging::{self, BasePageSize, PageSize, PageTableEntryFlags};
+use arch::x86_64::mm::physicalmem;
+use arch::x86_64::pci;
+use drivers::virtio::{self, VirtioDevice, Virtqueue};
+use mm;
+use synch::spinlock::SpinlockIrqSave;
+
+
+/// PCI Device ID of the transitional virtio balloon device.
+const VIRTIO_BALLOON_DEVICE_ID: u16 = 0x1002;
+
+/// The host must be told about pages before they are used again after deflating.
+/// The driver always does that, so the feature can be accepted without further changes.
+const VIRTIO_BALLOON_F_MUST_TELL_HOST: u32 = 1 << 0;
+
+/// Offsets of the fields in the device-specific configuration.
+const VIRTIO_BALLOON_CONFIG_NUM_PAGES: u16 = 0;
+const VIRTIO_BALLOON_CONFIG_ACTUAL: u16    = 4;
+
+const INFLATE_QUEUE: u16 = 0;
+const DEFLATE_QUEUE: u16 = 1;
+
+/// The balloon always operates on 4 KiB pages.
+const VIRTIO_BALLOON_PAGE_SIZE: usize = 4096;
+
+/// Maximum number of page frame numbers passed to the host in a single request.
+const PFNS_PER_REQUEST: usize = 256;
+
+/// The I/O APIC delivers legacy IRQs with this offset.
+const PCI_INTERRUPT_BASE: u8 = 32;
+
+
+struct Balloon {
+	device: VirtioDevice,
+	inflate_queue: Virtqueue,
+	deflate_queue: Virtqueue,
+	/// Virtual and physical address of the page holding the page frame numbers of a request.
+	pfn_buffer: usize,
+	pfn_buffer_physical: usize,
+	/// Physical addresses of all pages that have been passed to the host.
+	pages: Vec<usize>,
+}
+
+lazy_static! {
+	static ref BALLOON: SpinlockIrqSave<Option<Balloon>> = SpinlockIrqSave::new(None);
+}
+
+
+impl Balloon {
+	fn set_pfn(&self, index: usize, physical_address: usize) {
+		unsafe { *(self.pfn_buffer as *mut u32).offset(index as isize) = (physical_address / VIRTIO_BALLOON_PAGE_SIZE) as u32; }
+	}
+
+	fn inflate(&mut self, count: usize) {
+		let mut remaining = count;
+
+		while remaining > 0 {
+			let requested = if remaining < PFNS_PER_REQUEST { remaining } else { PFNS_PER_REQUEST };
+			let mut batch = 0;
+
+			{
+				let _lock = mm::MM_LOCK.lock();
+
+				while batch < requested {
+					match physicalmem::allocate_aligned_checked(VIRTIO_BALLOON_PAGE_SIZE, VIRTIO_BALLOON_PAGE_SIZE) {
+						Ok(physical_address) => {
+							self.set_pfn(batch, physical_address);
+							self.pages.push(physical_address);
+							batch += 1;
+						},
+						Err(e) => {
+							warn!("Could not inflate the memory balloon any further: {}", e);
+							break;
+						}
+					}
+				}
+			}
+
+			// The pages are no longer available to the kernel, even before the host has reclaimed them.
+			let first = self.pages.len() - batch;
+			for &physical_address in &self.pages[first..] {
+				physicalmem::reserve_region(physical_address, physical_address + VIRTIO_BALLOON_PAGE_SIZE).unwrap();
+			}
+
+			if batch > 0 {
+				self.inflate_queue.transfer(&self.device, self.pfn_buffer_physical, 4 * batch, false);
+			}
+
+			if batch < requested {
+				break;
+			}
+
+			remaining -= batch;
+		}
+	}
+
+	fn deflate(&mut self, count: usize) {
+		let mut remaining = if count < self.pages.len() { count } else { self.pages.len() };
+
+		while remaining > 0 {
+			let batch = if remaining < PFNS_PER_REQUEST { remaining } else { PFNS_PER_REQUEST };
+			let first = self.pages.len() - batch;
+
+			for i in 0..batch {
+				self.set_pfn(i, self.pages[first + i]);
+			}
+
+			self.deflate_queue.transfer(&self.device, self.pfn_buffer_physical, 4 * batch, false);
+
+			// The host has been told about the pages, so they may be used again.
+			for &physical_address in &self.pages[first..] {
+				physicalmem::add_region(physical_address, physical_address + VIRTIO_BALLOON_PAGE_SIZE).unwrap();
+			}
+
+			self.pages.truncate(first);
+			remaining -= batch;
+		}
+	}
+
+	fn update(&mut self) {
+		let target = self.device.read_config_u32(VIRTIO_BALLOON_CONFIG_NUM_PAGES) as usize;
+		let current = self.pages.len();
+
+		if target > current {
+			self.inflate(target - current);
+		} else if target < current {
+			self.deflate(current - target);
+		}
+
+		self.device.write_config_u32(VIRTIO_BALLOON_CONFIG_ACTUAL, self.pages.len() as u32);
+
+		if self.pages.len() != current {
+			info!("Memory balloon holds {} pages ({} KiB) for a target of {} pages ({} KiB reserved in total)", self.pages.len(), self.pages.len() * VIRTIO_BALLOON_PAGE_SIZE / 1024, target, physicalmem::reserved_memory() / 1024);
+		}
+	}
+}
+
+fn balloon_interrupt_handler(_vector: u8) {
+	let mut balloon_locked = BALLOON.lock();
+	if let Some(ref mut balloon) = *balloon_locked {
+		// Reading the interrupt status also acknowledges the interrupt.
+		if balloon.device.read_isr() & virtio::VIRTIO_ISR_CONFIG_CHANGED > 0 {
+			balloon.update();
+		}
+	}
+}
+
+/// Looks for a virtio balloon device and brings the balloon to the target size requested by the host.
+/// Later changes of the target are handled in the configuration change interrupt.
+pub fn init() {
+	assert!(BasePageSize::SIZE == VIRTIO_BALLOON_PAGE_SIZE);
+
+	let adapter = match pci::get_adapter(virtio::VIRTIO_VENDOR_ID, VIRTIO_BALLOON_DEVICE_ID) {
+		Some(adapter) => adapter,
+		None => return,
+	};
+
+	let device = match VirtioDevice::new(&adapter) {
+		Some(device) => device,
+		None => {
+			warn!("Ignoring the virtio balloon device, because it has no legacy I/O interface");
+			return;
+		}
+	};
+
+	device.negotiate_features(VIRTIO_BALLOON_F_MUST_TELL_HOST);
+
+	let (inflate_queue, deflate_queue) = match (device.setup_queue(INFLATE_QUEUE), device.setup_queue(DEFLATE_QUEUE)) {
+		(Ok(inflate_queue), Ok(deflate_queue)) => (inflate_queue, deflate_queue),
+		_ => {
+			error!("Could not set up the virtqueues of the virtio balloon device");
+			device.fail();
+			return;
+		}
+	};
+
+	let pfn_buffer = mm::allocate(BasePageSize::SIZE, PageTableEntryFlags::EXECUTE_DISABLE);
+	let pfn_buffer_physical = paging::virtual_to_physical(pfn_buffer);
+	if device.map_dma(pfn_buffer_physical, BasePageSize::SIZE, DmaFlags::READ).is_err() {
+		error!("Could not map the page frame number buffer of the virtio balloon device for DMA");
+		device.fail();
+		return;
+	}
+
+	if let Some(irq) = device.irq() {
+		if irq::register_handler(PCI_INTERRUPT_BASE + irq, balloon_interrupt_handler).is_err() {
+			warn!("Could not register a handler for IRQ {} of the virtio balloon device", irq);
+		}
+	} else {
+		warn!("The virtio balloon device has no IRQ, changes of its target size are only handled by balloon::update");
+	}
+
+	info!("Found a virtio balloon device at I/O port {:#X}", device.io_base());
+
+	let mut balloon_locked = BALLOON.lock();
+	*balloon_locked = Some(Balloon {
+		device: device,
+		inflate_queue: inflate_queue,
+		deflate_queue: deflate_queue,
+		pfn_buffer: pfn_buffer,
+		pfn_buffer_physical: pfn_buffer_physical,
+		pages: Vec::new(),
+	});
+
+	let balloon = balloon_locked.as_mut().unwrap();
+	balloon.device.driver_ok();
+	balloon.update();
+}
+
+/// Brings the balloon to the current target size requested by the host.
+pub fn update() {
+	if let Some(ref mut balloon) = *BALLOON.lock() {
+		balloon.update();
+	}
+}
+
+/// Returns the number of bytes currently given to the host through the balloon.
+pub fn size() -> usize {
+	BALLOON.lock().as_ref().map_or(0, |balloon| balloon.pages.len() * VIRTIO_BALLOON_PAGE_SIZE)
+}
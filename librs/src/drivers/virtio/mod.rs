@@ -0,0 +1,258 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Transport for virtio devices through the legacy virtio over PCI interface.
+//! Hypervisors like QEMU offer this interface in an I/O BAR for all transitional virtio devices.
+
+pub mod balloon;
+
+use arch::x86_64::io::Port;
+use arch::x86_64::iommu::{self, DmaFlags};
+use arch::x86_64::mm::paging::{self, PageTableEntryFlags};
+use arch::x86_64::pci::{self, PciAdapter};
+use arch::x86_64::processor;
+use core::ptr;
+use core::sync::atomic::{fence, Ordering};
+use mm;
+
+
+/// PCI Vendor ID of all virtio devices.
+pub const VIRTIO_VENDOR_ID: u16 = 0x1AF4;
+
+/// Registers of the legacy virtio header in I/O space.
+const VIRTIO_PCI_HOST_FEATURES: u16  = 0x00;
+const VIRTIO_PCI_GUEST_FEATURES: u16 = 0x04;
+const VIRTIO_PCI_QUEUE_PFN: u16      = 0x08;
+const VIRTIO_PCI_QUEUE_SIZE: u16     = 0x0C;
+const VIRTIO_PCI_QUEUE_SELECT: u16   = 0x0E;
+const VIRTIO_PCI_QUEUE_NOTIFY: u16   = 0x10;
+const VIRTIO_PCI_STATUS: u16         = 0x12;
+const VIRTIO_PCI_ISR: u16            = 0x13;
+/// Offset of the device-specific configuration when MSI-X is disabled.
+const VIRTIO_PCI_CONFIG: u16         = 0x14;
+
+const VIRTIO_STATUS_ACKNOWLEDGE: u8 = 1 << 0;
+const VIRTIO_STATUS_DRIVER: u8      = 1 << 1;
+const VIRTIO_STATUS_DRIVER_OK: u8   = 1 << 2;
+const VIRTIO_STATUS_FAILED: u8      = 1 << 7;
+
+/// Interrupt status bit indicating a change of the device-specific configuration.
+pub const VIRTIO_ISR_CONFIG_CHANGED: u8 = 1 << 1;
+
+/// Legacy devices expect the used ring and the queue address in units of this size.
+const VIRTIO_PCI_QUEUE_ALIGNMENT: usize = 4096;
+
+/// The buffer of a descriptor is written by the device instead of being read.
+const VIRTQ_DESC_F_WRITE: u16 = 1 << 1;
+
+
+#[repr(C)]
+struct VirtqDescriptor {
+	address: u64,
+	length: u32,
+	flags: u16,
+	next: u16,
+}
+
+/// A virtio device with a legacy I/O interface.
+pub struct VirtioDevice {
+	adapter: PciAdapter,
+	io_base: u16,
+	irq: u8,
+}
+
+impl VirtioDevice {
+	/// Resets the device of the given PCI adapter and tells it that a driver has been found.
+	/// Returns None if the adapter has no legacy I/O interface.
+	pub fn new(adapter: &PciAdapter) -> Option<Self> {
+		let base_address = adapter.base_addresses[0];
+		if base_address & pci::PCI_BASE_ADDRESS_IO_SPACE == 0 {
+			return None;
+		}
+
+		adapter.make_bus_master();
+
+		let device = Self { adapter: *adapter, io_base: (base_address & !0x3) as u16, irq: adapter.irq };
+		device.write_status(0);
+		device.write_status(VIRTIO_STATUS_ACKNOWLEDGE | VIRTIO_STATUS_DRIVER);
+		Some(device)
+	}
+
+	/// Returns the I/O port of the legacy virtio header.
+	pub fn io_base(&self) -> u16 {
+		self.io_base
+	}
+
+	/// Returns the legacy PCI IRQ of the device or None if it has none.
+	pub fn irq(&self) -> Option<u8> {
+		if self.irq != 0 && self.irq != u8::max_value() {
+			Some(self.irq)
+		} else {
+			None
+		}
+	}
+
+	/// Lets the device access `size` bytes of physical memory at `physical_address` through DMA.
+	/// With DMA remapping, the device can only reach the memory passed to this function.
+	pub fn map_dma(&self, physical_address: usize, size: usize, flags: DmaFlags) -> Result<(), ()> {
+		iommu::map_identity(&self.adapter, physical_address, size, flags)
+	}
+
+	/// Accepts all features of `supported` that are also offered by the device and returns them.
+	pub fn negotiate_features(&self, supported: u32) -> u32 {
+		unsafe {
+			let features = Port::<u32>::new(self.io_base + VIRTIO_PCI_HOST_FEATURES).read() & supported;
+			Port::<u32>::new(self.io_base + VIRTIO_PCI_GUEST_FEATURES).write(features);
+			features
+		}
+	}
+
+	/// Allocates the memory for the virtqueue with the given index and passes it to the device.
+	pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, ()> {
+		let size = unsafe {
+			Port::<u16>::new(self.io_base + VIRTIO_PCI_QUEUE_SELECT).write(index);
+			Port::<u16>::new(self.io_base + VIRTIO_PCI_QUEUE_SIZE).read()
+		};
+		if size == 0 {
+			return Err(());
+		}
+
+		// The descriptor table is followed by the available ring, the used ring begins at the next aligned address.
+		let used_offset = align_up!(16 * size as usize + 6 + 2 * size as usize, VIRTIO_PCI_QUEUE_ALIGNMENT);
+		let total_size = used_offset + align_up!(6 + 8 * size as usize, VIRTIO_PCI_QUEUE_ALIGNMENT);
+
+		// mm::allocate provides physically contiguous memory, so the physical address of its start is sufficient.
+		// Most of the rings is only touched much later, so zeroing them must not evict other data from the caches.
+		let virtual_address = mm::allocate(total_size, PageTableEntryFlags::EXECUTE_DISABLE);
+		unsafe { mm::memset_nt(virtual_address as *mut u8, 0, total_size); }
+		let physical_address = paging::virtual_to_physical(virtual_address);
+
+		// The device reads the descriptors and the available ring and writes the used ring.
+		if self.map_dma(physical_address, total_size, DmaFlags::READ | DmaFlags::WRITE).is_err() {
+			mm::deallocate(virtual_address, total_size);
+			return Err(());
+		}
+
+		let pfn = physical_address / VIRTIO_PCI_QUEUE_ALIGNMENT;
+		assert!(pfn <= u32::max_value() as usize, "Virtqueue at {:#X} is not addressable by a legacy device", physical_address);
+		unsafe { Port::<u32>::new(self.io_base + VIRTIO_PCI_QUEUE_PFN).write(pfn as u32); }
+
+		Ok(Virtqueue {
+			index: index,
+			size: size,
+			descriptors: virtual_address,
+			available: virtual_address + 16 * size as usize,
+			used: virtual_address + used_offset,
+			next_available: 0,
+			last_used: 0,
+		})
+	}
+
+	/// Tells the device that the driver is ready.
+	pub fn driver_ok(&self) {
+		let status = self.read_status();
+		self.write_status(status | VIRTIO_STATUS_DRIVER_OK);
+	}
+
+	/// Tells the device that the driver has given up on it.
+	pub fn fail(&self) {
+		let status = self.read_status();
+		self.write_status(status | VIRTIO_STATUS_FAILED);
+	}
+
+	/// Returns the interrupt status, which also acknowledges the interrupt.
+	pub fn read_isr(&self) -> u8 {
+		unsafe { Port::<u8>::new(self.io_base + VIRTIO_PCI_ISR).read() }
+	}
+
+	/// Reads a 32-bit value at the given offset of the device-specific configuration.
+	pub fn read_config_u32(&self, offset: u16) -> u32 {
+		unsafe { Port::<u32>::new(self.io_base + VIRTIO_PCI_CONFIG + offset).read() }
+	}
+
+	/// Writes a 32-bit value at the given offset of the device-specific configuration.
+	pub fn write_config_u32(&self, offset: u16, value: u32) {
+		unsafe { Port::<u32>::new(self.io_base + VIRTIO_PCI_CONFIG + offset).write(value); }
+	}
+
+	fn notify(&self, queue_index: u16) {
+		unsafe { Port::<u16>::new(self.io_base + VIRTIO_PCI_QUEUE_NOTIFY).write(queue_index); }
+	}
+
+	fn read_status(&self) -> u8 {
+		unsafe { Port::<u8>::new(self.io_base + VIRTIO_PCI_STATUS).read() }
+	}
+
+	fn write_status(&self, status: u8) {
+		unsafe { Port::<u8>::new(self.io_base + VIRTIO_PCI_STATUS).write(status); }
+	}
+}
+
+/// A split virtqueue in the legacy memory layout.
+/// All ring addresses are virtual addresses of memory allocated in VirtioDevice::setup_queue.
+pub struct Virtqueue {
+	index: u16,
+	size: u16,
+	descriptors: usize,
+	available: usize,
+	used: usize,
+	next_available: u16,
+	last_used: u16,
+}
+
+impl Virtqueue {
+	/// Passes a single buffer to the device and waits until the device has processed it.
+	/// The used ring is polled instead of waiting for an interrupt, so this also works with interrupts disabled.
+	pub fn transfer(&mut self, device: &VirtioDevice, physical_address: usize, length: usize, device_writable: bool) {
+		let slot = (self.next_available % self.size) as usize;
+
+		unsafe {
+			ptr::write_volatile(
+				(self.descriptors + 16 * slot) as *mut VirtqDescriptor,
+				VirtqDescriptor {
+					address: physical_address as u64,
+					length: length as u32,
+					flags: if device_writable { VIRTQ_DESC_F_WRITE } else { 0 },
+					next: 0,
+				}
+			);
+
+			// Put the descriptor into the available ring before publishing the new index.
+			ptr::write_volatile((self.available + 4 + 2 * slot) as *mut u16, slot as u16);
+			fence(Ordering::SeqCst);
+			self.next_available = self.next_available.wrapping_add(1);
+			ptr::write_volatile((self.available + 2) as *mut u16, self.next_available);
+			fence(Ordering::SeqCst);
+		}
+
+		device.notify(self.index);
+
+		// Wait until the device has moved the descriptor into the used ring.
+		while unsafe { ptr::read_volatile((self.used + 2) as *const u16) } == self.last_used {
+			processor::pause();
+		}
+
+		fence(Ordering::SeqCst);
+		self.last_used = self.last_used.wrapping_add(1);
+	}
+}
@@ -25,28 +25,119 @@
 //! vs. multi-kernel, hypervisor, etc.) as well as central parsing of the
 //! command-line parameters.
 
-use core::{slice, str};
+use alloc::string::String;
+use alloc::vec::Vec;
+use arch::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
+use core::{cmp, slice, str};
+use hermit_multiboot::{Framebuffer, Multiboot};
+use mm;
 
 
 extern "C" {
+	static boot_info: BootInfo;
 	static cmdline: *const u8;
 	static cmdsize: usize;
+	static limit: usize;
+	static mb_info: usize;
+	static possible_cpus: u32;
 	static single_kernel: u32;
 	static uhyve: u32;
 }
 
+/// "HCBI" in little-endian byte order.
+const BOOT_INFO_MAGIC: u32 = 0x4942_4348;
+
+/// BootInfo version implemented by this kernel.
+const BOOT_INFO_VERSION: u32 = 1;
+
+static mut COMMAND_LINE: &'static str = "";
 static mut COMMAND_LINE_CPU_FREQUENCY: u16 = 0;
 static mut IS_PROXY: bool = false;
+static mut MODULES: Option<Vec<Module>> = None;
+
+
+/// Boot information passed by uhyve at a fixed place in the kernel image (boot_info in entry.asm).
+///
+/// Newer versions may only append fields, so a kernel reads the fields it knows from any version
+/// at least as new as its own, and uhyve can check the version to find out which fields are read.
+/// A zero magic number means that uhyve only knows the legacy fields (limit, cmdline, etc.).
+#[repr(C)]
+pub struct BootInfo {
+	/// BOOT_INFO_MAGIC
+	pub magic: u32,
+	/// Version of this structure written by uhyve.
+	pub version: u32,
+	/// Size of the guest memory in bytes.
+	pub memory_size: u64,
+	/// Physical address of the command line.
+	pub cmdline: u64,
+	/// Length of the command line in bytes.
+	pub cmdsize: u64,
+	/// Number of virtual CPUs.
+	pub possible_cpus: u32,
+	/// TSC frequency of the host in kHz or zero if unknown.
+	pub tsc_freq: u32,
+	/// Wall-clock time at boot in microseconds since the Unix epoch or zero if unknown.
+	pub boot_time: u64,
+}
+
+/// A module loaded by the Multiboot boot loader, e.g. an initial ramdisk or an application binary.
+/// Its physical memory is reserved and not handed out by the memory manager.
+pub struct Module {
+	/// Physical address of the first byte of the module.
+	pub start_address: usize,
+	/// Physical address of the first byte after the module.
+	pub end_address: usize,
+	/// String associated to the module by the boot loader (usually its command line).
+	pub string: String,
+}
+
+/// Copies the NUL-terminated string at the given physical address.
+/// Strings longer than a page are truncated.
+fn read_physical_string(physical_address: usize) -> String {
+	let size = BasePageSize::SIZE;
+	let virtual_address = mm::map_physical(physical_address, size, PageTableEntryFlags::EXECUTE_DISABLE);
+
+	let string = {
+		let bytes = unsafe { slice::from_raw_parts(virtual_address as *const u8, size) };
+		let length = bytes.iter().position(|&byte| byte == 0).unwrap_or(size);
+		String::from_utf8_lossy(&bytes[..length]).into_owned()
+	};
+
+	mm::unmap_physical(virtual_address, size);
+	string
+}
+
+/// Collects the modules from the Multiboot information.
+/// The list of modules itself has already been identity-mapped by paging::init.
+unsafe fn parse_modules() {
+	let mut modules = Vec::new();
+
+	if mb_info > 0 {
+		let mb = Multiboot::new(mb_info);
+		for module in mb.modules().unwrap_or(&[]) {
+			modules.push(Module {
+				start_address: module.start_address(),
+				end_address: cmp::max(module.start_address(), module.end_address()),
+				string: module.string_address().map_or(String::new(), read_physical_string),
+			});
+		}
+	}
+
+	MODULES = Some(modules);
+}
 
 
 unsafe fn parse_command_line() {
-	if cmdsize == 0 {
+	let (address, size) = command_line_range();
+	if size == 0 {
 		return;
 	}
 
 	// Convert the command-line into a Rust string slice.
-	let slice = slice::from_raw_parts(cmdline, cmdsize);
+	let slice = slice::from_raw_parts(address as *const u8, size);
 	let cmdline_str = str::from_utf8_unchecked(slice);
+	COMMAND_LINE = cmdline_str;
 
 	// Check for the -freq option.
 	if let Some(freq_index) = cmdline_str.find("-freq") {
@@ -62,10 +153,16 @@ unsafe fn parse_command_line() {
 pub fn init() {
 	unsafe {
 		parse_command_line();
+		parse_modules();
 
 		if uhyve > 0 {
 			// We are running under uhyve, which implies unikernel mode and no communication with "proxy".
 			IS_PROXY = false;
+
+			match get_boot_info() {
+				Some(info) => debug!("uhyve passed BootInfo version {}", info.version),
+				None => debug!("uhyve passed no BootInfo, using the legacy boot fields"),
+			}
 		} else if single_kernel == 0 {
 			// We are running side-by-side to Linux, which implies communication with "proxy".
 			IS_PROXY = true;
@@ -73,6 +170,38 @@ pub fn init() {
 	}
 }
 
+/// Returns the value of a `name=value` command-line argument.
+/// A bare `name` argument yields an empty string, so flags can be checked using `is_some()`.
+/// Only valid after calling init()!
+pub fn get_arg(name: &str) -> Option<&'static str> {
+	let command_line = unsafe { COMMAND_LINE };
+
+	for arg in command_line.split_whitespace() {
+		if arg == name {
+			return Some("");
+		} else if arg.starts_with(name) && arg[name.len()..].starts_with('=') {
+			return Some(&arg[name.len() + 1..]);
+		}
+	}
+
+	None
+}
+
+/// Parses a decimal or a "0x"-prefixed hexadecimal number given on the command line.
+pub fn parse_integer(value: &str) -> Option<usize> {
+	if value.starts_with("0x") || value.starts_with("0X") {
+		usize::from_str_radix(&value[2..], 16).ok()
+	} else {
+		value.parse().ok()
+	}
+}
+
+/// Returns the modules loaded by the Multiboot boot loader (possibly none).
+/// Only valid after calling init()!
+pub fn modules() -> &'static [Module] {
+	unsafe { MODULES.as_ref().map_or(&[], |modules| &modules[..]) }
+}
+
 /// CPU Frequency in MHz if given through the -freq command-line parameter, otherwise zero.
 pub fn get_command_line_cpu_frequency() -> u16 {
 	unsafe { COMMAND_LINE_CPU_FREQUENCY }
@@ -93,3 +222,95 @@ pub fn is_single_kernel() -> bool {
 pub fn is_uhyve() -> bool {
 	unsafe { uhyve > 0 }
 }
+
+/// Returns the boot information passed by uhyve or None if there is none or it is older than this kernel.
+pub fn get_boot_info() -> Option<&'static BootInfo> {
+	let info = unsafe { &boot_info };
+	if is_uhyve() && info.magic == BOOT_INFO_MAGIC && info.version >= BOOT_INFO_VERSION {
+		Some(info)
+	} else {
+		None
+	}
+}
+
+/// Returns the physical address and length of the command line passed by the loader.
+/// The length is zero if there is no command line.
+pub fn command_line_range() -> (usize, usize) {
+	match get_boot_info() {
+		Some(info) => (info.cmdline as usize, info.cmdsize as usize),
+		None => unsafe { (cmdline as usize, cmdsize) },
+	}
+}
+
+/// Returns the end address of the physical memory if the loader has passed it instead of
+/// a Multiboot memory map, otherwise zero.
+pub fn get_memory_limit() -> usize {
+	match get_boot_info() {
+		Some(info) => info.memory_size as usize,
+		None => unsafe { limit },
+	}
+}
+
+/// Returns the number of CPUs passed by the loader or zero if it has not passed any.
+pub fn get_possible_cpus() -> u32 {
+	match get_boot_info() {
+		Some(info) => info.possible_cpus,
+		None => unsafe { possible_cpus },
+	}
+}
+
+/// Returns the TSC frequency in kHz passed by uhyve or zero if unknown.
+pub fn get_tsc_frequency() -> u32 {
+	get_boot_info().map_or(0, |info| info.tsc_freq)
+}
+
+/// Returns the wall-clock time at boot in microseconds since the Unix epoch passed by uhyve or None if unknown.
+pub fn get_boot_time() -> Option<u64> {
+	match get_boot_info() {
+		Some(info) if info.boot_time > 0 => Some(info.boot_time),
+		_ => None,
+	}
+}
+
+/// Returns the framebuffer set up by the Multiboot loader or None if there is none.
+pub fn get_framebuffer() -> Option<Framebuffer> {
+	unsafe {
+		if mb_info > 0 {
+			Multiboot::new(mb_info).framebuffer()
+		} else {
+			None
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn arguments_are_looked_up_by_their_full_name() {
+		unsafe { COMMAND_LINE = "-freq 2000 panicky=1 panic=reboot quiet cpus=0x4"; }
+
+		// "panicky=1" must not be taken for "panic", which the panic handler checks for "reboot".
+		assert_eq!(get_arg("panic"), Some("reboot"));
+		assert_eq!(get_arg("panicky"), Some("1"));
+		assert_eq!(get_arg("quiet"), Some(""));
+		assert_eq!(get_arg("-freq"), Some(""));
+		assert_eq!(get_arg("pan"), None);
+		assert_eq!(get_arg("cpus").and_then(parse_integer), Some(4));
+
+		unsafe { COMMAND_LINE = ""; }
+		assert_eq!(get_arg("panic"), None);
+	}
+
+	#[test]
+	fn integers_are_parsed_in_decimal_and_hexadecimal() {
+		assert_eq!(parse_integer("42"), Some(42));
+		assert_eq!(parse_integer("0x2A"), Some(42));
+		assert_eq!(parse_integer("0X2a"), Some(42));
+		assert_eq!(parse_integer("0x"), None);
+		assert_eq!(parse_integer("-1"), None);
+		assert_eq!(parse_integer(""), None);
+	}
+}
@@ -0,0 +1,141 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Filesystems implemented inside the kernel.
+
+pub mod ramfs;
+
+use alloc::btree_map::BTreeMap;
+use core::cmp;
+use ramdisk;
+use synch::spinlock::Spinlock;
+
+
+/// File descriptors of files opened from ramfs have this bit set, so that they never collide with
+/// the file descriptors of the host or of lwIP.
+pub const RAMFS_FD_BIT: i32 = 1 << 29;
+
+const SEEK_SET: i32 = 0;
+const SEEK_CUR: i32 = 1;
+const SEEK_END: i32 = 2;
+
+lazy_static! {
+	/// Files opened from ramfs, keyed by their file descriptor without RAMFS_FD_BIT.
+	static ref OPEN_FILES: Spinlock<BTreeMap<i32, OpenFile>> = Spinlock::new(BTreeMap::new());
+}
+
+
+struct OpenFile {
+	data: &'static [u8],
+	position: usize,
+}
+
+/// Returns the position after seeking by `offset` bytes relative to `whence` in a file of `size` bytes
+/// or None if it would be negative or `whence` is invalid. Seeking past the end is allowed.
+fn seek_position(position: usize, size: usize, offset: isize, whence: i32) -> Option<usize> {
+	let base = match whence {
+		SEEK_SET => 0,
+		SEEK_CUR => position,
+		SEEK_END => size,
+		_ => return None,
+	};
+
+	if offset < 0 {
+		base.checked_sub(offset.wrapping_neg() as usize)
+	} else {
+		base.checked_add(offset as usize)
+	}
+}
+
+/// Mounts the ramdisk selected on the command line as ramfs, if it holds a ustar archive.
+pub fn init() {
+	if let Some(ramdisk) = ramdisk::get() {
+		if ramfs::mount(ramdisk.as_slice()).is_err() {
+			warn!("Ramdisk is no ustar archive and is only available as block device");
+		}
+	}
+}
+
+/// Returns whether the file descriptor refers to a file opened from ramfs.
+pub fn is_ramfs_fd(fd: i32) -> bool {
+	fd >= 0 && (fd & RAMFS_FD_BIT) != 0
+}
+
+/// Opens the ramfs file at `path` for reading and returns its file descriptor,
+/// or None if there is no such file.
+pub fn open(path: &str) -> Option<i32> {
+	let data = ramfs::open(path)?;
+	let mut files = OPEN_FILES.lock();
+	let fd = (0..RAMFS_FD_BIT).find(|fd| !files.contains_key(fd))?;
+	files.insert(fd, OpenFile { data: data, position: 0 });
+
+	Some(fd | RAMFS_FD_BIT)
+}
+
+/// Reads from the current position of a ramfs file into `buffer` and returns the number of bytes read.
+pub fn read(fd: i32, buffer: &mut [u8]) -> Result<usize, ()> {
+	let mut files = OPEN_FILES.lock();
+	let file = files.get_mut(&(fd & !RAMFS_FD_BIT)).ok_or(())?;
+	if file.position >= file.data.len() {
+		return Ok(0);
+	}
+
+	let length = cmp::min(buffer.len(), file.data.len() - file.position);
+	buffer[..length].copy_from_slice(&file.data[file.position..file.position + length]);
+	file.position += length;
+	Ok(length)
+}
+
+/// Moves the position of a ramfs file like lseek and returns the new position.
+pub fn lseek(fd: i32, offset: isize, whence: i32) -> Result<usize, ()> {
+	let mut files = OPEN_FILES.lock();
+	let file = files.get_mut(&(fd & !RAMFS_FD_BIT)).ok_or(())?;
+	file.position = seek_position(file.position, file.data.len(), offset, whence).ok_or(())?;
+	Ok(file.position)
+}
+
+/// Closes a ramfs file.
+pub fn close(fd: i32) -> Result<(), ()> {
+	OPEN_FILES.lock().remove(&(fd & !RAMFS_FD_BIT)).map(|_| ()).ok_or(())
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn seek_positions_are_checked() {
+		assert_eq!(seek_position(5, 10, 3, SEEK_SET), Some(3));
+		assert_eq!(seek_position(5, 10, 3, SEEK_CUR), Some(8));
+		assert_eq!(seek_position(5, 10, -2, SEEK_CUR), Some(3));
+		assert_eq!(seek_position(5, 10, -10, SEEK_END), Some(0));
+		assert_eq!(seek_position(5, 10, 4, SEEK_END), Some(14));
+
+		assert_eq!(seek_position(5, 10, -6, SEEK_CUR), None);
+		assert_eq!(seek_position(5, 10, -1, SEEK_SET), None);
+		assert_eq!(seek_position(5, 10, isize::min_value(), SEEK_END), None);
+		assert_eq!(seek_position(usize::max_value(), 10, 1, SEEK_CUR), None);
+		assert_eq!(seek_position(5, 10, 0, 3), None);
+	}
+}
@@ -0,0 +1,382 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Read-only in-memory filesystem over a ustar archive.
+//!
+//! The archive is typically the contents of a ramdisk. All file contents are returned as
+//! slices into the archive, nothing is copied.
+//! Long names are supported through both the ustar prefix field and GNU long name ('L') entries.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+
+/// Size of a header and the granularity of all data in a tar archive.
+const BLOCK_SIZE: usize = 512;
+
+const NAME_OFFSET: usize = 0;
+const NAME_SIZE: usize = 100;
+const SIZE_OFFSET: usize = 124;
+const SIZE_SIZE: usize = 12;
+const CHECKSUM_OFFSET: usize = 148;
+const CHECKSUM_SIZE: usize = 8;
+const TYPEFLAG_OFFSET: usize = 156;
+const MAGIC_OFFSET: usize = 257;
+/// POSIX ustar magic. Old GNU archives have "ustar  " followed by a NUL instead and no prefix field.
+const USTAR_MAGIC: &[u8] = b"ustar\0";
+const PREFIX_OFFSET: usize = 345;
+const PREFIX_SIZE: usize = 155;
+
+const TYPE_REGULAR: u8 = b'0';
+const TYPE_REGULAR_OLD: u8 = 0;
+const TYPE_DIRECTORY: u8 = b'5';
+const TYPE_GNU_LONGNAME: u8 = b'L';
+
+/// The mounted archive.
+static mut ARCHIVE: Option<&'static [u8]> = None;
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntryKind {
+	File,
+	Directory,
+	/// Links, devices, FIFOs, etc. are listed, but cannot be opened.
+	Other,
+}
+
+pub struct Entry {
+	/// Normalized path without leading "./" or "/" and without a trailing "/".
+	pub path: String,
+	pub kind: EntryKind,
+	pub data: &'static [u8],
+}
+
+/// Iterator over the entries of a ustar archive.
+/// Stops at the end marker (a zero block) or at the first malformed header.
+pub struct Entries {
+	archive: &'static [u8],
+	offset: usize,
+}
+
+impl Iterator for Entries {
+	type Item = Entry;
+
+	fn next(&mut self) -> Option<Entry> {
+		let mut long_name: Option<&'static [u8]> = None;
+
+		loop {
+			if self.offset + BLOCK_SIZE > self.archive.len() {
+				return None;
+			}
+
+			let header = &self.archive[self.offset..self.offset + BLOCK_SIZE];
+			if header.iter().all(|&byte| byte == 0) {
+				// This is the end marker.
+				return None;
+			}
+
+			if !is_valid_header(header) {
+				warn!("ramfs: Invalid tar header at offset {:#X}", self.offset);
+				return None;
+			}
+
+			let size = parse_octal(&header[SIZE_OFFSET..SIZE_OFFSET + SIZE_SIZE])? as usize;
+			let data_start = self.offset + BLOCK_SIZE;
+			let data_end = data_start.checked_add(size)?;
+			if data_end > self.archive.len() {
+				warn!("ramfs: Truncated tar entry at offset {:#X}", self.offset);
+				return None;
+			}
+
+			let data = &self.archive[data_start..data_end];
+			self.offset = data_start + align_up!(size, BLOCK_SIZE);
+
+			let typeflag = header[TYPEFLAG_OFFSET];
+			if typeflag == TYPE_GNU_LONGNAME {
+				// The data of this entry is the name of the following entry.
+				long_name = Some(until_nul(data));
+				continue;
+			}
+
+			let raw_path = match long_name {
+				Some(name) => String::from_utf8_lossy(name).into_owned(),
+				None => header_path(header),
+			};
+
+			let kind = match typeflag {
+				TYPE_REGULAR | TYPE_REGULAR_OLD => EntryKind::File,
+				TYPE_DIRECTORY => EntryKind::Directory,
+				_ => EntryKind::Other,
+			};
+
+			return Some(Entry {
+				path: String::from(normalize(&raw_path)),
+				kind: kind,
+				data: data,
+			});
+		}
+	}
+}
+
+/// Returns the bytes up to the first NUL byte.
+fn until_nul(field: &[u8]) -> &[u8] {
+	match field.iter().position(|&byte| byte == 0) {
+		Some(length) => &field[..length],
+		None => field,
+	}
+}
+
+/// Parses a NUL- or space-terminated octal number field.
+fn parse_octal(field: &[u8]) -> Option<u64> {
+	let mut value: u64 = 0;
+
+	for &byte in field.iter().skip_while(|&&byte| byte == b' ') {
+		match byte {
+			b'0'...b'7' => value = value.checked_mul(8)? + (byte - b'0') as u64,
+			0 | b' ' => break,
+			_ => return None,
+		}
+	}
+
+	Some(value)
+}
+
+fn is_valid_header(header: &[u8]) -> bool {
+	let stored = match parse_octal(&header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + CHECKSUM_SIZE]) {
+		Some(checksum) => checksum,
+		None => return false,
+	};
+
+	// The checksum is calculated with the checksum field itself treated as spaces.
+	let computed = header.iter().enumerate().fold(0u64, |sum, (i, &byte)| {
+		if i >= CHECKSUM_OFFSET && i < CHECKSUM_OFFSET + CHECKSUM_SIZE {
+			sum + b' ' as u64
+		} else {
+			sum + byte as u64
+		}
+	});
+
+	stored == computed
+}
+
+/// Assembles the path from the name field and, for POSIX ustar archives, the prefix field.
+fn header_path(header: &[u8]) -> String {
+	let name = String::from_utf8_lossy(until_nul(&header[NAME_OFFSET..NAME_OFFSET + NAME_SIZE]));
+
+	if &header[MAGIC_OFFSET..MAGIC_OFFSET + USTAR_MAGIC.len()] == USTAR_MAGIC {
+		let prefix = until_nul(&header[PREFIX_OFFSET..PREFIX_OFFSET + PREFIX_SIZE]);
+		if !prefix.is_empty() {
+			let mut path = String::from_utf8_lossy(prefix).into_owned();
+			path.push('/');
+			path.push_str(&name);
+			return path;
+		}
+	}
+
+	name.into_owned()
+}
+
+fn normalize(path: &str) -> &str {
+	let mut path = path;
+
+	loop {
+		if path.starts_with("./") {
+			path = &path[2..];
+		} else if path.starts_with('/') {
+			path = &path[1..];
+		} else {
+			break;
+		}
+	}
+
+	let path = path.trim_right_matches('/');
+	if path == "." {
+		""
+	} else {
+		path
+	}
+}
+
+/// Returns the first path component of `path` below the directory `directory`, if any.
+/// This also yields directories, which only appear implicitly as part of a file path.
+fn child_name<'a>(directory: &str, path: &'a str) -> Option<&'a str> {
+	let relative = if directory.is_empty() {
+		path
+	} else if path.starts_with(directory) && path[directory.len()..].starts_with('/') {
+		&path[directory.len() + 1..]
+	} else {
+		return None;
+	};
+
+	match relative.find('/') {
+		Some(index) => Some(&relative[..index]),
+		None if relative.is_empty() => None,
+		None => Some(relative),
+	}
+}
+
+/// Mounts the given ustar archive, replacing any previously mounted one.
+/// Fails if the archive does not begin with a valid tar header.
+pub fn mount(archive: &'static [u8]) -> Result<(), ()> {
+	if archive.len() < BLOCK_SIZE || !is_valid_header(&archive[..BLOCK_SIZE]) {
+		return Err(());
+	}
+
+	unsafe {
+		ARCHIVE = Some(archive);
+	}
+
+	info!("ramfs: Mounted archive with {} entries", entries().count());
+	for name in read_dir("") {
+		debug!("ramfs: /{}", name);
+	}
+
+	Ok(())
+}
+
+/// Returns an iterator over all entries of the mounted archive.
+/// The iterator is empty if nothing has been mounted.
+pub fn entries() -> Entries {
+	Entries {
+		archive: unsafe { ARCHIVE.unwrap_or(&[]) },
+		offset: 0,
+	}
+}
+
+/// Returns the contents of the regular file at `path`, or None if there is no such file.
+pub fn open(path: &str) -> Option<&'static [u8]> {
+	let path = normalize(path);
+
+	entries()
+		.find(|entry| entry.kind == EntryKind::File && entry.path == path)
+		.map(|entry| entry.data)
+}
+
+/// Returns the names of all entries directly inside the directory `path`.
+/// Use "" or "/" for the root directory.
+pub fn read_dir(path: &str) -> Vec<String> {
+	let path = normalize(path);
+	let mut names: Vec<String> = Vec::new();
+
+	for entry in entries() {
+		if let Some(name) = child_name(path, &entry.path) {
+			if !names.iter().any(|existing| existing == name) {
+				names.push(String::from(name));
+			}
+		}
+	}
+
+	names
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Builds a POSIX ustar header with a valid checksum.
+	fn header(name: &str, prefix: &str, typeflag: u8, size: usize) -> Vec<u8> {
+		let mut header = vec![0u8; BLOCK_SIZE];
+		header[NAME_OFFSET..NAME_OFFSET + name.len()].copy_from_slice(name.as_bytes());
+		header[SIZE_OFFSET..SIZE_OFFSET + SIZE_SIZE].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+		header[TYPEFLAG_OFFSET] = typeflag;
+		header[MAGIC_OFFSET..MAGIC_OFFSET + USTAR_MAGIC.len()].copy_from_slice(USTAR_MAGIC);
+		header[PREFIX_OFFSET..PREFIX_OFFSET + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+		let checksum = header.iter().map(|&byte| byte as u64).sum::<u64>() + CHECKSUM_SIZE as u64 * b' ' as u64;
+		header[CHECKSUM_OFFSET..CHECKSUM_OFFSET + CHECKSUM_SIZE].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+		header
+	}
+
+	/// Appends an entry with the given data, padded to the block size.
+	fn push_entry(archive: &mut Vec<u8>, name: &str, prefix: &str, typeflag: u8, data: &[u8]) {
+		archive.extend(header(name, prefix, typeflag, data.len()));
+		archive.extend_from_slice(data);
+		let padding = align_up!(data.len(), BLOCK_SIZE) - data.len();
+		archive.extend(vec![0u8; padding]);
+	}
+
+	#[test]
+	fn octal_fields_are_parsed() {
+		assert_eq!(parse_octal(b"0000644\0"), Some(0o644));
+		assert_eq!(parse_octal(b"   17 \0"), Some(0o17));
+		assert_eq!(parse_octal(b"\0\0\0\0"), Some(0));
+		assert_eq!(parse_octal(b"0000008\0"), None);
+		assert_eq!(parse_octal(b"7777777777777777777777"), None);
+	}
+
+	#[test]
+	fn paths_are_assembled_and_normalized() {
+		assert_eq!(header_path(&header("data.bin", "usr/share", TYPE_REGULAR, 0)), "usr/share/data.bin");
+		assert_eq!(header_path(&header("data.bin", "", TYPE_REGULAR, 0)), "data.bin");
+
+		// Old GNU archives have no prefix field, so the bytes there must be ignored.
+		let mut old_gnu = header("data.bin", "usr/share", TYPE_REGULAR, 0);
+		old_gnu[MAGIC_OFFSET..MAGIC_OFFSET + 8].copy_from_slice(b"ustar  \0");
+		assert_eq!(header_path(&old_gnu), "data.bin");
+
+		assert_eq!(normalize("./etc/hosts"), "etc/hosts");
+		assert_eq!(normalize("/./etc/"), "etc");
+		assert_eq!(normalize("./"), "");
+		assert_eq!(normalize("."), "");
+
+		assert_eq!(child_name("", "etc/hosts"), Some("etc"));
+		assert_eq!(child_name("etc", "etc/hosts"), Some("hosts"));
+		assert_eq!(child_name("etc", "etc"), None);
+		assert_eq!(child_name("et", "etc/hosts"), None);
+	}
+
+	#[test]
+	fn archive_entries_are_listed() {
+		let long_name = format!("{}/file.txt", "a".repeat(120));
+		let data: Vec<u8> = (0..600).map(|i| i as u8).collect();
+
+		let mut archive = Vec::new();
+		push_entry(&mut archive, "./etc/", "", TYPE_DIRECTORY, b"");
+		push_entry(&mut archive, "./etc/hosts", "", TYPE_REGULAR, b"127.0.0.1 localhost\n");
+		push_entry(&mut archive, "././@LongLink", "", TYPE_GNU_LONGNAME, format!("{}\0", long_name).as_bytes());
+		push_entry(&mut archive, "ignored", "", TYPE_REGULAR, b"long");
+		push_entry(&mut archive, "data.bin", "usr/share", TYPE_REGULAR_OLD, &data);
+		push_entry(&mut archive, "link", "", b'2', b"");
+
+		// Nothing after the end marker is listed.
+		archive.extend(vec![0u8; 2 * BLOCK_SIZE]);
+		push_entry(&mut archive, "after_end", "", TYPE_REGULAR, b"");
+
+		let archive: &'static [u8] = Box::leak(archive.into_boxed_slice());
+		let entries: Vec<Entry> = Entries { archive: archive, offset: 0 }.collect();
+		let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+		assert_eq!(paths, vec!["etc", "etc/hosts", long_name.as_str(), "usr/share/data.bin", "link"]);
+
+		assert_eq!(entries[0].kind, EntryKind::Directory);
+		assert_eq!(entries[1].kind, EntryKind::File);
+		assert_eq!(entries[1].data, b"127.0.0.1 localhost\n");
+		assert_eq!(entries[2].data, b"long");
+		assert_eq!(entries[3].kind, EntryKind::File);
+		assert_eq!(entries[3].data, &data[..]);
+		assert_eq!(entries[4].kind, EntryKind::Other);
+
+		// The data is not copied.
+		assert_eq!(entries[1].data.as_ptr(), archive[2 * BLOCK_SIZE..].as_ptr());
+	}
+}
@@ -45,14 +45,20 @@
 #![feature(linkage)]
 #![feature(specialization)]
 #![feature(panic_implementation)]
+#![feature(panic_info_message)]
+#![cfg_attr(feature = "gdbstub", feature(naked_functions))]
 #![allow(unused_macros)]
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 include!(concat!(env!("CARGO_TARGET_DIR"), "/config.rs"));
 
 // EXTERNAL CRATES
 extern crate alloc;
 
+// Unit tests link against the standard library, which only puts std into the crate root.
+#[cfg(test)]
+extern crate core;
+
 #[macro_use]
 extern crate bitflags;
 
@@ -75,10 +81,15 @@ mod logging;
 mod arch;
 mod collections;
 mod console;
+mod drivers;
 mod environment;
 mod errno;
+mod fs;
 mod kernel_message_buffer;
 mod mm;
+mod panic_info;
+mod ramdisk;
+mod random;
 mod runtime_glue;
 mod scheduler;
 mod synch;
@@ -93,6 +104,7 @@ use processor::get_frequency;
 use core::ptr;
 use mm::allocator;
 
+#[cfg(not(test))]
 #[global_allocator]
 static ALLOCATOR: &'static allocator::HermitAllocator = &allocator::HermitAllocator;
 
@@ -178,6 +190,13 @@ pub unsafe extern "C" fn boot_processor_main() {
 	scheduler::init();
 	scheduler::add_current_core();
 
+	if environment::is_single_kernel() && !environment::is_uhyve() {
+		drivers::virtio::balloon::init();
+	}
+
+	ramdisk::init();
+	fs::init();
+
 	if environment::is_single_kernel() && !environment::is_uhyve() {
 		arch::boot_application_processors();
 	}
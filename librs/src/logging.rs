@@ -22,6 +22,9 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use arch;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 
 /// An enum representing the available verbosity levels of the logger.
 #[allow(dead_code)]
@@ -61,6 +64,43 @@ pub struct KernelLogger {
 /// default logger to handle kernel messages
 pub static LOGGER: KernelLogger = KernelLogger { log_level: LogLevel::Info };
 
+/// Per-callsite state of a message logged through log_ratelimited!
+pub struct RateLimit {
+	/// Uptime in milliseconds when the message may be emitted again.
+	next_emit_ms: AtomicUsize,
+	/// Number of messages suppressed since the last one was emitted.
+	suppressed: AtomicUsize,
+}
+
+impl RateLimit {
+	pub const fn new() -> Self {
+		Self {
+			next_emit_ms: AtomicUsize::new(0),
+			suppressed: AtomicUsize::new(0),
+		}
+	}
+
+	/// Returns the number of suppressed messages if the message shall be emitted now
+	/// or None if it shall be suppressed, because it has already been emitted in the last `interval_ms` milliseconds.
+	pub fn check(&self, interval_ms: usize) -> Option<usize> {
+		// Without a clock, we cannot limit anything.
+		if !arch::time::is_clock_available() {
+			return Some(0);
+		}
+
+		let now_ms = (arch::time::uptime_ns() / 1_000_000) as usize;
+		let next_emit_ms = self.next_emit_ms.load(Ordering::Relaxed);
+
+		// Only one concurrent caller wins the right to emit the message.
+		if now_ms >= next_emit_ms && self.next_emit_ms.compare_and_swap(next_emit_ms, now_ms + interval_ms, Ordering::Relaxed) == next_emit_ms {
+			Some(self.suppressed.swap(0, Ordering::Relaxed))
+		} else {
+			self.suppressed.fetch_add(1, Ordering::Relaxed);
+			None
+		}
+	}
+}
+
 
 macro_rules! printlog {
 	($type:expr, $cmp_level:expr, $($arg:tt)+) => ({
@@ -121,3 +161,21 @@ macro_rules! debug {
 macro_rules! debug_mem {
 	($($arg:tt)+) => (printlog!("DEBUG_MEM", $crate::logging::LogLevel::DebugMem, $($arg)+));
 }
+
+/// Print a formatted message at most once every `interval_ms` milliseconds from this callsite,
+/// along with the number of messages suppressed in between (like printk_ratelimited in Linux).
+/// The level can be given as the name of a log macro (warn if omitted), e.g. `log_ratelimited!(1000, info, "...")`.
+macro_rules! log_ratelimited {
+	($interval_ms:expr, $level:ident, $($arg:tt)+) => ({
+		static RATE_LIMIT: $crate::logging::RateLimit = $crate::logging::RateLimit::new();
+
+		if let Some(suppressed) = RATE_LIMIT.check($interval_ms) {
+			if suppressed > 0 {
+				$level!("{} ({} similar messages suppressed)", format_args!($($arg)+), suppressed);
+			} else {
+				$level!($($arg)+);
+			}
+		}
+	});
+	($interval_ms:expr, $($arg:tt)+) => (log_ratelimited!($interval_ms, warn, $($arg)+));
+}
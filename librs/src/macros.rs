@@ -35,6 +35,8 @@ macro_rules! align_up {
 ///
 /// From http://blog.phil-opp.com/rust-os/printing-to-screen.html, but tweaked
 /// for HermitCore.
+/// Unit tests run on the host and print through the standard library instead.
+#[cfg(not(test))]
 macro_rules! print {
 	($($arg:tt)+) => ({
 		use core::fmt::Write;
@@ -43,6 +45,32 @@ macro_rules! print {
 }
 
 /// Print formatted text to our console, followed by a newline.
+#[cfg(not(test))]
 macro_rules! println {
 	($($arg:tt)+) => (print!("{}\n", format_args!($($arg)+)));
 }
+
+/// Panic with the given message and let the panic handler dump the state of the current CPU core and the memory manager.
+///
+/// The dump is printed after the other cores have been stopped and doesn't allocate memory,
+/// so this is usable even when the heap is corrupted.
+macro_rules! kpanic {
+	($($arg:tt)+) => ({
+		$crate::runtime_glue::request_kernel_state();
+		panic!($($arg)+);
+	});
+}
+
+/// Like `assert!`, but dumps the state of the current CPU core and the memory manager through `kpanic!` on failure.
+macro_rules! kassert {
+	($cond:expr) => ({
+		if !$cond {
+			kpanic!("assertion failed: {}", stringify!($cond));
+		}
+	});
+	($cond:expr, $($arg:tt)+) => ({
+		if !$cond {
+			kpanic!($($arg)+);
+		}
+	});
+}
@@ -115,7 +115,7 @@ fn alloc_system(layout: Layout) -> *mut Opaque {
 	debug_mem!("Allocating {} bytes using the System Allocator", layout.size());
 
 	let size = align_up!(layout.size(), BasePageSize::SIZE);
-	mm::allocate(size, PageTableEntryFlags::EXECUTE_DISABLE) as *mut Opaque
+	mm::allocate_heap(size, PageTableEntryFlags::EXECUTE_DISABLE) as *mut Opaque
 }
 
 /// A deallocation using the initialized System Allocator.
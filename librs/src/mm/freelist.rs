@@ -27,18 +27,100 @@ use collections::{DoublyLinkedList, Node};
 use mm;
 
 
+#[derive(Clone, Copy)]
 pub struct FreeListEntry {
 	pub start: usize,
 	pub end: usize,
 }
 
+/// A Free List normally takes the nodes for new entries from mm::POOL and moves unused nodes there.
+/// During early boot, it can use an own arena of spare nodes instead (see use_arena), so it does not depend on the
+/// order of POOL maintenance.
 pub struct FreeList {
 	pub list: DoublyLinkedList<FreeListEntry>,
+	/// Spare nodes used instead of mm::POOL while uses_pool is false.
+	arena: DoublyLinkedList<FreeListEntry>,
+	uses_pool: bool,
+}
+
+/// Summary of a Free List gathered in a single pass by FreeList::statistics.
+#[derive(Clone, Copy)]
+pub struct FreeListStatistics {
+	/// Number of entries (free blocks) in the list.
+	pub node_count: usize,
+	/// Sum of the sizes of all free blocks.
+	pub free_bytes: usize,
+	/// Size of the largest free block.
+	pub largest_block: usize,
+}
+
+impl FreeListStatistics {
+	/// Returns 1 minus the share of the largest free block in the free memory.
+	/// This is 0.0 if all free memory is in a single block (or there is none) and approaches 1.0 the more
+	/// the free memory is split into small blocks.
+	pub fn fragmentation(&self) -> f32 {
+		if self.free_bytes == 0 {
+			0.0
+		} else {
+			1.0 - self.largest_block as f32 / self.free_bytes as f32
+		}
+	}
 }
 
 impl FreeList {
 	pub const fn new() -> Self {
-		Self { list: DoublyLinkedList::new() }
+		Self { list: DoublyLinkedList::new(), arena: DoublyLinkedList::new(), uses_pool: true }
+	}
+
+	/// Lets this Free List use an own arena of `count` spare nodes instead of mm::POOL.
+	/// Call this before the first operation on the list while still in the Bootstrap Allocator, which provides the nodes.
+	pub fn use_arena(&mut self, count: usize) {
+		for _i in 0..count {
+			self.arena.push(Node::new(FreeListEntry { start: 0, end: 0 }));
+		}
+
+		self.uses_pool = false;
+	}
+
+	/// Lets this Free List use mm::POOL again and moves the remaining spare nodes of the arena there.
+	pub fn use_pool(&mut self) {
+		while let Some(node) = self.arena.head() {
+			self.arena.remove(node.clone());
+			unsafe { mm::POOL.give_back(node); }
+		}
+
+		self.uses_pool = true;
+	}
+
+	/// Gets a node for a new entry from mm::POOL or the arena.
+	/// An exhausted arena falls back to Node::new, which is still served by the Bootstrap Allocator.
+	fn take_node(&mut self, operation: &'static str) -> Rc<RefCell<Node<FreeListEntry>>> {
+		if self.uses_pool {
+			unsafe { mm::POOL.take(operation, self as *const Self as usize) }
+		} else if let Some(node) = self.arena.head() {
+			self.arena.remove(node.clone());
+			node
+		} else {
+			debug_mem!("Free List arena is empty, allocating a new node for {}", operation);
+			Node::new(FreeListEntry { start: 0, end: 0 })
+		}
+	}
+
+	/// Gives a node that is no longer part of the list back to mm::POOL or the arena for deletion or reuse.
+	fn return_node(&mut self, node: Rc<RefCell<Node<FreeListEntry>>>) {
+		if self.uses_pool {
+			unsafe { mm::POOL.give_back(node); }
+		} else {
+			self.arena.push(node);
+		}
+	}
+
+	/// Returns an iterator over copies of all entries in this Free List, sorted by ascending address.
+	pub fn iter(&self) -> impl Iterator<Item = FreeListEntry> {
+		self.list.iter().map(|node| {
+			let entry = node.borrow().value;
+			entry
+		})
 	}
 
 	pub fn allocate(&mut self, size: usize) -> Result<usize, ()> {
@@ -60,7 +142,7 @@ impl FreeList {
 				// We have found a region that has exactly the requested size.
 				// Return the address to the beginning of that region and move the node into the pool for deletion or reuse.
 				self.list.remove(node.clone());
-				unsafe { mm::POOL.list.push(node); }
+				self.return_node(node);
 				return Ok(region_start);
 			}
 		}
@@ -68,6 +150,34 @@ impl FreeList {
 		Err(())
 	}
 
+	/// Like allocate, but takes the memory from the end of the highest region that has at least the requested size.
+	pub fn allocate_high(&mut self, size: usize) -> Result<usize, ()> {
+		debug_mem!("Allocating {} bytes from the top of Free List {:#X}", size, self as *const Self as usize);
+
+		for node in self.list.iter_rev() {
+			let (region_end, region_size) = {
+				let borrowed = node.borrow();
+				(borrowed.value.end, borrowed.value.end - borrowed.value.start)
+			};
+
+			if region_size > size {
+				// Return the address to the last `size` bytes of that region and shrink the region from its end.
+				let address = region_end - size;
+				node.borrow_mut().value.end = address;
+				return Ok(address);
+			} else if region_size == size {
+				// The region has exactly the requested size.
+				// Move the node into the pool for deletion or reuse.
+				let address = node.borrow().value.start;
+				self.list.remove(node.clone());
+				self.return_node(node);
+				return Ok(address);
+			}
+		}
+
+		Err(())
+	}
+
 	#[inline]
 	fn allocate_address_for_node(&mut self, address: usize, end: usize, node: Rc<RefCell<Node<FreeListEntry>>>) -> bool {
 		let (region_start, region_end) = {
@@ -80,7 +190,7 @@ impl FreeList {
 			// We found free space that has exactly the address and size of the block we want to allocate.
 			// Remove it.
 			self.list.remove(node.clone());
-			unsafe { mm::POOL.list.push(node); }
+			self.return_node(node);
 			return true;
 		} else if region_start < address && region_end == end {
 			// We found free space in which the block we want to allocate lies right-aligned.
@@ -97,8 +207,7 @@ impl FreeList {
 			// Resize the free space to end at our block and add another free space entry that begins where our block ends.
 			node.borrow_mut().value.end = address;
 
-			let new_node = unsafe { mm::POOL.list.head().expect("Pool is empty when reserving memory") };
-			unsafe { mm::POOL.list.remove(new_node.clone()); }
+			let new_node = self.take_node("reserving memory");
 
 			{
 				let mut new_node_borrowed = new_node.borrow_mut();
@@ -129,6 +238,32 @@ impl FreeList {
 		Err(())
 	}
 
+	/// Allocate `size` bytes that end at or below `limit`, using the highest suitable address.
+	/// Regions that extend beyond the limit are split.
+	pub fn allocate_below(&mut self, size: usize, limit: usize) -> Result<usize, ()> {
+		debug_mem!("Allocating {} bytes below {:#X} from Free List {:#X}", size, limit, self as *const Self as usize);
+
+		for node in self.list.iter_rev() {
+			let (region_start, region_end) = {
+				let borrowed = node.borrow();
+				(borrowed.value.start, borrowed.value.end)
+			};
+
+			// Only consider the part of this region that lies below the limit.
+			let usable_end = if region_end < limit { region_end } else { limit };
+			if usable_end < region_start + size {
+				continue;
+			}
+
+			let address = usable_end - size;
+			if self.allocate_address_for_node(address, usable_end, node) {
+				return Ok(address);
+			}
+		}
+
+		Err(())
+	}
+
 	pub fn reserve(&mut self, address: usize, size: usize) -> Result<(), ()> {
 		debug_mem!("Reserving {} bytes at address {:#X} in Free List {:#X}", size, address, self as *const Self as usize);
 		let end = address + size;
@@ -145,92 +280,251 @@ impl FreeList {
 		Err(())
 	}
 
-	pub fn deallocate(&mut self, address: usize, size: usize) {
-		debug_mem!("Deallocating {} bytes at {:#X} from Free List {:#X}", size, address, self as *const Self as usize);
+	/// Returns the free region containing the given address or None if the address is not free.
+	pub fn find(&self, address: usize) -> Option<FreeListEntry> {
+		self.iter()
+			.take_while(|entry| entry.start <= address)
+			.find(|entry| address < entry.end)
+	}
 
-		let end = address + size;
-		let mut iter = self.list.iter();
+	/// Inserts a free region at its position by address and merges it with adjacent free regions.
+	/// A new node is taken from the node pool if the region cannot be merged.
+	pub fn insert_sorted(&mut self, entry: FreeListEntry) {
+		let mut previous = None;
+		let mut next = None;
 
-		while let Some(node) = iter.next() {
+		// Find the first free region behind the new one.
+		for node in self.list.iter() {
 			let (region_start, region_end) = {
 				let borrowed = node.borrow();
 				(borrowed.value.start, borrowed.value.end)
 			};
 
-			if region_start == end {
-				// The deallocated memory extends this free memory region to the left.
-				node.borrow_mut().value.start = address;
-				return;
-			} else if region_end == address {
-				// The deallocated memory extends this free memory region to the right.
-				// Check if it can even reunite with the next region.
-				if let Some(next_node) = iter.next() {
-					let (next_region_start, next_region_end) = {
-						let borrowed = node.borrow();
-						(borrowed.value.start, borrowed.value.end)
-					};
-
-					if next_region_start == end {
-						// It can reunite, so let the current region span over the reunited region and move the duplicate node
-						// into the pool for deletion or reuse.
-						node.borrow_mut().value.end = next_region_end;
-						self.list.remove(next_node.clone());
-						unsafe { mm::POOL.list.push(next_node); }
-						return;
-					}
-				}
-
-				// It cannot reunite, so just extend this region to the right and we are done.
-				node.borrow_mut().value.end = end;
-				return;
-			} else if end < region_start {
-				// The deallocated memory does not extend any memory region and needs an own entry in the Free List.
-				// Get that entry from the node pool.
-				// We search the list from low to high addresses and insert us before the first entry that has a
-				// higher address than us.
-				let new_node = unsafe { mm::POOL.list.head().expect("Pool is empty when attempting insert_before") };
-				unsafe { mm::POOL.list.remove(new_node.clone()); }
-
-				{
-					let mut new_node_borrowed = new_node.borrow_mut();
-					new_node_borrowed.value.start = address;
-					new_node_borrowed.value.end = end;
-				}
-
-				self.list.insert_before(new_node, node);
-				return;
-			}
-		}
+			assert!(entry.end <= region_start || entry.start >= region_end,
+				"Free region {:#X} - {:#X} overlaps free region {:#X} - {:#X}", entry.start, entry.end, region_start, region_end);
 
-		// We could not find an entry with a higher address than us.
-		// So we become the new last entry in the list. Get that entry from the node pool.
-		let new_node = unsafe { mm::POOL.list.head().expect("Pool is empty when attempting insert_after") };
-		unsafe { mm::POOL.list.remove(new_node.clone()); }
+			if region_start >= entry.end {
+				next = Some(node);
+				break;
+			}
 
-		{
-			let mut new_node_borrowed = new_node.borrow_mut();
-			new_node_borrowed.value.start = address;
-			new_node_borrowed.value.end = end;
+			previous = Some(node);
 		}
 
-		if let Some(tail) = self.list.tail() {
-			self.list.insert_after(new_node, tail);
+		let merges_previous = previous.as_ref().map_or(false, |node| node.borrow().value.end == entry.start);
+		let merges_next = next.as_ref().map_or(false, |node| node.borrow().value.start == entry.end);
+
+		if merges_previous && merges_next {
+			// The new region closes the gap between both regions, so let the previous region span over all of them
+			// and move the node of the next region into the pool for deletion or reuse.
+			let previous_node = previous.unwrap();
+			let next_node = next.unwrap();
+			let next_end = next_node.borrow().value.end;
+			previous_node.borrow_mut().value.end = next_end;
+			self.list.remove(next_node.clone());
+			self.return_node(next_node);
+		} else if merges_previous {
+			previous.unwrap().borrow_mut().value.end = entry.end;
+		} else if merges_next {
+			next.unwrap().borrow_mut().value.start = entry.start;
 		} else {
-			self.list.push(new_node);
+			// The new region needs an own entry in the Free List. Get that entry from the node pool.
+			let new_node = self.take_node("inserting into the Free List");
+			new_node.borrow_mut().value = entry;
+
+			if let Some(next_node) = next {
+				self.list.insert_before(new_node, next_node);
+			} else if let Some(previous_node) = previous {
+				self.list.insert_after(new_node, previous_node);
+			} else {
+				self.list.push(new_node);
+			}
 		}
 	}
 
+	pub fn deallocate(&mut self, address: usize, size: usize) {
+		debug_mem!("Deallocating {} bytes at {:#X} from Free List {:#X}", size, address, self as *const Self as usize);
+		self.insert_sorted(FreeListEntry { start: address, end: address + size });
+	}
+
+	pub fn statistics(&self) -> FreeListStatistics {
+		let mut statistics = FreeListStatistics { node_count: 0, free_bytes: 0, largest_block: 0 };
+
+		for entry in self.iter() {
+			let size = entry.end - entry.start;
+			statistics.node_count += 1;
+			statistics.free_bytes += size;
+			if size > statistics.largest_block {
+				statistics.largest_block = size;
+			}
+		}
+
+		statistics
+	}
+
 	pub fn print_information(&self, header: &str) {
 		infoheader!(header);
 
-		for node in self.list.iter() {
-			let (region_start, region_end) = {
-				let borrowed = node.borrow();
-				(borrowed.value.start, borrowed.value.end)
-			};
-			info!("{:#016X} - {:#016X}", region_start, region_end);
+		for entry in self.iter() {
+			info!("{:#016X} - {:#016X}", entry.start, entry.end);
 		}
 
+		let statistics = self.statistics();
+		infoentry!("Free blocks", statistics.node_count);
+		infoentry!("Largest free block", "{:#X} bytes", statistics.largest_block);
+		infoentry!("Fragmentation", "{:.3}", statistics.fragmentation());
 		infofooter!();
 	}
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SLOT_SIZE: usize = 0x1000;
+	const SLOT_COUNT: usize = 64;
+
+	/// Returns a Free List that never touches mm::POOL.
+	fn free_list() -> FreeList {
+		let mut list = FreeList::new();
+		list.use_arena(SLOT_COUNT);
+		list
+	}
+
+	fn slot(index: usize) -> FreeListEntry {
+		FreeListEntry { start: index * SLOT_SIZE, end: (index + 1) * SLOT_SIZE }
+	}
+
+	/// Checks that the entries are sorted and that no two entries touch, because those must have been merged.
+	fn assert_sorted_and_merged(list: &FreeList) {
+		let entries: Vec<FreeListEntry> = list.iter().collect();
+		for pair in entries.windows(2) {
+			assert!(pair[0].end < pair[1].start, "{:#X} - {:#X} is not below {:#X} - {:#X}", pair[0].start, pair[0].end, pair[1].start, pair[1].end);
+		}
+	}
+
+	/// Floating-point results must not be compared exactly.
+	fn assert_fragmentation(statistics: &FreeListStatistics, expected: f32) {
+		let fragmentation = statistics.fragmentation();
+		assert!((fragmentation - expected).abs() < 1e-6, "fragmentation {} is not {}", fragmentation, expected);
+	}
+
+	#[test]
+	fn insertion_keeps_the_order_across_many_operations() {
+		let mut list = free_list();
+
+		// Insert all even slots first and then all odd slots, each in a scrambled order.
+		// 37 is coprime to SLOT_COUNT, so index * 37 % SLOT_COUNT visits every slot exactly once.
+		let order: Vec<usize> = (0..SLOT_COUNT).map(|i| i * 37 % SLOT_COUNT).collect();
+		for &index in order.iter().filter(|&&index| index % 2 == 0) {
+			list.insert_sorted(slot(index));
+			assert_sorted_and_merged(&list);
+		}
+		assert_eq!(list.statistics().node_count, SLOT_COUNT / 2);
+
+		for &index in order.iter().filter(|&&index| index % 2 == 1) {
+			list.insert_sorted(slot(index));
+			assert_sorted_and_merged(&list);
+		}
+
+		// Every gap has been closed, so a single region remains.
+		let entries: Vec<FreeListEntry> = list.iter().collect();
+		assert_eq!(entries.len(), 1);
+		assert_eq!((entries[0].start, entries[0].end), (0, SLOT_COUNT * SLOT_SIZE));
+	}
+
+	#[test]
+	fn allocations_and_insertions_can_be_interleaved() {
+		let mut list = free_list();
+		list.insert_sorted(FreeListEntry { start: 0, end: SLOT_COUNT * SLOT_SIZE });
+
+		let addresses: Vec<usize> = (0..8).map(|_| list.allocate(SLOT_SIZE).unwrap()).collect();
+		assert!(list.find(0).is_none());
+		assert_eq!(list.find(8 * SLOT_SIZE).map(|entry| entry.start), Some(8 * SLOT_SIZE));
+
+		// Free every other slot, punching holes into the allocated range, and then fill up the rest.
+		for &address in addresses.iter().step_by(2) {
+			list.deallocate(address, SLOT_SIZE);
+			assert_sorted_and_merged(&list);
+		}
+		assert_eq!(list.statistics().node_count, 5);
+		assert_eq!(list.find(2 * SLOT_SIZE + 1).map(|entry| entry.end), Some(3 * SLOT_SIZE));
+		assert!(list.find(3 * SLOT_SIZE).is_none());
+
+		for &address in addresses.iter().skip(1).step_by(2) {
+			list.deallocate(address, SLOT_SIZE);
+			assert_sorted_and_merged(&list);
+		}
+		assert_eq!(list.statistics().node_count, 1);
+		assert_eq!(list.statistics().free_bytes, SLOT_COUNT * SLOT_SIZE);
+	}
+
+	#[test]
+	fn early_boot_allocations_use_the_arena() {
+		// Like physicalmem::init, which adds the first region with a node from the Bootstrap Allocator.
+		let mut list = FreeList::new();
+		list.use_arena(2);
+		list.list.push(Node::new(FreeListEntry { start: 0, end: 16 * SLOT_SIZE }));
+
+		let addresses: Vec<usize> = (0..6).map(|_| list.allocate(SLOT_SIZE).unwrap()).collect();
+		assert_eq!(addresses, (0..6).map(|index| index * SLOT_SIZE).collect::<Vec<usize>>());
+		assert_eq!(list.arena.iter().count(), 2);
+
+		// Each isolated block needs a node. The third one exceeds the arena and gets a newly allocated node.
+		for &index in [0, 2, 4].iter() {
+			list.deallocate(addresses[index], SLOT_SIZE);
+		}
+		assert_eq!(list.statistics().node_count, 4);
+		assert!(list.arena.head().is_none());
+
+		// Merging blocks gives their nodes back to the arena.
+		for &index in [1, 3, 5].iter() {
+			list.deallocate(addresses[index], SLOT_SIZE);
+			assert_sorted_and_merged(&list);
+		}
+		assert_eq!(list.statistics().node_count, 1);
+		assert_eq!(list.arena.iter().count(), 3);
+
+		// Later operations reuse these nodes.
+		assert_eq!(list.allocate(2 * SLOT_SIZE), Ok(0));
+		list.deallocate(0, SLOT_SIZE);
+		assert_eq!(list.statistics().node_count, 2);
+		assert_eq!(list.arena.iter().count(), 2);
+	}
+
+	#[test]
+	fn fragmentation_is_computed_from_the_largest_block() {
+		let mut list = free_list();
+		assert_fragmentation(&list.statistics(), 0.0);
+
+		// A single block is not fragmented at all.
+		list.insert_sorted(FreeListEntry { start: 0, end: 4 * SLOT_SIZE });
+		assert_fragmentation(&list.statistics(), 0.0);
+
+		// Add blocks of 1, 1 and 2 slots separated by holes, so the largest block holds 4 of 8 free slots.
+		list.insert_sorted(slot(5));
+		list.insert_sorted(slot(7));
+		list.insert_sorted(FreeListEntry { start: 9 * SLOT_SIZE, end: 11 * SLOT_SIZE });
+
+		let statistics = list.statistics();
+		assert_eq!(statistics.node_count, 4);
+		assert_eq!(statistics.free_bytes, 8 * SLOT_SIZE);
+		assert_eq!(statistics.largest_block, 4 * SLOT_SIZE);
+		assert_fragmentation(&statistics, 0.5);
+
+		// Allocating from the largest block shifts the share to the remaining small blocks.
+		list.allocate(3 * SLOT_SIZE).unwrap();
+		let statistics = list.statistics();
+		assert_eq!(statistics.largest_block, 2 * SLOT_SIZE);
+		assert_fragmentation(&statistics, 0.6);
+	}
+
+	#[test]
+	#[should_panic]
+	fn overlapping_insertions_are_rejected() {
+		let mut list = free_list();
+		list.insert_sorted(slot(1));
+		list.insert_sorted(FreeListEntry { start: SLOT_SIZE / 2, end: SLOT_SIZE + 1 });
+	}
+}
@@ -25,9 +25,10 @@ pub mod allocator;
 pub mod freelist;
 mod mmlock;
 mod nodepool;
+pub mod shared;
 
 use arch;
-use arch::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
+use arch::mm::paging::{BasePageSize, LargePageSize, MemoryType, PageSize, PageTableEntryFlags};
 use mm::mmlock::MmLock;
 use mm::nodepool::NodePool;
 
@@ -38,7 +39,7 @@ extern "C" {
 }
 
 
-static MM_LOCK: MmLock = MmLock::new();
+pub static MM_LOCK: MmLock = MmLock::new();
 pub static mut POOL: NodePool = NodePool::new();
 
 
@@ -67,18 +68,37 @@ pub fn init() {
 	}
 
 	arch::mm::init();
+	arch::mm::physicalmem::init_kernel_reserve();
 	self::allocator::init();
+	arch::mm::physicalmem::use_node_pool();
+
+	let (start, end) = arch::mm::physicalmem::managed_range();
+	arch::mm::physicalmem::frame_ref::init(start, end);
 }
 
 pub fn print_information() {
 	arch::mm::physicalmem::print_information();
 	arch::mm::virtualmem::print_information();
+	arch::mm::paging::print_information();
 }
 
 pub fn allocate(size: usize, extra_flags: PageTableEntryFlags) -> usize {
 	let _lock = MM_LOCK.lock();
 
 	let physical_address = arch::mm::physicalmem::allocate(size);
+	map_allocation(physical_address, size, extra_flags)
+}
+
+/// Like allocate, but may also use the kernel reserve (see physicalmem::init_kernel_reserve).
+/// Only meant for the kernel heap.
+pub fn allocate_heap(size: usize, extra_flags: PageTableEntryFlags) -> usize {
+	let _lock = MM_LOCK.lock();
+
+	let physical_address = arch::mm::physicalmem::allocate_for_heap(size);
+	map_allocation(physical_address, size, extra_flags)
+}
+
+fn map_allocation(physical_address: usize, size: usize, extra_flags: PageTableEntryFlags) -> usize {
 	let virtual_address = arch::mm::virtualmem::allocate(size);
 	let count = size / BasePageSize::SIZE;
 	arch::mm::paging::map::<BasePageSize>(
@@ -102,3 +122,116 @@ pub fn deallocate(virtual_address: usize, size: usize) {
 		panic!("No page table entry for virtual address {:#X}", virtual_address);
 	}
 }
+
+/// Allocates a stack of `size` bytes with an unmapped guard page below it.
+/// A stack overflow then causes a page fault instead of silently corrupting the adjacent memory.
+/// Returns the lowest address of the usable stack.
+pub fn allocate_stack(size: usize, extra_flags: PageTableEntryFlags) -> usize {
+	let _lock = MM_LOCK.lock();
+
+	let guard_address = allocate(size + BasePageSize::SIZE, extra_flags);
+	arch::mm::paging::unmap::<BasePageSize>(guard_address, 1, true);
+	guard_address + BasePageSize::SIZE
+}
+
+/// Frees a stack allocated by allocate_stack, including its guard page.
+pub fn deallocate_stack(stack_address: usize, size: usize) {
+	let _lock = MM_LOCK.lock();
+
+	if let Some(entry) = arch::mm::paging::get_page_table_entry::<BasePageSize>(stack_address) {
+		let guard_address = stack_address - BasePageSize::SIZE;
+		arch::mm::virtualmem::deallocate(guard_address, size + BasePageSize::SIZE);
+		arch::mm::physicalmem::deallocate(entry.address() - BasePageSize::SIZE, size + BasePageSize::SIZE);
+	} else {
+		panic!("No page table entry for stack address {:#X}", stack_address);
+	}
+}
+
+/// Maps `size` bytes of physical memory at `physical_address` into the kernel's virtual address space.
+/// The physical address does not need to be page-aligned.
+/// Returns the virtual address corresponding to `physical_address`.
+pub fn map_physical(physical_address: usize, size: usize, extra_flags: PageTableEntryFlags) -> usize {
+	assert!(size > 0);
+	let _lock = MM_LOCK.lock();
+
+	let first_page = align_down!(physical_address, BasePageSize::SIZE);
+	let mapped_size = align_up!(physical_address + size, BasePageSize::SIZE) - first_page;
+	let virtual_address = arch::mm::virtualmem::allocate(mapped_size);
+	arch::mm::paging::map::<BasePageSize>(
+		virtual_address,
+		first_page,
+		mapped_size / BasePageSize::SIZE,
+		extra_flags,
+		true
+	);
+
+	virtual_address + (physical_address - first_page)
+}
+
+/// Copies `count` bytes without polluting the caches, e.g. for framebuffers.
+/// Falls back to a regular copy for small sizes. The areas must not overlap.
+#[inline]
+pub unsafe fn memcpy_nt(destination: *mut u8, source: *const u8, count: usize) {
+	arch::mm::memcpy_nt(destination, source, count);
+}
+
+/// Sets `count` bytes to `value` without polluting the caches.
+/// Falls back to regular stores for small sizes.
+#[inline]
+pub unsafe fn memset_nt(destination: *mut u8, value: u8, count: usize) {
+	arch::mm::memset_nt(destination, value, count);
+}
+
+/// Maps `size` bytes of device memory at `physical_address` into the kernel's virtual address space
+/// with the given memory type, e.g. Uncacheable for device registers or WriteCombining for framebuffers.
+/// Returns the virtual address corresponding to `physical_address`. Use unmap_physical to remove the mapping.
+pub fn map_device_memory(physical_address: usize, size: usize, memory_type: MemoryType) -> usize {
+	let flags = PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE | arch::mm::paging::device_memory_flags(memory_type);
+
+	// Map large areas like PCI BARs with 2 MiB pages to save page tables and TLB entries.
+	// The PAT bit is at a different position in 2 MiB page entries, so only memory types without it qualify.
+	if physical_address % LargePageSize::SIZE == 0 && size % LargePageSize::SIZE == 0 && !flags.contains(PageTableEntryFlags::PAT) {
+		let _lock = MM_LOCK.lock();
+		let virtual_address = arch::mm::virtualmem::allocate_aligned(size, LargePageSize::SIZE);
+		arch::mm::paging::map::<LargePageSize>(
+			virtual_address,
+			physical_address,
+			size / LargePageSize::SIZE,
+			flags,
+			true
+		);
+
+		return virtual_address;
+	}
+
+	map_physical(physical_address, size, flags)
+}
+
+/// Removes a mapping created by map_physical or map_device_memory without freeing the physical memory.
+pub fn unmap_physical(virtual_address: usize, size: usize) {
+	let _lock = MM_LOCK.lock();
+
+	let first_page = align_down!(virtual_address, BasePageSize::SIZE);
+	let mapped_size = align_up!(virtual_address + size, BasePageSize::SIZE) - first_page;
+
+	if arch::mm::paging::page_size(first_page) == Some(LargePageSize::SIZE) {
+		arch::mm::paging::unmap::<LargePageSize>(first_page, mapped_size / LargePageSize::SIZE, true);
+	} else {
+		arch::mm::paging::unmap::<BasePageSize>(first_page, mapped_size / BasePageSize::SIZE, true);
+	}
+
+	arch::mm::virtualmem::deallocate(first_page, mapped_size);
+}
+
+/// Reserves the virtual memory range at `virtual_address` for demand-zero mapping.
+/// Physical memory is only allocated and zeroed for each 4 KiB page on its first access, so large sparse
+/// buffers only consume memory for the pages that are actually touched.
+pub fn map_lazy(virtual_address: usize, size: usize, extra_flags: PageTableEntryFlags) {
+	assert!(size > 0);
+	assert!(virtual_address % BasePageSize::SIZE == 0, "Virtual address {:#X} is not a multiple of {:#X}", virtual_address, BasePageSize::SIZE);
+	assert!(size % BasePageSize::SIZE == 0, "Size {:#X} is not a multiple of {:#X}", size, BasePageSize::SIZE);
+
+	let _lock = MM_LOCK.lock();
+	let count = size / BasePageSize::SIZE;
+	arch::mm::paging::map_lazy(virtual_address, count, extra_flags);
+}
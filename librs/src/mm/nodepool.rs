@@ -21,7 +21,9 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use alloc::rc::Rc;
 use collections::{DoublyLinkedList, Node};
+use core::cell::RefCell;
 use mm::freelist::FreeListEntry;
 
 
@@ -29,10 +31,27 @@ use mm::freelist::FreeListEntry;
 /// As we use two free lists (for physical and virtual memories), we always need to guarantee a minimum of 2 nodes in the pool for any deallocation operation.
 const MINIMUM_POOL_ENTRIES: usize = 2;
 
+/// Maximum number of nodes taken out of the pool whose consumer is recorded in builds with debug assertions.
+#[cfg(debug_assertions)]
+const TRACKED_NODES: usize = 64;
+
+
+/// A node taken out of the pool along with the Free List operation and the address of the Free List that took it.
+#[cfg(debug_assertions)]
+#[derive(Clone, Copy)]
+struct Consumer {
+	node: usize,
+	operation: &'static str,
+	free_list: usize,
+}
 
 pub struct NodePool {
 	pub list: DoublyLinkedList<FreeListEntry>,
 	maintenance_in_progress: bool,
+	#[cfg(debug_assertions)]
+	consumers: [Option<Consumer>; TRACKED_NODES],
+	#[cfg(debug_assertions)]
+	untracked_nodes: usize,
 }
 
 impl NodePool {
@@ -40,9 +59,87 @@ impl NodePool {
 		Self {
 			list: DoublyLinkedList::new(),
 			maintenance_in_progress: false,
+			#[cfg(debug_assertions)]
+			consumers: [None; TRACKED_NODES],
+			#[cfg(debug_assertions)]
+			untracked_nodes: 0,
+		}
+	}
+
+	/// Takes a node out of the pool for the Free List operation `operation` on the Free List at `free_list`.
+	/// Panics if the pool is empty, which means that POOL.maintain() has not been called early enough.
+	pub fn take(&mut self, operation: &'static str, free_list: usize) -> Rc<RefCell<Node<FreeListEntry>>> {
+		let node = match self.list.head() {
+			Some(node) => node,
+			None => {
+				self.print_consumers();
+				panic!("Pool is empty when {} (Free List {:#X})", operation, free_list);
+			}
+		};
+
+		self.list.remove(node.clone());
+		self.record_consumer(&node, operation, free_list);
+		node
+	}
+
+	/// Puts a node that is no longer used by a Free List back into the pool.
+	pub fn give_back(&mut self, node: Rc<RefCell<Node<FreeListEntry>>>) {
+		self.forget_consumer(&node);
+		self.list.push(node);
+	}
+
+	#[cfg(debug_assertions)]
+	fn record_consumer(&mut self, node: &Rc<RefCell<Node<FreeListEntry>>>, operation: &'static str, free_list: usize) {
+		let consumer = Consumer { node: &**node as *const _ as usize, operation: operation, free_list: free_list };
+		match self.consumers.iter_mut().find(|slot| slot.is_none()) {
+			Some(slot) => *slot = Some(consumer),
+			None => self.untracked_nodes += 1,
 		}
 	}
 
+	#[cfg(not(debug_assertions))]
+	#[inline]
+	fn record_consumer(&mut self, _node: &Rc<RefCell<Node<FreeListEntry>>>, _operation: &'static str, _free_list: usize) {}
+
+	#[cfg(debug_assertions)]
+	fn forget_consumer(&mut self, node: &Rc<RefCell<Node<FreeListEntry>>>) {
+		let address = &**node as *const _ as usize;
+		if let Some(slot) = self.consumers.iter_mut().find(|slot| slot.map_or(false, |consumer| consumer.node == address)) {
+			*slot = None;
+		}
+	}
+
+	#[cfg(not(debug_assertions))]
+	#[inline]
+	fn forget_consumer(&mut self, _node: &Rc<RefCell<Node<FreeListEntry>>>) {}
+
+	/// Prints how many of the nodes currently taken out of the pool each Free List operation consumed.
+	/// Only builds with debug assertions record the consumers.
+	#[cfg(debug_assertions)]
+	fn print_consumers(&self) {
+		infoheader!(" NODE POOL CONSUMERS ");
+		for (i, consumer) in self.consumers.iter().enumerate().filter_map(|(i, slot)| slot.map(|consumer| (i, consumer))) {
+			let is_same_site = |slot: &Option<Consumer>| {
+				slot.map_or(false, |other| other.operation == consumer.operation && other.free_list == consumer.free_list)
+			};
+
+			// Print each site only once, at its first node.
+			if self.consumers[..i].iter().any(&is_same_site) {
+				continue;
+			}
+
+			let count = self.consumers[i..].iter().filter(|slot| is_same_site(slot)).count();
+			info!("{} nodes when {} (Free List {:#X})", count, consumer.operation, consumer.free_list);
+		}
+		if self.untracked_nodes > 0 {
+			info!("{} more nodes taken while all {} records were in use", self.untracked_nodes, TRACKED_NODES);
+		}
+		infofooter!();
+	}
+
+	#[cfg(not(debug_assertions))]
+	fn print_consumers(&self) {}
+
 	pub fn maintain(&mut self) {
 		// Prevent calling this function recursively (see below).
 		if self.maintenance_in_progress {
@@ -0,0 +1,246 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Shared memory regions between tasks.
+//!
+//! A region is created once and can then be mapped any number of times, either read-only, read-write or copy-on-write.
+//! A copy-on-write mapping reads the region until its first write to a page, which gives the mapping a private
+//! copy of that page. Writes through it are therefore never visible to other mappings.
+//! Every mapping holds a reference to each physical frame of the region, and so does the region itself
+//! until it is destroyed. The frames are freed after the last reference has been dropped.
+//!
+//! All mappings use write-back caching. x86-64 keeps the caches coherent between all CPUs, so writes
+//! through one mapping are visible through all other mappings without explicit cache maintenance.
+
+use alloc::btree_map::BTreeMap;
+use arch;
+use arch::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
+use arch::mm::physicalmem::frame_ref;
+use core::sync::atomic::{AtomicU32, Ordering};
+use mm;
+use mm::MM_LOCK;
+use synch::mutex::Mutex;
+
+
+lazy_static! {
+	/// All shared memory regions that have not been destroyed yet, keyed by their ID.
+	/// They are only accessed from system calls, so a blocking Mutex is enough.
+	static ref REGIONS: Mutex<BTreeMap<u32, SharedRegion>> = Mutex::new(BTreeMap::new());
+}
+
+/// Counter to assign a unique ID to each created region.
+static SHM_ID_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+
+/// Unique identifier of a shared memory region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ShmId(u32);
+
+impl ShmId {
+	pub const fn into(self) -> u32 {
+		self.0
+	}
+
+	pub const fn from(x: u32) -> Self {
+		ShmId(x)
+	}
+}
+
+/// Possible access rights for a mapping of a shared memory region.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+	ReadOnly,
+	ReadWrite,
+	CopyOnWrite,
+}
+
+/// The physically contiguous memory backing a shared memory region.
+#[derive(Clone, Copy)]
+struct SharedRegion {
+	physical_address: usize,
+	size: usize,
+}
+
+impl SharedRegion {
+	/// Calls `f` with the physical address of each frame of the region.
+	fn for_each_frame<F: FnMut(usize)>(&self, mut f: F) {
+		for frame in (self.physical_address..self.physical_address + self.size).step_by(BasePageSize::SIZE) {
+			f(frame);
+		}
+	}
+}
+
+/// Looks up the region with the given ID and takes a reference to each of its frames through `incref`.
+/// This happens under the same lock as the lookup, so the region cannot be destroyed in between.
+fn acquire_region<F: FnMut(usize)>(regions: &BTreeMap<u32, SharedRegion>, id: ShmId, incref: F) -> Option<SharedRegion> {
+	let region = regions.get(&id.0).cloned()?;
+	region.for_each_frame(incref);
+	Some(region)
+}
+
+/// Removes the region with the given ID and drops its own reference to each of its frames through `decref`.
+fn remove_region<F: FnMut(usize)>(regions: &mut BTreeMap<u32, SharedRegion>, id: ShmId, decref: F) -> Result<(), ()> {
+	let region = regions.remove(&id.0).ok_or(())?;
+	region.for_each_frame(decref);
+	Ok(())
+}
+
+/// A mapping of a shared memory region.
+/// The region is unmapped and its frames are released when this is dropped.
+pub struct SharedMapping {
+	id: ShmId,
+	virtual_address: usize,
+	size: usize,
+}
+
+impl SharedMapping {
+	/// Returns a pointer to the start of the mapped region.
+	pub fn as_ptr(&self) -> *mut u8 {
+		self.virtual_address as *mut u8
+	}
+
+	/// Returns the size of the mapped region in bytes.
+	pub fn size(&self) -> usize {
+		self.size
+	}
+}
+
+impl Drop for SharedMapping {
+	fn drop(&mut self) {
+		let _lock = MM_LOCK.lock();
+		let count = self.size / BasePageSize::SIZE;
+
+		// A page of a copy-on-write mapping may have been replaced by a private copy,
+		// so release the frames that are currently mapped instead of the ones of the region.
+		for address in (self.virtual_address..self.virtual_address + self.size).step_by(BasePageSize::SIZE) {
+			let (frame, _) = arch::mm::paging::translate(address).expect("Shared memory page is not mapped");
+			frame_ref::decref(frame);
+		}
+
+		arch::mm::paging::unmap::<BasePageSize>(self.virtual_address, count, true);
+		arch::mm::virtualmem::deallocate(self.virtual_address, self.size);
+		debug_mem!("Unmapped shared memory region {:?} from {:#X}", self.id, self.virtual_address);
+	}
+}
+
+
+/// Creates a new zeroed shared memory region of `size` bytes.
+pub fn create(size: usize) -> ShmId {
+	assert!(size > 0);
+	assert!(size % BasePageSize::SIZE == 0, "Size {:#X} is not a multiple of {:#X}", size, BasePageSize::SIZE);
+
+	let region = {
+		let _lock = MM_LOCK.lock();
+		let count = size / BasePageSize::SIZE;
+		let physical_address = arch::mm::physicalmem::allocate(size);
+
+		// Zero the region through a temporary mapping.
+		// Nothing reads it through this mapping, so non-temporal stores keep it out of the caches.
+		let virtual_address = arch::mm::virtualmem::allocate(size);
+		arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, count, PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE, false);
+		unsafe { mm::memset_nt(virtual_address as *mut u8, 0, size); }
+		arch::mm::paging::unmap::<BasePageSize>(virtual_address, count, true);
+		arch::mm::virtualmem::deallocate(virtual_address, size);
+
+		// The region itself holds the first reference to each frame.
+		let region = SharedRegion { physical_address: physical_address, size: size };
+		region.for_each_frame(|frame| { frame_ref::incref(frame); });
+		region
+	};
+
+	// REGIONS is always locked before MM_LOCK, so it is only locked after releasing MM_LOCK here.
+	let id = ShmId(SHM_ID_COUNTER.fetch_add(1, Ordering::SeqCst));
+	let physical_address = region.physical_address;
+	REGIONS.lock().insert(id.0, region);
+	debug_mem!("Created shared memory region {:?} at {:#X} ({:#X} bytes)", id, physical_address, size);
+
+	id
+}
+
+/// Maps the shared memory region with the given ID with the given access rights.
+/// Returns None if no region with this ID exists.
+pub fn map(id: ShmId, access: Access) -> Option<SharedMapping> {
+	// Keep REGIONS locked until the mapping holds its references, so a concurrent destroy cannot free the frames.
+	let regions = REGIONS.lock();
+	let _lock = MM_LOCK.lock();
+	let region = acquire_region(&regions, id, |frame| { frame_ref::incref(frame); })?;
+	let count = region.size / BasePageSize::SIZE;
+	let flags = match access {
+		Access::ReadOnly => PageTableEntryFlags::EXECUTE_DISABLE,
+		Access::ReadWrite => PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE,
+		Access::CopyOnWrite => arch::mm::paging::copy_on_write_flags(PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE),
+	};
+
+	let virtual_address = arch::mm::virtualmem::allocate(region.size);
+	arch::mm::paging::map::<BasePageSize>(virtual_address, region.physical_address, count, flags, true);
+	debug_mem!("Mapped shared memory region {:?} at {:#X} ({:?})", id, virtual_address, access);
+
+	Some(SharedMapping {
+		id: id,
+		virtual_address: virtual_address,
+		size: region.size,
+	})
+}
+
+/// Destroys the shared memory region with the given ID, so that it cannot be mapped anymore.
+/// Existing mappings stay valid and the memory is freed when the last of them is dropped.
+/// Returns an error if no region with this ID exists.
+pub fn destroy(id: ShmId) -> Result<(), ()> {
+	let mut regions = REGIONS.lock();
+	let _lock = MM_LOCK.lock();
+	remove_region(&mut regions, id, |frame| { frame_ref::decref(frame); })
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn mapping_outlives_destroyed_region() {
+		let mut regions = BTreeMap::new();
+		let mut refs = BTreeMap::new();
+		let id = ShmId(0);
+		let region = SharedRegion { physical_address: 0x10000, size: 2 * BasePageSize::SIZE };
+
+		// Create the region holding the first reference.
+		region.for_each_frame(|frame| { *refs.entry(frame).or_insert(0) += 1; });
+		regions.insert(id.0, region);
+
+		// Map it once.
+		let mapping = acquire_region(&regions, id, |frame| { *refs.entry(frame).or_insert(0) += 1; }).unwrap();
+		assert_eq!(refs.values().cloned().collect::<Vec<u32>>(), vec![2, 2]);
+
+		// Destroying the region leaves the reference of the mapping.
+		assert_eq!(remove_region(&mut regions, id, |frame| { *refs.get_mut(&frame).unwrap() -= 1; }), Ok(()));
+		assert_eq!(refs.values().cloned().collect::<Vec<u32>>(), vec![1, 1]);
+
+		// A destroyed region can neither be mapped nor destroyed again, and no references have been taken.
+		assert!(acquire_region(&regions, id, |_| panic!("Took a reference to a destroyed region")).is_none());
+		assert_eq!(remove_region(&mut regions, id, |_| panic!("Released a destroyed region")), Err(()));
+
+		// Unmapping drops the last reference.
+		mapping.for_each_frame(|frame| { *refs.get_mut(&frame).unwrap() -= 1; });
+		assert_eq!(refs.values().cloned().collect::<Vec<u32>>(), vec![0, 0]);
+	}
+}
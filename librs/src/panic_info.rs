@@ -0,0 +1,146 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Recording the number of panics and the reason of the last one, and an optional hook run on panic.
+//!
+//! The reason is kept in a statically allocated buffer, because a panic may
+//! be caused by a broken heap. The buffer is exported under the well-known symbol
+//! name `panic_reason`, so a hypervisor like uhyve can look it up in the symbol
+//! table of the kernel image and read it after the guest has halted.
+
+#![allow(non_upper_case_globals)]
+
+use core::{fmt, mem, str};
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+
+/// Size of the buffer holding the reason of the last panic, including the terminating NUL character.
+const PANIC_REASON_SIZE: usize = 256;
+
+/// NUL-terminated reason of the last panic.
+#[no_mangle]
+pub static mut panic_reason: [u8; PANIC_REASON_SIZE] = [0; PANIC_REASON_SIZE];
+
+static PANIC_REASON_LENGTH: AtomicUsize = AtomicUsize::new(0);
+static PANIC_COUNT: AtomicUsize = AtomicUsize::new(0);
+static RECORDING: AtomicBool = AtomicBool::new(false);
+
+/// Address of the function set by set_hook or 0 if none is set.
+static PANIC_HOOK: AtomicUsize = AtomicUsize::new(0);
+static RUNNING_HOOK: AtomicBool = AtomicBool::new(false);
+
+
+/// Writes formatted text into panic_reason, silently truncating everything that doesn't fit.
+struct PanicReasonWriter {
+	length: usize,
+}
+
+impl fmt::Write for PanicReasonWriter {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		for &byte in s.as_bytes() {
+			// Always leave space for the terminating NUL character.
+			if self.length == PANIC_REASON_SIZE - 1 {
+				break;
+			}
+
+			unsafe { panic_reason[self.length] = byte; }
+			self.length += 1;
+		}
+
+		Ok(())
+	}
+}
+
+
+/// Called by the panic handler to count the panic and record its reason.
+pub fn record(info: &PanicInfo) {
+	PANIC_COUNT.fetch_add(1, Ordering::SeqCst);
+
+	// If another core is recording a panic at the same time, keep its reason.
+	if RECORDING.swap(true, Ordering::SeqCst) {
+		return;
+	}
+
+	let mut writer = PanicReasonWriter { length: 0 };
+
+	if let Some(message) = info.message() {
+		let _ = write!(writer, "{}", message);
+	}
+
+	if let Some(location) = info.location() {
+		let _ = write!(writer, " at {}:{}", location.file(), location.line());
+	}
+
+	unsafe { panic_reason[writer.length] = 0; }
+	PANIC_REASON_LENGTH.store(writer.length, Ordering::SeqCst);
+	RECORDING.store(false, Ordering::SeqCst);
+}
+
+/// Total number of panics since boot.
+pub fn count() -> usize {
+	PANIC_COUNT.load(Ordering::SeqCst)
+}
+
+/// Reason of the last panic or `None` if no panic has occurred yet.
+pub fn last() -> Option<&'static str> {
+	if count() == 0 {
+		return None;
+	}
+
+	let bytes = unsafe { &panic_reason[..PANIC_REASON_LENGTH.load(Ordering::SeqCst)] };
+
+	// Truncation may have split a multi-byte character, so only return the valid part.
+	match str::from_utf8(bytes) {
+		Ok(reason) => Some(reason),
+		Err(e) => Some(unsafe { str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) }),
+	}
+}
+
+/// Sets a function that the panic handler calls before printing the panic and halting,
+/// e.g. to notify the host or dump additional state. Replaces any previously set hook.
+///
+/// The hook runs with interrupts disabled on the panicking core and must not rely on the heap,
+/// which may be the cause of the panic. If the hook panics itself or another core panics while
+/// it is running, the nested panic skips the hook and goes straight to the default handling.
+pub fn set_hook(hook: fn(&PanicInfo)) {
+	PANIC_HOOK.store(hook as usize, Ordering::SeqCst);
+}
+
+/// Removes the hook set by set_hook, restoring the default panic behavior.
+pub fn take_hook() {
+	PANIC_HOOK.store(0, Ordering::SeqCst);
+}
+
+/// Called by the panic handler to run the hook set by set_hook, if any.
+pub fn run_hook(info: &PanicInfo) {
+	let hook_address = PANIC_HOOK.load(Ordering::SeqCst);
+	if hook_address == 0 || RUNNING_HOOK.swap(true, Ordering::SeqCst) {
+		return;
+	}
+
+	let hook: fn(&PanicInfo) = unsafe { mem::transmute(hook_address) };
+	hook(info);
+	RUNNING_HOOK.store(false, Ordering::SeqCst);
+}
@@ -0,0 +1,166 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Read-only ramdisk backed by a Multiboot module.
+//!
+//! This provides a storage source without a virtual disk, e.g. for mounting a filesystem image
+//! passed along by the boot loader.
+
+use arch::mm::paging::PageTableEntryFlags;
+use core::slice;
+use environment;
+use mm;
+
+
+/// Size of a single block in bytes.
+pub const BLOCK_SIZE: usize = 512;
+
+/// The ramdisk selected on the command line.
+static mut RAMDISK: Option<Ramdisk> = None;
+
+
+/// A read-only block device over the memory of a Multiboot module.
+/// The module memory has been reserved by physicalmem and stays mapped for the lifetime of the kernel.
+#[derive(Clone, Copy)]
+pub struct Ramdisk {
+	virtual_address: usize,
+	size: usize,
+}
+
+impl Ramdisk {
+	/// Returns the size of the ramdisk in bytes.
+	pub fn size(&self) -> usize {
+		self.size
+	}
+
+	/// Returns the number of complete blocks of the ramdisk.
+	pub fn block_count(&self) -> usize {
+		self.size / BLOCK_SIZE
+	}
+
+	/// Returns the entire contents of the ramdisk.
+	pub fn as_slice(&self) -> &'static [u8] {
+		unsafe { slice::from_raw_parts(self.virtual_address as *const u8, self.size) }
+	}
+
+	/// Reads `buffer.len() / BLOCK_SIZE` blocks starting at block number `first_block` into `buffer`.
+	/// Fails if the buffer size is not a multiple of BLOCK_SIZE or the blocks exceed the ramdisk.
+	/// A partial block at the end of the ramdisk is padded with zeros. Reading no blocks always succeeds.
+	pub fn read_blocks(&self, first_block: usize, buffer: &mut [u8]) -> Result<(), ()> {
+		if buffer.len() % BLOCK_SIZE != 0 {
+			return Err(());
+		}
+		if buffer.is_empty() {
+			return Ok(());
+		}
+
+		let start = first_block.checked_mul(BLOCK_SIZE).ok_or(())?;
+		let end = start.checked_add(buffer.len()).ok_or(())?;
+		if end > align_up!(self.size, BLOCK_SIZE) {
+			return Err(());
+		}
+
+		let available = if end > self.size { self.size - start } else { buffer.len() };
+		buffer[..available].copy_from_slice(&self.as_slice()[start..start + available]);
+		for byte in buffer[available..].iter_mut() {
+			*byte = 0;
+		}
+
+		Ok(())
+	}
+}
+
+/// Creates a ramdisk from the Multiboot module with the given index.
+/// Returns None if there is no such module or it is empty.
+pub fn from_module(index: usize) -> Option<Ramdisk> {
+	let module = environment::modules().get(index)?;
+	let size = module.end_address - module.start_address;
+	if size == 0 {
+		return None;
+	}
+
+	let virtual_address = mm::map_physical(module.start_address, size, PageTableEntryFlags::EXECUTE_DISABLE);
+	info!("Ramdisk from module {} ({:#X} - {:#X}, {} bytes) mapped at {:#X}", index, module.start_address, module.end_address, size, virtual_address);
+
+	Some(Ramdisk {
+		virtual_address: virtual_address,
+		size: size,
+	})
+}
+
+/// Creates the ramdisk from the Multiboot module selected by the "ramdisk=<module index>" command-line argument.
+pub fn init() {
+	let index = match environment::get_arg("ramdisk").map(environment::parse_integer) {
+		None => return,
+		Some(Some(index)) => index,
+		Some(None) => {
+			warn!("Ignoring invalid ramdisk");
+			return;
+		}
+	};
+
+	match from_module(index) {
+		Some(ramdisk) => {
+			debug!("Ramdisk has {} complete blocks of {} bytes", ramdisk.block_count(), BLOCK_SIZE);
+			unsafe { RAMDISK = Some(ramdisk); }
+		},
+		None => warn!("There is no Multiboot module {} to use as ramdisk", index),
+	}
+}
+
+/// Returns the ramdisk selected on the command line, if any.
+pub fn get() -> Option<Ramdisk> {
+	unsafe { RAMDISK }
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reads_are_bounds_checked() {
+		let contents: Vec<u8> = (0..BLOCK_SIZE + 100).map(|i| (i % 251) as u8 + 1).collect();
+		let ramdisk = Ramdisk { virtual_address: contents.as_ptr() as usize, size: contents.len() };
+		assert_eq!(ramdisk.size(), BLOCK_SIZE + 100);
+		assert_eq!(ramdisk.block_count(), 1);
+
+		let mut buffer = [0u8; 2 * BLOCK_SIZE];
+		assert_eq!(ramdisk.read_blocks(0, &mut buffer[..BLOCK_SIZE]), Ok(()));
+		assert_eq!(&buffer[..BLOCK_SIZE], &contents[..BLOCK_SIZE]);
+
+		// The partial last block is padded with zeros.
+		assert_eq!(ramdisk.read_blocks(1, &mut buffer[..BLOCK_SIZE]), Ok(()));
+		assert_eq!(&buffer[..100], &contents[BLOCK_SIZE..]);
+		assert!(buffer[100..BLOCK_SIZE].iter().all(|&byte| byte == 0));
+
+		// Reads past the end, of partial blocks or overflowing the block number fail.
+		assert_eq!(ramdisk.read_blocks(2, &mut buffer[..BLOCK_SIZE]), Err(()));
+		assert_eq!(ramdisk.read_blocks(1, &mut buffer), Err(()));
+		assert_eq!(ramdisk.read_blocks(0, &mut buffer[..100]), Err(()));
+		assert_eq!(ramdisk.read_blocks(usize::max_value(), &mut buffer[..BLOCK_SIZE]), Err(()));
+
+		// Reading no blocks always succeeds.
+		assert_eq!(ramdisk.read_blocks(5, &mut buffer[..0]), Ok(()));
+	}
+}
@@ -0,0 +1,100 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! Source of random numbers for the kernel.
+//!
+//! By default, random numbers come from the hardware (RDRAND).
+//! For reproducible testing, a `random_seed=<number>` command-line argument switches to a deterministic
+//! pseudo-random number generator instead. This mode is insecure and must never be used in production!
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use environment;
+use synch::spinlock::SpinlockIrqSave;
+
+
+lazy_static! {
+	/// State of the deterministic xorshift64* generator, if a seed has been given on the command line.
+	static ref DETERMINISTIC_STATE: SpinlockIrqSave<Option<u64>> = SpinlockIrqSave::new(None);
+}
+
+/// Set once a seed has been given, so that it can be queried without taking the lock, e.g. while panicking.
+static DETERMINISTIC: AtomicBool = AtomicBool::new(false);
+
+
+fn next_deterministic(state: &mut u64) -> u32 {
+	*state ^= *state >> 12;
+	*state ^= *state << 25;
+	*state ^= *state >> 27;
+	(state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32) as u32
+}
+
+/// Switches to deterministic mode if requested on the command line.
+/// Must be called after environment::init() and before anything needs random numbers.
+pub fn init() {
+	let seed = match environment::get_arg("random_seed").map(environment::parse_integer) {
+		None => return,
+		Some(Some(seed)) => seed as u64,
+		Some(None) => {
+			warn!("Ignoring invalid random_seed, using the hardware random number generator");
+			return;
+		}
+	};
+
+	// xorshift gets stuck at zero, so mix in a constant.
+	*DETERMINISTIC_STATE.lock() = Some(seed ^ 0x9E37_79B9_7F4A_7C15);
+	DETERMINISTIC.store(true, Ordering::Relaxed);
+	warn!("Deterministic random numbers with seed {:#X} are active. This is INSECURE and only meant for testing!", seed);
+}
+
+/// Returns whether random numbers are generated deterministically from a seed.
+pub fn is_deterministic() -> bool {
+	DETERMINISTIC.load(Ordering::Relaxed)
+}
+
+/// Returns a random number from the deterministic generator if seeded, or from the hardware otherwise.
+/// Returns None if neither is available.
+pub fn generate_random_number() -> Option<u32> {
+	if let Some(ref mut state) = *DETERMINISTIC_STATE.lock() {
+		return Some(next_deterministic(state));
+	}
+
+	::arch::processor::generate_random_number()
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn deterministic_numbers_are_reproducible() {
+		let mut first = 42 ^ 0x9E37_79B9_7F4A_7C15;
+		let mut second = first;
+		let mut other = 43 ^ 0x9E37_79B9_7F4A_7C15;
+
+		let sequence: Vec<u32> = (0..16).map(|_| next_deterministic(&mut first)).collect();
+		assert_eq!(sequence, (0..16).map(|_| next_deterministic(&mut second)).collect::<Vec<u32>>());
+		assert_ne!(sequence, (0..16).map(|_| next_deterministic(&mut other)).collect::<Vec<u32>>());
+		assert!(first != 0);
+	}
+}
@@ -28,35 +28,118 @@
 #![allow(private_no_mangle_fns)]
 
 use arch;
+use arch::percore::*;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use environment;
+use panic_info;
+use random;
 
+
+/// Time in microseconds that the serial port gets to send out the panic message before a reboot.
+const PANIC_REBOOT_DELAY_US: u64 = 500_000;
+
+/// Whether a panic shall reboot the system instead of halting it.
+/// Enabled by the "panic_reboot" feature or by passing "panic=reboot" on the kernel command line.
+fn reboot_on_panic() -> bool {
+	cfg!(feature = "panic_reboot") || environment::get_arg("panic") == Some("reboot")
+}
+
+/// Set by the `kpanic!` and `kassert!` macros to extend the panic report by the kernel state.
+static KERNEL_STATE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Lets the panic handler print the kernel state after it has stopped the other cores.
+/// Printing it before would let the other cores keep allocating memory and logging in between.
+pub fn request_kernel_state() {
+	KERNEL_STATE_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Print the state of the current CPU core and the memory manager for a kernel failure report.
+/// This is called by the panic handler and must not allocate memory.
+#[cfg(not(test))]
+fn print_kernel_state() {
+	println!("[{}][!!!KERNEL STATE!!!]", core_id());
+
+	match try_core_scheduler() {
+		Some(scheduler) => match scheduler.current_task.try_borrow() {
+			Ok(task) => println!("Current Task: {} (priority {})", task.id, task.prio),
+			Err(_) => println!("Current Task: <borrowed>"),
+		},
+		None => println!("Current Task: <scheduler not initialized>"),
+	}
+
+	let uptime_us = arch::time::uptime_ns() / 1000;
+	println!("Uptime: {}.{:06} s", uptime_us / 1_000_000, uptime_us % 1_000_000);
+	if let Some(boot_time) = arch::time::boot_wall_time() {
+		println!("Wall-clock time: {} seconds since the Unix epoch", boot_time + uptime_us / 1_000_000);
+	}
+	if arch::mce::corrected_error_count() > 0 {
+		println!("Corrected Machine Check errors: {}", arch::mce::corrected_error_count());
+	}
+	if random::is_deterministic() {
+		println!("Random numbers: deterministic (random_seed given on the command line)");
+	}
+
+	arch::processor::print_registers();
+	arch::mm::physicalmem::print_information();
+}
+
+#[cfg(not(test))]
 #[lang = "eh_personality"]
 extern "C" fn eh_personality() {}
 
 // see https://users.rust-lang.org/t/psa-breaking-change-panic-fmt-language-item-removed-in-favor-of-panic-implementation/17875
+#[cfg(not(test))]
 #[panic_implementation]
 #[no_mangle]
 fn panic(info: &PanicInfo) -> ! {
+	arch::irq::disable();
+
+	// Stop the other cores before printing anything, so they cannot overwrite the report or the recorded panic.
+	arch::shutdown::quiesce_all_cores();
+
+	panic_info::record(info);
+	panic_info::run_hook(info);
+
+	if let Some(message) = info.message() {
+		println!("[{}][!!!PANIC!!!] {}", core_id(), message);
+	}
+
 	if let Some(location) = info.location() {
 		println!("panic occurred in file '{}' at line {}", location.file(), location.line());
 	} else {
 		println!("panic occurred but can't get location information...");
 	}
 
+	if KERNEL_STATE_REQUESTED.load(Ordering::SeqCst) {
+		print_kernel_state();
+	}
+
+	arch::flush_message_output();
+
+	if reboot_on_panic() {
+		arch::processor::udelay(PANIC_REBOOT_DELAY_US);
+		arch::processor::reboot();
+	}
+
 	loop {
 		arch::processor::halt();
 	}
 }
 
+#[cfg(not(test))]
 #[lang = "oom"]
 #[no_mangle]
 pub fn rust_oom() -> ! {
 	println!("[{}][!!!OOM!!!]", arch::percore::core_id());
+	arch::flush_message_output();
+
 	loop {
 		arch::processor::halt();
 	}
 }
 
+#[cfg(not(test))]
 #[no_mangle]
 #[allow(non_snake_case)]
 pub fn _Unwind_Resume()
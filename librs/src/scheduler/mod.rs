@@ -32,6 +32,8 @@ use arch;
 use arch::irq;
 use arch::percore::*;
 use core::cell::RefCell;
+use core::cmp;
+use core::u32;
 use core::sync::atomic::{AtomicI32, AtomicU32, AtomicUsize, Ordering};
 use scheduler::task::*;
 use synch::spinlock::*;
@@ -42,7 +44,17 @@ extern "C" {
 }
 
 
+/// Returned by current_task_id during early boot, before any task runs on the core.
+pub const NO_TASK_ID: TaskId = TaskId::from(u32::MAX);
+
+/// Number of timer ticks between two load balancing rounds of a core.
+const BALANCE_INTERVAL_TICKS: usize = 10;
+/// A core only pushes tasks to another core if it has more than this many ready tasks above the average.
+const BALANCE_THRESHOLD: usize = 2;
+
 static LAST_EXIT_CODE: AtomicI32 = AtomicI32::new(0);
+/// Number of tasks that have been moved to another core by the load balancer
+static MIGRATED_TASKS: AtomicUsize = AtomicUsize::new(0);
 static NEXT_CPU_NUMBER: AtomicUsize = AtomicUsize::new(1);
 static NO_TASKS: AtomicU32 = AtomicU32::new(0);
 /// Map between Core ID and per-core scheduler
@@ -76,6 +88,10 @@ pub struct PerCoreScheduler {
 	pub blocked_tasks: SpinlockIrqSave<BlockedTaskQueue>,
 	/// Processor Timer Tick when we last switched the current task.
 	last_task_switch_tick: usize,
+	/// Whether the periodic timer tick has been stopped while this core is idle.
+	is_tick_stopped: bool,
+	/// Processor Timer Tick when this core last checked whether it is overloaded.
+	last_balance_tick: usize,
 }
 
 impl PerCoreScheduler {
@@ -128,9 +144,9 @@ impl PerCoreScheduler {
 					core_id
 				},
 				None => {
-					// This CPU number does not exist, so start over again with CPU number 0 = Core ID 0.
+					// This CPU number does not exist, so start over again with CPU number 0 = Boot Processor.
 					NEXT_CPU_NUMBER.store(0, Ordering::SeqCst);
-					0
+					arch::get_core_id_for_cpu_number(0).unwrap()
 				}
 			}
 		};
@@ -174,6 +190,101 @@ impl PerCoreScheduler {
 		}
 	}
 
+	/// Number of ready tasks waiting in the queue of this core.
+	fn ready_task_count(&self) -> usize {
+		self.state.lock().ready_queue.len()
+	}
+
+	/// Pushes ready tasks to the least-loaded online core if this core has considerably more
+	/// ready tasks than the average. Called from the timer tick and only does something
+	/// every BALANCE_INTERVAL_TICKS.
+	///
+	/// Both scheduler states are never locked at the same time, so two cores balancing
+	/// simultaneously cannot deadlock. Tasks are moved one by one.
+	///
+	/// The Rc and RefCell of a task are not thread-safe, so this runs in interrupt context
+	/// without allocating and without cloning or dropping any Rc: the queue node of the task is
+	/// moved as a whole. The task itself is only borrowed while it is in no queue and the state
+	/// of this core is locked, so no other core can reach it through a ready queue meanwhile.
+	pub fn balance(&mut self) {
+		let ticks = arch::processor::update_timer_ticks();
+		if ticks < self.last_balance_tick + BALANCE_INTERVAL_TICKS {
+			return;
+		}
+		self.last_balance_tick = ticks;
+
+		// Determine the average load and the least-loaded other core.
+		// Parked cores still have a scheduler, but must not get any new tasks.
+		let online_cpus = arch::online_cpu_mask();
+		let mut online_count = 0;
+		let mut total_count = 0;
+		let mut target = None;
+
+		for (&core_id, scheduler) in unsafe { SCHEDULERS.as_ref().unwrap().iter() } {
+			if !online_cpus.contains(core_id) {
+				continue;
+			}
+
+			let count = scheduler.ready_task_count();
+			online_count += 1;
+			total_count += count;
+
+			if core_id != self.core_id {
+				target = match target {
+					Some((_, target_count)) if target_count <= count => target,
+					_ => Some((core_id, count)),
+				};
+			}
+		}
+
+		let (target_core_id, target_count) = match target {
+			Some(target) => target,
+			None => return,
+		};
+
+		let to_migrate = tasks_to_migrate(self.ready_task_count(), total_count / online_count, target_count);
+		if to_migrate == 0 {
+			return;
+		}
+
+		let target_scheduler = get_scheduler(target_core_id);
+
+		for _ in 0..to_migrate {
+			let (node, tid) = {
+				let mut state_locked = self.state.lock();
+				let node = match state_locked.ready_queue.pop_lowest_node() {
+					Some(node) => node,
+					None => break,
+				};
+
+				// The FPU registers of this core may still hold the FPU state of the task.
+				// Keep it here and stop, as it would be popped again right away.
+				if Rc::ptr_eq(&node.borrow().value, &self.fpu_owner) {
+					state_locked.ready_queue.push_node(node);
+					break;
+				}
+
+				let tid = {
+					let node_borrowed = node.borrow();
+					let mut task_borrowed = node_borrowed.value.borrow_mut();
+					task_borrowed.core_id = target_core_id;
+					task_borrowed.id
+				};
+
+				(node, tid)
+			};
+
+			let mut target_state = target_scheduler.state.lock();
+			target_state.ready_queue.push_node(node);
+			MIGRATED_TASKS.fetch_add(1, Ordering::SeqCst);
+			debug!("Migrating task {} to core {}", tid, target_core_id);
+
+			if target_state.is_halted {
+				arch::wakeup_core(target_core_id);
+			}
+		}
+	}
+
 	/// Check if a finished task could be deleted.
 	fn cleanup_tasks(&mut self) {
 		// Pop the first finished task and remove it from the TASKS list, which implicitly deallocates all associated memory.
@@ -194,6 +305,7 @@ impl PerCoreScheduler {
 		// Get information about the current task.
 		let (id, last_stack_pointer, prio, status) = {
 			let mut borrowed = self.current_task.borrow_mut();
+			borrowed.update_priority();
 			(borrowed.id, &mut borrowed.last_stack_pointer as *mut usize, borrowed.prio, borrowed.status)
 		};
 
@@ -264,7 +376,7 @@ impl PerCoreScheduler {
 			};
 
 			// If this is the Boot Processor and only the lwIP TCP/IP task is left, it's time to shut down the OS.
-			if core_id() == 0 && new_id.into() == get_lwip_tcpip_task_id() && NO_TASKS.load(Ordering::SeqCst) == 1 {
+			if arch::is_boot_processor() && new_id.into() == get_lwip_tcpip_task_id() && NO_TASKS.load(Ordering::SeqCst) == 1 {
 				debug!("Only lwIP TCP/IP task is left");
 				sys_shutdown();
 			}
@@ -273,19 +385,30 @@ impl PerCoreScheduler {
 			debug!("Switching task from {} to {} (stack {:#X} => {:#X})", id, new_id,
 				unsafe { *last_stack_pointer }, new_stack_pointer);
 			self.current_task = task;
+			set_current_task_id(new_id.into());
 			self.last_task_switch_tick = arch::processor::update_timer_ticks();
 
+			// A runnable task needs the periodic timer tick again.
+			if self.is_tick_stopped && new_id != self.idle_task.borrow().id {
+				arch::restart_periodic_tick();
+				self.is_tick_stopped = false;
+			}
+
 			// Unlock the state and reenable interrupts.
 			drop(state_locked);
 			irq::enable();
 
+			// Pick up hardware breakpoints that have been changed on another core.
+			#[cfg(feature = "debugger")]
+			arch::debug::synchronize();
+
 			// Finally save our current context and restore the context of the new task.
 			unsafe { switch(last_stack_pointer, new_stack_pointer); }
 		} else {
 			// There is no new task to switch to.
 
 			// If this is the Boot Processor and all tasks have finished, it's time to shut down the OS.
-			if core_id() == 0 && NO_TASKS.load(Ordering::SeqCst) == 0 {
+			if arch::is_boot_processor() && NO_TASKS.load(Ordering::SeqCst) == 0 {
 				sys_shutdown();
 			}
 
@@ -295,9 +418,14 @@ impl PerCoreScheduler {
 				state_locked.is_halted = true;
 				drop(state_locked);
 
-				// Reenable interrupts and simultaneously set the CPU into the HALT state to only wake up at the next interrupt.
-				// This atomic operation guarantees that we cannot miss a wakeup interrupt in between.
-				irq::enable_and_wait();
+				// Without any runnable task, a timer tick is only needed for the next wakeup time of a blocked task.
+				// Reprogram it on every halt, as the previous one-shot interrupt may not have woken up any task.
+				let wakeup_time = self.blocked_tasks.lock().next_wakeup_time();
+				self.is_tick_stopped = arch::stop_periodic_tick(wakeup_time);
+
+				// Reenable interrupts and simultaneously put the CPU to sleep to only wake up at the next interrupt.
+				// This guarantees that we cannot miss a wakeup interrupt in between.
+				arch::processor::idle();
 			} else {
 				// We now run a real task. Just reenable interrupts.
 				irq::enable();
@@ -351,13 +479,58 @@ pub fn add_current_core() {
 		finished_tasks: VecDeque::new(),
 		blocked_tasks: SpinlockIrqSave::new(BlockedTaskQueue::new()),
 		last_task_switch_tick: 0,
+		is_tick_stopped: false,
+		last_balance_tick: 0,
 	});
 
 	let scheduler = Box::into_raw(boxed_scheduler);
 	set_core_scheduler(scheduler);
+	set_current_task_id(tid.into());
 	unsafe { SCHEDULERS.as_mut().unwrap().insert(core_id, &(*scheduler)); }
 }
 
+/// Returns the ID of the task running on the current core or NO_TASK_ID before the scheduler
+/// has been added to this core.
+/// This only reads a per-core variable, so it needs no lock and is cheap enough to be called anywhere.
+#[inline]
+pub fn current_task_id() -> TaskId {
+	TaskId::from(arch::percore::current_task_id())
+}
+
+/// Moves a ready task to the queue of its current effective priority, e.g. after it has been
+/// boosted through priority inheritance. Returns whether the task has been found in a ready queue.
+///
+/// The task is only borrowed while it is removed from the ready queue of its core and that core
+/// is locked. A running or blocked task is left alone and picks up its new priority when it is
+/// scheduled or queued again.
+pub fn requeue_task(task: &Rc<RefCell<Task>>) -> bool {
+	for scheduler in unsafe { SCHEDULERS.as_ref().unwrap().values() } {
+		let mut state_locked = scheduler.state.lock();
+		if state_locked.ready_queue.remove(task.clone()) {
+			state_locked.ready_queue.push(task.clone());
+			return true;
+		}
+	}
+
+	false
+}
+
+/// Returns how many ready tasks a core with `own_count` of them pushes to the least-loaded other core, which has
+/// `target_count` ready tasks, if the online cores have `average` ready tasks.
+fn tasks_to_migrate(own_count: usize, average: usize, target_count: usize) -> usize {
+	if own_count <= average + BALANCE_THRESHOLD || own_count <= target_count + 1 {
+		return 0;
+	}
+
+	// Move just enough tasks to even out the load between this core and the target.
+	cmp::min(own_count - average, (own_count - target_count) / 2)
+}
+
+/// Returns the number of tasks that have been moved to another core by the load balancer.
+pub fn migrated_tasks() -> usize {
+	MIGRATED_TASKS.load(Ordering::SeqCst)
+}
+
 pub fn get_last_exit_code() -> i32 {
 	LAST_EXIT_CODE.load(Ordering::SeqCst)
 }
@@ -368,3 +541,76 @@ pub fn get_scheduler(core_id: u32) -> &'static PerCoreScheduler {
 	assert!(result.is_some(), "Trying to get the scheduler for core {}, but it isn't available", core_id);
 	result.unwrap()
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Lets every core run one balancing round in turn, like balance does with the ready queue lengths in `loads`.
+	/// Returns the number of migrated tasks.
+	fn balance_round(loads: &mut [usize]) -> usize {
+		let mut migrated = 0;
+
+		for core in 0..loads.len() {
+			let average = loads.iter().sum::<usize>() / loads.len();
+			let target = (0..loads.len()).filter(|&other| other != core).min_by_key(|&other| loads[other]).unwrap();
+			let count = tasks_to_migrate(loads[core], average, loads[target]);
+
+			loads[core] -= count;
+			loads[target] += count;
+			migrated += count;
+		}
+
+		migrated
+	}
+
+	/// Balances until no more tasks are migrated and returns the number of rounds this took.
+	fn balance_until_stable(loads: &mut [usize]) -> usize {
+		let total = loads.iter().sum::<usize>();
+
+		for round in 1..loads.len() * 4 + 1 {
+			if balance_round(loads) == 0 {
+				assert_eq!(loads.iter().sum::<usize>(), total, "tasks were lost");
+				return round;
+			}
+		}
+
+		panic!("Load balancing did not converge: {:?}", loads);
+	}
+
+	#[test]
+	fn balanced_cores_do_not_migrate() {
+		assert_eq!(tasks_to_migrate(5, 4, 3), 0);
+		assert_eq!(tasks_to_migrate(4 + BALANCE_THRESHOLD, 4, 0), 0);
+
+		let mut loads = [3, 4, 5, 4];
+		assert_eq!(balance_round(&mut loads), 0);
+	}
+
+	#[test]
+	fn asymmetric_spawns_converge_to_balanced_queues() {
+		// A single core has spawned all tasks.
+		let mut loads = [32, 0, 0, 0];
+		balance_until_stable(&mut loads);
+		assert!(loads.iter().all(|&load| load <= 8 + BALANCE_THRESHOLD), "{:?}", loads);
+		assert!(loads.iter().all(|&load| load > 0), "{:?}", loads);
+
+		// Two spawning cores, one of them far ahead, and idle cores.
+		let mut loads = [40, 12, 0, 0, 0, 0, 0, 0];
+		balance_until_stable(&mut loads);
+		let average = 52 / loads.len();
+		assert!(loads.iter().all(|&load| load <= average + BALANCE_THRESHOLD), "{:?}", loads);
+	}
+
+	#[test]
+	fn migration_never_overshoots() {
+		// Moving the tasks must not make the target the overloaded core.
+		for own_count in 0..64 {
+			for target_count in 0..own_count {
+				let count = tasks_to_migrate(own_count, (own_count + target_count) / 2, target_count);
+				assert!(target_count + count <= own_count - count, "{} -> {}", own_count, target_count);
+			}
+		}
+	}
+}
@@ -25,15 +25,18 @@
 include!(concat!(env!("CARGO_TARGET_DIR"), "/config.rs"));
 
 use alloc::rc::Rc;
+use alloc::sync::Arc;
 use arch;
 use arch::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
 use arch::processor::msb;
 use collections::{DoublyLinkedList, Node};
 use core::cell::RefCell;
-use core::fmt;
+use core::{cmp, fmt};
 use mm;
 use scheduler;
 use spin::RwLock;
+use synch::recmutex::RecursiveMutex;
+use synch::spinlock::SpinlockIrqSave;
 
 
 /// The status of the task - used for scheduling
@@ -104,10 +107,75 @@ pub const IDLE_PRIO: Priority = Priority::from(0);
 /// Maximum number of priorities
 pub const NO_PRIORITIES: usize = 31;
 
+struct InheritanceState {
+	/// Number of mutexes held by the task, whose waiters boost it to the respective priority
+	boosts: [u16; NO_PRIORITIES],
+	/// Mutex the task is currently waiting for
+	blocked_on: Option<*const RecursiveMutex>,
+}
+
+/// Priorities a task has inherited from the waiters of the mutexes it holds.
+///
+/// This is shared between the task and the mutexes it holds, so a waiter on another core
+/// can boost the task without borrowing it while it may be running.
+/// The task picks up its new priority through Task::update_priority whenever it is queued
+/// or scheduled.
+pub struct PriorityInheritance {
+	state: SpinlockIrqSave<InheritanceState>,
+}
+
+impl PriorityInheritance {
+	pub fn new() -> Self {
+		Self {
+			state: SpinlockIrqSave::new(InheritanceState {
+				boosts: [0; NO_PRIORITIES],
+				blocked_on: None,
+			}),
+		}
+	}
+
+	pub fn add_boost(&self, prio: Priority) {
+		self.state.lock().boosts[prio.into() as usize] += 1;
+	}
+
+	pub fn remove_boost(&self, prio: Priority) {
+		let mut state_locked = self.state.lock();
+		assert!(state_locked.boosts[prio.into() as usize] > 0, "Removing priority boost {}, which has never been added", prio);
+		state_locked.boosts[prio.into() as usize] -= 1;
+	}
+
+	/// Returns the highest priority inherited from any held mutex.
+	pub fn highest_boost(&self) -> Option<Priority> {
+		let state_locked = self.state.lock();
+		(0..NO_PRIORITIES).rev().find(|&i| state_locked.boosts[i] > 0).map(|i| Priority::from(i as u8))
+	}
+
+	/// Returns the highest one of `base_prio` and all inherited priorities.
+	pub fn effective_priority(&self, base_prio: Priority) -> Priority {
+		match self.highest_boost() {
+			Some(boost) => cmp::max(base_prio, boost),
+			None => base_prio,
+		}
+	}
+
+	pub fn blocked_on(&self) -> Option<*const RecursiveMutex> {
+		self.state.lock().blocked_on
+	}
+
+	pub fn set_blocked_on(&self, mutex: Option<*const RecursiveMutex>) {
+		self.state.lock().blocked_on = mutex;
+	}
+}
+
+/// A node of a PriorityTaskQueue, which can be moved between queues without allocating.
+pub type TaskNode = Rc<RefCell<Node<Rc<RefCell<Task>>>>>;
+
 /// Realize a priority queue for tasks
 pub struct PriorityTaskQueue {
 	queues: [DoublyLinkedList<Rc<RefCell<Task>>>; NO_PRIORITIES],
-	prio_bitmap: u64
+	prio_bitmap: u64,
+	/// Number of tasks in all queues
+	length: usize,
 }
 
 impl PriorityTaskQueue {
@@ -115,20 +183,43 @@ impl PriorityTaskQueue {
 	pub fn new() -> PriorityTaskQueue {
 		PriorityTaskQueue {
 			queues: Default::default(),
-			prio_bitmap: 0
+			prio_bitmap: 0,
+			length: 0,
 		}
 	}
 
+	/// Returns the number of tasks in the queue
+	pub fn len(&self) -> usize {
+		self.length
+	}
+
+	/// Returns the highest priority of all tasks in the queue
+	pub fn highest_priority(&self) -> Option<Priority> {
+		msb(self.prio_bitmap).map(|i| Priority::from(i as u8))
+	}
+
 	/// Add a task by its priority to the queue
 	pub fn push(&mut self, task: Rc<RefCell<Task>>) {
-		let i = task.borrow().prio.into() as usize;
+		self.push_node(Node::new(task));
+	}
+
+	/// Add a node popped from another queue by its priority to the queue.
+	/// The priority of the task is updated first, as it may have inherited a new one meanwhile.
+	pub fn push_node(&mut self, node: TaskNode) {
+		let i = {
+			let node_borrowed = node.borrow();
+			let mut task_borrowed = node_borrowed.value.borrow_mut();
+			task_borrowed.update_priority();
+			task_borrowed.prio.into() as usize
+		};
 		assert!(i < NO_PRIORITIES, "Priority {} is too high", i);
 
 		self.prio_bitmap |= 1 << i;
-		self.queues[i].push(Node::new(task));
+		self.queues[i].push(node);
+		self.length += 1;
 	}
 
-	fn pop_from_queue(&mut self, queue_index: usize) -> Option<Rc<RefCell<Task>>> {
+	fn pop_node_from_queue(&mut self, queue_index: usize) -> Option<TaskNode> {
 		let first_task = self.queues[queue_index].head();
 		first_task.map(|task| {
 			self.queues[queue_index].remove(task.clone());
@@ -137,10 +228,15 @@ impl PriorityTaskQueue {
 				self.prio_bitmap &= !(1 << queue_index as u64);
 			}
 
-			task.borrow().value.clone()
+			self.length -= 1;
+			task
 		})
 	}
 
+	fn pop_from_queue(&mut self, queue_index: usize) -> Option<Rc<RefCell<Task>>> {
+		self.pop_node_from_queue(queue_index).map(|node| node.borrow().value.clone())
+	}
+
 	/// Pop the task with the highest priority from the queue
 	pub fn pop(&mut self) -> Option<Rc<RefCell<Task>>> {
 		if let Some(i) = msb(self.prio_bitmap) {
@@ -161,22 +257,41 @@ impl PriorityTaskQueue {
 		None
 	}
 
-	/// Remove a specific task from the priority queue.
-	pub fn remove(&mut self, task: Rc<RefCell<Task>>) {
-		let i = task.borrow().prio.into() as usize;
-		assert!(i < NO_PRIORITIES, "Priority {} is too high", i);
+	/// Pop the node of the task with the lowest priority from the queue, which is the cheapest one
+	/// to move to another core. Pass it to push_node of the other queue.
+	pub fn pop_lowest_node(&mut self) -> Option<TaskNode> {
+		if self.prio_bitmap == 0 {
+			return None;
+		}
 
-		for node in self.queues[i].iter() {
-			if Rc::ptr_eq(&node.borrow().value, &task) {
-				self.queues[i].remove(node.clone());
+		let i = self.prio_bitmap.trailing_zeros() as usize;
+		self.pop_node_from_queue(i)
+	}
 
-				if self.queues[i].head().is_none() {
-					self.prio_bitmap &= !(1 << i as u64);
-				}
+	/// Remove a specific task from the priority queue and return whether it has been found.
+	/// All queues are searched, because the priority of the task may have been changed by
+	/// priority inheritance after it has been pushed.
+	pub fn remove(&mut self, task: Rc<RefCell<Task>>) -> bool {
+		for i in 0..NO_PRIORITIES {
+			if self.prio_bitmap & (1 << i as u64) == 0 {
+				continue;
+			}
 
-				break;
+			for node in self.queues[i].iter() {
+				if Rc::ptr_eq(&node.borrow().value, &task) {
+					self.queues[i].remove(node.clone());
+
+					if self.queues[i].head().is_none() {
+						self.prio_bitmap &= !(1 << i as u64);
+					}
+
+					self.length -= 1;
+					return true;
+				}
 			}
 		}
+
+		false
 	}
 }
 
@@ -221,8 +336,12 @@ pub struct Task {
 	pub id: TaskId,
 	/// Status of a task, e.g. if the task is ready or blocked
 	pub status: TaskStatus,
-	/// Task priority,
+	/// Task priority, which may be temporarily boosted by priority inheritance
 	pub prio: Priority,
+	/// Task priority without any boost from priority inheritance
+	pub base_prio: Priority,
+	/// Priorities inherited from the waiters of mutexes held by this task
+	pub inheritance: Arc<PriorityInheritance>,
 	/// Last stack pointer before a context switch to another task
 	pub last_stack_pointer: usize,
 	/// Last FPU state before a context switch to another task using the FPU
@@ -255,7 +374,7 @@ impl Drop for Task {
 
 			// deallocate stacks
 			mm::deallocate(self.stack as usize, DEFAULT_STACK_SIZE);
-			mm::deallocate(self.ist as usize, DEFAULT_STACK_SIZE);
+			mm::deallocate_stack(self.ist as usize, arch::kernel_stack_size());
 		}
 	}
 }
@@ -265,7 +384,7 @@ impl Task {
 	fn allocate_stacks() -> (usize, usize) {
 		// Allocate an executable stack to possibly support dynamically generated code on the stack (see https://security.stackexchange.com/a/47825).
 		let stack = mm::allocate(DEFAULT_STACK_SIZE, PageTableEntryFlags::empty());
-		let ist = mm::allocate(KERNEL_STACK_SIZE, PageTableEntryFlags::EXECUTE_DISABLE);
+		let ist = mm::allocate_stack(arch::kernel_stack_size(), PageTableEntryFlags::EXECUTE_DISABLE);
 		(stack, ist)
 	}
 
@@ -277,6 +396,8 @@ impl Task {
 			id: tid,
 			status: task_status,
 			prio: task_prio,
+			base_prio: task_prio,
+			inheritance: Arc::new(PriorityInheritance::new()),
 			last_stack_pointer: 0,
 			last_fpu_state: arch::processor::FPUState::new(),
 			core_id: core_id,
@@ -297,6 +418,8 @@ impl Task {
 			id: tid,
 			status: TaskStatus::TaskIdle,
 			prio: IDLE_PRIO,
+			base_prio: IDLE_PRIO,
+			inheritance: Arc::new(PriorityInheritance::new()),
 			last_stack_pointer: 0,
 			last_fpu_state: arch::processor::FPUState::new(),
 			core_id: core_id,
@@ -316,7 +439,9 @@ impl Task {
 		Task {
 			id: tid,
 			status: TaskStatus::TaskReady,
-			prio: task.prio,
+			prio: task.base_prio,
+			base_prio: task.base_prio,
+			inheritance: Arc::new(PriorityInheritance::new()),
 			last_stack_pointer: 0,
 			last_fpu_state: arch::processor::FPUState::new(),
 			core_id: core_id,
@@ -328,6 +453,11 @@ impl Task {
 			lwip_errno: 0,
 		}
 	}
+
+	/// Sets the effective priority to the highest one of the base priority and all inherited priorities.
+	pub fn update_priority(&mut self) {
+		self.prio = self.inheritance.effective_priority(self.base_prio);
+	}
 }
 
 struct BlockedTask {
@@ -416,6 +546,11 @@ impl BlockedTaskQueue {
 		}
 	}
 
+	/// Returns the earliest wakeup time (in timer ticks) of all blocked tasks or None if no task has a timeout.
+	pub fn next_wakeup_time(&self) -> Option<usize> {
+		self.list.iter().next().and_then(|node| node.borrow().value.wakeup_time)
+	}
+
 	/// Manually wake up a blocked task.
 	pub fn custom_wakeup(&mut self, task: Rc<RefCell<Task>>) {
 		let mut first_task = true;
@@ -469,3 +604,51 @@ impl BlockedTaskQueue {
 		}
 	}
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn inheritance_resolves_the_three_task_inversion() {
+		// A low-priority task holds a mutex, a medium-priority task is ready to run and a high-priority task
+		// starts waiting for the mutex. The mutex passes the priority of its highest waiter to its owner.
+		let low = PriorityInheritance::new();
+		assert_eq!(low.effective_priority(LOW_PRIO), LOW_PRIO);
+		low.add_boost(HIGH_PRIO);
+
+		// The owner now runs before the medium-priority task and cannot be starved by it.
+		assert_eq!(low.effective_priority(LOW_PRIO), HIGH_PRIO);
+		assert!(low.effective_priority(LOW_PRIO) > NORMAL_PRIO);
+
+		// Releasing the mutex restores the base priority, so the medium-priority task runs after the high one.
+		low.remove_boost(HIGH_PRIO);
+		assert_eq!(low.effective_priority(LOW_PRIO), LOW_PRIO);
+		assert!(low.highest_boost().is_none());
+	}
+
+	#[test]
+	fn boosts_of_other_held_mutexes_remain() {
+		let owner = PriorityInheritance::new();
+		owner.add_boost(NORMAL_PRIO);
+		owner.add_boost(HIGH_PRIO);
+		owner.add_boost(HIGH_PRIO);
+		assert_eq!(owner.effective_priority(LOW_PRIO), HIGH_PRIO);
+
+		// Two mutexes still have high-priority waiters after releasing the first one.
+		owner.remove_boost(HIGH_PRIO);
+		assert_eq!(owner.effective_priority(LOW_PRIO), HIGH_PRIO);
+		owner.remove_boost(HIGH_PRIO);
+		assert_eq!(owner.effective_priority(LOW_PRIO), NORMAL_PRIO);
+
+		// A boost never lowers the priority of a task.
+		assert_eq!(owner.effective_priority(HIGH_PRIO), HIGH_PRIO);
+	}
+
+	#[test]
+	#[should_panic]
+	fn removing_a_boost_that_was_never_added_panics() {
+		PriorityInheritance::new().remove_boost(NORMAL_PRIO);
+	}
+}
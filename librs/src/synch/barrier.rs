@@ -0,0 +1,166 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use arch::processor;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+
+/// This type lets a fixed number of cores wait for each other before any of them proceeds.
+///
+/// # Description
+///
+/// The barrier is reusable: After all cores have arrived, it is immediately ready for the
+/// next phase. A generation counter tells the cores waiting for the previous phase that they
+/// have been released, so a fast core entering the next phase cannot hold them back.
+///
+/// The number of cores can be changed for the next phase through `reset`. This allows arming a
+/// barrier only once the number of participants is known, e.g. after booting the Application Processors.
+///
+/// Waiting cores busy-wait, so the barrier can be used before the scheduler is running.
+///
+/// # Simple examples
+///
+/// ```
+/// static BARRIER: synch::barrier::Barrier = synch::barrier::Barrier::new(4);
+///
+/// // Called on each of the 4 cores
+/// first_phase();
+/// BARRIER.wait();
+/// second_phase();
+/// BARRIER.wait();
+/// ```
+pub struct Barrier {
+	/// Number of cores that need to arrive
+	count: AtomicUsize,
+	/// Number of cores that have arrived in the current phase
+	arrived: AtomicUsize,
+	/// Number of completed phases
+	generation: AtomicUsize,
+}
+
+impl Barrier {
+	/// Creates a barrier for `count` cores.
+	/// A barrier for 0 cores releases nobody until it has been armed through `reset`.
+	pub const fn new(count: usize) -> Barrier {
+		Barrier {
+			count: AtomicUsize::new(count),
+			arrived: AtomicUsize::new(0),
+			generation: AtomicUsize::new(0),
+		}
+	}
+
+	/// Sets the number of cores that need to arrive for the current phase.
+	/// Cores already waiting for this phase are counted towards the new number, so this may be called
+	/// after some of them have arrived. It must not be called while the current phase is being released.
+	pub fn reset(&self, count: usize) {
+		self.count.store(count, Ordering::SeqCst);
+	}
+
+	/// Blocks until all cores have called this function for the current phase.
+	/// Returns true for exactly one core per phase, namely the last one to arrive.
+	pub fn wait(&self) -> bool {
+		let generation = self.generation.load(Ordering::SeqCst);
+
+		if self.arrived.fetch_add(1, Ordering::SeqCst) + 1 == self.count.load(Ordering::SeqCst) {
+			// We are the last core, so release all others.
+			// The counter has to be reset before, as released cores may immediately enter the next phase.
+			self.arrived.store(0, Ordering::SeqCst);
+			self.generation.fetch_add(1, Ordering::SeqCst);
+			true
+		} else {
+			while self.generation.load(Ordering::SeqCst) == generation {
+				processor::wait_for_change(&self.generation, generation);
+			}
+
+			false
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Arc;
+	use std::thread;
+
+	#[test]
+	fn phases_release_all_cores_together() {
+		const CORES: usize = 4;
+		const PHASES: usize = 8;
+
+		let barrier = Arc::new(Barrier::new(CORES));
+		let arrivals = Arc::new(AtomicUsize::new(0));
+		let last_arrivals = Arc::new(AtomicUsize::new(0));
+
+		let threads: Vec<_> = (0..CORES).map(|_| {
+			let barrier = barrier.clone();
+			let arrivals = arrivals.clone();
+			let last_arrivals = last_arrivals.clone();
+
+			thread::spawn(move || {
+				for phase in 0..PHASES {
+					arrivals.fetch_add(1, Ordering::SeqCst);
+					if barrier.wait() {
+						last_arrivals.fetch_add(1, Ordering::SeqCst);
+					}
+
+					// Nobody may have left the previous phase before everybody has arrived.
+					assert!(arrivals.load(Ordering::SeqCst) >= CORES * (phase + 1));
+
+					// Nobody may enter the next phase before everybody has left this one.
+					barrier.wait();
+				}
+			})
+		}).collect();
+
+		for thread in threads {
+			thread.join().unwrap();
+		}
+
+		assert_eq!(arrivals.load(Ordering::SeqCst), CORES * PHASES);
+		assert_eq!(last_arrivals.load(Ordering::SeqCst), PHASES);
+	}
+
+	#[test]
+	fn reset_counts_already_waiting_cores() {
+		let barrier = Arc::new(Barrier::new(0));
+
+		let threads: Vec<_> = (0..3).map(|_| {
+			let barrier = barrier.clone();
+			thread::spawn(move || barrier.wait())
+		}).collect();
+
+		// Wait until all threads have arrived at the unarmed barrier before arming it.
+		while barrier.arrived.load(Ordering::SeqCst) < 3 {
+			thread::yield_now();
+		}
+
+		barrier.reset(4);
+		assert!(barrier.wait());
+
+		for thread in threads {
+			assert!(!thread.join().unwrap());
+		}
+	}
+}
@@ -25,6 +25,9 @@
 
 //! Synchronization primitives
 
+pub mod barrier;
+pub mod mutex;
+pub mod once;
 pub mod recmutex;
 pub mod semaphore;
 pub mod spinlock;
@@ -0,0 +1,197 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use arch;
+use arch::irq;
+use arch::percore::*;
+use arch::processor;
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::marker::Sync;
+use core::ops::{Deref, DerefMut, Drop};
+use core::sync::atomic::{AtomicBool, Ordering};
+use scheduler;
+use scheduler::task::PriorityTaskQueue;
+use synch::spinlock::Spinlock;
+
+/// Number of checks of a contended Mutex before the waiting task blocks.
+/// This avoids the cost of blocking and waking up a task for short critical sections.
+const MUTEX_SPIN_CHECKS: usize = 100;
+
+
+/// This type provides a lock, which blocks the waiting task instead of busy waiting.
+///
+/// # Description
+///
+/// On contention, the task first checks the lock for a short time (only if other cores are
+/// online, which could release it meanwhile) and then blocks until the lock is released.
+/// This makes it suitable for longer critical sections, where a Spinlock wastes a lot of cycles.
+///
+/// - It must not be used in interrupt handlers. Before the scheduler is running or while
+///   interrupts are disabled, it falls back to busy waiting.
+/// - Unlike RecursiveMutex, it does not track its owner and therefore does not implement
+///   priority inheritance.
+/// - Waiting tasks are woken up by their priority, but a running task may acquire the lock
+///   before a woken one.
+///
+/// # Simple examples
+///
+/// ```
+/// let mutex = synch::mutex::Mutex::new(0);
+///
+/// // Modify the data
+/// {
+///     let mut data = mutex.lock();
+///     *data = 2;
+/// }
+/// ```
+pub struct Mutex<T: ?Sized> {
+	is_locked: AtomicBool,
+	/// Priority queue of blocked tasks waiting for the lock
+	queue: Spinlock<PriorityTaskQueue>,
+	data: UnsafeCell<T>,
+}
+
+/// A guard to which the protected data can be accessed
+///
+/// When the guard falls out of scope it will release the lock.
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+	mutex: &'a Mutex<T>,
+}
+
+// Same unsafe impls as `Spinlock`
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+	pub fn new(user_data: T) -> Mutex<T> {
+		Mutex {
+			is_locked: AtomicBool::new(false),
+			queue: Spinlock::new(PriorityTaskQueue::new()),
+			data: UnsafeCell::new(user_data),
+		}
+	}
+}
+
+impl<T: ?Sized> Mutex<T> {
+	#[inline]
+	fn try_obtain_lock(&self) -> bool {
+		!self.is_locked.compare_and_swap(false, true, Ordering::SeqCst)
+	}
+
+	fn obtain_lock(&self) {
+		// The uncontended case only costs the single check below.
+		if self.try_obtain_lock() {
+			return;
+		}
+
+		// On a single core, the owner cannot release the lock while we are checking it.
+		if arch::online_cpus() > 1 {
+			for _ in 0..MUTEX_SPIN_CHECKS {
+				processor::pause();
+				if !self.is_locked.load(Ordering::Relaxed) && self.try_obtain_lock() {
+					return;
+				}
+			}
+		}
+
+		loop {
+			if self.try_obtain_lock() {
+				return;
+			}
+
+			// Blocking is only possible in task context.
+			let core_scheduler = match try_core_scheduler() {
+				Some(core_scheduler) => core_scheduler,
+				None => {
+					processor::pause();
+					continue;
+				}
+			};
+
+			if !irq::interrupts_enabled() {
+				processor::pause();
+				continue;
+			}
+
+			{
+				// Check the lock again while holding the queue lock.
+				// The owner releases the lock before checking the queue, so it cannot miss us.
+				let mut queue = self.queue.lock();
+				if self.try_obtain_lock() {
+					return;
+				}
+
+				// Block the current task and add it to the wakeup queue.
+				core_scheduler.blocked_tasks.lock().add(core_scheduler.current_task.clone(), None);
+				queue.push(core_scheduler.current_task.clone());
+			}
+
+			// Switch to the next task.
+			core_scheduler.scheduler();
+		}
+	}
+
+	pub fn lock(&self) -> MutexGuard<T> {
+		self.obtain_lock();
+		MutexGuard { mutex: self }
+	}
+
+	fn release_lock(&self) {
+		self.is_locked.store(false, Ordering::SeqCst);
+
+		// Wake up the task with the highest priority that has been waiting for this mutex.
+		if let Some(task) = self.queue.lock().pop() {
+			let core_scheduler = scheduler::get_scheduler(task.borrow().core_id);
+			core_scheduler.blocked_tasks.lock().custom_wakeup(task);
+		}
+	}
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "is_locked: {}", self.is_locked.load(Ordering::SeqCst))
+	}
+}
+
+impl<T: ?Sized + Default> Default for Mutex<T> {
+	fn default() -> Mutex<T> {
+		Mutex::new(Default::default())
+	}
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+	type Target = T;
+	fn deref<'b>(&'b self) -> &'b T { unsafe { &*self.mutex.data.get() } }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+	fn deref_mut<'b>(&'b mut self) -> &'b mut T { unsafe { &mut *self.mutex.data.get() } }
+}
+
+impl<'a, T: ?Sized> Drop for MutexGuard<'a, T> {
+	/// The dropping of the MutexGuard will release the lock it was created from.
+	fn drop(&mut self) {
+		self.mutex.release_lock();
+	}
+}
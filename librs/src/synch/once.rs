@@ -0,0 +1,141 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use arch::processor;
+use core::cell::UnsafeCell;
+use core::marker::Sync;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// The value has not been initialized yet.
+const INCOMPLETE: usize = 0;
+/// A core is currently running the initializer.
+const RUNNING: usize = 1;
+/// The value is initialized and can be read without any synchronization.
+const COMPLETE: usize = 2;
+
+
+/// This type initializes a value exactly once, even if several cores race for it.
+///
+/// # Description
+///
+/// Unlike lazy_static!, it can be declared as a regular static and the place of the
+/// initialization is explicit. The first caller of `call_once` runs the initializer, while
+/// all concurrent callers busy-wait until it has finished. Therefore, it can be used before
+/// the scheduler is running, but the initializer must not call `call_once` on the same
+/// instance again.
+///
+/// # Simple examples
+///
+/// ```
+/// static VALUE: synch::once::Once<usize> = synch::once::Once::new();
+///
+/// let value = VALUE.call_once(|| 42);
+/// assert_eq!(*value, 42);
+/// ```
+pub struct Once<T> {
+	state: AtomicUsize,
+	data: UnsafeCell<Option<T>>,
+}
+
+// The value is only written once before the state becomes COMPLETE and then only shared.
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+	pub const fn new() -> Once<T> {
+		Once {
+			state: AtomicUsize::new(INCOMPLETE),
+			data: UnsafeCell::new(None),
+		}
+	}
+
+	/// Returns the value, which is initialized by calling `f` if this is the first call.
+	pub fn call_once<F: FnOnce() -> T>(&self, f: F) -> &T {
+		if self.state.compare_and_swap(INCOMPLETE, RUNNING, Ordering::SeqCst) == INCOMPLETE {
+			unsafe { *self.data.get() = Some(f()); }
+			self.state.store(COMPLETE, Ordering::SeqCst);
+		} else {
+			while self.state.load(Ordering::SeqCst) != COMPLETE {
+				processor::pause();
+			}
+		}
+
+		self.get().unwrap()
+	}
+
+	/// Returns the value or `None` if it has not been initialized yet.
+	pub fn get(&self) -> Option<&T> {
+		if self.state.load(Ordering::SeqCst) == COMPLETE {
+			unsafe { (*self.data.get()).as_ref() }
+		} else {
+			None
+		}
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::{Arc, Barrier};
+	use std::thread;
+
+	#[test]
+	fn exactly_one_racing_initializer_runs() {
+		const CORES: usize = 8;
+
+		let once = Arc::new(Once::new());
+		let initializations = Arc::new(AtomicUsize::new(0));
+		let start = Arc::new(Barrier::new(CORES));
+
+		let threads: Vec<_> = (0..CORES).map(|i| {
+			let (once, initializations, start) = (once.clone(), initializations.clone(), start.clone());
+			thread::spawn(move || {
+				// Release all threads at once, so that they race for the initialization.
+				start.wait();
+				*once.call_once(|| {
+					initializations.fetch_add(1, Ordering::SeqCst);
+					thread::yield_now();
+					i
+				})
+			})
+		}).collect();
+
+		let values: Vec<usize> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+		assert_eq!(initializations.load(Ordering::SeqCst), 1);
+
+		// Every thread sees the value of the winning initializer.
+		assert!(values.iter().all(|&value| value == values[0]));
+		assert_eq!(once.get(), Some(&values[0]));
+	}
+
+	#[test]
+	fn uninitialized_values_are_not_returned() {
+		let once = Once::new();
+		assert_eq!(once.get(), None);
+		assert_eq!(*once.call_once(|| 1), 1);
+
+		// Later initializers are never called.
+		assert_eq!(*once.call_once(|| 2), 1);
+	}
+}
@@ -21,18 +21,64 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use alloc::rc::Rc;
+use alloc::sync::Arc;
 use arch::percore::*;
+use core::cell::RefCell;
 use scheduler;
-use scheduler::task::{PriorityTaskQueue, TaskId};
+use scheduler::task::{Priority, PriorityInheritance, PriorityTaskQueue, Task};
 use synch::spinlock::Spinlock;
 
 
 struct RecursiveMutexState {
-	current_tid: Option<TaskId>,
+	/// Task that currently holds the mutex
+	owner: Option<Rc<RefCell<Task>>>,
+	/// Inherited priorities of the owner, which can be changed without borrowing it
+	owner_inheritance: Option<Arc<PriorityInheritance>>,
+	/// Priority the owner currently inherits through this mutex
+	boost: Option<Priority>,
 	count: usize,
 	queue: PriorityTaskQueue,
 }
 
+impl RecursiveMutexState {
+	/// Passes the highest priority of all waiting tasks on to the owner.
+	/// Returns whether the inherited priorities of the owner have changed.
+	fn update_boost(&mut self) -> bool {
+		let inheritance = match self.owner_inheritance {
+			Some(ref inheritance) => inheritance.clone(),
+			None => return false,
+		};
+
+		let boost = self.queue.highest_priority();
+		if boost == self.boost {
+			return false;
+		}
+
+		if let Some(old_boost) = self.boost {
+			inheritance.remove_boost(old_boost);
+		}
+		if let Some(new_boost) = boost {
+			inheritance.add_boost(new_boost);
+		}
+
+		self.boost = boost;
+		true
+	}
+}
+
+/// A blocking mutex, which may be acquired multiple times by the same task.
+///
+/// The mutex implements priority inheritance: The owner inherits the highest priority of all
+/// tasks waiting for it, so a task with a medium priority cannot keep the owner from releasing
+/// the mutex. Every task tracks the priorities inherited through all mutexes it holds and runs
+/// with the highest of them, so releasing one mutex only drops the boost gained through it.
+/// Inheritance is transitive: If the owner itself waits for another mutex, the owner of that
+/// one is boosted as well.
+///
+/// Priority inheritance is only possible for locks integrated with the scheduler, which
+/// know their owner. Semaphores have no owner and the spinlocks in synch::spinlock never
+/// block a task, so neither of them inherits priorities.
 pub struct RecursiveMutex {
 	state: Spinlock<RecursiveMutexState>,
 }
@@ -41,7 +87,9 @@ impl RecursiveMutex {
 	pub fn new() -> Self {
 		Self {
 			state: Spinlock::new(RecursiveMutexState {
-				current_tid: None,
+				owner: None,
+				owner_inheritance: None,
+				boost: None,
 				count: 0,
 				queue: PriorityTaskQueue::new(),
 			}),
@@ -51,31 +99,51 @@ impl RecursiveMutex {
 	pub fn acquire(&self) {
 		// Get information about the current task.
 		let core_scheduler = core_scheduler();
-		let tid = core_scheduler.current_task.borrow().id;
+		let inheritance = core_scheduler.current_task.borrow().inheritance.clone();
 
 		loop {
-			{
+			let boosted_owner = {
 				let mut locked_state = self.state.lock();
 
 				// Is the mutex currently acquired?
-				if let Some(current_tid) = locked_state.current_tid {
+				if let Some(owner) = locked_state.owner.clone() {
 					// Has it been acquired by the same task?
-					if current_tid == tid {
+					if Rc::ptr_eq(&owner, &core_scheduler.current_task) {
 						// Yes, so just increment the counter (recursive mutex behavior).
 						locked_state.count += 1;
 						return;
 					}
 				} else {
 					// The mutex is currently not acquired, so we become its new owner.
-					locked_state.current_tid = Some(tid);
+					// We inherit the priority of the tasks still waiting for it.
+					inheritance.set_blocked_on(None);
+					locked_state.owner = Some(core_scheduler.current_task.clone());
+					locked_state.owner_inheritance = Some(inheritance.clone());
 					locked_state.count = 1;
+					if locked_state.update_boost() {
+						core_scheduler.current_task.borrow_mut().update_priority();
+					}
 					return;
 				}
 
 				// The mutex is currently acquired by another task.
 				// Block the current task and add it to the wakeup queue.
+				inheritance.set_blocked_on(Some(self as *const Self));
 				core_scheduler.blocked_tasks.lock().add(core_scheduler.current_task.clone(), None);
 				locked_state.queue.push(core_scheduler.current_task.clone());
+
+				// Boost the owner to our priority, so it can release the mutex without being
+				// preempted by tasks with a priority between its own one and ours.
+				let mut boosted_owner = None;
+				if locked_state.update_boost() {
+					boosted_owner = Some((locked_state.owner.clone().unwrap(), locked_state.owner_inheritance.clone().unwrap()));
+				}
+
+				boosted_owner
+			};
+
+			if let Some((owner, owner_inheritance)) = boosted_owner {
+				apply_boost(owner, owner_inheritance);
 			}
 
 			// Switch to the next task.
@@ -92,8 +160,15 @@ impl RecursiveMutex {
 		// Decrement the counter (recursive mutex behavior).
 		locked_state.count -= 1;
 		if locked_state.count == 0 {
-			// Release the entire recursive mutex.
-			locked_state.current_tid = None;
+			// Release the entire recursive mutex and drop the priority inherited through it.
+			// Priorities inherited through other mutexes still held by the owner are kept.
+			if let Some(owner) = locked_state.owner.take() {
+				let inheritance = locked_state.owner_inheritance.take().unwrap();
+				if let Some(boost) = locked_state.boost.take() {
+					inheritance.remove_boost(boost);
+					owner.borrow_mut().update_priority();
+				}
+			}
 
 			// Wake up any task that has been waiting for this mutex.
 			if let Some(task) = locked_state.queue.pop() {
@@ -103,3 +178,44 @@ impl RecursiveMutex {
 		}
 	}
 }
+
+/// Makes a task pick up a changed inherited priority and follows the mutexes it waits for,
+/// so that the owners of these mutexes inherit the priority as well.
+///
+/// A task is only borrowed while it is removed from a wait queue or a ready queue and the queue
+/// is locked. A running task may be borrowed by its core at any time, so it is left alone and
+/// picks up its new priority the next time it is scheduled.
+fn apply_boost(mut task: Rc<RefCell<Task>>, mut inheritance: Arc<PriorityInheritance>) {
+	loop {
+		if let Some(mutex) = inheritance.blocked_on() {
+			let mut next = None;
+
+			{
+				let mut locked_state = unsafe { (*mutex).state.lock() };
+
+				// Requeue the task with its new priority, if it is still waiting for the mutex.
+				if locked_state.queue.remove(task.clone()) {
+					locked_state.queue.push(task.clone());
+					if !locked_state.update_boost() {
+						return;
+					}
+
+					if let (Some(owner), Some(owner_inheritance)) = (locked_state.owner.clone(), locked_state.owner_inheritance.clone()) {
+						next = Some((owner, owner_inheritance));
+					} else {
+						return;
+					}
+				}
+			}
+
+			if let Some((owner, owner_inheritance)) = next {
+				task = owner;
+				inheritance = owner_inheritance;
+				continue;
+			}
+		}
+
+		scheduler::requeue_task(&task);
+		return;
+	}
+}
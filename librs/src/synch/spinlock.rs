@@ -22,11 +22,98 @@
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use arch::irq;
-use core::sync::atomic::{AtomicUsize, AtomicBool, Ordering, spin_loop_hint};
+use arch::percore;
+use arch::processor;
+use core::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
 use core::cell::UnsafeCell;
 use core::marker::Sync;
 use core::fmt;
 use core::ops::{Drop, Deref, DerefMut};
+use environment;
+
+/// Number of busy-waiting iterations per task queued in front of us before the first check of a contended lock.
+static BACKOFF_ITERATIONS_PER_WAITER: AtomicUsize = AtomicUsize::new(32);
+
+/// Upper bound for the busy-waiting iterations between two checks of a contended lock.
+static BACKOFF_MAXIMUM_ITERATIONS: AtomicUsize = AtomicUsize::new(4096);
+
+/// Number of unsuccessful checks of a contended Spinlock after which the waiting task yields the CPU, or 0 to never yield.
+static YIELD_THRESHOLD: AtomicUsize = AtomicUsize::new(0);
+
+/// Configure the backoff of contended Spinlocks and SpinlockIrqSaves.
+///
+/// A waiting task initially busy-waits `iterations_per_waiter` iterations for each task queued in front of it
+/// before checking the lock again. The wait doubles after each unsuccessful check, up to `maximum_iterations`.
+pub fn set_backoff_parameters(iterations_per_waiter: usize, maximum_iterations: usize) {
+	BACKOFF_ITERATIONS_PER_WAITER.store(iterations_per_waiter, Ordering::SeqCst);
+	BACKOFF_MAXIMUM_ITERATIONS.store(maximum_iterations, Ordering::SeqCst);
+}
+
+/// Let a task waiting for a contended Spinlock yield the CPU after `checks` unsuccessful checks, or never for 0.
+///
+/// This is off by default: Once a waiter has drawn a ticket, all later tickets depend on it taking and
+/// releasing the lock promptly, which a descheduled waiter does not do. Yielding only pays off if the
+/// lock holder may be waiting for the CPU itself, e.g. on an oversubscribed host.
+pub fn set_yield_threshold(checks: usize) {
+	YIELD_THRESHOLD.store(checks, Ordering::SeqCst);
+}
+
+/// Applies the "spin_backoff=<iterations per waiter>,<maximum iterations>" and "spin_yield=<checks>"
+/// command-line arguments.
+pub fn configure() {
+	if let Some(value) = environment::get_arg("spin_backoff") {
+		let mut parameters = value.split(',').map(environment::parse_integer);
+		match (parameters.next(), parameters.next(), parameters.next()) {
+			(Some(Some(iterations_per_waiter)), Some(Some(maximum_iterations)), None) => {
+				set_backoff_parameters(iterations_per_waiter, maximum_iterations);
+			},
+			_ => warn!("Ignoring invalid spin_backoff \"{}\"", value),
+		}
+	}
+
+	if let Some(value) = environment::get_arg("spin_yield") {
+		match environment::parse_integer(value) {
+			Some(checks) => set_yield_threshold(checks),
+			None => warn!("Ignoring invalid spin_yield \"{}\"", value),
+		}
+	}
+}
+
+/// Wait before checking a contended ticket lock again.
+///
+/// As tickets are served in order, the initial wait is proportional to the number of tasks in front of us.
+/// This keeps waiting cores from hammering the cache line of the lock.
+#[inline]
+fn backoff(waiters_in_front: usize, checks: usize) {
+	let maximum_iterations = BACKOFF_MAXIMUM_ITERATIONS.load(Ordering::Relaxed);
+	let mut iterations = waiters_in_front.saturating_mul(BACKOFF_ITERATIONS_PER_WAITER.load(Ordering::Relaxed));
+	for _ in 1..checks {
+		if iterations >= maximum_iterations {
+			break;
+		}
+		iterations = iterations.saturating_mul(2);
+	}
+	if iterations > maximum_iterations {
+		iterations = maximum_iterations;
+	}
+
+	for _ in 0..iterations {
+		processor::pause();
+	}
+}
+
+/// Lets other tasks run after `checks` unsuccessful checks of a contended Spinlock if enabled through set_yield_threshold.
+/// Code running with interrupts disabled, which includes interrupt handlers, and cores without a scheduler keep spinning.
+#[inline]
+fn yield_if_spinning_long(checks: usize) {
+	let threshold = YIELD_THRESHOLD.load(Ordering::Relaxed);
+	if threshold > 0 && checks >= threshold && irq::interrupts_enabled() {
+		if let Some(core_scheduler) = percore::try_core_scheduler() {
+			core_scheduler.scheduler();
+		}
+	}
+}
+
 
 /// This type provides a lock based on busy waiting to realize mutual exclusion of tasks.
 ///
@@ -37,6 +124,9 @@ use core::ops::{Drop, Deref, DerefMut};
 /// - By using busy waiting, it can be used outside the runtime.
 /// - It is a so called ticket lock (https://en.wikipedia.org/wiki/Ticket_lock)
 ///   and completly fair.
+/// - Contended waiters back off exponentially, starting proportionally to their position
+///   in the queue (see set_backoff_parameters), and may optionally yield the CPU
+///   (see set_yield_threshold).
 ///
 /// The interface is derived from https://mvdnes.github.io/rust-docs/spin-rs/spin/index.html.
 ///
@@ -107,8 +197,18 @@ impl<T: ?Sized> Spinlock<T>
 {
 	fn obtain_lock(&self) {
 		let ticket = self.queue.fetch_add(1, Ordering::SeqCst) + 1;
-		while self.dequeue.load(Ordering::SeqCst) != ticket {
-			spin_loop_hint();
+		let mut checks = 0;
+
+		// The uncontended case only costs the single check below.
+		loop {
+			let current_ticket = self.dequeue.load(Ordering::SeqCst);
+			if current_ticket == ticket {
+				break;
+			}
+
+			checks += 1;
+			backoff(ticket.wrapping_sub(current_ticket), checks);
+			yield_if_spinning_long(checks);
 		}
 	}
 
@@ -170,6 +270,7 @@ impl<'a, T: ?Sized> Drop for SpinlockGuard<'a, T>
 /// - By using busy waiting, it can be used outside the runtime.
 /// - It is a so called ticket lock (https://en.wikipedia.org/wiki/Ticket_lock)
 ///   and completly fair.
+/// - Contended waiters back off like for Spinlock, but never yield the CPU.
 ///
 /// The interface is derived from https://mvdnes.github.io/rust-docs/spin-rs/spin/index.html.
 ///
@@ -245,8 +346,17 @@ impl<T: ?Sized> SpinlockIrqSave<T>
 		let irq = irq::nested_disable();
 
 		let ticket = self.queue.fetch_add(1, Ordering::SeqCst) + 1;
-		while self.dequeue.load(Ordering::SeqCst) != ticket {
-			spin_loop_hint();
+		let mut checks = 0;
+
+		// Interrupts are disabled, so a waiter backs off like for Spinlock, but never yields.
+		loop {
+			let current_ticket = self.dequeue.load(Ordering::SeqCst);
+			if current_ticket == ticket {
+				break;
+			}
+
+			checks += 1;
+			backoff(ticket.wrapping_sub(current_ticket), checks);
 		}
 
 		self.irq.store(irq, Ordering::SeqCst);
@@ -263,6 +373,28 @@ impl<T: ?Sized> SpinlockIrqSave<T>
 			data: unsafe { &mut *self.data.get() },
 		}
 	}
+
+	/// Takes the lock only if it is free, without queueing up behind other tasks.
+	pub fn try_lock(&self) -> Option<SpinlockIrqSaveGuard<T>>
+	{
+		let irq = irq::nested_disable();
+
+		// The lock is free if the last ticket handed out is the one before the ticket served next.
+		let ticket = self.dequeue.load(Ordering::SeqCst);
+		if self.queue.compare_exchange(ticket.wrapping_sub(1), ticket, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+			irq::nested_enable(irq);
+			return None;
+		}
+
+		self.irq.store(irq, Ordering::SeqCst);
+		Some(SpinlockIrqSaveGuard
+		{
+			//queue: &self.queue,
+			dequeue: &self.dequeue,
+			irq: &self.irq,
+			data: unsafe { &mut *self.data.get() },
+		})
+	}
 }
 
 impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinlockIrqSave<T>
@@ -302,3 +434,38 @@ impl<'a, T: ?Sized> Drop for SpinlockIrqSaveGuard<'a, T>
 		irq::nested_enable(irq);
 	}
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Arc;
+	use std::thread;
+
+	#[test]
+	fn lock_is_granted_in_fifo_order() {
+		const WAITERS: usize = 8;
+
+		let spinlock = Arc::new(Spinlock::new(Vec::new()));
+		let guard = spinlock.lock();
+
+		// Let the waiters queue up one after another, so their tickets are in the order of their indices.
+		let threads: Vec<_> = (0..WAITERS).map(|i| {
+			let waiter_spinlock = spinlock.clone();
+			let thread = thread::spawn(move || waiter_spinlock.lock().push(i));
+
+			while spinlock.queue.load(Ordering::SeqCst) != i + 2 {
+				thread::yield_now();
+			}
+
+			thread
+		}).collect();
+
+		drop(guard);
+		for thread in threads {
+			thread.join().unwrap();
+		}
+
+		assert_eq!(*spinlock.lock(), (0..WAITERS).collect::<Vec<usize>>());
+	}
+}
@@ -0,0 +1,71 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use arch::fb;
+use errno::*;
+
+
+/// Stores the width and height of the framebuffer in pixels in `width` and `height`.
+/// Returns -ENODEV if the kernel has not found a supported framebuffer.
+#[no_mangle]
+pub extern "C" fn sys_framebuffer_dimensions(width: *mut usize, height: *mut usize) -> i32 {
+	let (framebuffer_width, framebuffer_height) = match fb::dimensions() {
+		Some(dimensions) => dimensions,
+		None => return -ENODEV,
+	};
+
+	if width.is_null() || height.is_null() {
+		return -EINVAL;
+	}
+
+	unsafe {
+		*width = framebuffer_width;
+		*height = framebuffer_height;
+	}
+	0
+}
+
+/// Sets the pixel at `x`, `y` to the 0x00RRGGBB color `color`.
+/// Like all drawing functions, this only becomes visible through sys_framebuffer_present.
+#[no_mangle]
+pub extern "C" fn sys_framebuffer_put_pixel(x: usize, y: usize, color: u32) {
+	fb::put_pixel(x, y, color);
+}
+
+/// Fills a rectangle with the 0x00RRGGBB color `color`. Parts outside the screen are clipped.
+#[no_mangle]
+pub extern "C" fn sys_framebuffer_fill_rect(x: usize, y: usize, width: usize, height: usize, color: u32) {
+	fb::fill_rect(x, y, width, height, color);
+}
+
+/// Moves the screen content up by `lines` pixel lines and fills the freed lines with `color`.
+#[no_mangle]
+pub extern "C" fn sys_framebuffer_scroll_up(lines: usize, color: u32) {
+	fb::scroll_up(lines, color);
+}
+
+/// Makes all drawing since the last call visible on the screen.
+#[no_mangle]
+pub extern "C" fn sys_framebuffer_present() {
+	fb::present();
+}
@@ -22,12 +22,12 @@
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use arch;
+use arch::io::Port;
 use arch::mm::paging;
 use scheduler;
 use syscalls::{LWIP_FD_BIT,LWIP_LOCK};
 use syscalls::interfaces::SyscallInterface;
 use syscalls::lwip::sys_lwip_get_errno;
-use x86::shared::io::*;
 
 const UHYVE_PORT_WRITE: u16 = 0x400;
 const UHYVE_PORT_OPEN:	u16 = 0x440;
@@ -45,7 +45,7 @@ extern "C" {
 /// forward a request to the hypervisor uhyve
 fn uhyve_send(port: u16, data: usize)
 {
-	unsafe { outl(port, data as u32); }
+	unsafe { Port::<u32>::new(port).write(data as u32); }
 }
 
 #[repr(C)]
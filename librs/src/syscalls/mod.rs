@@ -22,30 +22,45 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+mod framebuffer;
 mod interfaces;
 mod lwip;
+mod panic;
 mod processor;
+mod ramdisk;
 mod random;
 mod recmutex;
 mod semaphore;
+mod shm;
 mod spinlock;
 mod tasks;
 mod timer;
 
+pub use self::framebuffer::*;
 pub use self::lwip::*;
+pub use self::panic::*;
 pub use self::processor::*;
+pub use self::ramdisk::*;
 pub use self::random::*;
 pub use self::recmutex::*;
 pub use self::semaphore::*;
+pub use self::shm::*;
 pub use self::spinlock::*;
 pub use self::tasks::*;
 pub use self::timer::*;
+use core::{slice, str};
 use environment;
+use errno::*;
+use fs;
 use synch::spinlock::SpinlockIrqSave;
 use syscalls::interfaces::SyscallInterface;
 
 const LWIP_FD_BIT: i32	= (1 << 30);
 
+/// Mask of the access mode in the flags of sys_open and the read-only mode.
+const O_ACCMODE: i32 = 3;
+const O_RDONLY: i32 = 0;
+
 pub static LWIP_LOCK: SpinlockIrqSave<()> = SpinlockIrqSave::new(());
 static mut SYS: &'static SyscallInterface = &interfaces::Generic;
 
@@ -69,28 +84,77 @@ pub extern "C" fn sys_shutdown() -> ! {
 	unsafe { SYS.shutdown() }
 }
 
+/// Returns the NUL-terminated string at `name` or None if it is no valid UTF-8.
+unsafe fn c_str<'a>(name: *const u8) -> Option<&'a str> {
+	let mut length = 0;
+	while *name.offset(length as isize) != 0 {
+		length += 1;
+	}
+
+	str::from_utf8(slice::from_raw_parts(name, length)).ok()
+}
+
+/// Opens the file `name`. Files of the mounted ramfs are opened read-only inside the kernel,
+/// everything else is passed on to the host.
 #[no_mangle]
 pub extern "C" fn sys_open(name: *const u8, flags: i32, mode: i32) -> i32 {
+	if !name.is_null() && (flags & O_ACCMODE) == O_RDONLY {
+		if let Some(fd) = unsafe { c_str(name) }.and_then(fs::open) {
+			return fd;
+		}
+	}
+
 	unsafe { SYS.open(name, flags, mode) }
 }
 
 #[no_mangle]
 pub extern "C" fn sys_close(fd: i32) -> i32 {
+	if fs::is_ramfs_fd(fd) {
+		return match fs::close(fd) {
+			Ok(()) => 0,
+			Err(()) => -EBADF,
+		};
+	}
+
 	unsafe { SYS.close(fd) }
 }
 
 #[no_mangle]
 pub extern "C" fn sys_read(fd: i32, buf: *mut u8, len: usize) -> isize {
+	if fs::is_ramfs_fd(fd) {
+		if buf.is_null() && len > 0 {
+			return -EINVAL as isize;
+		}
+
+		let buffer = if len > 0 { unsafe { slice::from_raw_parts_mut(buf, len) } } else { &mut [][..] };
+		return match fs::read(fd, buffer) {
+			Ok(length) => length as isize,
+			Err(()) => -EBADF as isize,
+		};
+	}
+
 	unsafe { SYS.read(fd, buf, len) }
 }
 
 #[no_mangle]
 pub extern "C" fn sys_write(fd: i32, buf: *const u8, len: usize) -> isize {
+	if fs::is_ramfs_fd(fd) {
+		// ramfs is read-only.
+		return -EBADF as isize;
+	}
+
 	unsafe { SYS.write(fd, buf, len) }
 }
 
 #[no_mangle]
 pub extern "C" fn sys_lseek(fd: i32, offset: isize, whence: i32) -> isize {
+	if fs::is_ramfs_fd(fd) {
+		return match fs::lseek(fd, offset, whence) {
+			Ok(position) => position as isize,
+			Err(()) => -EINVAL as isize,
+		};
+	}
+
 	unsafe { SYS.lseek(fd, offset, whence) }
 }
 
@@ -0,0 +1,66 @@
+// Copyright (c) 2017 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use core::panic::PanicInfo;
+use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use panic_info;
+
+
+/// Signature of a panic hook set by the application.
+/// It gets the reason of the panic (without a terminating NUL character) and the number of panics since boot.
+pub type PanicHook = extern "C" fn(reason: *const u8, length: usize, count: usize);
+
+/// Address of the hook set by sys_set_panic_hook or 0 if none is set.
+static APPLICATION_HOOK: AtomicUsize = AtomicUsize::new(0);
+
+
+/// Forwards the panic recorded by the panic handler to the hook of the application.
+fn run_application_hook(_info: &PanicInfo) {
+	let hook_address = APPLICATION_HOOK.load(Ordering::SeqCst);
+	if hook_address == 0 {
+		return;
+	}
+
+	let hook: PanicHook = unsafe { mem::transmute(hook_address) };
+	let reason = panic_info::last().unwrap_or("");
+	hook(reason.as_ptr(), reason.len(), panic_info::count());
+}
+
+/// Sets a hook that is called when the kernel panics, before the panic is printed and the system halts.
+/// Passing a null pointer removes the hook again.
+///
+/// The hook runs with interrupts disabled and must neither allocate memory nor call back into the kernel.
+#[no_mangle]
+pub extern "C" fn sys_set_panic_hook(hook: Option<PanicHook>) {
+	match hook {
+		Some(hook) => {
+			APPLICATION_HOOK.store(hook as usize, Ordering::SeqCst);
+			panic_info::set_hook(run_application_hook);
+		},
+		None => {
+			panic_info::take_hook();
+			APPLICATION_HOOK.store(0, Ordering::SeqCst);
+		}
+	}
+}
@@ -0,0 +1,57 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use core::slice;
+use errno::*;
+use ramdisk;
+
+
+/// Returns the size in bytes of the ramdisk selected by the "ramdisk" command-line argument
+/// or 0 if there is none.
+#[no_mangle]
+pub extern "C" fn sys_ramdisk_size() -> usize {
+	ramdisk::get().map_or(0, |ramdisk| ramdisk.size())
+}
+
+/// Reads `len / 512` blocks of the ramdisk starting at block `first_block` into `buf`.
+/// `len` must be a multiple of the block size of 512 bytes.
+#[no_mangle]
+pub extern "C" fn sys_ramdisk_read(first_block: usize, buf: *mut u8, len: usize) -> i32 {
+	let ramdisk = match ramdisk::get() {
+		Some(ramdisk) => ramdisk,
+		None => return -ENODEV,
+	};
+
+	if len == 0 {
+		return 0;
+	}
+	if buf.is_null() {
+		return -EINVAL;
+	}
+
+	let buffer = unsafe { slice::from_raw_parts_mut(buf, len) };
+	match ramdisk.read_blocks(first_block, buffer) {
+		Ok(()) => 0,
+		Err(()) => -EINVAL,
+	}
+}
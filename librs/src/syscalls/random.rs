@@ -22,24 +22,32 @@
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
 use arch;
-use synch::spinlock::Spinlock;
-
-lazy_static! {
-	static ref PARK_MILLER_LEHMER_SEED: Spinlock<u32> =
-		Spinlock::new(arch::processor::get_timestamp() as u32);
-}
+use arch::irq;
+use arch::percore;
+use random;
 
 
+/// Each core keeps its own seed in its per-core scratch area, so no lock is taken.
+/// Interrupts are disabled to keep the task on this core and no other task from using the same seed meanwhile.
 fn generate_park_miller_lehmer_random_number() -> u32 {
-	let mut seed = PARK_MILLER_LEHMER_SEED.lock();
-	let random = (((*seed) as u64 * 48271) % 2147483647) as u32;
-	*seed = random;
-	random
+	let irq = irq::nested_disable();
+
+	let mut seed = percore::scratch(percore::SCRATCH_RANDOM_SEED) as u64;
+	if seed == 0 {
+		// The seed must be in the range 1 to 2^31 - 2.
+		seed = arch::processor::get_timestamp() as u64 % 2147483646 + 1;
+	}
+
+	let random = (seed * 48271) % 2147483647;
+	percore::set_scratch(percore::SCRATCH_RANDOM_SEED, random as usize);
+
+	irq::nested_enable(irq);
+	random as u32
 }
 
 #[no_mangle]
 pub extern "C" fn sys_rand() -> u32 {
-	if let Some(value) = arch::processor::generate_random_number() {
+	if let Some(value) = random::generate_random_number() {
 		value
 	} else {
 		generate_park_miller_lehmer_random_number()
@@ -0,0 +1,116 @@
+// Copyright (c) 2018 Colin Finck, RWTH Aachen University
+//
+// MIT License
+//
+// Permission is hereby granted, free of charge, to any person obtaining
+// a copy of this software and associated documentation files (the
+// "Software"), to deal in the Software without restriction, including
+// without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to
+// permit persons to whom the Software is furnished to do so, subject to
+// the following conditions:
+//
+// The above copyright notice and this permission notice shall be
+// included in all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND,
+// EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF
+// MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE
+// LIABLE FOR ANY CLAIM, DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION
+// OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
+// WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use alloc::boxed::Box;
+use arch::mm::paging::{BasePageSize, PageSize};
+use core::i32;
+use errno::*;
+use mm::shared::{self, Access, SharedMapping, ShmId};
+
+
+/// Creates a zeroed shared memory region of at least `size` bytes and returns its ID.
+#[no_mangle]
+pub extern "C" fn sys_shm_create(size: usize) -> i32 {
+	if size == 0 {
+		return -EINVAL;
+	}
+
+	let id = shared::create(align_up!(size, BasePageSize::SIZE));
+	if id.into() > i32::MAX as u32 {
+		let _ = shared::destroy(id);
+		return -ENOMEM;
+	}
+
+	id.into() as i32
+}
+
+/// Value of `writable` in sys_shm_map to get a copy-on-write mapping.
+const SHM_COPY_ON_WRITE: i32 = 2;
+
+/// Maps the shared memory region `id`, read-only if `writable` is zero, copy-on-write if it is SHM_COPY_ON_WRITE
+/// and read-write otherwise. Writes through a copy-on-write mapping are not visible to other mappings.
+/// The mapping is returned through `mapping` and must be released through sys_shm_unmap.
+#[no_mangle]
+pub extern "C" fn sys_shm_map(id: i32, writable: i32, mapping: *mut *mut SharedMapping) -> i32 {
+	if id < 0 || mapping.is_null() {
+		return -EINVAL;
+	}
+
+	let access = match writable {
+		0 => Access::ReadOnly,
+		SHM_COPY_ON_WRITE => Access::CopyOnWrite,
+		_ => Access::ReadWrite,
+	};
+	match shared::map(ShmId::from(id as u32), access) {
+		Some(shared_mapping) => {
+			unsafe { *mapping = Box::into_raw(Box::new(shared_mapping)); }
+			0
+		},
+		None => -EINVAL,
+	}
+}
+
+/// Returns the address of a mapping created by sys_shm_map.
+#[no_mangle]
+pub extern "C" fn sys_shm_address(mapping: *const SharedMapping) -> *mut u8 {
+	if mapping.is_null() {
+		return 0 as *mut u8;
+	}
+
+	unsafe { (*mapping).as_ptr() }
+}
+
+/// Returns the size in bytes of a mapping created by sys_shm_map.
+#[no_mangle]
+pub extern "C" fn sys_shm_size(mapping: *const SharedMapping) -> usize {
+	if mapping.is_null() {
+		return 0;
+	}
+
+	unsafe { (*mapping).size() }
+}
+
+#[no_mangle]
+pub extern "C" fn sys_shm_unmap(mapping: *mut SharedMapping) -> i32 {
+	if mapping.is_null() {
+		return -EINVAL;
+	}
+
+	// Consume the pointer to the raw memory into a Box again
+	// and drop the Box to remove the mapping.
+	unsafe { Box::from_raw(mapping); }
+	0
+}
+
+/// Destroys the shared memory region `id`. Existing mappings stay valid.
+#[no_mangle]
+pub extern "C" fn sys_shm_destroy(id: i32) -> i32 {
+	if id < 0 {
+		return -EINVAL;
+	}
+
+	match shared::destroy(ShmId::from(id as u32)) {
+		Ok(()) => 0,
+		Err(()) => -EINVAL,
+	}
+}
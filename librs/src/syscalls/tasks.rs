@@ -34,8 +34,7 @@ pub type Tid = u32;
 
 #[no_mangle]
 pub extern "C" fn sys_getpid() -> Tid {
-	let current_task_borrowed = core_scheduler().current_task.borrow();
-	current_task_borrowed.id.into() as Tid
+	scheduler::current_task_id().into() as Tid
 }
 
 #[no_mangle]
@@ -43,7 +42,7 @@ pub extern "C" fn sys_getprio(id: *const Tid) -> i32 {
 	let current_task_borrowed = core_scheduler().current_task.borrow();
 
 	if id.is_null() || unsafe {*id} == current_task_borrowed.id.into() as u32 {
-		current_task_borrowed.prio.into() as i32
+		current_task_borrowed.base_prio.into() as i32
 	} else {
 		-EINVAL
 	}
@@ -129,6 +128,12 @@ pub extern "C" fn sys_yield() {
 	core_scheduler().scheduler();
 }
 
+/// Returns the number of tasks that have been moved to another core by the load balancer.
+#[no_mangle]
+pub extern "C" fn sys_get_migrated_tasks() -> usize {
+	scheduler::migrated_tasks()
+}
+
 #[no_mangle]
 pub extern "C" fn sys_kill(dest: Tid, signum: i32) -> i32 {
 	debug!("sys_kill is unimplemented, returning -ENOSYS for killing {} with signal {}", dest, signum);